@@ -0,0 +1,557 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::backend::lexer::{tokenize, Token};
+use crate::backend::query_exec::ResultSink;
+use crate::backend::State;
+use crate::proto::messages::{
+    BackendMessage, ErrorResponse, ExtendedMessage, Field, Target, TransactionStatus,
+};
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+pub struct AuditEntry<'a> {
+    pub timestamp: SystemTime,
+    // Unique for every connection within this process
+    pub session_id: u64,
+    pub peer_addr: Option<SocketAddr>,
+    pub user: &'a str,
+    pub database: &'a str,
+    pub application_name: Option<&'a str>,
+    pub statement: &'a str,
+    pub duration: Duration,
+    pub rows: u64,
+    // 00000 when the statement succeeded
+    pub sqlstate: &'a str,
+}
+
+pub trait AuditSink: Send + Sync {
+    fn record(&self, entry: &AuditEntry);
+}
+
+// Replaces all literals in the query by `?`
+pub fn redact_literals(query: &str) -> String {
+    let mut redacted = String::with_capacity(query.len());
+    let mut last = 0;
+
+    for token in tokenize(query) {
+        if let Token::Literal(literal) = token {
+            let start = literal.as_ptr() as usize - query.as_ptr() as usize;
+            redacted.push_str(&query[last..start]);
+            redacted.push('?');
+            last = start + literal.len();
+        }
+    }
+
+    redacted.push_str(&query[last..]);
+    redacted
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+fn write_json_option(out: &mut String, s: Option<&str>) {
+    match s {
+        Some(s) => write_json_string(out, s),
+        None => out.push_str("null"),
+    }
+}
+
+// Formats the time as RFC 3339 in UTC, see http://howardhinnant.github.io/date_algorithms.html
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let days = secs.div_euclid(86400);
+    let secs_of_day = secs.rem_euclid(86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+// Writes every entry as a JSON object on its own line
+pub struct JsonLinesAudit {
+    path: PathBuf,
+    redact: bool,
+    // Rotation is disabled when zero
+    max_size: u64,
+    max_files: usize,
+    file: Mutex<LogFile>,
+}
+
+impl JsonLinesAudit {
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            redact: false,
+            max_size: 0,
+            max_files: 0,
+            file: Mutex::new(LogFile { file, size }),
+        })
+    }
+
+    // Replace literals in the statements so that no sensitive values end up in the log
+    pub fn redact(mut self) -> Self {
+        self.redact = true;
+        self
+    }
+
+    // Once the file exceeds the given size it's renamed to `<path>.1` (and older files to
+    // `<path>.2` etc.), only the given number of rotated files is kept
+    pub fn rotate(mut self, max_size: u64, max_files: usize) -> Self {
+        self.max_size = max_size;
+        self.max_files = max_files;
+        self
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate_files(&self, log: &mut LogFile) -> io::Result<()> {
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let path = self.rotated_path(n);
+
+                if path.exists() {
+                    fs::rename(path, self.rotated_path(n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        log.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        log.size = 0;
+
+        Ok(())
+    }
+
+    fn format(&self, entry: &AuditEntry) -> String {
+        let mut line = String::new();
+
+        line.push_str("{\"timestamp\":");
+        write_json_string(&mut line, &format_timestamp(entry.timestamp));
+        write!(line, ",\"session_id\":{}", entry.session_id).unwrap();
+        line.push_str(",\"peer_addr\":");
+        write_json_option(
+            &mut line,
+            entry.peer_addr.map(|addr| addr.to_string()).as_deref(),
+        );
+        line.push_str(",\"user\":");
+        write_json_string(&mut line, entry.user);
+        line.push_str(",\"database\":");
+        write_json_string(&mut line, entry.database);
+        line.push_str(",\"application_name\":");
+        write_json_option(&mut line, entry.application_name);
+        line.push_str(",\"statement\":");
+
+        if self.redact {
+            write_json_string(&mut line, &redact_literals(entry.statement));
+        } else {
+            write_json_string(&mut line, entry.statement);
+        }
+
+        write!(
+            line,
+            ",\"duration_ms\":{:.3},\"rows\":{}",
+            entry.duration.as_secs_f64() * 1000.0,
+            entry.rows
+        )
+        .unwrap();
+        line.push_str(",\"sqlstate\":");
+        write_json_string(&mut line, entry.sqlstate);
+        line.push_str("}\n");
+
+        line
+    }
+
+    fn write(&self, line: &str) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap();
+
+        if self.max_size > 0 && log.size > 0 && log.size + line.len() as u64 > self.max_size {
+            self.rotate_files(&mut log)?;
+        }
+
+        log.file.write_all(line.as_bytes())?;
+        log.size += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl AuditSink for JsonLinesAudit {
+    fn record(&self, entry: &AuditEntry) {
+        if let Err(e) = self.write(&self.format(entry)) {
+            log::error!("failed to write audit log: {}", e);
+        }
+    }
+}
+
+// Collects the statements and results of a connection and passes them to the sink once they
+// are complete. Extended protocol messages are recorded as one entry per Sync.
+pub(crate) struct Auditor {
+    sink: Arc<dyn AuditSink>,
+    session_id: u64,
+    peer_addr: Option<SocketAddr>,
+    // Query text of the prepared statements and portals
+    statements: HashMap<String, String>,
+    portals: HashMap<String, String>,
+    started: Option<Instant>,
+    executed: Vec<String>,
+    // Statements parsed or bound since the last call, recorded when the batch fails early
+    attempted: Vec<String>,
+    rows: u64,
+    sqlstate: Option<String>,
+}
+
+impl Auditor {
+    pub(crate) fn new(sink: Arc<dyn AuditSink>, peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            sink,
+            session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            statements: HashMap::new(),
+            portals: HashMap::new(),
+            started: None,
+            executed: vec![],
+            attempted: vec![],
+            rows: 0,
+            sqlstate: None,
+        }
+    }
+
+    pub(crate) fn query(&mut self, query: &str) {
+        self.started = Some(Instant::now());
+        self.executed.push(query.to_string());
+    }
+
+    pub(crate) fn extended(&mut self, msg: &ExtendedMessage) {
+        self.started.get_or_insert_with(Instant::now);
+
+        match msg {
+            ExtendedMessage::Parse(parse) => {
+                self.attempt(&parse.query);
+                self.statements
+                    .insert(parse.name.clone(), parse.query.clone());
+            }
+            ExtendedMessage::Bind(bind) => {
+                if let Some(query) = self.statements.get(&bind.statement).cloned() {
+                    self.attempt(&query);
+                    self.portals.insert(bind.portal.clone(), query);
+                }
+            }
+            ExtendedMessage::Execute(execute) => {
+                if let Some(query) = self.portals.get(&execute.portal) {
+                    self.executed.push(query.clone());
+                }
+            }
+            ExtendedMessage::Close(close) => {
+                match close.target {
+                    Target::Statement => self.statements.remove(&close.name),
+                    Target::Portal => self.portals.remove(&close.name),
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn attempt(&mut self, query: &str) {
+        if self.attempted.last().map(String::as_str) != Some(query) {
+            self.attempted.push(query.to_string());
+        }
+    }
+
+    pub(crate) fn observe(&mut self, msg: &BackendMessage) {
+        match msg {
+            BackendMessage::CommandComplete(cc) => {
+                self.rows += cc.command_tag.rows().unwrap_or_default().max(0) as u64
+            }
+            BackendMessage::ErrorResponse(e) => self.error(e),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn error(&mut self, e: &ErrorResponse) {
+        if self.sqlstate.is_none() {
+            self.sqlstate = e.get_field(Field::Code).map(str::to_string);
+        }
+    }
+
+    // Records the statements executed since the last call, portals end with the transaction.
+    // A batch which failed before Execute is recorded with the statements it parsed or bound.
+    pub(crate) fn complete(&mut self, state: &State, transaction_status: TransactionStatus) {
+        if transaction_status == TransactionStatus::Idle {
+            self.portals.clear();
+        }
+
+        let started = self.started.take();
+        let mut executed = std::mem::take(&mut self.executed);
+        let attempted = std::mem::take(&mut self.attempted);
+        let rows = std::mem::take(&mut self.rows);
+        let sqlstate = self.sqlstate.take();
+
+        if executed.is_empty() && sqlstate.is_some() {
+            executed = attempted;
+        }
+
+        if executed.is_empty() {
+            return;
+        }
+
+        let settings = state.settings();
+
+        self.sink.record(&AuditEntry {
+            timestamp: SystemTime::now(),
+            session_id: self.session_id,
+            peer_addr: self.peer_addr,
+            user: state.user(),
+            database: state.database(),
+            application_name: settings
+                .get("application_name")
+                .filter(|name| !name.is_empty()),
+            statement: &executed.join("; "),
+            duration: started.map(|s| s.elapsed()).unwrap_or_default(),
+            rows,
+            sqlstate: sqlstate.as_deref().unwrap_or("00000"),
+        });
+    }
+}
+
+// Lets the auditor observe the results on their way to the client
+pub(crate) struct AuditedSink<'a> {
+    inner: &'a mut dyn ResultSink,
+    auditor: Option<&'a mut Auditor>,
+}
+
+impl<'a> AuditedSink<'a> {
+    pub(crate) fn new(inner: &'a mut dyn ResultSink, auditor: Option<&'a mut Auditor>) -> Self {
+        Self { inner, auditor }
+    }
+}
+
+impl ResultSink for AuditedSink<'_> {
+    fn push(&mut self, msg: BackendMessage) -> io::Result<()> {
+        if let Some(auditor) = self.auditor.as_mut() {
+            auditor.observe(&msg);
+        }
+
+        self.inner.push(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::messages::{Bind, Close, Parse, Severity};
+
+    #[test]
+    fn test_redact_literals() {
+        assert_eq!(
+            redact_literals("select * from t where name = 'bob' and id = 42 -- 'x'"),
+            "select * from t where name = ? and id = ? -- 'x'"
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(format_timestamp(time), "2023-11-14T22:13:20.123Z");
+    }
+
+    #[test]
+    fn test_json_lines_rotation() {
+        let dir = std::env::temp_dir().join(format!("audit-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+
+        let audit = JsonLinesAudit::open(&path).unwrap().redact().rotate(300, 1);
+        let entry = AuditEntry {
+            timestamp: UNIX_EPOCH,
+            session_id: 1,
+            peer_addr: None,
+            user: "bob",
+            database: "bob",
+            application_name: Some("psql"),
+            statement: "select \"a\", 'secret'",
+            duration: Duration::from_micros(1500),
+            rows: 1,
+            sqlstate: "00000",
+        };
+
+        audit.record(&entry);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"session_id\":1,\"peer_addr\":null,\
+             \"user\":\"bob\",\"database\":\"bob\",\"application_name\":\"psql\",\
+             \"statement\":\"select \\\"a\\\", ?\",\"duration_ms\":1.500,\"rows\":1,\
+             \"sqlstate\":\"00000\"}\n"
+        );
+
+        // The second entry doesn't fit anymore
+        audit.record(&entry);
+        audit.record(&entry);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(
+            fs::read_to_string(dir.join("audit.log.1"))
+                .unwrap()
+                .lines()
+                .count(),
+            1
+        );
+        assert!(!dir.join("audit.log.2").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Keeps the application names of the entries
+    struct Names(Mutex<Vec<Option<String>>>);
+
+    impl AuditSink for Names {
+        fn record(&self, entry: &AuditEntry) {
+            let name = entry.application_name.map(str::to_string);
+            self.0.lock().unwrap().push(name);
+        }
+    }
+
+    #[test]
+    fn test_auditor() {
+        let names = Arc::new(Names(Mutex::new(vec![])));
+        let mut auditor = Auditor::new(names.clone(), None);
+        let state = State::new("bob".to_string(), "bob".to_string());
+
+        // The application name can be changed after the startup
+        auditor.query("select 1");
+        auditor.complete(&state, TransactionStatus::Idle);
+        state
+            .settings()
+            .set("application_name", Some("psql"), false);
+        auditor.query("select 1");
+        auditor.complete(&state, TransactionStatus::Idle);
+        assert_eq!(
+            *names.0.lock().unwrap(),
+            vec![None, Some("psql".to_string())]
+        );
+
+        // Closed statements and portals are forgotten, as are portals once the transaction ends
+        auditor.extended(&ExtendedMessage::Parse(Parse {
+            len: 0,
+            name: "s".to_string(),
+            query: "select 1".to_string(),
+            param_types: vec![],
+        }));
+        auditor.extended(&ExtendedMessage::Bind(Bind {
+            len: 0,
+            portal: "p".to_string(),
+            statement: "s".to_string(),
+            param_formats: vec![],
+            params: vec![],
+            result_formats: vec![],
+        }));
+        auditor.complete(&state, TransactionStatus::InTransaction);
+        assert_eq!((auditor.statements.len(), auditor.portals.len()), (1, 1));
+
+        auditor.extended(&ExtendedMessage::Close(Close {
+            len: 0,
+            target: Target::Statement,
+            name: "s".to_string(),
+        }));
+        auditor.complete(&state, TransactionStatus::Idle);
+        assert!(auditor.statements.is_empty() && auditor.portals.is_empty());
+    }
+
+    // Keeps the statements and sqlstates of the entries
+    struct Statements(Mutex<Vec<(String, String)>>);
+
+    impl AuditSink for Statements {
+        fn record(&self, entry: &AuditEntry) {
+            let statement = (entry.statement.to_string(), entry.sqlstate.to_string());
+            self.0.lock().unwrap().push(statement);
+        }
+    }
+
+    #[test]
+    fn test_auditor_failed_parse() {
+        let statements = Arc::new(Statements(Mutex::new(vec![])));
+        let mut auditor = Auditor::new(statements.clone(), None);
+        let state = State::new("bob".to_string(), "bob".to_string());
+
+        // A batch which is only parsed is not recorded unless it fails
+        let parse = ExtendedMessage::Parse(Parse {
+            len: 0,
+            name: "".to_string(),
+            query: "selec 1".to_string(),
+            param_types: vec![],
+        });
+        auditor.extended(&parse);
+        auditor.complete(&state, TransactionStatus::Idle);
+        assert!(statements.0.lock().unwrap().is_empty());
+
+        auditor.extended(&parse);
+        auditor.error(&ErrorResponse::new(
+            Severity::Error,
+            "42601".to_string(),
+            "syntax error".to_string(),
+        ));
+        auditor.complete(&state, TransactionStatus::Idle);
+        assert_eq!(
+            *statements.0.lock().unwrap(),
+            vec![("selec 1".to_string(), "42601".to_string())]
+        );
+    }
+}
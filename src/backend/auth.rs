@@ -5,6 +5,8 @@ use crate::proto::messages::{ErrorResponse, PasswordMessage, Severity};
 pub enum AuthMethod {
     CleartextPassword,
     None,
+    // Refuse the connection without asking for credentials
+    Reject(ErrorResponse),
 }
 
 pub type AuthResult = Result<(), ErrorResponse>;
@@ -20,6 +22,7 @@ pub trait Auth {
     }
 }
 
+#[derive(Default)]
 pub struct NoopAuth {}
 
 impl NoopAuth {
@@ -0,0 +1,125 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Hands out the keys sent to clients in BackendKeyData and cancels the running query of the
+// connection a CancelRequest names. Shared by all connections of a server.
+pub struct CancelRegistry {
+    next_process_id: AtomicI32,
+    keys: Mutex<HashMap<i32, Registered>>,
+}
+
+struct Registered {
+    secret_key: Vec<u8>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Default for CancelRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelRegistry {
+    pub fn new() -> Self {
+        Self {
+            next_process_id: AtomicI32::new(1),
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Protocol 3.2 allows longer keys than the 4 bytes of earlier versions
+    pub(crate) fn register(self: &Arc<Self>, len: usize) -> CancelKey {
+        let process_id = self.next_process_id.fetch_add(1, Ordering::Relaxed);
+        let mut secret_key = Vec::with_capacity(len);
+
+        // The std hasher is randomly seeded, good enough to keep clients from guessing keys
+        while secret_key.len() < len {
+            let random = RandomState::new().build_hasher().finish();
+            secret_key.extend_from_slice(&random.to_be_bytes());
+        }
+
+        secret_key.truncate(len);
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        self.keys.lock().unwrap().insert(
+            process_id,
+            Registered {
+                secret_key: secret_key.clone(),
+                cancelled: cancelled.clone(),
+            },
+        );
+
+        CancelKey {
+            registry: self.clone(),
+            process_id,
+            secret_key,
+            cancelled,
+        }
+    }
+
+    // Returns false when no connection has the key, like PostgreSQL the client isn't told
+    pub fn cancel(&self, process_id: i32, secret_key: &[u8]) -> bool {
+        match self.keys.lock().unwrap().get(&process_id) {
+            Some(registered) if registered.secret_key == secret_key => {
+                registered.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// The key of a connection, it's removed from the registry once the connection is dropped
+pub(crate) struct CancelKey {
+    registry: Arc<CancelRegistry>,
+    pub(crate) process_id: i32,
+    pub(crate) secret_key: Vec<u8>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelKey {
+    // Requests that arrive while nothing is running are ignored, so this is reset for every query
+    pub(crate) fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CancelKey {
+    fn drop(&mut self) {
+        self.registry.keys.lock().unwrap().remove(&self.process_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let registry = Arc::new(CancelRegistry::new());
+        let key = registry.register(32);
+        let other = registry.register(4);
+        assert_ne!(key.process_id, other.process_id);
+        assert_eq!((key.secret_key.len(), other.secret_key.len()), (32, 4));
+
+        assert!(!registry.cancel(key.process_id, &key.secret_key[..4]));
+        assert!(registry.cancel(key.process_id, &key.secret_key));
+        assert!(key.is_cancelled());
+        assert!(!other.is_cancelled());
+
+        key.reset();
+        assert!(!key.is_cancelled());
+
+        let (process_id, secret_key) = (key.process_id, key.secret_key.clone());
+        drop(key);
+        assert!(!registry.cancel(process_id, &secret_key));
+    }
+}
@@ -0,0 +1,3451 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::backend::lexer::{tokenize, unquote, Token};
+use crate::backend::pattern::{like_match, regex_match};
+use crate::backend::query_exec::{QueryResult, ResultSink};
+use crate::backend::response::{self, field};
+#[cfg(feature = "sql-parser")]
+use crate::backend::ParseResult;
+use crate::backend::{CopyBoth, QueryExec, RowStream, SetStatement, State};
+use crate::proto::messages::{
+    BackendMessage, CommandComplete, CommandTag, DataRow, ErrorResponse, ExtendedMessage,
+    FunctionCall, RowDescription, TransactionStatus,
+};
+
+const PG_CATALOG_OID: i64 = 11;
+const PUBLIC_OID: i64 = 2200;
+const INFORMATION_SCHEMA_OID: i64 = 13183;
+// Objects described by the executor get oids starting from here, just like in PostgreSQL
+const FIRST_USER_OID: i64 = 16384;
+const BOOTSTRAP_SUPERUSER_OID: i64 = 10;
+const DEFAULT_TABLESPACE_OID: i64 = 1663;
+const HEAP_AM_OID: i64 = 2;
+const BTREE_AM_OID: i64 = 403;
+const DEFAULT_COLLATION_OID: i64 = 100;
+const C_COLLATION_OID: i64 = 950;
+const UTF8_ENCODING: i64 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Type {
+    pub oid: i32,
+    pub name: &'static str,
+    // As reported by information_schema
+    pub sql_name: &'static str,
+    pub len: i16,
+}
+
+impl Type {
+    pub const BOOL: Type = Type::new(16, "bool", "boolean", 1);
+    pub const BYTEA: Type = Type::new(17, "bytea", "bytea", -1);
+    pub const CHAR: Type = Type::new(18, "char", "\"char\"", 1);
+    pub const NAME: Type = Type::new(19, "name", "name", 64);
+    pub const INT8: Type = Type::new(20, "int8", "bigint", 8);
+    pub const INT2: Type = Type::new(21, "int2", "smallint", 2);
+    pub const INT4: Type = Type::new(23, "int4", "integer", 4);
+    pub const TEXT: Type = Type::new(25, "text", "text", -1);
+    pub const OID: Type = Type::new(26, "oid", "oid", 4);
+    pub const JSON: Type = Type::new(114, "json", "json", -1);
+    pub const FLOAT4: Type = Type::new(700, "float4", "real", 4);
+    pub const FLOAT8: Type = Type::new(701, "float8", "double precision", 8);
+    pub const VARCHAR: Type = Type::new(1043, "varchar", "character varying", -1);
+    pub const DATE: Type = Type::new(1082, "date", "date", 4);
+    pub const TIMESTAMP: Type = Type::new(1114, "timestamp", "timestamp without time zone", 8);
+    pub const TIMESTAMPTZ: Type = Type::new(1184, "timestamptz", "timestamp with time zone", 8);
+    pub const NUMERIC: Type = Type::new(1700, "numeric", "numeric", -1);
+    pub const UUID: Type = Type::new(2950, "uuid", "uuid", 16);
+    pub const JSONB: Type = Type::new(3802, "jsonb", "jsonb", -1);
+
+    const BUILTIN: [Type; 19] = [
+        Self::BOOL,
+        Self::BYTEA,
+        Self::CHAR,
+        Self::NAME,
+        Self::INT8,
+        Self::INT2,
+        Self::INT4,
+        Self::TEXT,
+        Self::OID,
+        Self::JSON,
+        Self::FLOAT4,
+        Self::FLOAT8,
+        Self::VARCHAR,
+        Self::DATE,
+        Self::TIMESTAMP,
+        Self::TIMESTAMPTZ,
+        Self::NUMERIC,
+        Self::UUID,
+        Self::JSONB,
+    ];
+
+    pub const fn new(oid: i32, name: &'static str, sql_name: &'static str, len: i16) -> Self {
+        Self {
+            oid,
+            name,
+            sql_name,
+            len,
+        }
+    }
+
+    // Looks up a builtin type by its name or the name information_schema reports
+    fn builtin(name: &str) -> Option<Type> {
+        Self::BUILTIN
+            .into_iter()
+            .find(|ty| ty.name.eq_ignore_ascii_case(name) || ty.sql_name.eq_ignore_ascii_case(name))
+    }
+}
+
+pub struct Column {
+    name: String,
+    ty: Type,
+    nullable: bool,
+}
+
+impl Column {
+    pub fn new(name: &str, ty: Type) -> Self {
+        Self {
+            name: name.to_string(),
+            ty,
+            nullable: true,
+        }
+    }
+
+    pub fn not_null(mut self) -> Self {
+        self.nullable = false;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableKind {
+    Table,
+    View,
+}
+
+pub struct Table {
+    schema: String,
+    name: String,
+    kind: TableKind,
+    columns: Vec<Column>,
+}
+
+impl Table {
+    pub fn new(schema: &str, name: &str) -> Self {
+        Self {
+            schema: schema.to_string(),
+            name: name.to_string(),
+            kind: TableKind::Table,
+            columns: vec![],
+        }
+    }
+
+    pub fn view(schema: &str, name: &str) -> Self {
+        Self {
+            kind: TableKind::View,
+            ..Self::new(schema, name)
+        }
+    }
+
+    pub fn column(mut self, column: Column) -> Self {
+        self.columns.push(column);
+        self
+    }
+}
+
+// Describes the schemas, tables and types an executor provides, used to answer the catalog
+// queries clients send.
+//
+// The catalog file contains one schema, table or view per line, tables without a schema are in
+// public:
+//
+//   schema app
+//   table app.users (id int4 not null, name text)
+//   view active_users (id integer, name character varying)
+pub struct Catalog {
+    schemas: Vec<String>,
+    tables: Vec<Table>,
+    types: Vec<Type>,
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Self {
+            schemas: vec![],
+            tables: vec![],
+            types: Type::BUILTIN.to_vec(),
+        }
+    }
+
+    pub fn schema(mut self, name: &str) -> Self {
+        self.schemas.push(name.to_string());
+        self
+    }
+
+    pub fn table(mut self, table: Table) -> Self {
+        self.tables.push(table);
+        self
+    }
+
+    pub fn type_(mut self, ty: Type) -> Self {
+        self.types.push(ty);
+        self
+    }
+
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut catalog = Self::new();
+
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let result = match keyword {
+                "schema" if !rest.trim().is_empty() => {
+                    catalog = catalog.schema(rest.trim());
+                    Ok(())
+                }
+                "table" => Self::parse_table(rest, Table::new).map(|table| {
+                    catalog.add_table(table);
+                }),
+                "view" => Self::parse_table(rest, Table::view).map(|table| {
+                    catalog.add_table(table);
+                }),
+                _ => Err(format!("invalid line: {}", line)),
+            };
+
+            result.map_err(|e| format!("line {}: {}", n + 1, e))?;
+        }
+
+        Ok(catalog)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // `[schema.]name (column type [not null], ...)`
+    fn parse_table(definition: &str, new: fn(&str, &str) -> Table) -> Result<Table, String> {
+        let (name, columns) = definition
+            .split_once('(')
+            .and_then(|(name, rest)| Some((name.trim(), rest.trim().strip_suffix(')')?)))
+            .ok_or_else(|| format!("invalid table: {}", definition.trim()))?;
+        let mut table = match name.split_once('.') {
+            Some((schema, name)) => new(schema, name),
+            None => new("public", name),
+        };
+
+        for column in columns.split(',') {
+            let mut words = column.split_whitespace().collect::<Vec<_>>();
+            let not_null = matches!(
+                words.as_slice(),
+                [.., not, null] if not.eq_ignore_ascii_case("not") && null.eq_ignore_ascii_case("null")
+            );
+
+            if not_null {
+                words.truncate(words.len() - 2);
+            }
+
+            let (name, ty) = match words.split_first() {
+                Some((name, ty)) if !ty.is_empty() => (*name, ty.join(" ")),
+                _ => return Err(format!("invalid column: {}", column.trim())),
+            };
+            let ty = Type::builtin(&ty).ok_or_else(|| format!("unknown type: {}", ty))?;
+
+            table = table.column(match not_null {
+                true => Column::new(name, ty).not_null(),
+                false => Column::new(name, ty),
+            });
+        }
+
+        Ok(table)
+    }
+
+    // Tables can be in schemas which weren't declared
+    fn add_table(&mut self, table: Table) {
+        if table.schema != "public" && !self.schemas.contains(&table.schema) {
+            self.schemas.push(table.schema.clone());
+        }
+
+        self.tables.push(table);
+    }
+
+    // The user defined schemas (public always exists) along with their oids
+    fn namespaces(&self) -> Vec<(i64, &str)> {
+        let mut namespaces = vec![
+            (PG_CATALOG_OID, "pg_catalog"),
+            (PUBLIC_OID, "public"),
+            (INFORMATION_SCHEMA_OID, "information_schema"),
+        ];
+
+        for schema in self.schemas.iter() {
+            if !namespaces.iter().any(|(_, name)| name == schema) {
+                namespaces.push((FIRST_USER_OID + namespaces.len() as i64 - 3, schema));
+            }
+        }
+
+        namespaces
+    }
+
+    fn namespace_oid(&self, schema: &str) -> i64 {
+        self.namespaces()
+            .iter()
+            .find(|(_, name)| *name == schema)
+            .map_or(PUBLIC_OID, |(oid, _)| *oid)
+    }
+
+    fn table_oid(&self, n: usize) -> i64 {
+        FIRST_USER_OID + self.schemas.len() as i64 + n as i64
+    }
+
+    fn namespace_name(&self, oid: i64) -> Option<&str> {
+        self.namespaces()
+            .into_iter()
+            .find(|(n, _)| *n == oid)
+            .map(|(_, name)| name)
+    }
+
+    fn find_table(&self, oid: i64) -> Option<&Table> {
+        (0..self.tables.len())
+            .find(|n| self.table_oid(*n) == oid)
+            .map(|n| &self.tables[n])
+    }
+
+    // The schemas of the search path which exist, `"$user"` stands for the schema named like the
+    // user
+    fn search_path(&self, state: &State) -> Vec<String> {
+        let path = state
+            .settings()
+            .get("search_path")
+            .unwrap_or("\"$user\", public")
+            .to_string();
+        let namespaces = self.namespaces();
+
+        path.split(',')
+            .map(|schema| match unquote(schema.trim()) {
+                Some(schema) if schema == "$user" => state.user().to_string(),
+                Some(schema) => schema,
+                None => schema.trim().to_lowercase(),
+            })
+            .filter(|schema| namespaces.iter().any(|(_, name)| name == schema))
+            .collect()
+    }
+
+    fn is_visible(&self, state: &State, table: &Table) -> bool {
+        self.search_path(state).contains(&table.schema)
+    }
+
+    // The name a regclass is shown as, qualified when the table isn't on the search path
+    fn class_name(&self, state: &State, oid: i64) -> String {
+        if let Some((name, _)) = CATALOG_TABLES.iter().find(|(_, n)| *n == oid) {
+            return name.to_string();
+        }
+
+        match self.find_table(oid) {
+            Some(table) if self.is_visible(state, table) => table.name.clone(),
+            Some(table) => format!("{}.{}", table.schema, table.name),
+            None => oid.to_string(),
+        }
+    }
+
+    fn class_oid(&self, state: &State, name: &str) -> Option<i64> {
+        let parts = name
+            .split('.')
+            .map(|part| unquote(part.trim()).unwrap_or_else(|| part.trim().to_lowercase()))
+            .collect::<Vec<_>>();
+        let (schemas, name) = match parts.as_slice() {
+            [name] => (self.search_path(state), name),
+            [schema, name] => (vec![schema.clone()], name),
+            _ => return None,
+        };
+
+        if parts.len() == 1 || parts[0] == "pg_catalog" {
+            if let Some((_, oid)) = CATALOG_TABLES.iter().find(|(table, _)| table == name) {
+                return Some(*oid);
+            }
+        }
+
+        schemas.iter().find_map(|schema| {
+            self.tables
+                .iter()
+                .position(|table| table.schema == *schema && table.name == *name)
+                .map(|n| self.table_oid(n))
+        })
+    }
+
+    fn relation(&self, state: &State, name: &str) -> Option<Relation> {
+        let text = |s: &str| Value::Text(s.to_string());
+        let int = |n: i64| Value::Int(n);
+        let kind = |table: &Table| match table.kind {
+            TableKind::Table => "r",
+            TableKind::View => "v",
+        };
+
+        Some(match name {
+            "pg_catalog.pg_namespace" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("nspname", Type::NAME),
+                    ("nspowner", Type::OID),
+                    ("nspacl", Type::TEXT),
+                ],
+                self.namespaces()
+                    .into_iter()
+                    .map(|(oid, name)| {
+                        vec![
+                            int(oid),
+                            text(name),
+                            int(BOOTSTRAP_SUPERUSER_OID),
+                            Value::Null,
+                        ]
+                    })
+                    .collect(),
+            ),
+            "pg_catalog.pg_class" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("relname", Type::NAME),
+                    ("relnamespace", Type::OID),
+                    ("reltype", Type::OID),
+                    ("reloftype", Type::OID),
+                    ("relowner", Type::OID),
+                    ("relam", Type::OID),
+                    ("relfilenode", Type::OID),
+                    ("reltablespace", Type::OID),
+                    ("relpages", Type::INT4),
+                    ("reltuples", Type::FLOAT4),
+                    ("reltoastrelid", Type::OID),
+                    ("relhasindex", Type::BOOL),
+                    ("relisshared", Type::BOOL),
+                    ("relpersistence", Type::CHAR),
+                    ("relkind", Type::CHAR),
+                    ("relnatts", Type::INT2),
+                    ("relchecks", Type::INT2),
+                    ("relhasrules", Type::BOOL),
+                    ("relhastriggers", Type::BOOL),
+                    ("relhassubclass", Type::BOOL),
+                    ("relrowsecurity", Type::BOOL),
+                    ("relforcerowsecurity", Type::BOOL),
+                    ("relispopulated", Type::BOOL),
+                    ("relreplident", Type::CHAR),
+                    ("relispartition", Type::BOOL),
+                    ("relacl", Type::TEXT),
+                    ("reloptions", Type::TEXT),
+                    ("relpartbound", Type::TEXT),
+                ],
+                self.tables
+                    .iter()
+                    .enumerate()
+                    .map(|(n, table)| {
+                        let view = table.kind == TableKind::View;
+
+                        vec![
+                            int(self.table_oid(n)),
+                            text(&table.name),
+                            int(self.namespace_oid(&table.schema)),
+                            int(0),
+                            int(0),
+                            int(BOOTSTRAP_SUPERUSER_OID),
+                            int(if view { 0 } else { HEAP_AM_OID }),
+                            int(if view { 0 } else { self.table_oid(n) }),
+                            int(0),
+                            int(0),
+                            int(-1),
+                            int(0),
+                            Value::Bool(false),
+                            Value::Bool(false),
+                            text("p"),
+                            text(kind(table)),
+                            int(table.columns.len() as i64),
+                            int(0),
+                            // Views are implemented by a rule
+                            Value::Bool(view),
+                            Value::Bool(false),
+                            Value::Bool(false),
+                            Value::Bool(false),
+                            Value::Bool(false),
+                            Value::Bool(true),
+                            text(if view { "n" } else { "d" }),
+                            Value::Bool(false),
+                            Value::Null,
+                            Value::Null,
+                            Value::Null,
+                        ]
+                    })
+                    .collect(),
+            ),
+            "pg_catalog.pg_attribute" => Relation::new(
+                &[
+                    ("attrelid", Type::OID),
+                    ("attname", Type::NAME),
+                    ("atttypid", Type::OID),
+                    ("attlen", Type::INT2),
+                    ("attnum", Type::INT2),
+                    ("atttypmod", Type::INT4),
+                    ("attndims", Type::INT2),
+                    ("attnotnull", Type::BOOL),
+                    ("atthasdef", Type::BOOL),
+                    ("attidentity", Type::CHAR),
+                    ("attgenerated", Type::CHAR),
+                    ("attisdropped", Type::BOOL),
+                    ("attislocal", Type::BOOL),
+                    ("attcollation", Type::OID),
+                    ("attacl", Type::TEXT),
+                    ("attoptions", Type::TEXT),
+                ],
+                self.tables
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(n, table)| {
+                        table.columns.iter().enumerate().map(move |(i, column)| {
+                            vec![
+                                int(self.table_oid(n)),
+                                text(&column.name),
+                                int(column.ty.oid as i64),
+                                int(column.ty.len as i64),
+                                int(i as i64 + 1),
+                                int(-1),
+                                int(0),
+                                Value::Bool(!column.nullable),
+                                Value::Bool(false),
+                                text(""),
+                                text(""),
+                                Value::Bool(false),
+                                Value::Bool(true),
+                                int(0),
+                                Value::Null,
+                                Value::Null,
+                            ]
+                        })
+                    })
+                    .collect(),
+            ),
+            "pg_catalog.pg_type" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("typname", Type::NAME),
+                    ("typnamespace", Type::OID),
+                    ("typowner", Type::OID),
+                    ("typlen", Type::INT2),
+                    ("typtype", Type::CHAR),
+                    ("typrelid", Type::OID),
+                    ("typelem", Type::OID),
+                    ("typarray", Type::OID),
+                    ("typnotnull", Type::BOOL),
+                    ("typbasetype", Type::OID),
+                    ("typtypmod", Type::INT4),
+                    ("typcollation", Type::OID),
+                ],
+                self.types
+                    .iter()
+                    .map(|ty| {
+                        vec![
+                            int(ty.oid as i64),
+                            text(ty.name),
+                            int(PG_CATALOG_OID),
+                            int(BOOTSTRAP_SUPERUSER_OID),
+                            int(ty.len as i64),
+                            text("b"),
+                            int(0),
+                            int(0),
+                            int(0),
+                            Value::Bool(false),
+                            int(0),
+                            int(-1),
+                            int(0),
+                        ]
+                    })
+                    .collect(),
+            ),
+            "pg_catalog.pg_database" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("datname", Type::NAME),
+                    ("datdba", Type::OID),
+                    ("encoding", Type::INT4),
+                    ("datlocprovider", Type::CHAR),
+                    ("datistemplate", Type::BOOL),
+                    ("datallowconn", Type::BOOL),
+                    ("datconnlimit", Type::INT4),
+                    ("dattablespace", Type::OID),
+                    ("datcollate", Type::TEXT),
+                    ("datctype", Type::TEXT),
+                    ("daticulocale", Type::TEXT),
+                    ("datacl", Type::TEXT),
+                ],
+                vec![vec![
+                    int(FIRST_USER_OID - 1),
+                    text(state.database()),
+                    int(BOOTSTRAP_SUPERUSER_OID),
+                    int(UTF8_ENCODING),
+                    text("c"),
+                    Value::Bool(false),
+                    Value::Bool(true),
+                    int(-1),
+                    int(DEFAULT_TABLESPACE_OID),
+                    text("C"),
+                    text("C"),
+                    Value::Null,
+                    Value::Null,
+                ]],
+            ),
+            "pg_catalog.pg_am" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("amname", Type::NAME),
+                    ("amtype", Type::CHAR),
+                ],
+                vec![
+                    vec![int(HEAP_AM_OID), text("heap"), text("t")],
+                    vec![int(BTREE_AM_OID), text("btree"), text("i")],
+                ],
+            ),
+            "pg_catalog.pg_collation" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("collname", Type::NAME),
+                    ("collnamespace", Type::OID),
+                ],
+                vec![
+                    vec![
+                        int(DEFAULT_COLLATION_OID),
+                        text("default"),
+                        int(PG_CATALOG_OID),
+                    ],
+                    vec![int(C_COLLATION_OID), text("C"), int(PG_CATALOG_OID)],
+                ],
+            ),
+            "pg_catalog.pg_roles" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("rolname", Type::NAME),
+                    ("rolsuper", Type::BOOL),
+                    ("rolcreaterole", Type::BOOL),
+                    ("rolcreatedb", Type::BOOL),
+                    ("rolcanlogin", Type::BOOL),
+                ],
+                vec![vec![
+                    int(BOOTSTRAP_SUPERUSER_OID),
+                    text(state.user()),
+                    Value::Bool(true),
+                    Value::Bool(true),
+                    Value::Bool(true),
+                    Value::Bool(true),
+                ]],
+            ),
+            // Nothing in the catalog has defaults, comments, indexes, constraints or functions
+            "pg_catalog.pg_attrdef" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("adrelid", Type::OID),
+                    ("adnum", Type::INT2),
+                    ("adbin", Type::TEXT),
+                ],
+                vec![],
+            ),
+            "pg_catalog.pg_description" => Relation::new(
+                &[
+                    ("objoid", Type::OID),
+                    ("classoid", Type::OID),
+                    ("objsubid", Type::INT4),
+                    ("description", Type::TEXT),
+                ],
+                vec![],
+            ),
+            "pg_catalog.pg_index" => Relation::new(
+                &[
+                    ("indexrelid", Type::OID),
+                    ("indrelid", Type::OID),
+                    ("indnatts", Type::INT2),
+                    ("indisunique", Type::BOOL),
+                    ("indisprimary", Type::BOOL),
+                    ("indisvalid", Type::BOOL),
+                    ("indkey", Type::TEXT),
+                ],
+                vec![],
+            ),
+            "pg_catalog.pg_constraint" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("conname", Type::NAME),
+                    ("connamespace", Type::OID),
+                    ("contype", Type::CHAR),
+                    ("conrelid", Type::OID),
+                    ("conindid", Type::OID),
+                    ("confrelid", Type::OID),
+                    ("conkey", Type::TEXT),
+                ],
+                vec![],
+            ),
+            "pg_catalog.pg_inherits" => Relation::new(
+                &[
+                    ("inhrelid", Type::OID),
+                    ("inhparent", Type::OID),
+                    ("inhseqno", Type::INT4),
+                    ("inhdetachpending", Type::BOOL),
+                ],
+                vec![],
+            ),
+            "pg_catalog.pg_proc" => Relation::new(
+                &[
+                    ("oid", Type::OID),
+                    ("proname", Type::NAME),
+                    ("pronamespace", Type::OID),
+                    ("proowner", Type::OID),
+                    ("prokind", Type::CHAR),
+                    ("prorettype", Type::OID),
+                ],
+                vec![],
+            ),
+            "pg_catalog.pg_settings" => Relation::new(
+                &[
+                    ("name", Type::TEXT),
+                    ("setting", Type::TEXT),
+                    ("unit", Type::TEXT),
+                    ("vartype", Type::TEXT),
+                ],
+                state
+                    .settings()
+                    .iter()
+                    .map(|(name, value)| vec![text(name), text(value), Value::Null, text("string")])
+                    .collect(),
+            ),
+            "information_schema.schemata" => Relation::new(
+                &[
+                    ("catalog_name", Type::NAME),
+                    ("schema_name", Type::NAME),
+                    ("schema_owner", Type::NAME),
+                ],
+                self.namespaces()
+                    .into_iter()
+                    .map(|(_, name)| vec![text(state.database()), text(name), text(state.user())])
+                    .collect(),
+            ),
+            "information_schema.tables" => Relation::new(
+                &[
+                    ("table_catalog", Type::NAME),
+                    ("table_schema", Type::NAME),
+                    ("table_name", Type::NAME),
+                    ("table_type", Type::TEXT),
+                ],
+                self.tables
+                    .iter()
+                    .map(|table| {
+                        vec![
+                            text(state.database()),
+                            text(&table.schema),
+                            text(&table.name),
+                            text(match table.kind {
+                                TableKind::Table => "BASE TABLE",
+                                TableKind::View => "VIEW",
+                            }),
+                        ]
+                    })
+                    .collect(),
+            ),
+            "information_schema.columns" => Relation::new(
+                &[
+                    ("table_catalog", Type::NAME),
+                    ("table_schema", Type::NAME),
+                    ("table_name", Type::NAME),
+                    ("column_name", Type::NAME),
+                    ("ordinal_position", Type::INT4),
+                    ("column_default", Type::TEXT),
+                    ("is_nullable", Type::TEXT),
+                    ("data_type", Type::TEXT),
+                    ("character_maximum_length", Type::INT4),
+                    ("udt_name", Type::NAME),
+                    ("is_identity", Type::TEXT),
+                ],
+                self.tables
+                    .iter()
+                    .flat_map(|table| {
+                        table.columns.iter().enumerate().map(move |(i, column)| {
+                            vec![
+                                text(state.database()),
+                                text(&table.schema),
+                                text(&table.name),
+                                text(&column.name),
+                                int(i as i64 + 1),
+                                Value::Null,
+                                text(if column.nullable { "YES" } else { "NO" }),
+                                text(column.ty.sql_name),
+                                Value::Null,
+                                text(column.ty.name),
+                                text("NO"),
+                            ]
+                        })
+                    })
+                    .collect(),
+            ),
+            _ => return None,
+        })
+    }
+
+    fn call(&self, state: &State, name: &str, args: &[Value]) -> Result<Value, Unanswerable> {
+        let text = |s: &str| Value::Text(s.to_string());
+        let string = |value: &Value| value.as_text().unwrap_or_default();
+
+        Ok(match (name, args) {
+            ("version", []) => Value::Text(format!(
+                "PostgreSQL {} (postgres-conn)",
+                state.settings().get("server_version").unwrap_or_default()
+            )),
+            ("current_database" | "current_catalog", []) => text(state.database()),
+            ("current_schema", []) => self
+                .search_path(state)
+                .into_iter()
+                .next()
+                .map_or(Value::Null, Value::Text),
+            ("current_schemas", [Value::Bool(implicit)]) => {
+                let implicit = implicit.then(|| "pg_catalog".to_string());
+
+                Value::Array(
+                    implicit
+                        .into_iter()
+                        .chain(self.search_path(state))
+                        .map(Value::Text)
+                        .collect(),
+                )
+            }
+            ("current_user" | "session_user" | "user" | "current_role", []) => text(state.user()),
+            ("current_setting", [Value::Text(setting)])
+            | ("current_setting", [Value::Text(setting), Value::Bool(false)]) => {
+                match state.settings().get(setting) {
+                    Some(value) => text(value),
+                    None => {
+                        return Err(error(
+                            "42704",
+                            format!("unrecognized configuration parameter \"{}\"", setting),
+                        ))
+                    }
+                }
+            }
+            ("current_setting", [Value::Text(setting), Value::Bool(true)]) => {
+                state.settings().get(setting).map_or(Value::Null, text)
+            }
+            ("coalesce", args) => args
+                .iter()
+                .find(|arg| **arg != Value::Null)
+                .cloned()
+                .unwrap_or(Value::Null),
+            ("nullif", [a, b]) => match apply(Op::Eq, a, b)? {
+                Value::Bool(true) => Value::Null,
+                _ => a.clone(),
+            },
+            // The remaining functions return NULL for NULL arguments
+            (_, args) if args.contains(&Value::Null) => Value::Null,
+            ("pg_get_userbyid", [oid]) => match oid.as_int() {
+                Some(BOOTSTRAP_SUPERUSER_OID) => text(state.user()),
+                Some(oid) => Value::Text(format!("unknown (OID={})", oid)),
+                None => return Err(Unanswerable::Unsupported),
+            },
+            ("pg_table_is_visible", [oid]) => Value::Bool(
+                oid.as_int()
+                    .and_then(|oid| self.find_table(oid))
+                    .is_none_or(|table| self.is_visible(state, table)),
+            ),
+            ("format_type", [oid, _]) => match oid.as_int().and_then(|oid| self.type_by_oid(oid)) {
+                Some(ty) => text(ty.sql_name),
+                None => text("???"),
+            },
+            ("pg_encoding_to_char", [encoding]) => match encoding.as_int() {
+                Some(UTF8_ENCODING) => text("UTF8"),
+                Some(0) => text("SQL_ASCII"),
+                _ => text(""),
+            },
+            // Nothing in the catalog has a comment, a default or an expression
+            (
+                "obj_description" | "col_description" | "shobj_description" | "pg_get_expr"
+                | "pg_get_partkeydef" | "pg_get_viewdef",
+                _,
+            ) => Value::Null,
+            ("pg_relation_is_publishable", [_]) => Value::Bool(false),
+            (
+                "has_table_privilege"
+                | "has_schema_privilege"
+                | "has_database_privilege"
+                | "has_column_privilege"
+                | "pg_has_role",
+                _,
+            ) => Value::Bool(true),
+            ("array_to_string", [Value::Array(values), separator]) => Value::Text(
+                values
+                    .iter()
+                    .filter_map(Value::as_text)
+                    .collect::<Vec<_>>()
+                    .join(&string(separator)),
+            ),
+            ("string_to_array", [value, separator]) => {
+                let (value, separator) = (string(value), string(separator));
+
+                Value::Array(match (value.as_str(), separator.as_str()) {
+                    ("", _) => vec![],
+                    (value, "") => vec![text(value)],
+                    (value, separator) => value.split(separator).map(text).collect(),
+                })
+            }
+            ("array_lower" | "array_upper" | "array_length", [Value::Array(values), dimension]) => {
+                match (name, dimension.as_int()) {
+                    (_, Some(1)) if values.is_empty() => Value::Null,
+                    ("array_lower", Some(1)) => Value::Int(1),
+                    (_, Some(1)) => Value::Int(values.len() as i64),
+                    _ => Value::Null,
+                }
+            }
+            ("trim" | "btrim", [value]) => text(string(value).trim_matches(' ')),
+            ("lower", [value]) => Value::Text(string(value).to_lowercase()),
+            ("upper", [value]) => Value::Text(string(value).to_uppercase()),
+            ("length", [value]) => Value::Int(string(value).chars().count() as i64),
+            ("quote_ident", [value]) => Value::Text(quote_ident(&string(value))),
+            _ => return Err(Unanswerable::Unsupported),
+        })
+    }
+
+    fn type_by_oid(&self, oid: i64) -> Option<&Type> {
+        self.types.iter().find(|ty| ty.oid as i64 == oid)
+    }
+
+    fn cast(&self, state: &State, value: Value, ty: &str) -> Result<Value, Unanswerable> {
+        let invalid = |value: &Value| {
+            error(
+                "22P02",
+                format!(
+                    "invalid input syntax for type {}: \"{}\"",
+                    ty,
+                    value.as_text().unwrap_or_default()
+                ),
+            )
+        };
+
+        if value == Value::Null {
+            return Ok(Value::Null);
+        }
+
+        Ok(match ty {
+            "text" | "varchar" | "name" | "char" | "bpchar" => {
+                Value::Text(value.as_text().unwrap_or_default())
+            }
+            "int2" | "int4" | "int8" | "int" | "integer" | "smallint" | "bigint" | "oid" => {
+                Value::Int(value.as_int().ok_or_else(|| invalid(&value))?)
+            }
+            "bool" | "boolean" => match &value {
+                Value::Bool(_) => value,
+                _ => Value::Bool(
+                    parse_bool(&value.as_text().unwrap_or_default())
+                        .ok_or_else(|| invalid(&value))?,
+                ),
+            },
+            "regclass" | "regtype" | "regnamespace" => {
+                let oid = match (&value, value.as_int()) {
+                    (_, Some(oid)) => oid,
+                    (Value::Text(name), None) => {
+                        let oid = match ty {
+                            "regclass" => self.class_oid(state, name),
+                            "regtype" => Type::builtin(name).map(|ty| ty.oid as i64),
+                            _ => Some(self.namespace_oid(name)).filter(|_| {
+                                self.namespace_name(self.namespace_oid(name)) == Some(name)
+                            }),
+                        };
+
+                        oid.ok_or_else(|| {
+                            error("42704", format!("{} \"{}\" does not exist", ty, name))
+                        })?
+                    }
+                    _ => return Err(Unanswerable::Unsupported),
+                };
+                let name = match ty {
+                    _ if oid == 0 => "-".to_string(),
+                    "regclass" => self.class_name(state, oid),
+                    "regtype" => self
+                        .type_by_oid(oid)
+                        .map_or_else(|| oid.to_string(), |ty| ty.sql_name.to_string()),
+                    _ => self
+                        .namespace_name(oid)
+                        .map_or_else(|| oid.to_string(), str::to_string),
+                };
+
+                Value::Reg(oid, name)
+            }
+            ty if ty.ends_with("[]") => match value {
+                Value::Array(_) => value,
+                Value::Text(text) => {
+                    Value::Array(parse_array(&text).ok_or_else(|| invalid(&Value::Text(text)))?)
+                }
+                _ => return Err(Unanswerable::Unsupported),
+            },
+            _ => return Err(Unanswerable::Unsupported),
+        })
+    }
+
+    // Answers the query when it's a catalog query, anything else is left to the executor
+    pub fn answer(
+        &self,
+        state: &State,
+        query: &str,
+    ) -> Option<Result<Vec<BackendMessage>, ErrorResponse>> {
+        let select = Parser::new(query).statement()?;
+
+        // Without a catalog table we only answer queries which call one of our functions
+        let calls_function = select.items.iter().any(|item| {
+            matches!(item, Item::Expr(expr, _) if expr.any(&|expr| matches!(expr, Expr::Function(..))))
+        });
+
+        if select.from.is_empty() && !calls_function {
+            return None;
+        }
+
+        match self.evaluate(state, &select) {
+            Ok(messages) => Some(Ok(messages)),
+            Err(Unanswerable::Error(e)) => Some(Err(e)),
+            Err(Unanswerable::Unsupported) => None,
+        }
+    }
+
+    fn evaluate(
+        &self,
+        state: &State,
+        select: &Select,
+    ) -> Result<Vec<BackendMessage>, Unanswerable> {
+        let (columns, rows) = self.query(state, select, None)?;
+        let fields = columns.iter().map(|(name, ty)| field(name, *ty)).collect();
+        let mut messages = vec![BackendMessage::RowDescription(RowDescription::new(fields))];
+        let count = rows.len();
+
+        for row in rows {
+            messages.push(BackendMessage::DataRow(DataRow::new(
+                row.iter().map(Value::to_text).collect(),
+            )));
+        }
+
+        messages.push(BackendMessage::CommandComplete(CommandComplete::new(
+            CommandTag::Select(count as i32),
+        )));
+
+        Ok(messages)
+    }
+
+    // Returns the names and types of the result columns along with the rows, `outer` is the row
+    // a subquery is evaluated for
+    #[allow(clippy::type_complexity)]
+    fn query(
+        &self,
+        state: &State,
+        select: &Select,
+        outer: Option<&Scope>,
+    ) -> Result<(Vec<(String, Type)>, Vec<Vec<Value>>), Unanswerable> {
+        let (columns, rows) = self.from(state, &select.from, outer)?;
+        let mut filtered = vec![];
+
+        for row in rows {
+            let scope = Scope {
+                columns: &columns,
+                row: &row,
+                outer,
+            };
+            let matches = match &select.filter {
+                Some(filter) => self.eval(state, &scope, filter)? == Value::Bool(true),
+                None => true,
+            };
+
+            if matches {
+                filtered.push(row);
+            }
+        }
+
+        let types = Scope {
+            columns: &columns,
+            row: &[],
+            outer,
+        };
+        let outputs = self.outputs(state, &types, &select.items)?;
+        let names = outputs
+            .iter()
+            .map(|(name, ty, _)| (name.clone(), *ty))
+            .collect();
+
+        let is_count = |expr: &Expr| matches!(expr, Expr::Function(name, _) if name == "count");
+
+        if select
+            .items
+            .iter()
+            .any(|item| matches!(item, Item::Expr(expr, _) if expr.any(&is_count)))
+        {
+            return Ok((
+                names,
+                vec![self.count(state, &columns, &filtered, outer, select)?],
+            ));
+        }
+
+        let mut results = vec![];
+
+        for row in filtered.iter() {
+            let scope = Scope {
+                columns: &columns,
+                row,
+                outer,
+            };
+            let mut values = vec![];
+
+            for (_, _, output) in outputs.iter() {
+                values.push(match output {
+                    Output::Column(n) => row[*n].clone(),
+                    Output::Expr(expr) => self.eval(state, &scope, expr)?,
+                });
+            }
+
+            let mut keys = vec![];
+
+            for (expr, _) in select.order_by.iter() {
+                let output = match expr {
+                    // Output columns are referenced by their position or their name
+                    Expr::Literal(Value::Int(n)) => Some(
+                        (*n as usize)
+                            .checked_sub(1)
+                            .filter(|n| *n < values.len())
+                            .ok_or_else(|| {
+                                error(
+                                    "42P10",
+                                    format!("ORDER BY position {} is not in select list", n),
+                                )
+                            })?,
+                    ),
+                    Expr::Column(parts) if parts.len() == 1 => {
+                        outputs.iter().position(|(name, _, _)| *name == parts[0])
+                    }
+                    _ => None,
+                };
+
+                keys.push(match output {
+                    Some(n) => values[n].clone(),
+                    None => self.eval(state, &scope, expr)?,
+                });
+            }
+
+            results.push((values, keys));
+        }
+
+        results.sort_by(|(_, a), (_, b)| {
+            for ((a, b), (_, desc)) in a.iter().zip(b.iter()).zip(select.order_by.iter()) {
+                let ordering = match desc {
+                    true => a.sort_cmp(b).reverse(),
+                    false => a.sort_cmp(b),
+                };
+
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+
+            Ordering::Equal
+        });
+
+        let rows = results
+            .into_iter()
+            .map(|(values, _)| values)
+            .skip(select.offset)
+            .take(select.limit.unwrap_or(usize::MAX))
+            .collect();
+
+        Ok((names, rows))
+    }
+
+    fn outputs<'s>(
+        &self,
+        state: &State,
+        scope: &Scope,
+        items: &'s [Item],
+    ) -> Result<Vec<(String, Type, Output<'s>)>, Unanswerable> {
+        let mut outputs = vec![];
+
+        for item in items {
+            match item {
+                Item::Wildcard(qualifier) => {
+                    let before = outputs.len();
+
+                    for (n, column) in scope.columns.iter().enumerate() {
+                        if qualifier.as_ref().is_none_or(|q| *q == column.qualifier) {
+                            outputs.push((column.name.clone(), column.ty, Output::Column(n)));
+                        }
+                    }
+
+                    if let (Some(qualifier), true) = (qualifier, outputs.len() == before) {
+                        return Err(error(
+                            "42P01",
+                            format!("missing FROM-clause entry for table \"{}\"", qualifier),
+                        ));
+                    }
+                }
+                Item::Expr(expr, alias) => outputs.push((
+                    alias.clone().unwrap_or_else(|| expr.name()),
+                    self.expr_type(state, scope, expr)?,
+                    Output::Expr(expr),
+                )),
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    // Aggregates the rows into a single one, only `count` is supported (without GROUP BY) and the
+    // other items have to be constant
+    fn count(
+        &self,
+        state: &State,
+        columns: &[ScopeColumn],
+        rows: &[Vec<Value>],
+        outer: Option<&Scope>,
+        select: &Select,
+    ) -> Result<Vec<Value>, Unanswerable> {
+        let mut values = vec![];
+
+        for item in select.items.iter() {
+            values.push(match item {
+                Item::Expr(Expr::Function(name, args), _) if name == "count" => {
+                    let mut count = 0;
+
+                    for row in rows {
+                        let scope = Scope {
+                            columns,
+                            row,
+                            outer,
+                        };
+                        let counted = match args.as_slice() {
+                            [] => true,
+                            [arg] => self.eval(state, &scope, arg)? != Value::Null,
+                            _ => return Err(Unanswerable::Unsupported),
+                        };
+
+                        count += counted as i64;
+                    }
+
+                    Value::Int(count)
+                }
+                Item::Expr(Expr::Literal(value), _) => value.clone(),
+                _ => return Err(Unanswerable::Unsupported),
+            });
+        }
+
+        Ok(values)
+    }
+
+    // Joins the FROM items with nested loops
+    fn from(
+        &self,
+        state: &State,
+        items: &[FromItem],
+        outer: Option<&Scope>,
+    ) -> Result<(Vec<ScopeColumn>, Vec<Vec<Value>>), Unanswerable> {
+        let mut columns = vec![];
+        let mut rows = vec![vec![]];
+
+        for item in items {
+            let (source_columns, source_rows) = self.source(state, item, outer)?;
+            let width = source_columns.len();
+            let mut joined = vec![];
+
+            columns.extend(source_columns);
+
+            for left in rows.iter() {
+                let mut matched = false;
+
+                for right in source_rows.iter() {
+                    let row = left.iter().chain(right.iter()).cloned().collect::<Vec<_>>();
+
+                    if let Some(on) = &item.on {
+                        let scope = Scope {
+                            columns: &columns,
+                            row: &row,
+                            outer,
+                        };
+
+                        if self.eval(state, &scope, on)? != Value::Bool(true) {
+                            continue;
+                        }
+                    }
+
+                    matched = true;
+                    joined.push(row);
+                }
+
+                if !matched && item.join == Join::Left {
+                    joined.push(
+                        left.iter()
+                            .cloned()
+                            .chain(iter::repeat_n(Value::Null, width))
+                            .collect(),
+                    );
+                }
+
+                if joined.len() > MAX_JOIN_ROWS {
+                    return Err(error(
+                        "54000",
+                        format!("catalog query joins more than {} rows", MAX_JOIN_ROWS),
+                    ));
+                }
+            }
+
+            rows = joined;
+        }
+
+        Ok((columns, rows))
+    }
+
+    fn source(
+        &self,
+        state: &State,
+        item: &FromItem,
+        outer: Option<&Scope>,
+    ) -> Result<(Vec<ScopeColumn>, Vec<Vec<Value>>), Unanswerable> {
+        match &item.source {
+            Source::Table(name) => {
+                let relation = self
+                    .relation(state, name)
+                    .ok_or(Unanswerable::Unsupported)?;
+                let (schema, table) = name.split_once('.').unwrap_or(("", name));
+                let columns = relation
+                    .columns
+                    .iter()
+                    .map(|(column, ty)| ScopeColumn {
+                        qualifier: item.alias.clone().unwrap_or_else(|| table.to_string()),
+                        schema: match item.alias {
+                            Some(_) => None,
+                            None => Some(schema.to_string()),
+                        },
+                        name: column.to_string(),
+                        ty: *ty,
+                    })
+                    .collect();
+
+                Ok((columns, relation.rows))
+            }
+            Source::Function(name, args) => {
+                let scope = Scope {
+                    columns: &[],
+                    row: &[],
+                    outer,
+                };
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(state, &scope, arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let rows = match (name.as_str(), args.as_slice()) {
+                    ("generate_series", [start, stop]) if args.contains(&Value::Null) => vec![],
+                    ("generate_series", [start, stop]) => match (start.as_int(), stop.as_int()) {
+                        (Some(start), Some(stop)) if stop.saturating_sub(start) <= MAX_SERIES => {
+                            (start..=stop).map(|n| vec![Value::Int(n)]).collect()
+                        }
+                        _ => return Err(Unanswerable::Unsupported),
+                    },
+                    _ => vec![vec![self.call(state, name, &args)?]],
+                };
+                let column = ScopeColumn {
+                    qualifier: item.alias.clone().unwrap_or_else(|| name.clone()),
+                    schema: None,
+                    name: item.alias.clone().unwrap_or_else(|| name.clone()),
+                    ty: function_type(name),
+                };
+
+                Ok((vec![column], rows))
+            }
+        }
+    }
+
+    fn eval(&self, state: &State, scope: &Scope, expr: &Expr) -> Result<Value, Unanswerable> {
+        let eval = |expr: &Expr| self.eval(state, scope, expr);
+
+        Ok(match expr {
+            Expr::Column(parts) => scope.lookup(parts)?.1,
+            Expr::Literal(value) => value.clone(),
+            // Aggregates are only supported as select items
+            Expr::Function(name, _) if name == "count" => return Err(Unanswerable::Unsupported),
+            Expr::Function(name, args) => {
+                let args = args.iter().map(eval).collect::<Result<Vec<_>, _>>()?;
+                self.call(state, name, &args)?
+            }
+            Expr::Not(expr) => not(truth(eval(expr)?)?),
+            // `false AND x` is false and `true OR x` is true, even when x is NULL
+            Expr::Binary(a, op @ (Op::And | Op::Or), b) => {
+                let decisive = *op == Op::Or;
+
+                match truth(eval(a)?)? {
+                    Some(a) if a == decisive => Value::Bool(decisive),
+                    a => match (a, truth(eval(b)?)?) {
+                        (_, Some(b)) if b == decisive => Value::Bool(decisive),
+                        (Some(_), Some(_)) => Value::Bool(!decisive),
+                        _ => Value::Null,
+                    },
+                }
+            }
+            Expr::Binary(a, op, b) => apply(*op, &eval(a)?, &eval(b)?)?,
+            Expr::Any(expr, op, array) => {
+                let value = eval(expr)?;
+                let values = match eval(array)? {
+                    Value::Array(values) => values,
+                    Value::Text(text) => parse_array(&text).ok_or(Unanswerable::Unsupported)?,
+                    Value::Null => return Ok(Value::Null),
+                    _ => return Err(Unanswerable::Unsupported),
+                };
+
+                any(values
+                    .iter()
+                    .map(|v| apply(*op, &value, v))
+                    .collect::<Result<Vec<_>, _>>()?)
+            }
+            Expr::In(expr, list, negated) => {
+                let value = eval(expr)?;
+                let mut results = vec![];
+
+                for item in list {
+                    results.push(apply(Op::Eq, &value, &eval(item)?)?);
+                }
+
+                negate(any(results), *negated)
+            }
+            Expr::InSelect(expr, select, negated) => {
+                let value = eval(expr)?;
+                let (columns, rows) = self.query(state, select, Some(scope))?;
+
+                if columns.len() != 1 {
+                    return Err(error("42601", "subquery has too many columns".to_string()));
+                }
+
+                let results = rows
+                    .iter()
+                    .map(|row| apply(Op::Eq, &value, &row[0]))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                negate(any(results), *negated)
+            }
+            Expr::IsNull(expr, negated) => Value::Bool((eval(expr)? == Value::Null) != *negated),
+            Expr::Case(operand, branches, default) => {
+                let operand = operand.as_deref().map(eval).transpose()?;
+
+                for (condition, result) in branches {
+                    let condition = match &operand {
+                        Some(operand) => apply(Op::Eq, operand, &eval(condition)?)?,
+                        None => eval(condition)?,
+                    };
+
+                    if condition == Value::Bool(true) {
+                        return eval(result);
+                    }
+                }
+
+                default
+                    .as_deref()
+                    .map(eval)
+                    .transpose()?
+                    .unwrap_or(Value::Null)
+            }
+            Expr::Cast(expr, ty) => self.cast(state, eval(expr)?, ty)?,
+            Expr::Subquery(select) => {
+                let (columns, rows) = self.query(state, select, Some(scope))?;
+
+                if columns.len() != 1 {
+                    return Err(error(
+                        "42601",
+                        "subquery must return only one column".to_string(),
+                    ));
+                }
+
+                match rows.as_slice() {
+                    [] => Value::Null,
+                    [row] => row[0].clone(),
+                    _ => {
+                        return Err(error(
+                            "21000",
+                            "more than one row returned by a subquery used as an expression"
+                                .to_string(),
+                        ))
+                    }
+                }
+            }
+            Expr::Exists(select) => {
+                Value::Bool(!self.query(state, select, Some(scope))?.1.is_empty())
+            }
+            Expr::Array(items) => {
+                Value::Array(items.iter().map(eval).collect::<Result<Vec<_>, _>>()?)
+            }
+            Expr::Subscript(array, index) => match (eval(array)?, eval(index)?.as_int()) {
+                (Value::Array(values), Some(n)) if n >= 1 => {
+                    values.get(n as usize - 1).cloned().unwrap_or(Value::Null)
+                }
+                (Value::Array(_) | Value::Null, _) => Value::Null,
+                _ => return Err(Unanswerable::Unsupported),
+            },
+        })
+    }
+
+    // The type of the expression's values, without evaluating it
+    fn expr_type(&self, state: &State, scope: &Scope, expr: &Expr) -> Result<Type, Unanswerable> {
+        Ok(match expr {
+            Expr::Column(parts) => scope.lookup(parts)?.0,
+            Expr::Literal(Value::Int(_)) => Type::INT4,
+            Expr::Literal(Value::Bool(_)) => Type::BOOL,
+            Expr::Literal(_) | Expr::Array(_) | Expr::Subscript(..) => Type::TEXT,
+            Expr::Function(name, _) => function_type(name),
+            Expr::Binary(_, Op::Concat, _) => Type::TEXT,
+            Expr::Not(_)
+            | Expr::Binary(..)
+            | Expr::Any(..)
+            | Expr::In(..)
+            | Expr::InSelect(..)
+            | Expr::IsNull(..)
+            | Expr::Exists(_) => Type::BOOL,
+            Expr::Case(_, branches, default) => {
+                match branches
+                    .iter()
+                    .map(|(_, result)| result)
+                    .chain(default.as_deref())
+                    .find(|result| **result != Expr::Literal(Value::Null))
+                {
+                    Some(result) => self.expr_type(state, scope, result)?,
+                    None => Type::TEXT,
+                }
+            }
+            Expr::Cast(_, ty) => cast_type(ty),
+            Expr::Subquery(select) => {
+                let mut columns = vec![];
+
+                for item in select.from.iter() {
+                    columns.extend(self.source(state, item, Some(scope))?.0);
+                }
+
+                let inner = Scope {
+                    columns: &columns,
+                    row: &[],
+                    outer: Some(scope),
+                };
+
+                match self.outputs(state, &inner, &select.items)?.first() {
+                    Some((_, ty, _)) => *ty,
+                    None => Type::TEXT,
+                }
+            }
+        })
+    }
+}
+
+enum Unanswerable {
+    // The query isn't supported, the executor has to answer it
+    Unsupported,
+    Error(ErrorResponse),
+}
+
+fn error(code: &str, message: String) -> Unanswerable {
+    Unanswerable::Error(response::error(code, message))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Text(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+    // An oid shown as the name of the object, such as a regclass
+    Reg(i64, String),
+}
+
+impl Value {
+    fn to_text(&self) -> Option<Vec<u8>> {
+        self.as_text().map(String::into_bytes)
+    }
+
+    fn as_text(&self) -> Option<String> {
+        Some(match self {
+            Self::Null => return None,
+            Self::Text(s) => s.clone(),
+            Self::Int(n) => n.to_string(),
+            Self::Bool(b) => (if *b { "t" } else { "f" }).to_string(),
+            Self::Array(values) => format!(
+                "{{{}}}",
+                values
+                    .iter()
+                    .map(Self::array_element)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            Self::Reg(_, name) => name.clone(),
+        })
+    }
+
+    // Elements are quoted when they would be ambiguous otherwise
+    fn array_element(&self) -> String {
+        match self.as_text() {
+            None => "NULL".to_string(),
+            Some(s)
+                if s.is_empty()
+                    || s.eq_ignore_ascii_case("null")
+                    || s.contains(|c: char| "{},\"\\".contains(c) || c.is_whitespace()) =>
+            {
+                format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Some(s) => s,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(n) | Self::Reg(n, _) => Some(*n),
+            Self::Text(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    // Compares like PostgreSQL would after casting the (untyped) literal to the column type, None
+    // when either side is NULL
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        Some(match (self, other) {
+            (Self::Null, _) | (_, Self::Null) => return None,
+            (Self::Reg(a, _), b) => Self::Int(*a).compare(b)?,
+            (a, Self::Reg(b, _)) => a.compare(&Self::Int(*b))?,
+            (Self::Int(a), Self::Int(b)) => a.cmp(b),
+            (Self::Int(a), Self::Text(b)) => a.cmp(&b.trim().parse().ok()?),
+            (Self::Text(a), Self::Int(b)) => a.trim().parse::<i64>().ok()?.cmp(b),
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::Bool(a), Self::Text(b)) => a.cmp(&parse_bool(b)?),
+            (Self::Text(a), Self::Bool(b)) => parse_bool(a)?.cmp(b),
+            (Self::Array(_), Self::Text(b)) => self.compare(&Self::Array(parse_array(b)?))?,
+            (Self::Text(a), Self::Array(_)) => Self::Array(parse_array(a)?).compare(other)?,
+            (Self::Array(a), Self::Array(b)) => {
+                for (a, b) in a.iter().zip(b.iter()) {
+                    match a.compare(b)? {
+                        Ordering::Equal => {}
+                        ordering => return Some(ordering),
+                    }
+                }
+
+                a.len().cmp(&b.len())
+            }
+            (a, b) => a.as_text().cmp(&b.as_text()),
+        })
+    }
+
+    // Nulls sort last
+    fn sort_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Self::Null, Self::Null) => Ordering::Equal,
+            (Self::Null, _) => Ordering::Greater,
+            (_, Self::Null) => Ordering::Less,
+            (a, b) => a.compare(b).unwrap_or(Ordering::Equal),
+        }
+    }
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.trim().to_lowercase().as_str() {
+        "t" | "true" | "on" | "yes" | "y" | "1" => Some(true),
+        "f" | "false" | "off" | "no" | "n" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// A one dimensional array in its text form (`{a,"b c",NULL}`)
+fn parse_array(s: &str) -> Option<Vec<Value>> {
+    let inner = s.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let mut values = vec![];
+    let mut chars = inner.chars().peekable();
+
+    if inner.trim().is_empty() {
+        return Some(values);
+    }
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let value = if chars.next_if_eq(&'"').is_some() {
+            let mut value = String::new();
+
+            loop {
+                match chars.next()? {
+                    '"' => break,
+                    '\\' => value.push(chars.next()?),
+                    c => value.push(c),
+                }
+            }
+
+            Value::Text(value)
+        } else {
+            let mut value = String::new();
+
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                if c == '{' || c == '}' || c == '"' {
+                    return None;
+                }
+
+                value.push(c);
+            }
+
+            match value.trim() {
+                null if null.eq_ignore_ascii_case("null") => Value::Null,
+                value => Value::Text(value.to_string()),
+            }
+        };
+
+        values.push(value);
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        match chars.next() {
+            Some(',') => {}
+            None => return Some(values),
+            Some(_) => return None,
+        }
+    }
+}
+
+fn quote_ident(s: &str) -> String {
+    let plain = s.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '$');
+
+    match plain {
+        true => s.to_string(),
+        false => format!("\"{}\"", s.replace('"', "\"\"")),
+    }
+}
+
+// Three-valued logic: NULL is neither true nor false
+fn truth(value: Value) -> Result<Option<bool>, Unanswerable> {
+    match value {
+        Value::Bool(b) => Ok(Some(b)),
+        Value::Null => Ok(None),
+        _ => Err(Unanswerable::Unsupported),
+    }
+}
+
+fn not(value: Option<bool>) -> Value {
+    value.map_or(Value::Null, |b| Value::Bool(!b))
+}
+
+fn negate(value: Value, negated: bool) -> Value {
+    match value {
+        Value::Bool(b) => Value::Bool(b != negated),
+        value => value,
+    }
+}
+
+// True when any of the results is, otherwise NULL when any of them is
+fn any(results: Vec<Value>) -> Value {
+    let mut any = Value::Bool(false);
+
+    for result in results {
+        match result {
+            Value::Bool(true) => return result,
+            Value::Null => any = Value::Null,
+            _ => {}
+        }
+    }
+
+    any
+}
+
+fn apply(op: Op, a: &Value, b: &Value) -> Result<Value, Unanswerable> {
+    if *a == Value::Null || *b == Value::Null {
+        return Ok(Value::Null);
+    }
+
+    let ordering = a.compare(b);
+    let text = |value: &Value| value.as_text().unwrap_or_default();
+
+    Ok(Value::Bool(match op {
+        Op::Eq => ordering == Some(Ordering::Equal),
+        Op::Ne => ordering.is_some_and(|ordering| ordering != Ordering::Equal),
+        Op::Lt => ordering == Some(Ordering::Less),
+        Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => ordering == Some(Ordering::Greater),
+        Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        Op::Concat => return Ok(Value::Text(text(a) + &text(b))),
+        Op::Match {
+            negated,
+            case_insensitive,
+        } => {
+            regex_match(&text(b), &text(a), case_insensitive).ok_or(Unanswerable::Unsupported)?
+                != negated
+        }
+        Op::Like {
+            negated,
+            case_insensitive,
+        } => {
+            like_match(&text(b), &text(a), case_insensitive).ok_or(Unanswerable::Unsupported)?
+                != negated
+        }
+        Op::And | Op::Or => return Err(Unanswerable::Unsupported),
+    }))
+}
+
+// The result type of the functions `call` supports
+fn function_type(name: &str) -> Type {
+    match name {
+        "count" => Type::INT8,
+        "array_lower" | "array_upper" | "array_length" | "length" | "generate_series" => Type::INT4,
+        "pg_table_is_visible"
+        | "pg_relation_is_publishable"
+        | "has_table_privilege"
+        | "has_schema_privilege"
+        | "has_database_privilege"
+        | "has_column_privilege"
+        | "pg_has_role" => Type::BOOL,
+        "current_database"
+        | "current_catalog"
+        | "current_schema"
+        | "current_user"
+        | "session_user"
+        | "user"
+        | "current_role"
+        | "pg_get_userbyid"
+        | "pg_encoding_to_char" => Type::NAME,
+        _ => Type::TEXT,
+    }
+}
+
+fn cast_type(ty: &str) -> Type {
+    match ty {
+        "int" => Type::INT4,
+        ty => Type::builtin(ty).unwrap_or(Type::TEXT),
+    }
+}
+
+struct Relation {
+    columns: Vec<(&'static str, Type)>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Relation {
+    fn new(columns: &[(&'static str, Type)], rows: Vec<Vec<Value>>) -> Self {
+        Self {
+            columns: columns.to_vec(),
+            rows,
+        }
+    }
+}
+
+struct ScopeColumn {
+    // The alias, or the name of the table along with its schema
+    qualifier: String,
+    schema: Option<String>,
+    name: String,
+    ty: Type,
+}
+
+// The columns of the row being evaluated, and the row of the query around it for correlated
+// subqueries. The row is empty when only the types are needed.
+#[derive(Clone, Copy)]
+struct Scope<'a> {
+    columns: &'a [ScopeColumn],
+    row: &'a [Value],
+    outer: Option<&'a Scope<'a>>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, parts: &[String]) -> Result<(Type, Value), Unanswerable> {
+        let (schema, qualifier, name) = match parts {
+            [name] => (None, None, name),
+            [qualifier, name] => (None, Some(qualifier), name),
+            [schema, qualifier, name] => (Some(schema), Some(qualifier), name),
+            _ => return Err(Unanswerable::Unsupported),
+        };
+        let mut found = self.columns.iter().enumerate().filter(|(_, column)| {
+            column.name == *name
+                && qualifier.is_none_or(|qualifier| column.qualifier == *qualifier)
+                && schema.is_none_or(|schema| column.schema.as_ref() == Some(schema))
+        });
+
+        match (found.next(), found.next()) {
+            (Some((n, column)), None) => {
+                Ok((column.ty, self.row.get(n).cloned().unwrap_or(Value::Null)))
+            }
+            (Some(_), Some(_)) => Err(error(
+                "42702",
+                format!("column reference \"{}\" is ambiguous", name),
+            )),
+            (None, _) => match self.outer {
+                Some(outer) => outer.lookup(parts),
+                None => Err(error(
+                    "42703",
+                    format!("column \"{}\" does not exist", parts.join(".")),
+                )),
+            },
+        }
+    }
+}
+
+enum Output<'a> {
+    Column(usize),
+    Expr(&'a Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Concat,
+    // `~`, `~*`, `!~` and `!~*`
+    Match {
+        negated: bool,
+        case_insensitive: bool,
+    },
+    Like {
+        negated: bool,
+        case_insensitive: bool,
+    },
+}
+
+// The operators which can be written as `OPERATOR(pg_catalog.op)`
+fn operator(symbol: &str) -> Option<Op> {
+    Some(match symbol {
+        "=" => Op::Eq,
+        "<>" | "!=" => Op::Ne,
+        "<" => Op::Lt,
+        "<=" => Op::Le,
+        ">" => Op::Gt,
+        ">=" => Op::Ge,
+        "||" => Op::Concat,
+        "~" | "!~" | "~*" | "!~*" => Op::Match {
+            negated: symbol.starts_with('!'),
+            case_insensitive: symbol.ends_with('*'),
+        },
+        "~~" | "!~~" | "~~*" | "!~~*" => Op::Like {
+            negated: symbol.starts_with('!'),
+            case_insensitive: symbol.ends_with('*'),
+        },
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    // Optionally qualified by the table, and the schema of the table
+    Column(Vec<String>),
+    Literal(Value),
+    // Without the schema, `count(*)` has no arguments
+    Function(String, Vec<Expr>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+    // `expr op ANY (array)`
+    Any(Box<Expr>, Op, Box<Expr>),
+    In(Box<Expr>, Vec<Expr>, bool),
+    InSelect(Box<Expr>, Box<Select>, bool),
+    IsNull(Box<Expr>, bool),
+    // `CASE [operand] WHEN ... THEN ... [ELSE ...] END`
+    Case(Option<Box<Expr>>, Vec<(Expr, Expr)>, Option<Box<Expr>>),
+    // Type names are lowercase without the schema, array types end with `[]`
+    Cast(Box<Expr>, String),
+    Subquery(Box<Select>),
+    Exists(Box<Select>),
+    Array(Vec<Expr>),
+    Subscript(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // Whether the expression or one of its operands satisfies `f`, subqueries aren't searched
+    fn any(&self, f: &dyn Fn(&Expr) -> bool) -> bool {
+        f(self)
+            || match self {
+                Self::Column(_) | Self::Literal(_) | Self::Subquery(_) | Self::Exists(_) => false,
+                Self::Function(_, args) | Self::Array(args) => args.iter().any(|arg| arg.any(f)),
+                Self::Not(expr)
+                | Self::IsNull(expr, _)
+                | Self::Cast(expr, _)
+                | Self::InSelect(expr, _, _) => expr.any(f),
+                Self::Binary(a, _, b) | Self::Any(a, _, b) | Self::Subscript(a, b) => {
+                    a.any(f) || b.any(f)
+                }
+                Self::In(expr, list, _) => expr.any(f) || list.iter().any(|item| item.any(f)),
+                Self::Case(operand, branches, default) => {
+                    operand.iter().chain(default.iter()).any(|expr| expr.any(f))
+                        || branches
+                            .iter()
+                            .any(|(condition, result)| condition.any(f) || result.any(f))
+                }
+            }
+    }
+
+    // The column name PostgreSQL gives the expression
+    fn name(&self) -> String {
+        match self {
+            Self::Column(parts) => parts.last().cloned().unwrap_or_default(),
+            Self::Function(name, _) => name.clone(),
+            Self::Cast(expr, ty) => match **expr {
+                Self::Column(_) | Self::Function(..) | Self::Cast(..) => expr.name(),
+                _ => ty.trim_end_matches("[]").to_string(),
+            },
+            Self::Case(..) => "case".to_string(),
+            Self::Exists(_) => "exists".to_string(),
+            Self::Array(_) => "array".to_string(),
+            Self::Subquery(select) => match select.items.first() {
+                Some(Item::Expr(_, Some(alias))) => alias.clone(),
+                Some(Item::Expr(expr, None)) => expr.name(),
+                _ => "?column?".to_string(),
+            },
+            _ => "?column?".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Item {
+    // `*` or `table.*`
+    Wildcard(Option<String>),
+    Expr(Expr, Option<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Join {
+    Cross,
+    Inner,
+    Left,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    // Fully qualified (`pg_catalog.pg_class`)
+    Table(String),
+    Function(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FromItem {
+    join: Join,
+    source: Source,
+    alias: Option<String>,
+    on: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Select {
+    items: Vec<Item>,
+    from: Vec<FromItem>,
+    filter: Option<Expr>,
+    // Output columns can be referenced by their position or their name
+    order_by: Vec<(Expr, bool)>,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+// The oids PostgreSQL gives its catalog tables, for `'pg_class'::regclass`
+const CATALOG_TABLES: [(&str, i64); 13] = [
+    ("pg_type", 1247),
+    ("pg_attribute", 1249),
+    ("pg_proc", 1255),
+    ("pg_class", 1259),
+    ("pg_database", 1262),
+    ("pg_am", 2601),
+    ("pg_attrdef", 2604),
+    ("pg_constraint", 2606),
+    ("pg_description", 2609),
+    ("pg_index", 2610),
+    ("pg_inherits", 2611),
+    ("pg_namespace", 2615),
+    ("pg_collation", 3456),
+];
+
+// generate_series in FROM is only used to walk arrays, longer series are left to the executor
+const MAX_SERIES: i64 = 10_000;
+
+// Joins are nested loops over rows held in memory, larger results (usually cross joins) fail
+const MAX_JOIN_ROWS: usize = 100_000;
+
+// Functions which can be called without parentheses
+const SQL_FUNCTIONS: [&str; 6] = [
+    "current_catalog",
+    "current_role",
+    "current_schema",
+    "current_user",
+    "session_user",
+    "user",
+];
+
+// Keywords which can't be aliases or start an expression
+const RESERVED: [&str; 46] = [
+    "all",
+    "and",
+    "any",
+    "array",
+    "as",
+    "asc",
+    "by",
+    "case",
+    "collate",
+    "cross",
+    "desc",
+    "distinct",
+    "else",
+    "end",
+    "except",
+    "exists",
+    "false",
+    "fetch",
+    "for",
+    "from",
+    "full",
+    "group",
+    "having",
+    "ilike",
+    "in",
+    "inner",
+    "intersect",
+    "is",
+    "join",
+    "left",
+    "like",
+    "limit",
+    "natural",
+    "not",
+    "null",
+    "offset",
+    "on",
+    "or",
+    "order",
+    "outer",
+    "right",
+    "select",
+    "then",
+    "true",
+    "union",
+    "where",
+];
+
+// Parses the subset of SELECT statements catalog queries use: joins, expressions with the usual
+// operators, CASE, casts and subqueries, ORDER BY, LIMIT and OFFSET. Anything else (such as
+// GROUP BY or UNION) is left to the executor.
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(query: &'a str) -> Self {
+        Self {
+            tokens: tokenize(query)
+                .into_iter()
+                .filter(|token| !matches!(token, Token::Comment(_)))
+                .collect(),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, n: usize) -> Option<Token<'a>> {
+        self.tokens.get(self.pos + n).copied()
+    }
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    fn punct(&mut self, c: char) -> bool {
+        if self.peek() == Some(Token::Punct(c)) {
+            self.pos += 1;
+            return true;
+        }
+
+        false
+    }
+
+    // An operator made of several punctuation characters, such as `<>` or `::`
+    fn puncts(&mut self, s: &str) -> bool {
+        let found = s
+            .chars()
+            .enumerate()
+            .all(|(n, c)| self.peek_at(n) == Some(Token::Punct(c)));
+
+        if found {
+            self.pos += s.chars().count();
+        }
+
+        found
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+
+        if found {
+            self.pos += 1;
+        }
+
+        found
+    }
+
+    fn identifier(&mut self) -> Option<String> {
+        match self.next()? {
+            Token::Word(word) => Some(word.to_lowercase()),
+            Token::QuotedIdent(ident) => unquote(ident),
+            _ => None,
+        }
+    }
+
+    // An identifier which isn't a keyword, used for aliases
+    fn alias(&mut self) -> Option<String> {
+        if self.keyword("as") {
+            return self.identifier();
+        }
+
+        match self.peek() {
+            Some(Token::Word(word)) if !is_reserved(word) => self.identifier(),
+            Some(Token::QuotedIdent(_)) => self.identifier(),
+            _ => None,
+        }
+    }
+
+    fn statement(&mut self) -> Option<Select> {
+        let select = self.select()?;
+
+        while self.peek() == Some(Token::Semicolon) {
+            self.pos += 1;
+        }
+
+        // Anything we didn't understand (such as UNION) is left to the executor
+        match self.peek() {
+            None => Some(select),
+            Some(_) => None,
+        }
+    }
+
+    fn select(&mut self) -> Option<Select> {
+        if !self.keyword("select") || self.is_keyword("distinct") {
+            return None;
+        }
+
+        self.keyword("all");
+
+        let mut select = Select {
+            items: vec![self.item()?],
+            from: vec![],
+            filter: None,
+            order_by: vec![],
+            limit: None,
+            offset: 0,
+        };
+
+        while self.punct(',') {
+            select.items.push(self.item()?);
+        }
+
+        if self.keyword("from") {
+            select.from = self.from()?;
+        }
+
+        if self.keyword("where") {
+            select.filter = Some(self.expr()?);
+        }
+
+        if self.keyword("order") {
+            if !self.keyword("by") {
+                return None;
+            }
+
+            loop {
+                let expr = self.expr()?;
+                let desc = self.keyword("desc");
+
+                if !desc {
+                    self.keyword("asc");
+                }
+
+                // Nulls sort last, which is the default for ascending order only
+                if self.keyword("nulls") && (desc || !self.keyword("last")) {
+                    return None;
+                }
+
+                select.order_by.push((expr, desc));
+
+                if !self.punct(',') {
+                    break;
+                }
+            }
+        }
+
+        loop {
+            if self.keyword("limit") {
+                select.limit = Some(self.count()?);
+            } else if self.keyword("offset") {
+                select.offset = self.count()?;
+            } else {
+                return Some(select);
+            }
+        }
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        match self.next()? {
+            Token::Literal(literal) => literal.parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn item(&mut self) -> Option<Item> {
+        if self.punct('*') {
+            return Some(Item::Wildcard(None));
+        }
+
+        // `table.*`
+        if matches!(self.peek(), Some(Token::Word(_) | Token::QuotedIdent(_)))
+            && self.peek_at(1) == Some(Token::Punct('.'))
+            && self.peek_at(2) == Some(Token::Punct('*'))
+        {
+            let qualifier = self.identifier()?;
+            self.pos += 2;
+            return Some(Item::Wildcard(Some(qualifier)));
+        }
+
+        let expr = self.expr()?;
+        Some(Item::Expr(expr, self.alias()))
+    }
+
+    fn from(&mut self) -> Option<Vec<FromItem>> {
+        let mut items = vec![self.table_ref(Join::Cross)?];
+
+        loop {
+            let join = if self.punct(',') {
+                Join::Cross
+            } else if self.keyword("cross") {
+                if !self.keyword("join") {
+                    return None;
+                }
+
+                Join::Cross
+            } else if self.keyword("join") {
+                Join::Inner
+            } else if self.keyword("inner") {
+                if !self.keyword("join") {
+                    return None;
+                }
+
+                Join::Inner
+            } else if self.keyword("left") {
+                self.keyword("outer");
+
+                if !self.keyword("join") {
+                    return None;
+                }
+
+                Join::Left
+            } else {
+                return Some(items);
+            };
+
+            let mut item = self.table_ref(join)?;
+
+            if join != Join::Cross {
+                if !self.keyword("on") {
+                    return None;
+                }
+
+                item.on = Some(self.expr()?);
+            }
+
+            items.push(item);
+        }
+    }
+
+    fn table_ref(&mut self, join: Join) -> Option<FromItem> {
+        let mut name = vec![self.identifier()?];
+
+        while self.punct('.') {
+            name.push(self.identifier()?);
+        }
+
+        let source = if self.punct('(') {
+            Source::Function(name.pop()?, self.args()?)
+        } else {
+            Source::Table(match name.as_slice() {
+                [table] => format!("pg_catalog.{}", table),
+                [schema, table] => format!("{}.{}", schema, table),
+                _ => return None,
+            })
+        };
+        let alias = self.alias();
+
+        // Column aliases (`AS t(a, b)`) aren't supported
+        if self.peek() == Some(Token::Punct('(')) {
+            return None;
+        }
+
+        Some(FromItem {
+            join,
+            source,
+            alias,
+            on: None,
+        })
+    }
+
+    // The arguments of a function call after the opening parenthesis, `count(*)` has none
+    fn args(&mut self) -> Option<Vec<Expr>> {
+        let mut args = vec![];
+
+        if self.punct(')') {
+            return Some(args);
+        }
+
+        if self.punct('*') {
+            return self.punct(')').then_some(args);
+        }
+
+        loop {
+            args.push(self.expr()?);
+
+            if self.punct(')') {
+                return Some(args);
+            }
+
+            if !self.punct(',') {
+                return None;
+            }
+        }
+    }
+
+    fn expr(&mut self) -> Option<Expr> {
+        let mut expr = self.and()?;
+
+        while self.keyword("or") {
+            expr = Expr::Binary(Box::new(expr), Op::Or, Box::new(self.and()?));
+        }
+
+        Some(expr)
+    }
+
+    fn and(&mut self) -> Option<Expr> {
+        let mut expr = self.not()?;
+
+        while self.keyword("and") {
+            expr = Expr::Binary(Box::new(expr), Op::And, Box::new(self.not()?));
+        }
+
+        Some(expr)
+    }
+
+    fn not(&mut self) -> Option<Expr> {
+        if self.keyword("not") {
+            return Some(Expr::Not(Box::new(self.not()?)));
+        }
+
+        self.is()
+    }
+
+    fn is(&mut self) -> Option<Expr> {
+        let mut expr = self.comparison()?;
+
+        while self.keyword("is") {
+            let negated = self.keyword("not");
+
+            if !self.keyword("null") {
+                return None;
+            }
+
+            expr = Expr::IsNull(Box::new(expr), negated);
+        }
+
+        Some(expr)
+    }
+
+    fn comparison(&mut self) -> Option<Expr> {
+        let expr = self.predicate()?;
+        let op = match self.comparison_op() {
+            Some(op) => op,
+            None => return Some(expr),
+        };
+
+        if self.keyword("any") || self.keyword("some") {
+            if !self.punct('(') {
+                return None;
+            }
+
+            let array = self.expr()?;
+
+            if !self.punct(')') {
+                return None;
+            }
+
+            return Some(Expr::Any(Box::new(expr), op, Box::new(array)));
+        }
+
+        Some(Expr::Binary(
+            Box::new(expr),
+            op,
+            Box::new(self.predicate()?),
+        ))
+    }
+
+    fn comparison_op(&mut self) -> Option<Op> {
+        // `<` is also the start of `<>` and `<=`
+        for (symbol, op) in [
+            ("<>", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("!=", Op::Ne),
+            ("=", Op::Eq),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ] {
+            if self.puncts(symbol) {
+                return Some(op);
+            }
+        }
+
+        None
+    }
+
+    // `[NOT] IN`, `[NOT] LIKE` and `[NOT] ILIKE`
+    fn predicate(&mut self) -> Option<Expr> {
+        let expr = self.operation()?;
+        let start = self.pos;
+        let negated = self.keyword("not");
+
+        if self.keyword("in") {
+            if !self.punct('(') {
+                return None;
+            }
+
+            if self.is_keyword("select") {
+                let select = self.select()?;
+
+                if !self.punct(')') {
+                    return None;
+                }
+
+                return Some(Expr::InSelect(Box::new(expr), Box::new(select), negated));
+            }
+
+            let mut list = vec![self.expr()?];
+
+            while self.punct(',') {
+                list.push(self.expr()?);
+            }
+
+            if !self.punct(')') {
+                return None;
+            }
+
+            return Some(Expr::In(Box::new(expr), list, negated));
+        }
+
+        for (keyword, case_insensitive) in [("like", false), ("ilike", true)] {
+            if self.keyword(keyword) {
+                let op = Op::Like {
+                    negated,
+                    case_insensitive,
+                };
+
+                return Some(Expr::Binary(
+                    Box::new(expr),
+                    op,
+                    Box::new(self.operation()?),
+                ));
+            }
+        }
+
+        self.pos = start;
+        Some(expr)
+    }
+
+    // Operators such as `||` and `~`, which can also be written as `OPERATOR(pg_catalog.~)`
+    fn operation(&mut self) -> Option<Expr> {
+        let mut expr = self.unary()?;
+
+        loop {
+            let op = if self.keyword("operator") {
+                if !self.punct('(') {
+                    return None;
+                }
+
+                if matches!(self.peek(), Some(Token::Word(_))) {
+                    self.identifier()?;
+
+                    if !self.punct('.') {
+                        return None;
+                    }
+                }
+
+                let mut symbol = String::new();
+
+                while let Some(Token::Punct(c)) = self.peek() {
+                    if c == ')' {
+                        break;
+                    }
+
+                    symbol.push(c);
+                    self.pos += 1;
+                }
+
+                if !self.punct(')') {
+                    return None;
+                }
+
+                operator(&symbol)?
+            } else if let Some(symbol) = ["!~*", "!~", "~*", "~", "||"]
+                .into_iter()
+                .find(|symbol| self.puncts(symbol))
+            {
+                operator(symbol)?
+            } else {
+                return Some(expr);
+            };
+
+            expr = Expr::Binary(Box::new(expr), op, Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Option<Expr> {
+        if self.punct('-') {
+            return match self.next()? {
+                Token::Literal(literal) => {
+                    Some(Expr::Literal(Value::Int(-literal.parse::<i64>().ok()?)))
+                }
+                _ => None,
+            };
+        }
+
+        self.postfix()
+    }
+
+    // Casts, subscripts and COLLATE
+    fn postfix(&mut self) -> Option<Expr> {
+        let mut expr = self.primary()?;
+
+        loop {
+            if self.puncts("::") {
+                expr = Expr::Cast(Box::new(expr), self.type_name()?);
+            } else if self.punct('[') {
+                let index = self.expr()?;
+
+                if !self.punct(']') {
+                    return None;
+                }
+
+                expr = Expr::Subscript(Box::new(expr), Box::new(index));
+            } else if self.keyword("collate") {
+                // Comparisons don't depend on the collation here
+                self.identifier()?;
+
+                if self.punct('.') {
+                    self.identifier()?;
+                }
+            } else {
+                return Some(expr);
+            }
+        }
+    }
+
+    // Lowercase without the schema, array types end with `[]`
+    fn type_name(&mut self) -> Option<String> {
+        let mut name = self.identifier()?;
+
+        if self.punct('.') {
+            name = self.identifier()?;
+        }
+
+        if self.punct('[') {
+            if !self.punct(']') {
+                return None;
+            }
+
+            name.push_str("[]");
+        }
+
+        Some(name)
+    }
+
+    fn primary(&mut self) -> Option<Expr> {
+        Some(match self.peek()? {
+            Token::Literal(literal) => {
+                self.pos += 1;
+                Expr::Literal(literal_value(literal)?)
+            }
+            Token::Punct('(') => {
+                self.pos += 1;
+
+                let expr = match self.is_keyword("select") {
+                    true => Expr::Subquery(Box::new(self.select()?)),
+                    false => self.expr()?,
+                };
+
+                if !self.punct(')') {
+                    return None;
+                }
+
+                expr
+            }
+            Token::Word(word) if is_reserved(word) => {
+                self.pos += 1;
+
+                match word.to_lowercase().as_str() {
+                    "true" => Expr::Literal(Value::Bool(true)),
+                    "false" => Expr::Literal(Value::Bool(false)),
+                    "null" => Expr::Literal(Value::Null),
+                    "case" => self.case()?,
+                    "exists" => {
+                        if !self.punct('(') {
+                            return None;
+                        }
+
+                        let select = self.select()?;
+
+                        if !self.punct(')') {
+                            return None;
+                        }
+
+                        Expr::Exists(Box::new(select))
+                    }
+                    "array" => {
+                        if !self.punct('[') {
+                            return None;
+                        }
+
+                        let mut items = vec![];
+
+                        while !self.punct(']') {
+                            if !items.is_empty() && !self.punct(',') {
+                                return None;
+                            }
+
+                            items.push(self.expr()?);
+                        }
+
+                        Expr::Array(items)
+                    }
+                    _ => return None,
+                }
+            }
+            Token::Word(_) | Token::QuotedIdent(_) => self.reference()?,
+            _ => return None,
+        })
+    }
+
+    fn case(&mut self) -> Option<Expr> {
+        let operand = match self.is_keyword("when") {
+            true => None,
+            false => Some(Box::new(self.expr()?)),
+        };
+        let mut branches = vec![];
+
+        while self.keyword("when") {
+            let condition = self.expr()?;
+
+            if !self.keyword("then") {
+                return None;
+            }
+
+            branches.push((condition, self.expr()?));
+        }
+
+        let default = match self.keyword("else") {
+            true => Some(Box::new(self.expr()?)),
+            false => None,
+        };
+
+        if branches.is_empty() || !self.keyword("end") {
+            return None;
+        }
+
+        Some(Expr::Case(operand, branches, default))
+    }
+
+    // A column or a function call
+    fn reference(&mut self) -> Option<Expr> {
+        let mut name = vec![self.identifier()?];
+
+        while self.peek() == Some(Token::Punct('.')) && self.peek_at(1) != Some(Token::Punct('*')) {
+            self.pos += 1;
+            name.push(self.identifier()?);
+        }
+
+        if self.punct('(') {
+            return Some(Expr::Function(name.pop()?, self.args()?));
+        }
+
+        match name.as_slice() {
+            [function] if SQL_FUNCTIONS.contains(&function.as_str()) => {
+                Some(Expr::Function(function.clone(), vec![]))
+            }
+            _ => Some(Expr::Column(name)),
+        }
+    }
+}
+
+fn is_reserved(word: &str) -> bool {
+    RESERVED
+        .iter()
+        .any(|reserved| word.eq_ignore_ascii_case(reserved))
+}
+
+fn literal_value(literal: &str) -> Option<Value> {
+    if literal.starts_with('\'') {
+        return unquote(literal).map(Value::Text);
+    }
+
+    if let Some(escaped) = literal.strip_prefix(['e', 'E']) {
+        return unescape(escaped).map(Value::Text);
+    }
+
+    // `$tag$...$tag$`
+    if let Some(rest) = literal.strip_prefix('$') {
+        let tag = &literal[..rest.find('$')? + 2];
+
+        return literal[tag.len()..]
+            .strip_suffix(tag)
+            .filter(|_| literal.len() >= tag.len() * 2)
+            .map(|s| Value::Text(s.to_string()));
+    }
+
+    literal.parse().ok().map(Value::Int)
+}
+
+// The contents of an escape string (`E'\n'`)
+fn unescape(quoted: &str) -> Option<String> {
+    let mut chars = quoted.strip_prefix('\'')?.strip_suffix('\'')?.chars();
+    let mut s = String::new();
+
+    while let Some(c) = chars.next() {
+        s.push(match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                c => c,
+            },
+            // Quotes are doubled
+            '\'' if chars.next()? == '\'' => '\'',
+            '\'' => return None,
+            c => c,
+        });
+    }
+
+    Some(s)
+}
+
+type Answer = Option<Result<Vec<BackendMessage>, ErrorResponse>>;
+
+// Answers catalog queries from the catalog and passes everything else to the executor. Only the
+// simple query protocol is intercepted.
+pub struct CatalogQueryExec<Q: QueryExec> {
+    inner: Q,
+    catalog: Arc<Catalog>,
+    state: State,
+    transaction_status: TransactionStatus,
+    // The answer to the last query described or streamed, kept until it's executed. Anything
+    // the executor runs may change the settings it depends on.
+    answered: Option<(String, Answer)>,
+}
+
+impl<Q: QueryExec> CatalogQueryExec<Q> {
+    pub fn new(inner: Q, catalog: Arc<Catalog>) -> Self {
+        Self {
+            inner,
+            catalog,
+            state: State::default(),
+            transaction_status: TransactionStatus::Idle,
+            answered: None,
+        }
+    }
+
+    fn answer(&mut self, query: &str) -> &Answer {
+        if !matches!(&self.answered, Some((answered, _)) if answered == query) {
+            let answer = self.catalog.answer(&self.state, query);
+            self.answered = Some((query.to_string(), answer));
+        }
+
+        &self.answered.as_ref().unwrap().1
+    }
+
+    fn take_answer(&mut self, query: &str) -> Answer {
+        self.answer(query);
+        self.answered.take().and_then(|(_, answer)| answer)
+    }
+
+    // Answers catalog queries, `run` passes everything else to the executor
+    fn answer_or(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+        run: impl FnOnce(&mut Q, &mut dyn ResultSink) -> io::Result<TransactionStatus>,
+    ) -> io::Result<TransactionStatus> {
+        match self.take_answer(query) {
+            Some(Ok(messages)) => {
+                for msg in messages {
+                    sink.push(msg)?;
+                }
+            }
+            Some(Err(e)) => sink.push(BackendMessage::ErrorResponse(e))?,
+            None => self.transaction_status = run(&mut self.inner, sink)?,
+        }
+
+        Ok(self.transaction_status)
+    }
+}
+
+impl<Q: QueryExec> QueryExec for CatalogQueryExec<Q> {
+    fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        self.state = state.clone();
+        self.inner.startup(state)
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult {
+        match self.take_answer(query) {
+            Some(Ok(messages)) => messages
+                .into_iter()
+                .find_map(|msg| match msg {
+                    BackendMessage::CommandComplete(cc) => Some(Ok(cc)),
+                    _ => None,
+                })
+                .unwrap(),
+            Some(Err(e)) => Err(e),
+            None => self.inner.execute(query),
+        }
+    }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.answer_or(query, sink, |inner, sink| inner.execute_to(query, sink))
+    }
+
+    #[cfg(feature = "sql-parser")]
+    fn execute_parsed(
+        &mut self,
+        query: &str,
+        parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.answer_or(query, sink, |inner, sink| {
+            inner.execute_parsed(query, parsed, sink)
+        })
+    }
+
+    fn extended(
+        &mut self,
+        msg: ExtendedMessage,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        self.answered = None;
+        self.inner.extended(msg, sink)
+    }
+
+    #[cfg(feature = "sql-parser")]
+    fn extended_parsed(
+        &mut self,
+        msg: ExtendedMessage,
+        parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        self.answered = None;
+        self.inner.extended_parsed(msg, parsed, sink)
+    }
+
+    fn flush(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        self.inner.flush(sink)
+    }
+
+    fn sync(&mut self, sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        self.answered = None;
+        self.transaction_status = self.inner.sync(sink)?;
+        Ok(self.transaction_status)
+    }
+
+    fn set(&mut self, statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        self.answered = None;
+        self.inner.set(statement)
+    }
+
+    fn function_call(
+        &mut self,
+        call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.answered = None;
+        self.transaction_status = self.inner.function_call(call, sink)?;
+        Ok(self.transaction_status)
+    }
+
+    fn copy_both(&mut self, query: &str) -> io::Result<Option<Box<dyn CopyBoth>>> {
+        self.answered = None;
+        self.inner.copy_both(query)
+    }
+
+    // Answering a catalog query has no side effects, the description is taken from the answer
+    fn describe(&mut self, query: &str) -> io::Result<Option<RowDescription>> {
+        match self.answer(query) {
+            Some(Ok(messages)) => Ok(messages.iter().find_map(|msg| match msg {
+                BackendMessage::RowDescription(description) => Some(description.clone()),
+                _ => None,
+            })),
+            Some(Err(_)) => Ok(None),
+            None => self.inner.describe(query),
+        }
+    }
+
+    // Catalog queries are answered by `execute_to`
+    fn stream(&mut self, query: &str) -> io::Result<Option<RowStream>> {
+        match self.answer(query) {
+            Some(_) => Ok(None),
+            None => {
+                let stream = self.inner.stream(query)?;
+
+                // The executor runs the query itself instead of `execute_to`
+                if stream.is_some() {
+                    self.answered = None;
+                }
+
+                Ok(stream)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NoopQueryExec;
+    use crate::proto::messages::Field;
+
+    fn query_exec() -> CatalogQueryExec<NoopQueryExec> {
+        let catalog = Catalog::new()
+            .schema("app")
+            .table(
+                Table::new("app", "users")
+                    .column(Column::new("id", Type::INT4).not_null())
+                    .column(Column::new("name", Type::TEXT)),
+            )
+            .table(
+                Table::new("public", "orders")
+                    .column(Column::new("id", Type::INT8).not_null())
+                    .column(Column::new("user_id", Type::INT4)),
+            );
+
+        let mut query_exec = CatalogQueryExec::new(NoopQueryExec::new(), Arc::new(catalog));
+        query_exec
+            .startup(&State::new("bob".to_string(), "shop".to_string()))
+            .unwrap()
+            .unwrap();
+        query_exec
+    }
+
+    fn rows(query_exec: &mut CatalogQueryExec<NoopQueryExec>, query: &str) -> Vec<Vec<String>> {
+        let mut messages = vec![];
+        query_exec.execute_to(query, &mut messages).unwrap();
+
+        messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                BackendMessage::DataRow(row) => Some(
+                    row.values
+                        .into_iter()
+                        .map(|v| String::from_utf8(v.unwrap_or_default()).unwrap())
+                        .collect(),
+                ),
+                BackendMessage::ErrorResponse(e) => panic!("unexpected error: {}", e),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let catalog = Catalog::parse(
+            "# comment\n\
+             schema app\n\
+             table app.users (id int4 not null, name character varying)\n\
+             view reports.active (id integer NOT NULL)\n",
+        )
+        .unwrap();
+
+        assert_eq!(catalog.schemas, vec!["app", "reports"]);
+        assert_eq!(catalog.tables[0].columns[1].ty, Type::VARCHAR);
+        assert!(!catalog.tables[0].columns[0].nullable);
+        assert_eq!(catalog.tables[1].kind, TableKind::View);
+        assert_eq!(catalog.tables[1].schema, "reports");
+
+        assert_eq!(
+            Catalog::parse("table users (id int4)\ntable orders (id serial)").err(),
+            Some("line 2: unknown type: serial".to_string())
+        );
+        assert!(Catalog::parse("table users").is_err());
+        assert!(Catalog::parse("index users_pkey").is_err());
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut query_exec = query_exec();
+
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "SELECT current_database(), current_schema, current_setting('DateStyle')"
+            ),
+            vec![vec!["shop", "public", "ISO, MDY"]]
+        );
+        assert!(rows(&mut query_exec, "select version();")[0][0].starts_with("PostgreSQL 14.0"));
+
+        let e = query_exec
+            .execute("select current_setting('nope')")
+            .unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("42704"));
+    }
+
+    #[test]
+    fn test_catalog_tables() {
+        let mut query_exec = query_exec();
+
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "select c.relname, c.relkind from pg_catalog.pg_class c \
+                 where c.relnamespace = 16384 order by relname"
+            ),
+            vec![vec!["users", "r"]]
+        );
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "select column_name, data_type, is_nullable from information_schema.columns \
+                 where table_schema = 'app' and table_name = 'users' order by ordinal_position desc"
+            ),
+            vec![vec!["name", "text", "YES"], vec!["id", "integer", "NO"]]
+        );
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "select nspname from pg_namespace where nspname not in ('pg_catalog', 'information_schema') limit 1"
+            ),
+            vec![vec!["public"]]
+        );
+
+        // Queries we don't understand are left to the executor
+        assert_eq!(
+            query_exec
+                .execute("select * from users")
+                .unwrap()
+                .command_tag,
+            CommandTag::Select(0)
+        );
+        assert!(rows(
+            &mut query_exec,
+            "select nspname from pg_namespace union select relname from pg_class"
+        )
+        .is_empty());
+        assert!(rows(&mut query_exec, "select '日").is_empty());
+
+        let e = query_exec
+            .execute("select oid from pg_class c join pg_namespace n on true")
+            .unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("42702"));
+
+        // Cross joins can't grow without bounds
+        let e = query_exec
+            .execute("select count(*) from pg_type a, pg_type b, pg_type c, pg_type d")
+            .unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("54000"));
+
+        // Queries are described by the columns of their answer
+        let description = query_exec
+            .describe("select nspname from pg_namespace")
+            .unwrap()
+            .unwrap();
+        assert_eq!(description.fields[0].name, "nspname");
+
+        // The answer is kept for the query's execution and not evaluated again
+        assert!(query_exec
+            .stream("select nspname from pg_namespace")
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            &query_exec.answered,
+            Some((query, Some(Ok(_)))) if query == "select nspname from pg_namespace"
+        ));
+        assert_eq!(
+            rows(&mut query_exec, "select nspname from pg_namespace").len(),
+            4
+        );
+        assert!(query_exec.answered.is_none());
+
+        assert!(query_exec
+            .describe("select * from users")
+            .unwrap()
+            .is_none());
+        assert!(rows(&mut query_exec, "select \"日 from pg_class").is_empty());
+    }
+
+    #[test]
+    fn test_psql() {
+        let mut query_exec = query_exec();
+
+        // \dt
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                r#"SELECT n.nspname as "Schema",
+                  c.relname as "Name",
+                  CASE c.relkind WHEN 'r' THEN 'table' WHEN 'v' THEN 'view' WHEN 'm' THEN 'materialized view' WHEN 'i' THEN 'index' WHEN 'S' THEN 'sequence' WHEN 't' THEN 'TOAST table' WHEN 'f' THEN 'foreign table' WHEN 'p' THEN 'partitioned table' WHEN 'I' THEN 'partitioned index' END as "Type",
+                  pg_catalog.pg_get_userbyid(c.relowner) as "Owner"
+                FROM pg_catalog.pg_class c
+                     LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+                     LEFT JOIN pg_catalog.pg_am am ON am.oid = c.relam
+                WHERE c.relkind IN ('r','p','')
+                      AND n.nspname <> 'pg_catalog'
+                      AND n.nspname !~ '^pg_toast'
+                      AND n.nspname <> 'information_schema'
+                  AND pg_catalog.pg_table_is_visible(c.oid)
+                ORDER BY 1,2;"#
+            ),
+            vec![vec!["public", "orders", "table", "bob"]]
+        );
+
+        // \dn
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                r#"SELECT n.nspname AS "Name",
+                  pg_catalog.pg_get_userbyid(n.nspowner) AS "Owner"
+                FROM pg_catalog.pg_namespace n
+                WHERE n.nspname !~ '^pg_' AND n.nspname <> 'information_schema'
+                ORDER BY 1;"#
+            ),
+            vec![vec!["app", "bob"], vec!["public", "bob"]]
+        );
+
+        // \l
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                r#"SELECT d.datname as "Name",
+                       pg_catalog.pg_get_userbyid(d.datdba) as "Owner",
+                       pg_catalog.pg_encoding_to_char(d.encoding) as "Encoding",
+                       d.datcollate as "Collate",
+                       d.datctype as "Ctype",
+                       d.daticulocale as "ICU Locale",
+                       CASE d.datlocprovider WHEN 'c' THEN 'libc' WHEN 'i' THEN 'icu' END AS "Locale Provider",
+                       pg_catalog.array_to_string(d.datacl, E'\n') AS "Access privileges"
+                FROM pg_catalog.pg_database d
+                ORDER BY 1;"#
+            ),
+            vec![vec!["shop", "bob", "UTF8", "C", "C", "", "libc", ""]]
+        );
+
+        // \d orders looks the table up, then describes it and its columns
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "SELECT c.oid,
+                  n.nspname,
+                  c.relname
+                FROM pg_catalog.pg_class c
+                     LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+                WHERE c.relname OPERATOR(pg_catalog.~) '^(orders)$' COLLATE pg_catalog.default
+                  AND pg_catalog.pg_table_is_visible(c.oid)
+                ORDER BY 2, 3;"
+            ),
+            vec![vec!["16386", "public", "orders"]]
+        );
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "SELECT c.relchecks, c.relkind, c.relhasindex, c.relhasrules, c.relhastriggers, c.relrowsecurity, c.relforcerowsecurity, false AS relhasoids, c.relispartition, '', c.reltablespace, CASE WHEN c.reloftype = 0 THEN '' ELSE c.reloftype::pg_catalog.regtype::pg_catalog.text END, c.relpersistence, c.relreplident, am.amname
+                FROM pg_catalog.pg_class c
+                 LEFT JOIN pg_catalog.pg_class tc ON (c.reltoastrelid = tc.oid)
+                LEFT JOIN pg_catalog.pg_am am ON (c.relam = am.oid)
+                WHERE c.oid = '16386';"
+            ),
+            vec![vec![
+                "0", "r", "f", "f", "f", "f", "f", "f", "f", "", "0", "", "p", "d", "heap"
+            ]]
+        );
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "SELECT a.attname,
+                  pg_catalog.format_type(a.atttypid, a.atttypmod),
+                  (SELECT pg_catalog.pg_get_expr(d.adbin, d.adrelid, true)
+                   FROM pg_catalog.pg_attrdef d
+                   WHERE d.adrelid = a.attrelid AND d.adnum = a.attnum AND a.atthasdef),
+                  a.attnotnull,
+                  (SELECT c.collname FROM pg_catalog.pg_collation c, pg_catalog.pg_type t
+                   WHERE c.oid = a.attcollation AND t.oid = a.atttypid AND a.attcollation <> t.typcollation) AS attcollation,
+                  a.attidentity,
+                  a.attgenerated
+                FROM pg_catalog.pg_attribute a
+                WHERE a.attrelid = '16386' AND a.attnum > 0 AND NOT a.attisdropped
+                ORDER BY a.attnum;"
+            ),
+            vec![
+                vec!["id", "bigint", "", "t", "", "", ""],
+                vec!["user_id", "integer", "", "f", "", "", ""]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dbeaver() {
+        let mut query_exec = query_exec();
+
+        let schemas = rows(
+            &mut query_exec,
+            "SELECT n.oid,n.*,d.description FROM pg_catalog.pg_namespace n
+            LEFT OUTER JOIN pg_catalog.pg_description d ON d.objoid=n.oid AND d.objsubid=0 AND d.classoid='pg_namespace'::regclass
+            ORDER BY nspname",
+        );
+        assert_eq!(
+            schemas
+                .iter()
+                .map(|row| (row.len(), row[2].as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (6, "app"),
+                (6, "information_schema"),
+                (6, "pg_catalog"),
+                (6, "public")
+            ]
+        );
+
+        let tables = rows(
+            &mut query_exec,
+            "SELECT c.oid,c.*,d.description,pg_catalog.pg_get_expr(c.relpartbound, c.oid) as partition_expr, pg_catalog.pg_get_partkeydef(c.oid) as partition_key
+            FROM pg_catalog.pg_class c
+            LEFT OUTER JOIN pg_catalog.pg_description d ON d.objoid=c.oid AND d.objsubid=0 AND d.classoid='pg_class'::regclass
+            WHERE c.relnamespace=16384 AND c.relkind not in ('i','I','c')",
+        );
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0][2], "users");
+    }
+
+    #[test]
+    fn test_grafana() {
+        let mut query_exec = query_exec();
+
+        // The tables on the search path
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                r#"select quote_ident(table_name) as "table" from information_schema.tables
+                where quote_ident(table_schema) not in ('information_schema',
+                                         'pg_catalog',
+                                         '_timescaledb_cache',
+                                         '_timescaledb_catalog',
+                                         '_timescaledb_internal',
+                                         '_timescaledb_config',
+                                         'timescaledb_information',
+                                         'timescaledb_experimental')
+                  and table_type = 'BASE TABLE' and
+                      quote_ident(table_schema) IN (
+                      SELECT
+                        CASE WHEN trim(s[i]) = '"$user"' THEN user ELSE trim(s[i]) END
+                      FROM
+                        generate_series(
+                          array_lower(string_to_array(current_setting('search_path'),','),1),
+                          array_upper(string_to_array(current_setting('search_path'),','),1)
+                        ) as i,
+                        string_to_array(current_setting('search_path'),',') s
+                      )"#
+            ),
+            vec![vec!["orders"]]
+        );
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                r#"select quote_ident(column_name) as "column", data_type as "type"
+                from information_schema.columns
+                where quote_ident(table_name) = 'users'"#
+            ),
+            vec![vec!["id", "integer"], vec!["name", "text"]]
+        );
+    }
+
+    #[test]
+    fn test_orms() {
+        let mut query_exec = query_exec();
+
+        // Django
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "SELECT
+                    c.relname,
+                    CASE
+                        WHEN c.relispartition THEN 'p'
+                        WHEN c.relkind IN ('m', 'v') THEN 'v'
+                        ELSE 't'
+                    END,
+                    obj_description(c.oid, 'pg_class')
+                FROM pg_catalog.pg_class c
+                LEFT JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace
+                WHERE c.relkind IN ('f', 'm', 'p', 'r', 'v')
+                    AND n.nspname NOT IN ('pg_catalog', 'pg_toast')
+                    AND pg_catalog.pg_table_is_visible(c.oid)"
+            ),
+            vec![vec!["orders", "t", ""]]
+        );
+
+        // SQLAlchemy
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "SELECT pg_catalog.pg_class.relname
+                FROM pg_catalog.pg_class JOIN pg_catalog.pg_namespace ON pg_catalog.pg_namespace.oid = pg_catalog.pg_class.relnamespace
+                WHERE pg_catalog.pg_class.relname = 'orders' AND pg_catalog.pg_class.relkind = ANY (ARRAY['r', 'p', 'f', 'v', 'm'])
+                AND pg_catalog.pg_table_is_visible(pg_catalog.pg_class.oid) AND pg_catalog.pg_namespace.nspname != 'pg_catalog'"
+            ),
+            vec![vec!["orders"]]
+        );
+
+        // Active Record
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                "SELECT COUNT(*) FROM pg_class c LEFT JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE n.nspname = ANY (current_schemas(false)) AND c.relname = 'users' AND c.relkind IN ('r','p')"
+            ),
+            vec![vec!["0"]]
+        );
+        assert_eq!(
+            rows(
+                &mut query_exec,
+                r#"SELECT a.attname, format_type(a.atttypid, a.atttypmod),
+                       pg_get_expr(d.adbin, d.adrelid), a.attnotnull, a.atttypid, a.atttypmod,
+                       c.collname, col_description(a.attrelid, a.attnum) AS comment
+                  FROM pg_attribute a
+                  LEFT JOIN pg_attrdef d ON a.attrelid = d.adrelid AND a.attnum = d.adnum
+                  LEFT JOIN pg_type t ON a.atttypid = t.oid
+                  LEFT JOIN pg_collation c ON a.attcollation = c.oid AND a.attcollation <> t.typcollation
+                 WHERE a.attrelid = '"orders"'::regclass
+                   AND a.attnum > 0 AND NOT a.attisdropped
+                 ORDER BY a.attnum"#
+            ),
+            vec![
+                vec!["id", "bigint", "", "t", "20", "-1", "", ""],
+                vec!["user_id", "integer", "", "f", "23", "-1", "", ""]
+            ]
+        );
+    }
+}
@@ -1,28 +1,67 @@
 use std::io;
-use std::net::TcpStream;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
 
-use crate::proto::{Decode, Encode, Reader, Writer};
+use crate::backend::Session;
 
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+// Moves bytes between a socket and a session, this is all the I/O the blocking driver needs
 pub struct Conn {
-    reader: Reader<TcpStream>,
-    writer: Writer<TcpStream>,
+    stream: TcpStream,
+    buf: Box<[u8]>,
 }
 
 impl Conn {
     pub fn new(stream: TcpStream) -> io::Result<Self> {
         Ok(Self {
-            reader: Reader::new(stream.try_clone()?),
-            writer: Writer::new(stream.try_clone()?),
+            stream,
+            buf: vec![0; READ_BUFFER_SIZE].into_boxed_slice(),
         })
     }
 
-    #[inline]
-    pub fn recv<T: Decode>(&mut self) -> io::Result<T> {
-        T::decode(&mut self.reader)
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr().ok()
+    }
+
+    // Blocks until data is available and hands it to the session, returns 0 when the client closed
+    // the connection
+    pub fn recv(&mut self, session: &mut Session) -> io::Result<usize> {
+        let n = loop {
+            match self.stream.read(&mut self.buf) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        session.receive(&self.buf[..n]);
+
+        Ok(n)
+    }
+
+    // Like `recv` but returns None instead of blocking when the client hasn't sent anything
+    pub fn try_recv(&mut self, session: &mut Session) -> io::Result<Option<usize>> {
+        self.stream.set_nonblocking(true)?;
+        let result = self.recv(session);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(n) => Ok(Some(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
-    #[inline]
-    pub fn send<T: Encode>(&mut self, msg: T) -> io::Result<()> {
-        msg.encode(&mut self.writer)
+    // Sends everything the session has queued so far
+    pub fn flush(&mut self, session: &mut Session) -> io::Result<()> {
+        let output = session.take_output();
+
+        if output.is_empty() {
+            return Ok(());
+        }
+
+        self.stream.write_all(&output)?;
+        self.stream.flush()
     }
 }
@@ -0,0 +1,37 @@
+use std::io;
+use std::time::Duration;
+
+use crate::proto::messages::{CommandTag, ErrorResponse};
+
+// How long the manager waits for data to send before it checks for messages from the client
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// The default for how long the client may stay silent in copy-both mode (wal_sender_timeout)
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub enum CopyOutput {
+    Data(Vec<u8>),
+    // Nothing to send yet
+    Idle,
+    // Ends copy-both mode from our side
+    Done,
+}
+
+// The server side of copy-both mode, both sides send CopyData whenever they want to. The manager
+// drives it, passes on what the client sends and closes the connection when the client stops
+// replying.
+pub trait CopyBoth {
+    // Called for each CopyData from the client, the returned data is sent back right away
+    fn receive(&mut self, data: Vec<u8>) -> io::Result<Result<Option<Vec<u8>>, ErrorResponse>>;
+
+    // Waits up to `timeout` for the next data to send
+    fn next(&mut self, timeout: Duration) -> io::Result<Result<CopyOutput, ErrorResponse>>;
+
+    // Sent once the client has been silent for half the timeout, it should ask for a reply
+    fn keepalive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    // Completes the query once copy-both mode ended without an error
+    fn command_tag(&self) -> CommandTag;
+}
@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::backend::lexer::{tokenize, Token};
+use crate::backend::{classify, Intercept, Interceptor, State, StatementKind};
+use crate::proto::messages::{ErrorResponse, Field, Severity};
+
+// Normalizes every statement in the query, literals and parameters are replaced by `?` and
+// comments are stripped so that queries which only differ in their values are equal
+pub fn fingerprints(query: &str) -> Vec<String> {
+    let mut statements = vec![String::new()];
+
+    for token in tokenize(query) {
+        let fingerprint = statements.last_mut().unwrap();
+        let (text, glue) = match token {
+            Token::Word(word) => (word.to_lowercase(), false),
+            Token::QuotedIdent(ident) => (ident.to_string(), false),
+            Token::Literal(_) | Token::Param(_) => ("?".to_string(), false),
+            Token::Comment(_) => continue,
+            Token::Semicolon => {
+                statements.push(String::new());
+                continue;
+            }
+            Token::Punct(c) => (c.to_string(), matches!(c, ',' | '(' | ')' | '.' | ':')),
+        };
+
+        let after_glue = fingerprint.ends_with(['(', '.', ':']);
+
+        if !fingerprint.is_empty() && !glue && !after_glue {
+            fingerprint.push(' ');
+        }
+
+        fingerprint.push_str(&text);
+    }
+
+    statements.retain(|fingerprint| !fingerprint.is_empty());
+    statements
+}
+
+// Keywords which can be followed by a parenthesis without calling a function
+const KEYWORDS: &[&str] = &[
+    "all",
+    "and",
+    "any",
+    "as",
+    "between",
+    "by",
+    "case",
+    "else",
+    "except",
+    "exists",
+    "filter",
+    "from",
+    "in",
+    "intersect",
+    "is",
+    "join",
+    "lateral",
+    "not",
+    "on",
+    "or",
+    "over",
+    "select",
+    "some",
+    "then",
+    "union",
+    "using",
+    "values",
+    "when",
+    "where",
+    "with",
+    "within",
+];
+
+// Built-in functions without side effects, types are included for casts such as `numeric(10, 2)`
+const READ_ONLY_FUNCTIONS: &[&str] = &[
+    "abs",
+    "array",
+    "array_agg",
+    "array_length",
+    "avg",
+    "bool_and",
+    "bool_or",
+    "cast",
+    "ceil",
+    "char",
+    "character",
+    "coalesce",
+    "concat",
+    "count",
+    "current_setting",
+    "date_part",
+    "date_trunc",
+    "decimal",
+    "dense_rank",
+    "extract",
+    "first_value",
+    "floor",
+    "format",
+    "greatest",
+    "interval",
+    "json_agg",
+    "json_build_object",
+    "jsonb_agg",
+    "jsonb_build_object",
+    "lag",
+    "last_value",
+    "lead",
+    "least",
+    "left",
+    "length",
+    "lower",
+    "max",
+    "min",
+    "now",
+    "nullif",
+    "numeric",
+    "rank",
+    "replace",
+    "right",
+    "round",
+    "row",
+    "row_number",
+    "split_part",
+    "string_agg",
+    "substr",
+    "substring",
+    "sum",
+    "time",
+    "timestamp",
+    "to_char",
+    "to_date",
+    "to_timestamp",
+    "trim",
+    "upper",
+    "varchar",
+];
+
+// Whether the statement calls a function which might have side effects, such as nextval,
+// set_config, pg_terminate_backend, lo_unlink or any user defined function
+fn calls_function(fingerprint: &str) -> bool {
+    let tokens = tokenize(fingerprint);
+
+    tokens.windows(2).any(|tokens| match tokens {
+        [Token::Word(word), Token::Punct('(')] => {
+            let word = word.to_lowercase();
+
+            !KEYWORDS.contains(&word.as_str()) && !READ_ONLY_FUNCTIONS.contains(&word.as_str())
+        }
+        [Token::QuotedIdent(_), Token::Punct('(')] => true,
+        _ => false,
+    })
+}
+
+fn statement_type(kind: StatementKind) -> &'static str {
+    match kind {
+        StatementKind::Read => "read",
+        StatementKind::Write => "write",
+        StatementKind::Begin { .. } | StatementKind::TransactionEnd => "transaction",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+// An empty list of values matches everything
+struct Rule {
+    action: Action,
+    users: Vec<String>,
+    databases: Vec<String>,
+    // Either read, write or transaction
+    statements: Vec<String>,
+    fingerprint: Option<String>,
+}
+
+fn matches(values: &[String], value: &str) -> bool {
+    values.is_empty() || values.iter().any(|v| v == value)
+}
+
+impl Rule {
+    fn matches(&self, state: &State, statement: &str, fingerprint: &str) -> bool {
+        matches(&self.users, state.user())
+            && matches(&self.databases, state.database())
+            && matches(&self.statements, statement)
+            && self.fingerprint.as_deref().is_none_or(|f| f == fingerprint)
+    }
+
+    // Parses the conditions of a rule, such as `user=alice,bob statement=read`. The fingerprint
+    // has to come last as it contains whitespace.
+    fn parse(action: Action, conditions: &str) -> Result<Self, String> {
+        let mut rule = Rule {
+            action,
+            users: vec![],
+            databases: vec![],
+            statements: vec![],
+            fingerprint: None,
+        };
+
+        let conditions = match conditions.split_once("fingerprint=") {
+            Some((conditions, fingerprint)) => {
+                rule.fingerprint = fingerprints(fingerprint).into_iter().next();
+                conditions
+            }
+            None => conditions,
+        };
+
+        for condition in conditions.split_whitespace() {
+            let (key, value) = condition
+                .split_once('=')
+                .ok_or_else(|| format!("invalid condition: {}", condition))?;
+            let values = match value {
+                "*" => vec![],
+                value => value.split(',').map(str::to_string).collect(),
+            };
+
+            match key {
+                "user" => rule.users = values,
+                "database" => rule.databases = values,
+                "statement" => {
+                    if let Some(value) = values
+                        .iter()
+                        .find(|v| !matches!(v.as_str(), "read" | "write" | "transaction"))
+                    {
+                        return Err(format!("invalid statement type: {}", value));
+                    }
+
+                    rule.statements = values;
+                }
+                key => return Err(format!("unknown condition: {}", key)),
+            }
+        }
+
+        Ok(rule)
+    }
+}
+
+// Appends the fingerprints of statements which aren't allowed yet to the rules file
+struct Learning {
+    file: File,
+    seen: HashSet<String>,
+}
+
+// Allows or denies statements based on an ordered list of rules, the first matching rule wins.
+// Reads which call functions other than a few built-in ones count as writes, as the functions
+// might have side effects.
+//
+// The rules file contains one rule per line:
+//
+//   default deny
+//   allow user=analyst statement=read
+//   deny database=prod statement=write
+//   allow user=etl fingerprint=insert into events values (?, ?)
+pub struct Firewall {
+    rules: Vec<Rule>,
+    default: Action,
+    learning: Option<Mutex<Learning>>,
+}
+
+impl Firewall {
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut firewall = Self {
+            rules: vec![],
+            default: Action::Allow,
+            learning: None,
+        };
+
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let action = match rest.trim() {
+                "allow" => Some(Action::Allow),
+                "deny" => Some(Action::Deny),
+                _ => None,
+            };
+
+            let rule = match (keyword, action) {
+                ("default", Some(action)) => {
+                    firewall.default = action;
+                    continue;
+                }
+                ("allow", _) => Rule::parse(Action::Allow, rest),
+                ("deny", _) => Rule::parse(Action::Deny, rest),
+                _ => Err(format!("invalid rule: {}", line)),
+            };
+
+            firewall
+                .rules
+                .push(rule.map_err(|e| format!("line {}: {}", n + 1, e))?);
+        }
+
+        Ok(firewall)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // In learning mode every statement is allowed, those which wouldn't be are recorded as rules
+    // in the given file so that it can be used as allowlist afterwards
+    pub fn learn<P: AsRef<Path>>(mut self, path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        self.learning = Some(Mutex::new(Learning {
+            file,
+            seen: HashSet::new(),
+        }));
+
+        Ok(self)
+    }
+
+    fn action(&self, state: &State, statement: &str, fingerprint: &str) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(state, statement, fingerprint))
+            .map_or(self.default, |rule| rule.action)
+    }
+
+    pub fn check(&self, state: &State, query: &str) -> Result<(), ErrorResponse> {
+        for fingerprint in fingerprints(query) {
+            let kind = match classify(&fingerprint).first() {
+                Some(StatementKind::Read) | None if calls_function(&fingerprint) => {
+                    StatementKind::Write
+                }
+                Some(kind) => *kind,
+                None => StatementKind::Read,
+            };
+
+            if self.action(state, statement_type(kind), &fingerprint) == Action::Allow {
+                continue;
+            }
+
+            let rule = format!(
+                "allow user={} database={} fingerprint={}",
+                state.user(),
+                state.database(),
+                fingerprint
+            );
+
+            match &self.learning {
+                Some(learning) => {
+                    let mut learning = learning.lock().unwrap();
+
+                    if learning.seen.insert(rule.clone()) {
+                        log::info!("learned firewall rule: {}", rule);
+
+                        if let Err(e) = writeln!(learning.file, "{}", rule) {
+                            log::warn!("failed to record firewall rule: {}", e);
+                        }
+                    }
+                }
+                None => {
+                    return Err(ErrorResponse::new(
+                        Severity::Error,
+                        "42501".to_string(),
+                        format!(
+                            "{} statement not allowed by the query firewall",
+                            statement_type(kind)
+                        ),
+                    )
+                    .with_field(
+                        Field::Hint,
+                        format!("Add the following rule to allow it: {}", rule),
+                    ))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Rejects statements the firewall doesn't allow before they are executed
+pub struct FirewallInterceptor {
+    firewall: Arc<Firewall>,
+}
+
+impl FirewallInterceptor {
+    pub fn new(firewall: Arc<Firewall>) -> Self {
+        Self { firewall }
+    }
+}
+
+impl Interceptor for FirewallInterceptor {
+    fn before(&mut self, state: &State, query: String) -> Intercept {
+        match self.firewall.check(state, &query) {
+            Ok(_) => Intercept::Continue(query),
+            Err(e) => Intercept::Respond(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(user: &str) -> State {
+        State::new(user.to_string(), "analytics".to_string())
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        assert_eq!(
+            fingerprints("SELECT a.b, 'x' FROM t WHERE id = $1 -- c\n AND x IN (1, 2); insert into t values(1)"),
+            vec![
+                "select a.b, ? from t where id = ? and x in(?, ?)",
+                "insert into t values(?)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rules() {
+        let firewall = Firewall::parse(
+            "# analytics endpoint
+            default deny
+            allow user=etl fingerprint=insert into events values (1, 2)
+            allow statement=read,transaction
+            ",
+        )
+        .unwrap();
+
+        assert!(firewall.check(&state("alice"), "select 1").is_ok());
+        assert!(firewall
+            .check(&state("etl"), "insert into events values (3, 4)")
+            .is_ok());
+
+        let e = firewall
+            .check(&state("alice"), "begin; delete from events; commit")
+            .unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("42501"));
+        assert_eq!(
+            e.get_field(Field::Hint),
+            Some("Add the following rule to allow it: allow user=alice database=analytics fingerprint=delete from events")
+        );
+
+        // Functions might have side effects, reads calling them aren't reads
+        assert!(firewall
+            .check(
+                &state("alice"),
+                "select count(*), lower(name) from users where id in (1)"
+            )
+            .is_ok());
+        for query in [
+            "select pg_terminate_backend(42)",
+            "select lo_unlink(1234)",
+            "select set_config('role', 'admin', false)",
+            "select * from t where id = nextval('s')",
+            "select \"MyFunction\"()",
+        ] {
+            assert!(firewall.check(&state("alice"), query).is_err(), "{}", query);
+        }
+
+        assert!(Firewall::parse("allow statement=delete").is_err());
+        assert!(Firewall::parse("block user=alice").is_err());
+    }
+}
@@ -0,0 +1,655 @@
+use std::io;
+
+use crate::backend::large_object::function_name;
+use crate::backend::query_exec::{QueryResult, ResultSink};
+#[cfg(feature = "sql-parser")]
+use crate::backend::ParseResult;
+use crate::backend::{CopyBoth, QueryExec, RowStream, SetStatement, State};
+use crate::proto::messages::{
+    BackendMessage, ErrorResponse, ExtendedMessage, FunctionCall, RowDescription, Severity,
+    TransactionStatus,
+};
+
+pub enum Intercept {
+    // Executes the (possibly rewritten) query
+    Continue(String),
+    // Answers the query without executing it
+    Respond(QueryResult),
+}
+
+pub trait Interceptor {
+    // Called before a query (or the query of a Parse message) is executed
+    fn before(&mut self, _state: &State, query: String) -> Intercept {
+        Intercept::Continue(query)
+    }
+
+    // Called for every message the executor produces, returning None drops the message
+    fn after(&mut self, _state: &State, msg: BackendMessage) -> Option<BackendMessage> {
+        Some(msg)
+    }
+}
+
+// The query a function call is equivalent to, with the arguments as parameters. Only the large
+// object functions are known by name, others are called `function_<oid>`.
+fn function_query(call: &FunctionCall) -> String {
+    let name = match function_name(call.function_oid) {
+        Some(name) => name.to_string(),
+        None => format!("function_{}", call.function_oid),
+    };
+    let params: Vec<_> = (1..=call.args.len()).map(|n| format!("${}", n)).collect();
+
+    format!("select {}({})", name, params.join(", "))
+}
+
+// Passes the results through the interceptors in reverse order
+struct InterceptSink<'a> {
+    state: &'a State,
+    interceptors: &'a mut [Box<dyn Interceptor>],
+    inner: &'a mut dyn ResultSink,
+}
+
+impl ResultSink for InterceptSink<'_> {
+    fn push(&mut self, msg: BackendMessage) -> io::Result<()> {
+        let mut msg = Some(msg);
+
+        for interceptor in self.interceptors.iter_mut().rev() {
+            msg = msg.and_then(|msg| interceptor.after(self.state, msg));
+        }
+
+        match msg {
+            Some(msg) => self.inner.push(msg),
+            None => Ok(()),
+        }
+    }
+}
+
+// Runs an ordered chain of interceptors around any executor
+pub struct InterceptingQueryExec<Q: QueryExec> {
+    inner: Q,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    state: State,
+    // As reported by the executor, answered queries don't change it
+    transaction_status: TransactionStatus,
+    // The outcome of the interceptors for the query `copy_both` was called with, which `execute_to`
+    // is called with next unless it switched to copy-both mode
+    intercepted: Option<(String, Intercept)>,
+}
+
+impl<Q: QueryExec> InterceptingQueryExec<Q> {
+    pub fn new(inner: Q) -> Self {
+        Self {
+            inner,
+            interceptors: vec![],
+            state: State::default(),
+            transaction_status: TransactionStatus::Idle,
+            intercepted: None,
+        }
+    }
+
+    pub fn with<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    fn before(&mut self, mut query: String) -> Intercept {
+        for interceptor in self.interceptors.iter_mut() {
+            match interceptor.before(&self.state, query) {
+                Intercept::Continue(rewritten) => query = rewritten,
+                respond => return respond,
+            }
+        }
+
+        Intercept::Continue(query)
+    }
+
+    fn sink<'a>(&'a mut self, inner: &'a mut dyn ResultSink) -> (&'a mut Q, InterceptSink<'a>) {
+        (
+            &mut self.inner,
+            InterceptSink {
+                state: &self.state,
+                interceptors: &mut self.interceptors,
+                inner,
+            },
+        )
+    }
+
+    // Runs the query unless an interceptor answers it, `run` passes the (possibly rewritten) query
+    // to the executor
+    fn intercept_or(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+        run: impl FnOnce(&mut Q, &str, &mut dyn ResultSink) -> io::Result<TransactionStatus>,
+    ) -> io::Result<TransactionStatus> {
+        let intercept = match self.intercepted.take() {
+            Some((intercepted, intercept)) if intercepted == query => intercept,
+            _ => self.before(query.to_string()),
+        };
+        let (inner, mut sink) = self.sink(sink);
+
+        match intercept {
+            Intercept::Continue(query) => self.transaction_status = run(inner, &query, &mut sink)?,
+            Intercept::Respond(result) => sink.push(match result {
+                Ok(command_complete) => BackendMessage::CommandComplete(command_complete),
+                Err(e) => BackendMessage::ErrorResponse(e),
+            })?,
+        }
+
+        Ok(self.transaction_status)
+    }
+
+    fn before_extended(&mut self, msg: ExtendedMessage) -> Result<ExtendedMessage, ErrorResponse> {
+        match msg {
+            ExtendedMessage::Parse(mut parse) => match self.before(parse.query) {
+                Intercept::Continue(query) => {
+                    parse.query = query;
+                    Ok(ExtendedMessage::Parse(parse))
+                }
+                Intercept::Respond(Err(e)) => Err(e),
+                Intercept::Respond(Ok(_)) => Err(ErrorResponse::new(
+                    Severity::Error,
+                    "0A000".to_string(),
+                    "intercepted queries can't be answered in the extended query protocol"
+                        .to_string(),
+                )),
+            },
+            msg => Ok(msg),
+        }
+    }
+}
+
+impl<Q: QueryExec> QueryExec for InterceptingQueryExec<Q> {
+    fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        self.state = state.clone();
+        self.inner.startup(state)
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult {
+        match self.before(query.to_string()) {
+            Intercept::Continue(query) => self.inner.execute(&query),
+            Intercept::Respond(result) => result,
+        }
+    }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.intercept_or(query, sink, |inner, query, sink| {
+            inner.execute_to(query, sink)
+        })
+    }
+
+    // A rewritten query is parsed again
+    #[cfg(feature = "sql-parser")]
+    fn execute_parsed(
+        &mut self,
+        query: &str,
+        parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.intercept_or(query, sink, |inner, rewritten, sink| {
+            let parsed = match rewritten == query {
+                true => parsed,
+                false => crate::backend::parse(rewritten),
+            };
+
+            inner.execute_parsed(rewritten, parsed, sink)
+        })
+    }
+
+    // Parse messages can be rewritten or rejected, answering them requires the remaining extended
+    // protocol messages to be faked and isn't supported
+    fn extended(
+        &mut self,
+        msg: ExtendedMessage,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        let msg = match self.before_extended(msg) {
+            Ok(msg) => msg,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        let (inner, mut sink) = self.sink(sink);
+        inner.extended(msg, &mut sink)
+    }
+
+    #[cfg(feature = "sql-parser")]
+    fn extended_parsed(
+        &mut self,
+        msg: ExtendedMessage,
+        parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        let query = match &msg {
+            ExtendedMessage::Parse(parse) => parse.query.clone(),
+            _ => String::new(),
+        };
+        let msg = match self.before_extended(msg) {
+            Ok(msg) => msg,
+            Err(e) => return Ok(Err(e)),
+        };
+        let parsed = match &msg {
+            ExtendedMessage::Parse(parse) if parse.query != query => {
+                crate::backend::parse(&parse.query)
+            }
+            _ => parsed,
+        };
+
+        let (inner, mut sink) = self.sink(sink);
+        inner.extended_parsed(msg, parsed, &mut sink)
+    }
+
+    fn flush(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        let (inner, mut sink) = self.sink(sink);
+        inner.flush(&mut sink)
+    }
+
+    fn sync(&mut self, sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        let (inner, mut sink) = self.sink(sink);
+        self.transaction_status = inner.sync(&mut sink)?;
+        Ok(self.transaction_status)
+    }
+
+    // Function calls are shown to the interceptors as the equivalent query, they can only reject
+    // them
+    fn function_call(
+        &mut self,
+        call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let e = match self.before(function_query(&call)) {
+            Intercept::Continue(_) => None,
+            Intercept::Respond(Err(e)) => Some(e),
+            Intercept::Respond(Ok(_)) => Some(ErrorResponse::new(
+                Severity::Error,
+                "0A000".to_string(),
+                "intercepted function calls can't be answered".to_string(),
+            )),
+        };
+
+        if let Some(e) = e {
+            sink.push(BackendMessage::ErrorResponse(e))?;
+            return Ok(self.transaction_status);
+        }
+
+        let (inner, mut sink) = self.sink(sink);
+        self.transaction_status = inner.function_call(call, &mut sink)?;
+        Ok(self.transaction_status)
+    }
+
+    // Interceptors see settings changes as queries, they can only reject them
+    fn set(&mut self, statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        match self.before(statement.to_string()) {
+            Intercept::Respond(Err(e)) => Ok(Err(e)),
+            _ => self.inner.set(statement),
+        }
+    }
+
+    // The interceptors run before the executor is asked, a rejected query is left for
+    // `execute_to` to respond to without running them again
+    fn copy_both(&mut self, query: &str) -> io::Result<Option<Box<dyn CopyBoth>>> {
+        let intercept = self.before(query.to_string());
+
+        if let Intercept::Continue(rewritten) = &intercept {
+            if let Some(handler) = self.inner.copy_both(rewritten)? {
+                return Ok(Some(handler));
+            }
+        }
+
+        self.intercepted = Some((query.to_string(), intercept));
+        Ok(None)
+    }
+
+    fn describe(&mut self, query: &str) -> io::Result<Option<RowDescription>> {
+        self.inner.describe(query)
+    }
+
+    // Streamed rows would bypass `after`, so queries are only streamed without interceptors
+    fn stream(&mut self, query: &str) -> io::Result<Option<RowStream>> {
+        if !self.interceptors.is_empty() {
+            return Ok(None);
+        }
+
+        self.inner.stream(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::backend::{CopyOutput, NoopQueryExec};
+    use crate::proto::messages::{CommandComplete, CommandTag, Field};
+
+    // Echoes the query as command tag
+    struct EchoQueryExec;
+
+    impl QueryExec for EchoQueryExec {
+        fn execute(&mut self, query: &str) -> QueryResult {
+            Ok(CommandComplete::new(CommandTag::Other(query.to_string())))
+        }
+    }
+
+    struct TenantFilter;
+
+    impl Interceptor for TenantFilter {
+        fn before(&mut self, state: &State, query: String) -> Intercept {
+            Intercept::Continue(format!("{} where tenant = '{}'", query, state.user()))
+        }
+    }
+
+    struct BlockDrop;
+
+    impl Interceptor for BlockDrop {
+        fn before(&mut self, _state: &State, query: String) -> Intercept {
+            if query.to_lowercase().starts_with("drop") || query.contains("function_2096") {
+                return Intercept::Respond(Err(ErrorResponse::new(
+                    Severity::Error,
+                    "42501".to_string(),
+                    "drop is not allowed".to_string(),
+                )));
+            }
+
+            Intercept::Continue(query)
+        }
+    }
+
+    // Hides the row counts from the client
+    struct HideRowCounts;
+
+    impl Interceptor for HideRowCounts {
+        fn after(&mut self, _state: &State, msg: BackendMessage) -> Option<BackendMessage> {
+            match msg {
+                BackendMessage::CommandComplete(_) => Some(BackendMessage::CommandComplete(
+                    CommandComplete::new(CommandTag::Other("DONE".to_string())),
+                )),
+                msg => Some(msg),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let mut query_exec = InterceptingQueryExec::new(EchoQueryExec).with(TenantFilter);
+        query_exec
+            .startup(&State::new("bob".to_string(), "bob".to_string()))
+            .unwrap()
+            .unwrap();
+
+        let mut messages = vec![];
+        query_exec
+            .execute_to("select * from t", &mut messages)
+            .unwrap();
+
+        match &messages[..] {
+            [BackendMessage::CommandComplete(cc)] => assert_eq!(
+                cc.command_tag.to_string(),
+                "select * from t where tenant = 'bob'"
+            ),
+            _ => panic!("expected a command complete"),
+        }
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let mut query_exec = InterceptingQueryExec::new(NoopQueryExec::new())
+            .with(BlockDrop)
+            .with(HideRowCounts);
+
+        let e = query_exec.execute("drop table t").unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("42501"));
+
+        let mut messages = vec![];
+        query_exec.execute_to("select 1", &mut messages).unwrap();
+        assert!(matches!(
+            &messages[..],
+            [BackendMessage::CommandComplete(cc)] if cc.command_tag.to_string() == "DONE"
+        ));
+
+        // Answered queries report the transaction status of the executor
+        assert_eq!(
+            query_exec.execute_to("BEGIN", &mut messages).unwrap(),
+            TransactionStatus::InTransaction
+        );
+        assert_eq!(
+            query_exec
+                .execute_to("drop table t", &mut messages)
+                .unwrap(),
+            TransactionStatus::InTransaction
+        );
+    }
+
+    #[test]
+    fn test_function_call() {
+        let mut query_exec = InterceptingQueryExec::new(NoopQueryExec::new()).with(BlockDrop);
+
+        assert_eq!(
+            function_query(&FunctionCall::new(952, vec![None, None])),
+            "select lo_open($1, $2)"
+        );
+
+        // pg_terminate_backend
+        let mut messages = vec![];
+        query_exec
+            .function_call(FunctionCall::new(2096, vec![None]), &mut messages)
+            .unwrap();
+        assert!(matches!(
+            &messages[..],
+            [BackendMessage::ErrorResponse(e)] if e.get_field(Field::Code) == Some("42501")
+        ));
+    }
+
+    // Switches to copy-both mode for any query, counting how often it's asked
+    struct CopyBothQueryExec(Rc<Cell<usize>>);
+
+    struct Echo;
+
+    impl CopyBoth for Echo {
+        fn receive(&mut self, data: Vec<u8>) -> io::Result<Result<Option<Vec<u8>>, ErrorResponse>> {
+            Ok(Ok(Some(data)))
+        }
+
+        fn next(&mut self, _timeout: Duration) -> io::Result<Result<CopyOutput, ErrorResponse>> {
+            Ok(Ok(CopyOutput::Done))
+        }
+
+        fn command_tag(&self) -> CommandTag {
+            CommandTag::Other("ECHO".to_string())
+        }
+    }
+
+    impl QueryExec for CopyBothQueryExec {
+        fn execute(&mut self, query: &str) -> QueryResult {
+            Ok(CommandComplete::new(CommandTag::Other(query.to_string())))
+        }
+
+        fn copy_both(&mut self, _query: &str) -> io::Result<Option<Box<dyn CopyBoth>>> {
+            self.0.set(self.0.get() + 1);
+            Ok(Some(Box::new(Echo)))
+        }
+    }
+
+    // Counts the queries it sees
+    struct Count(Rc<Cell<usize>>);
+
+    impl Interceptor for Count {
+        fn before(&mut self, _state: &State, query: String) -> Intercept {
+            self.0.set(self.0.get() + 1);
+            Intercept::Continue(query)
+        }
+    }
+
+    #[test]
+    fn test_copy_both() {
+        let (asked, seen) = (Rc::new(Cell::new(0)), Rc::new(Cell::new(0)));
+        let mut query_exec = InterceptingQueryExec::new(CopyBothQueryExec(asked.clone()))
+            .with(Count(seen.clone()))
+            .with(BlockDrop);
+
+        assert!(query_exec.copy_both("start").unwrap().is_some());
+        assert_eq!((asked.get(), seen.get()), (1, 1));
+
+        // Rejected queries never reach the executor and the interceptors run once
+        assert!(query_exec.copy_both("drop table t").unwrap().is_none());
+        let mut messages = vec![];
+        query_exec
+            .execute_to("drop table t", &mut messages)
+            .unwrap();
+        assert_eq!((asked.get(), seen.get()), (1, 2));
+        assert!(matches!(
+            &messages[..],
+            [BackendMessage::ErrorResponse(e)] if e.get_field(Field::Code) == Some("42501")
+        ));
+    }
+
+    // Records the statements it's given, parse errors as their code
+    #[cfg(feature = "sql-parser")]
+    struct Parsed(Rc<std::cell::RefCell<Vec<String>>>);
+
+    #[cfg(feature = "sql-parser")]
+    impl Parsed {
+        fn record(&self, parsed: ParseResult) {
+            self.0.borrow_mut().push(match parsed {
+                Ok(statements) => statements[0].to_string(),
+                Err(e) => e.get_field(Field::Code).unwrap().to_string(),
+            });
+        }
+    }
+
+    #[cfg(feature = "sql-parser")]
+    impl QueryExec for Parsed {
+        fn execute(&mut self, query: &str) -> QueryResult {
+            Ok(CommandComplete::new(CommandTag::Other(query.to_string())))
+        }
+
+        fn execute_parsed(
+            &mut self,
+            query: &str,
+            parsed: ParseResult,
+            sink: &mut dyn ResultSink,
+        ) -> io::Result<TransactionStatus> {
+            self.record(parsed);
+            self.execute_to(query, sink)
+        }
+
+        fn extended(
+            &mut self,
+            _msg: ExtendedMessage,
+            _sink: &mut dyn ResultSink,
+        ) -> io::Result<Result<(), ErrorResponse>> {
+            Ok(Ok(()))
+        }
+
+        fn extended_parsed(
+            &mut self,
+            msg: ExtendedMessage,
+            parsed: ParseResult,
+            sink: &mut dyn ResultSink,
+        ) -> io::Result<Result<(), ErrorResponse>> {
+            self.record(parsed);
+            self.extended(msg, sink)
+        }
+    }
+
+    #[cfg(feature = "sql-parser")]
+    #[test]
+    fn test_parsed() {
+        use std::sync::Arc;
+
+        use crate::backend::{
+            Catalog, CatalogQueryExec, LargeObjectQueryExec, MemoryLargeObjects, PortalQueryExec,
+        };
+        use crate::proto::messages::{Bind, Execute, Parse};
+
+        let parse = |query: &str| {
+            ExtendedMessage::Parse(Parse {
+                len: 0,
+                name: String::new(),
+                query: query.to_string(),
+                param_types: vec![],
+            })
+        };
+        let state = State::new("bob".to_string(), "bob".to_string());
+        let mut messages = vec![];
+
+        // Rewritten queries are parsed again
+        let statements = Rc::new(std::cell::RefCell::new(vec![]));
+        let mut query_exec =
+            InterceptingQueryExec::new(Parsed(statements.clone())).with(TenantFilter);
+        query_exec.startup(&state).unwrap().unwrap();
+        query_exec
+            .execute_parsed(
+                "select * from t",
+                crate::backend::parse("select * from t"),
+                &mut messages,
+            )
+            .unwrap();
+        query_exec
+            .extended_parsed(
+                parse("select * from t"),
+                crate::backend::parse("select * from t"),
+                &mut messages,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            *statements.borrow(),
+            vec!["SELECT * FROM t WHERE tenant = 'bob'"; 2]
+        );
+
+        // The statements reach the executor through the default stack, portals parse the query
+        // they run
+        let statements = Rc::new(std::cell::RefCell::new(vec![]));
+        let mut query_exec =
+            InterceptingQueryExec::new(PortalQueryExec::new(LargeObjectQueryExec::new(
+                CatalogQueryExec::new(Parsed(statements.clone()), Arc::new(Catalog::new())),
+                Arc::new(MemoryLargeObjects::new()),
+            )));
+        query_exec.startup(&state).unwrap().unwrap();
+        query_exec
+            .execute_parsed("select 1", crate::backend::parse("select 1"), &mut messages)
+            .unwrap();
+        query_exec
+            .execute_parsed(
+                "select from",
+                crate::backend::parse("select from"),
+                &mut messages,
+            )
+            .unwrap();
+
+        for msg in [
+            parse("select 2"),
+            ExtendedMessage::Bind(Bind {
+                len: 0,
+                portal: String::new(),
+                statement: String::new(),
+                param_formats: vec![],
+                params: vec![],
+                result_formats: vec![],
+            }),
+            ExtendedMessage::Execute(Execute {
+                len: 0,
+                portal: String::new(),
+                max_rows: 0,
+            }),
+        ] {
+            // As the manager dispatches them
+            match &msg {
+                ExtendedMessage::Parse(parse) => {
+                    let parsed = crate::backend::parse(&parse.query);
+                    query_exec.extended_parsed(msg, parsed, &mut messages)
+                }
+                _ => query_exec.extended(msg, &mut messages),
+            }
+            .unwrap()
+            .unwrap();
+        }
+
+        assert_eq!(*statements.borrow(), vec!["SELECT 1", "42601", "SELECT 2"]);
+    }
+}
@@ -0,0 +1,1490 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::backend::lexer::{tokenize, unquote, Token};
+use crate::backend::query_exec::{QueryResult, ResultSink};
+use crate::backend::response::{error, field};
+#[cfg(feature = "sql-parser")]
+use crate::backend::ParseResult;
+use crate::backend::{
+    classify, CopyBoth, QueryExec, RowStream, SetStatement, State, StatementKind, Type,
+};
+use crate::proto::messages::{
+    BackendMessage, CommandComplete, CommandTag, DataRow, ErrorResponse, ExtendedMessage,
+    FunctionCall, FunctionCallResponse, RowDescription, TransactionStatus,
+};
+
+// Large objects get oids starting from here, like other objects created by users
+const FIRST_OID: u32 = 16384;
+// The modes of lo_open, objects opened for writing can be read as well
+const INV_WRITE: i64 = 0x20000;
+const INV_READ: i64 = 0x40000;
+// The largest size PostgreSQL supports (about 4TB)
+const MAX_SIZE: i64 = 2048 * i32::MAX as i64;
+const DEFAULT_MEMORY_LIMIT: u64 = 1 << 30;
+
+// Stores the contents of large objects, shared by all connections. Changes are applied right away,
+// the executor undoes those of transactions which roll back. Operations on objects which don't
+// exist fail with `NotFound`.
+pub trait LargeObjectStore: Send + Sync {
+    // Creates an empty object, with a free oid when `oid` is 0. Returns None when the oid is used.
+    fn create(&self, oid: u32) -> io::Result<Option<u32>>;
+
+    fn exists(&self, oid: u32) -> io::Result<bool>;
+
+    fn size(&self, oid: u32) -> io::Result<u64>;
+
+    // Returns fewer bytes than requested at the end of the object
+    fn read(&self, oid: u32, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+
+    // Writing past the end fills the gap with zeroes
+    fn write(&self, oid: u32, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    fn truncate(&self, oid: u32, len: u64) -> io::Result<()>;
+
+    // Returns false when the object doesn't exist
+    fn unlink(&self, oid: u32) -> io::Result<bool>;
+}
+
+fn not_found(oid: u32) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("large object {} does not exist", oid),
+    )
+}
+
+// The name of a large object function, as reported to clients which look it up
+pub(crate) fn function_name(oid: i32) -> Option<&'static str> {
+    FUNCTIONS
+        .iter()
+        .find(|signature| signature.oid == oid)
+        .map(|signature| signature.name)
+}
+
+// Returns the oid to try next, wrapping around like the oid counter of PostgreSQL
+fn next_oid(next: &mut u32) -> u32 {
+    let oid = *next;
+    *next = oid.checked_add(1).unwrap_or(FIRST_OID);
+    oid
+}
+
+struct Objects {
+    next_oid: u32,
+    contents: HashMap<u32, Vec<u8>>,
+    // The sum of the sizes of all objects
+    size: u64,
+}
+
+// Keeps the objects in memory, they're gone once the server stops. Writes which would take the
+// total size over the limit fail with `OutOfMemory`.
+pub struct MemoryLargeObjects {
+    objects: Mutex<Objects>,
+    limit: u64,
+}
+
+impl Default for MemoryLargeObjects {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryLargeObjects {
+    pub fn new() -> Self {
+        Self::with_limit(DEFAULT_MEMORY_LIMIT)
+    }
+
+    pub fn with_limit(limit: u64) -> Self {
+        Self {
+            objects: Mutex::new(Objects {
+                next_oid: FIRST_OID,
+                contents: HashMap::new(),
+                size: 0,
+            }),
+            limit,
+        }
+    }
+
+    fn with<T>(&self, oid: u32, f: impl FnOnce(&mut Vec<u8>) -> T) -> io::Result<T> {
+        match self.objects.lock().unwrap().contents.get_mut(&oid) {
+            Some(contents) => Ok(f(contents)),
+            None => Err(not_found(oid)),
+        }
+    }
+
+    // Changes the size of the object (given the current one) and then its contents, checking the
+    // limit before allocating anything
+    fn resize(
+        &self,
+        oid: u32,
+        len: impl FnOnce(u64) -> u64,
+        f: impl FnOnce(&mut Vec<u8>),
+    ) -> io::Result<()> {
+        let mut objects = self.objects.lock().unwrap();
+        let Objects { contents, size, .. } = &mut *objects;
+        let contents = contents.get_mut(&oid).ok_or_else(|| not_found(oid))?;
+        let len = len(contents.len() as u64);
+        let total = *size - contents.len() as u64 + len;
+
+        if total > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!(
+                    "large objects are limited to {} bytes in memory",
+                    self.limit
+                ),
+            ));
+        }
+
+        contents.resize(len as usize, 0);
+        f(contents);
+        *size = total;
+        Ok(())
+    }
+}
+
+impl LargeObjectStore for MemoryLargeObjects {
+    fn create(&self, oid: u32) -> io::Result<Option<u32>> {
+        let mut objects = self.objects.lock().unwrap();
+
+        let oid = match oid {
+            0 => loop {
+                let oid = next_oid(&mut objects.next_oid);
+
+                if !objects.contents.contains_key(&oid) {
+                    break oid;
+                }
+            },
+            oid if objects.contents.contains_key(&oid) => return Ok(None),
+            oid => oid,
+        };
+
+        objects.contents.insert(oid, vec![]);
+        Ok(Some(oid))
+    }
+
+    fn exists(&self, oid: u32) -> io::Result<bool> {
+        Ok(self.objects.lock().unwrap().contents.contains_key(&oid))
+    }
+
+    fn size(&self, oid: u32) -> io::Result<u64> {
+        self.with(oid, |contents| contents.len() as u64)
+    }
+
+    fn read(&self, oid: u32, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        self.with(oid, |contents| {
+            let start = (offset as usize).min(contents.len());
+            let end = start.saturating_add(len).min(contents.len());
+            contents[start..end].to_vec()
+        })
+    }
+
+    fn write(&self, oid: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+
+        self.resize(
+            oid,
+            |len| len.max(end),
+            |contents| contents[offset as usize..end as usize].copy_from_slice(data),
+        )
+    }
+
+    fn truncate(&self, oid: u32, len: u64) -> io::Result<()> {
+        self.resize(oid, |_| len, |_| ())
+    }
+
+    fn unlink(&self, oid: u32) -> io::Result<bool> {
+        let mut objects = self.objects.lock().unwrap();
+
+        match objects.contents.remove(&oid) {
+            Some(contents) => {
+                objects.size -= contents.len() as u64;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+// Keeps every object in a file named after its oid
+pub struct FileLargeObjects {
+    dir: PathBuf,
+    next_oid: Mutex<u32>,
+}
+
+impl FileLargeObjects {
+    // The directory is created when it doesn't exist, new oids follow the largest one in it
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let last = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+            .max();
+
+        Ok(Self {
+            dir,
+            next_oid: Mutex::new(last.map_or(FIRST_OID, |oid| {
+                oid.checked_add(1).unwrap_or(FIRST_OID).max(FIRST_OID)
+            })),
+        })
+    }
+
+    fn path(&self, oid: u32) -> PathBuf {
+        self.dir.join(oid.to_string())
+    }
+
+    fn file(&self, oid: u32, write: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(self.path(oid))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => not_found(oid),
+                _ => e,
+            })
+    }
+}
+
+impl LargeObjectStore for FileLargeObjects {
+    fn create(&self, oid: u32) -> io::Result<Option<u32>> {
+        // Other servers might use the directory too, so a file is only ever created if it's new
+        let create = |oid| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.path(oid))
+        };
+
+        if oid != 0 {
+            return match create(oid) {
+                Ok(_) => Ok(Some(oid)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+                Err(e) => Err(e),
+            };
+        }
+
+        let mut next = self.next_oid.lock().unwrap();
+
+        loop {
+            let oid = next_oid(&mut next);
+
+            match create(oid) {
+                Ok(_) => return Ok(Some(oid)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn exists(&self, oid: u32) -> io::Result<bool> {
+        Ok(self.path(oid).is_file())
+    }
+
+    fn size(&self, oid: u32) -> io::Result<u64> {
+        Ok(self.file(oid, false)?.metadata()?.len())
+    }
+
+    fn read(&self, oid: u32, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut file = self.file(oid, false)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![];
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write(&self, oid: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut file = self.file(oid, true)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn truncate(&self, oid: u32, len: u64) -> io::Result<()> {
+        self.file(oid, true)?.set_len(len)
+    }
+
+    fn unlink(&self, oid: u32) -> io::Result<bool> {
+        match fs::remove_file(self.path(oid)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Create,
+    Open,
+    Close,
+    Read,
+    Write,
+    Seek,
+    Creat,
+    Tell,
+    Unlink,
+    Truncate,
+    Seek64,
+    Tell64,
+    Truncate64,
+}
+
+struct Signature {
+    function: Function,
+    name: &'static str,
+    // As in pg_proc, clients look them up before calling the functions with FunctionCall
+    oid: i32,
+    args: &'static [Type],
+    result: Type,
+}
+
+const FUNCTIONS: [Signature; 13] = [
+    Signature {
+        function: Function::Create,
+        name: "lo_create",
+        oid: 715,
+        args: &[Type::OID],
+        result: Type::OID,
+    },
+    Signature {
+        function: Function::Open,
+        name: "lo_open",
+        oid: 952,
+        args: &[Type::OID, Type::INT4],
+        result: Type::INT4,
+    },
+    Signature {
+        function: Function::Close,
+        name: "lo_close",
+        oid: 953,
+        args: &[Type::INT4],
+        result: Type::INT4,
+    },
+    Signature {
+        function: Function::Read,
+        name: "loread",
+        oid: 954,
+        args: &[Type::INT4, Type::INT4],
+        result: Type::BYTEA,
+    },
+    Signature {
+        function: Function::Write,
+        name: "lowrite",
+        oid: 955,
+        args: &[Type::INT4, Type::BYTEA],
+        result: Type::INT4,
+    },
+    Signature {
+        function: Function::Seek,
+        name: "lo_lseek",
+        oid: 956,
+        args: &[Type::INT4, Type::INT4, Type::INT4],
+        result: Type::INT4,
+    },
+    Signature {
+        function: Function::Creat,
+        name: "lo_creat",
+        oid: 957,
+        args: &[Type::INT4],
+        result: Type::OID,
+    },
+    Signature {
+        function: Function::Tell,
+        name: "lo_tell",
+        oid: 958,
+        args: &[Type::INT4],
+        result: Type::INT4,
+    },
+    Signature {
+        function: Function::Unlink,
+        name: "lo_unlink",
+        oid: 964,
+        args: &[Type::OID],
+        result: Type::INT4,
+    },
+    Signature {
+        function: Function::Truncate,
+        name: "lo_truncate",
+        oid: 1004,
+        args: &[Type::INT4, Type::INT4],
+        result: Type::INT4,
+    },
+    Signature {
+        function: Function::Seek64,
+        name: "lo_lseek64",
+        oid: 3170,
+        args: &[Type::INT4, Type::INT8, Type::INT4],
+        result: Type::INT8,
+    },
+    Signature {
+        function: Function::Tell64,
+        name: "lo_tell64",
+        oid: 3171,
+        args: &[Type::INT4],
+        result: Type::INT8,
+    },
+    Signature {
+        function: Function::Truncate64,
+        name: "lo_truncate64",
+        oid: 3172,
+        args: &[Type::INT4, Type::INT8],
+        result: Type::INT4,
+    },
+];
+
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    fn int(&self) -> i64 {
+        match self {
+            Value::Int(n) => *n,
+            Value::Bytes(_) => unreachable!(),
+        }
+    }
+
+    fn encode(&self, ty: Type, format: i16) -> Vec<u8> {
+        match (self, format) {
+            (Value::Bytes(data), 1) => data.clone(),
+            (Value::Bytes(data), _) => {
+                let mut text = String::from("\\x");
+
+                for b in data {
+                    text.push_str(&format!("{:02x}", b));
+                }
+
+                text.into_bytes()
+            }
+            (Value::Int(n), 1) if ty.len == 8 => n.to_be_bytes().to_vec(),
+            // Oids are unsigned, truncating works for both them and int4
+            (Value::Int(n), 1) => (*n as u32).to_be_bytes().to_vec(),
+            (Value::Int(n), _) => n.to_string().into_bytes(),
+        }
+    }
+}
+
+fn store_error(oid: u32, e: io::Error) -> ErrorResponse {
+    match e.kind() {
+        io::ErrorKind::NotFound => error("42704", format!("large object {} does not exist", oid)),
+        io::ErrorKind::OutOfMemory => error("53200", e.to_string()),
+        _ => error(
+            "58030",
+            format!("could not access large object {}: {}", oid, e),
+        ),
+    }
+}
+
+// Decodes the text format of bytea, either hex (\x0102) or escaped (\001\002)
+fn parse_bytea(text: &[u8]) -> Option<Vec<u8>> {
+    if let Some(hex) = text.strip_prefix(b"\\x") {
+        let hex = std::str::from_utf8(hex).ok()?;
+
+        return (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect();
+    }
+
+    let mut data = vec![];
+    let mut i = 0;
+
+    while i < text.len() {
+        match &text[i..] {
+            [b'\\', b'\\', ..] => {
+                data.push(b'\\');
+                i += 2;
+            }
+            [b'\\', octal @ ..] => {
+                let octal = std::str::from_utf8(octal.get(..3)?).ok()?;
+                data.push(u8::from_str_radix(octal, 8).ok()?);
+                i += 4;
+            }
+            [b, ..] => {
+                data.push(*b);
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+
+    Some(data)
+}
+
+fn decode(ty: Type, format: i16, data: &[u8], i: usize) -> Result<Value, ErrorResponse> {
+    if ty == Type::BYTEA {
+        return match format {
+            1 => Ok(Value::Bytes(data.to_vec())),
+            _ => parse_bytea(data)
+                .map(Value::Bytes)
+                .ok_or_else(|| error("22P02", "invalid input syntax for type bytea".to_string())),
+        };
+    }
+
+    if format == 1 {
+        return match (data.len(), ty.len) {
+            (4, 4) if ty == Type::OID => Ok(u32::from_be_bytes(data.try_into().unwrap()) as i64),
+            (4, 4) => Ok(i32::from_be_bytes(data.try_into().unwrap()) as i64),
+            (8, 8) => Ok(i64::from_be_bytes(data.try_into().unwrap())),
+            _ => Err(error(
+                "22P03",
+                format!(
+                    "incorrect binary data format in function argument {}",
+                    i + 1
+                ),
+            )),
+        }
+        .map(Value::Int);
+    }
+
+    let text = String::from_utf8_lossy(data);
+    let text = text.trim();
+
+    let n = if ty == Type::OID {
+        // Negative oids wrap around, lo_import(-1) is a common way to write the largest one
+        text.parse::<u32>()
+            .ok()
+            .or_else(|| text.parse::<i32>().ok().map(|n| n as u32))
+            .map(i64::from)
+    } else if ty == Type::INT4 {
+        text.parse::<i32>().ok().map(i64::from)
+    } else {
+        text.parse::<i64>().ok()
+    };
+
+    n.map(Value::Int).ok_or_else(|| {
+        error(
+            "22P02",
+            format!(
+                "invalid input syntax for type {}: \"{}\"",
+                ty.sql_name, text
+            ),
+        )
+    })
+}
+
+// A constant argument, which may be negative or cast
+fn constant(tokens: &[Token]) -> Option<Option<Vec<u8>>> {
+    let tokens = match tokens {
+        [value @ .., Token::Punct(':'), Token::Punct(':'), Token::Word(_)] => value,
+        _ => tokens,
+    };
+
+    Some(match tokens {
+        [Token::Word(word)] if word.eq_ignore_ascii_case("null") => None,
+        [Token::Literal(literal)] if literal.starts_with('\'') => {
+            Some(unquote(literal)?.into_bytes())
+        }
+        [Token::Literal(literal)] if literal.starts_with(|c: char| c.is_ascii_digit()) => {
+            Some(literal.as_bytes().to_vec())
+        }
+        [Token::Punct('-'), Token::Literal(literal)]
+            if literal.starts_with(|c: char| c.is_ascii_digit()) =>
+        {
+            Some(format!("-{}", literal).into_bytes())
+        }
+        _ => return None,
+    })
+}
+
+// Parses a call such as `SELECT lo_open(16384, 262144)` with constant arguments
+fn parse_call(query: &str) -> Option<(&'static Signature, Vec<Option<Vec<u8>>>)> {
+    let tokens: Vec<_> = tokenize(query)
+        .into_iter()
+        .filter(|token| !matches!(token, Token::Comment(_)))
+        .collect();
+    let tokens = match &tokens[..] {
+        [rest @ .., Token::Semicolon] => rest,
+        tokens => tokens,
+    };
+
+    let (name, args) = match tokens {
+        [Token::Word(select), Token::Word(name), Token::Punct('('), args @ .., Token::Punct(')')]
+            if select.eq_ignore_ascii_case("select") =>
+        {
+            (name, args)
+        }
+        _ => return None,
+    };
+
+    let signature = FUNCTIONS
+        .iter()
+        .find(|signature| name.eq_ignore_ascii_case(signature.name))?;
+    let args = match args {
+        [] => vec![],
+        args => args
+            .split(|token| *token == Token::Punct(','))
+            .map(constant)
+            .collect::<Option<_>>()?,
+    };
+
+    Some((signature, args))
+}
+
+// libpq looks up the oids of the functions before its first large object call
+fn lookup_functions(query: &str) -> Option<Vec<BackendMessage>> {
+    let tokens = tokenize(query);
+    let words: Vec<_> = tokens
+        .iter()
+        .filter_map(|token| match token {
+            Token::Word(word) => Some(word.to_lowercase()),
+            _ => None,
+        })
+        .collect();
+
+    if !words.starts_with(&["select", "proname", "oid", "from"].map(String::from))
+        || !words.iter().any(|word| word == "pg_proc")
+    {
+        return None;
+    }
+
+    let names: Vec<_> = tokens
+        .iter()
+        .filter_map(|token| match token {
+            Token::Literal(literal) if literal.starts_with('\'') => unquote(literal),
+            _ => None,
+        })
+        .collect();
+
+    let mut messages = vec![BackendMessage::RowDescription(RowDescription::new(vec![
+        field("proname", Type::NAME),
+        field("oid", Type::OID),
+    ]))];
+
+    for signature in FUNCTIONS
+        .iter()
+        .filter(|s| names.iter().any(|name| name == s.name))
+    {
+        messages.push(BackendMessage::DataRow(DataRow::new(vec![
+            Some(signature.name.as_bytes().to_vec()),
+            Some(signature.oid.to_string().into_bytes()),
+        ])));
+    }
+
+    messages.push(BackendMessage::CommandComplete(CommandComplete::new(
+        CommandTag::Select(messages.len() as i32 - 1),
+    )));
+
+    Some(messages)
+}
+
+// The first keyword of the last statement, it tells whether a query ending a transaction rolled
+// it back
+fn last_command(query: &str) -> Option<String> {
+    let tokens = tokenize(query);
+
+    tokens
+        .split(|token| *token == Token::Semicolon)
+        .filter_map(|statement| {
+            statement.iter().find_map(|token| match token {
+                Token::Word(word) => Some(word.to_uppercase()),
+                _ => None,
+            })
+        })
+        .next_back()
+}
+
+struct Descriptor {
+    oid: u32,
+    offset: i64,
+    writable: bool,
+}
+
+// Implements the server side large object functions (lo_creat, lo_open, loread, lowrite, lo_lseek64,
+// lo_truncate64, lo_unlink, ...) on top of a store, both for FunctionCall and queries calling them
+// with constants. Like in PostgreSQL descriptors are closed and changes are undone when the
+// transaction ends or rolls back, a call outside a transaction block runs in one of its own.
+// Savepoints aren't supported, rolling back to one rolls back the whole transaction.
+pub struct LargeObjectQueryExec<Q: QueryExec> {
+    inner: Q,
+    store: Arc<dyn LargeObjectStore>,
+    descriptors: BTreeMap<i32, Descriptor>,
+    // The contents of the objects before the transaction changed them, None for new objects
+    undo: HashMap<u32, Option<Vec<u8>>>,
+    transaction_status: TransactionStatus,
+    // A call failed in the transaction of the executor, it's rolled back at its end
+    aborted: bool,
+}
+
+impl<Q: QueryExec> LargeObjectQueryExec<Q> {
+    pub fn new(inner: Q, store: Arc<dyn LargeObjectStore>) -> Self {
+        Self {
+            inner,
+            store,
+            descriptors: BTreeMap::new(),
+            undo: HashMap::new(),
+            transaction_status: TransactionStatus::Idle,
+            aborted: false,
+        }
+    }
+
+    fn status(&self) -> TransactionStatus {
+        match self.aborted {
+            true => TransactionStatus::Failed,
+            false => self.transaction_status,
+        }
+    }
+
+    fn descriptor(&mut self, fd: i64) -> Result<&mut Descriptor, ErrorResponse> {
+        i32::try_from(fd)
+            .ok()
+            .and_then(|fd| self.descriptors.get_mut(&fd))
+            .ok_or_else(|| error("42704", format!("invalid large-object descriptor: {}", fd)))
+    }
+
+    fn writable(&mut self, fd: i64) -> Result<u32, ErrorResponse> {
+        match self.descriptor(fd)? {
+            Descriptor {
+                oid,
+                writable: true,
+                ..
+            } => Ok(*oid),
+            _ => Err(error(
+                "55000",
+                format!("large object descriptor {} was not opened for writing", fd),
+            )),
+        }
+    }
+
+    // Keeps the contents of the object before the first change in the transaction
+    fn remember(&mut self, oid: u32) -> Result<(), ErrorResponse> {
+        if !self.undo.contains_key(&oid) {
+            let contents = self
+                .store
+                .size(oid)
+                .and_then(|size| self.store.read(oid, 0, size as usize))
+                .map_err(|e| store_error(oid, e))?;
+
+            self.undo.insert(oid, Some(contents));
+        }
+
+        Ok(())
+    }
+
+    fn restore(&self, oid: u32, contents: Option<Vec<u8>>) -> io::Result<()> {
+        match contents {
+            None => {
+                self.store.unlink(oid)?;
+            }
+            Some(contents) => {
+                if !self.store.exists(oid)? {
+                    self.store.create(oid)?;
+                }
+
+                self.store.truncate(oid, 0)?;
+                self.store.write(oid, 0, &contents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Called with the status after every query, commits or rolls back once the transaction ended
+    fn end_transaction(&mut self, status: TransactionStatus, rollback: bool) {
+        let rollback =
+            rollback || self.aborted || self.transaction_status == TransactionStatus::Failed;
+        self.transaction_status = status;
+
+        if status != TransactionStatus::Idle {
+            return;
+        }
+
+        for (oid, contents) in std::mem::take(&mut self.undo) {
+            if !rollback {
+                continue;
+            }
+
+            if let Err(e) = self.restore(oid, contents) {
+                log::warn!("failed to roll back large object {}: {}", oid, e);
+            }
+        }
+
+        self.descriptors.clear();
+        self.aborted = false;
+    }
+
+    fn call(&mut self, function: Function, args: &[Value]) -> Result<Value, ErrorResponse> {
+        let int = |i: usize| args[i].int();
+
+        let value = match function {
+            Function::Create | Function::Creat => {
+                let oid = match function {
+                    Function::Create => int(0) as u32,
+                    _ => 0,
+                };
+
+                match self.store.create(oid).map_err(|e| store_error(oid, e))? {
+                    Some(oid) => {
+                        self.undo.insert(oid, None);
+                        Value::Int(oid.into())
+                    }
+                    None => {
+                        return Err(error(
+                            "42710",
+                            format!("large object {} already exists", oid),
+                        ))
+                    }
+                }
+            }
+            Function::Open => {
+                let (oid, mode) = (int(0) as u32, int(1));
+
+                if mode & (INV_READ | INV_WRITE) == 0 {
+                    return Err(error(
+                        "22023",
+                        format!("invalid flags for opening a large object: {}", mode),
+                    ));
+                }
+
+                if !self.store.exists(oid).map_err(|e| store_error(oid, e))? {
+                    return Err(store_error(oid, not_found(oid)));
+                }
+
+                let fd = (0..).find(|fd| !self.descriptors.contains_key(fd)).unwrap();
+                self.descriptors.insert(
+                    fd,
+                    Descriptor {
+                        oid,
+                        offset: 0,
+                        writable: mode & INV_WRITE != 0,
+                    },
+                );
+
+                Value::Int(fd.into())
+            }
+            Function::Close => {
+                self.descriptor(int(0))?;
+                self.descriptors.remove(&(int(0) as i32));
+                Value::Int(0)
+            }
+            Function::Read => {
+                let len = int(1);
+
+                if len < 0 {
+                    return Err(error(
+                        "22023",
+                        "requested length cannot be negative".to_string(),
+                    ));
+                }
+
+                let store = self.store.clone();
+                let descriptor = self.descriptor(int(0))?;
+                let data = store
+                    .read(descriptor.oid, descriptor.offset as u64, len as usize)
+                    .map_err(|e| store_error(descriptor.oid, e))?;
+
+                descriptor.offset += data.len() as i64;
+                Value::Bytes(data)
+            }
+            Function::Write => {
+                let data = match &args[1] {
+                    Value::Bytes(data) => data,
+                    Value::Int(_) => unreachable!(),
+                };
+
+                let oid = self.writable(int(0))?;
+                let offset = self.descriptor(int(0))?.offset;
+
+                if offset + data.len() as i64 > MAX_SIZE {
+                    return Err(error(
+                        "22023",
+                        format!("invalid large object write request size: {}", data.len()),
+                    ));
+                }
+
+                self.remember(oid)?;
+                self.store
+                    .write(oid, offset as u64, data)
+                    .map_err(|e| store_error(oid, e))?;
+
+                self.descriptor(int(0))?.offset += data.len() as i64;
+                Value::Int(data.len() as i64)
+            }
+            Function::Seek | Function::Seek64 => {
+                let (fd, offset, whence) = (int(0), int(1), int(2));
+                let store = self.store.clone();
+                let descriptor = self.descriptor(fd)?;
+
+                let base = match whence {
+                    0 => 0,
+                    1 => descriptor.offset,
+                    2 => store
+                        .size(descriptor.oid)
+                        .map_err(|e| store_error(descriptor.oid, e))?
+                        as i64,
+                    _ => {
+                        return Err(error(
+                            "22023",
+                            format!("invalid whence setting: {}", whence),
+                        ))
+                    }
+                };
+
+                let position = match base.checked_add(offset) {
+                    Some(position) if (0..=MAX_SIZE).contains(&position) => position,
+                    _ => return Err(error("22023", format!("invalid seek offset: {}", offset))),
+                };
+
+                if function == Function::Seek && position > i32::MAX as i64 {
+                    return Err(error(
+                        "22003",
+                        format!(
+                            "lo_lseek result out of range for large-object descriptor {}",
+                            fd
+                        ),
+                    ));
+                }
+
+                descriptor.offset = position;
+                Value::Int(position)
+            }
+            Function::Tell | Function::Tell64 => {
+                let offset = self.descriptor(int(0))?.offset;
+
+                if function == Function::Tell && offset > i32::MAX as i64 {
+                    return Err(error(
+                        "22003",
+                        format!(
+                            "lo_tell result out of range for large-object descriptor {}",
+                            int(0)
+                        ),
+                    ));
+                }
+
+                Value::Int(offset)
+            }
+            Function::Truncate | Function::Truncate64 => {
+                let oid = self.writable(int(0))?;
+                let len = int(1);
+
+                if !(0..=MAX_SIZE).contains(&len) {
+                    return Err(error(
+                        "22023",
+                        format!("invalid large object truncation target: {}", len),
+                    ));
+                }
+
+                self.remember(oid)?;
+                self.store
+                    .truncate(oid, len as u64)
+                    .map_err(|e| store_error(oid, e))?;
+
+                Value::Int(0)
+            }
+            Function::Unlink => {
+                let oid = int(0) as u32;
+
+                self.remember(oid)?;
+                self.store.unlink(oid).map_err(|e| store_error(oid, e))?;
+                self.descriptors
+                    .retain(|_, descriptor| descriptor.oid != oid);
+
+                Value::Int(1)
+            }
+        };
+
+        Ok(value)
+    }
+
+    // Runs a call in the current transaction, or in one of its own outside a transaction block.
+    // The functions are strict, a NULL argument returns NULL.
+    fn run(
+        &mut self,
+        signature: &Signature,
+        args: &[Option<Vec<u8>>],
+        format: impl Fn(usize) -> i16,
+    ) -> Result<Option<Value>, ErrorResponse> {
+        let result = if self.status() == TransactionStatus::Failed {
+            Err(error(
+                "25P02",
+                "current transaction is aborted, commands ignored until end of transaction block"
+                    .to_string(),
+            ))
+        } else {
+            args.iter()
+                .zip(signature.args)
+                .enumerate()
+                .map(|(i, (arg, ty))| match arg {
+                    Some(data) => decode(*ty, format(i), data, i).map(Some),
+                    None => Ok(None),
+                })
+                .collect::<Result<Option<Vec<_>>, _>>()
+                .and_then(|args| match args {
+                    Some(args) => self.call(signature.function, &args).map(Some),
+                    None => Ok(None),
+                })
+        };
+
+        match self.transaction_status {
+            TransactionStatus::Idle => {
+                self.end_transaction(TransactionStatus::Idle, result.is_err())
+            }
+            _ if result.is_err() => self.aborted = true,
+            _ => {}
+        }
+
+        result
+    }
+
+    // Answers the function lookup of libpq and queries calling one of the functions
+    fn answer(&mut self, query: &str) -> Option<Vec<BackendMessage>> {
+        if let Some(messages) = lookup_functions(query) {
+            return Some(messages);
+        }
+
+        let (signature, args) = parse_call(query)?;
+
+        let result = match args.len() == signature.args.len() {
+            true => self.run(signature, &args, |_| 0),
+            false => Err(error(
+                "42883",
+                format!(
+                    "function {} with {} arguments does not exist",
+                    signature.name,
+                    args.len()
+                ),
+            )),
+        };
+
+        Some(match result {
+            Ok(value) => vec![
+                BackendMessage::RowDescription(RowDescription::new(vec![field(
+                    signature.name,
+                    signature.result,
+                )])),
+                BackendMessage::DataRow(DataRow::new(vec![
+                    value.map(|v| v.encode(signature.result, 0))
+                ])),
+                BackendMessage::CommandComplete(CommandComplete::new(CommandTag::Select(1))),
+            ],
+            Err(e) => vec![BackendMessage::ErrorResponse(e)],
+        })
+    }
+
+    // Answers large object queries, `run` passes everything else to the executor
+    fn answer_or(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+        run: impl FnOnce(&mut Q, &mut dyn ResultSink) -> io::Result<TransactionStatus>,
+    ) -> io::Result<TransactionStatus> {
+        if let Some(messages) = self.answer(query) {
+            for msg in messages {
+                sink.push(msg)?;
+            }
+
+            return Ok(self.status());
+        }
+
+        if self.aborted {
+            // The executor doesn't know about the failed call, so the transaction is rolled back
+            // there too instead of committed
+            let kinds = classify(query);
+
+            if !kinds.is_empty() && kinds.iter().all(|k| *k == StatementKind::TransactionEnd) {
+                let status = self.inner.execute_to("ROLLBACK", sink)?;
+                self.end_transaction(status, true);
+            } else {
+                sink.push(BackendMessage::ErrorResponse(error(
+                    "25P02",
+                    "current transaction is aborted, commands ignored until end of transaction \
+                     block"
+                        .to_string(),
+                )))?;
+            }
+
+            return Ok(self.status());
+        }
+
+        let status = run(&mut self.inner, sink)?;
+        let rollback = matches!(last_command(query).as_deref(), Some("ROLLBACK" | "ABORT"));
+        self.end_transaction(status, rollback);
+
+        Ok(self.status())
+    }
+}
+
+impl<Q: QueryExec> QueryExec for LargeObjectQueryExec<Q> {
+    fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        self.inner.startup(state)
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult {
+        match self.answer(query) {
+            Some(messages) => messages
+                .into_iter()
+                .find_map(|msg| match msg {
+                    BackendMessage::CommandComplete(cc) => Some(Ok(cc)),
+                    BackendMessage::ErrorResponse(e) => Some(Err(e)),
+                    _ => None,
+                })
+                .unwrap(),
+            None => self.inner.execute(query),
+        }
+    }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.answer_or(query, sink, |inner, sink| inner.execute_to(query, sink))
+    }
+
+    #[cfg(feature = "sql-parser")]
+    fn execute_parsed(
+        &mut self,
+        query: &str,
+        parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.answer_or(query, sink, |inner, sink| {
+            inner.execute_parsed(query, parsed, sink)
+        })
+    }
+
+    fn extended(
+        &mut self,
+        msg: ExtendedMessage,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        self.inner.extended(msg, sink)
+    }
+
+    #[cfg(feature = "sql-parser")]
+    fn extended_parsed(
+        &mut self,
+        msg: ExtendedMessage,
+        parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        self.inner.extended_parsed(msg, parsed, sink)
+    }
+
+    fn flush(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        self.inner.flush(sink)
+    }
+
+    fn sync(&mut self, sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        let status = self.inner.sync(sink)?;
+        self.end_transaction(status, false);
+
+        Ok(self.status())
+    }
+
+    fn set(&mut self, statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        self.inner.set(statement)
+    }
+
+    fn function_call(
+        &mut self,
+        call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let signature = match FUNCTIONS.iter().find(|s| s.oid == call.function_oid) {
+            Some(signature) => signature,
+            None => {
+                let status = self.inner.function_call(call, sink)?;
+                self.end_transaction(status, false);
+
+                return Ok(self.status());
+            }
+        };
+
+        if call.args.len() != signature.args.len() {
+            sink.push(BackendMessage::ErrorResponse(error(
+                "08P01",
+                format!(
+                    "function call message contains {} arguments but function requires {}",
+                    call.args.len(),
+                    signature.args.len()
+                ),
+            )))?;
+
+            return Ok(self.status());
+        }
+
+        let msg = match self.run(signature, &call.args, |i| call.arg_format(i)) {
+            Ok(value) => BackendMessage::FunctionCallResponse(FunctionCallResponse::new(
+                value.map(|v| v.encode(signature.result, call.result_format)),
+            )),
+            Err(e) => BackendMessage::ErrorResponse(e),
+        };
+
+        sink.push(msg)?;
+
+        Ok(self.status())
+    }
+
+    fn copy_both(&mut self, query: &str) -> io::Result<Option<Box<dyn CopyBoth>>> {
+        self.inner.copy_both(query)
+    }
+
+    fn describe(&mut self, query: &str) -> io::Result<Option<RowDescription>> {
+        if lookup_functions(query).is_some() {
+            return Ok(Some(RowDescription::new(vec![
+                field("proname", Type::NAME),
+                field("oid", Type::OID),
+            ])));
+        }
+
+        match parse_call(query) {
+            Some((signature, _)) => Ok(Some(RowDescription::new(vec![field(
+                signature.name,
+                signature.result,
+            )]))),
+            None => self.inner.describe(query),
+        }
+    }
+
+    fn stream(&mut self, query: &str) -> io::Result<Option<RowStream>> {
+        match lookup_functions(query).is_some() || parse_call(query).is_some() {
+            true => Ok(None),
+            false => self.inner.stream(query),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NoopQueryExec;
+    use crate::proto::messages::Field;
+
+    fn query_exec(store: Arc<dyn LargeObjectStore>) -> LargeObjectQueryExec<NoopQueryExec> {
+        LargeObjectQueryExec::new(NoopQueryExec::new(), store)
+    }
+
+    // Returns the value of the single row or the error code
+    fn query(query_exec: &mut LargeObjectQueryExec<NoopQueryExec>, query: &str) -> String {
+        let mut messages = vec![];
+        query_exec.execute_to(query, &mut messages).unwrap();
+
+        messages
+            .into_iter()
+            .find_map(|msg| match msg {
+                BackendMessage::DataRow(row) => {
+                    Some(row.values[0].as_ref().map_or("NULL".to_string(), |v| {
+                        String::from_utf8_lossy(v).to_string()
+                    }))
+                }
+                BackendMessage::ErrorResponse(e) => {
+                    Some(e.get_field(Field::Code).unwrap().to_string())
+                }
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_stores() {
+        let dir = std::env::temp_dir().join(format!("large-object-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let stores: [Arc<dyn LargeObjectStore>; 2] = [
+            Arc::new(MemoryLargeObjects::new()),
+            Arc::new(FileLargeObjects::open(&dir).unwrap()),
+        ];
+
+        for store in stores {
+            let oid = store.create(0).unwrap().unwrap();
+            assert_eq!(oid, FIRST_OID);
+            assert_eq!(store.create(oid).unwrap(), None);
+            assert_eq!(store.create(20000).unwrap(), Some(20000));
+
+            store.write(oid, 2, b"abc").unwrap();
+            assert_eq!(store.read(oid, 0, 10).unwrap(), b"\0\0abc");
+            assert_eq!(store.read(oid, 3, 1).unwrap(), b"b");
+
+            store.truncate(oid, 3).unwrap();
+            assert_eq!(store.size(oid).unwrap(), 3);
+
+            assert!(store.unlink(oid).unwrap());
+            assert!(!store.unlink(oid).unwrap());
+            assert!(!store.exists(oid).unwrap());
+            assert_eq!(
+                store.read(oid, 0, 1).unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+        }
+
+        // New oids follow the existing files
+        let store = FileLargeObjects::open(&dir).unwrap();
+        assert_eq!(store.create(0).unwrap(), Some(20001));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The memory store refuses to grow past its limit
+        let store = MemoryLargeObjects::with_limit(10);
+        let (a, b) = (
+            store.create(0).unwrap().unwrap(),
+            store.create(0).unwrap().unwrap(),
+        );
+        store.write(a, 0, b"12345678").unwrap();
+        assert_eq!(
+            store.write(b, 0, b"abc").unwrap_err().kind(),
+            io::ErrorKind::OutOfMemory
+        );
+        assert_eq!(
+            store.truncate(a, u64::MAX).unwrap_err().kind(),
+            io::ErrorKind::OutOfMemory
+        );
+        store.truncate(a, 2).unwrap();
+        store.write(b, 0, b"abc").unwrap();
+        store.unlink(b).unwrap();
+        store.write(a, 0, b"12345678").unwrap();
+    }
+
+    #[test]
+    fn test_transactions() {
+        let store = Arc::new(MemoryLargeObjects::new());
+        let mut query_exec = query_exec(store.clone());
+
+        query(&mut query_exec, "BEGIN");
+        let oid = query(&mut query_exec, "select lo_creat(-1)");
+        assert_eq!(
+            query(&mut query_exec, &format!("SELECT lo_open({}, 131072)", oid)),
+            "0"
+        );
+        assert_eq!(query(&mut query_exec, "select lowrite(0, 'hello')"), "5");
+        assert_eq!(query(&mut query_exec, "select lo_lseek64(0, -2, 1)"), "3");
+        assert_eq!(query(&mut query_exec, "select loread(0, 10)"), "\\x6c6f");
+        assert_eq!(query(&mut query_exec, "select loread(0, NULL)"), "NULL");
+
+        // Unterminated literals are left to the executor
+        assert_eq!(query(&mut query_exec, "select lowrite(0, '日)"), "");
+        assert_eq!(
+            query(
+                &mut query_exec,
+                "select proname, oid from pg_proc where proname = '日"
+            ),
+            ""
+        );
+
+        assert_eq!(query(&mut query_exec, "COMMIT"), "");
+
+        // Descriptors end with the transaction
+        assert_eq!(query(&mut query_exec, "select lo_tell(0)"), "42704");
+
+        let oid = oid.parse::<u32>().unwrap();
+
+        // Reading only
+        query(&mut query_exec, "BEGIN");
+        query(&mut query_exec, &format!("select lo_open({}, 262144)", oid));
+        assert_eq!(query(&mut query_exec, "select lo_truncate(0, 1)"), "55000");
+        assert_eq!(query(&mut query_exec, "select lo_close(0)"), "25P02");
+        query(&mut query_exec, "COMMIT");
+
+        // Changes are undone on rollback, including unlinking
+        query(&mut query_exec, "BEGIN");
+        query(&mut query_exec, &format!("select lo_open({}, 393216)", oid));
+        assert_eq!(query(&mut query_exec, "select lo_truncate64(0, 2)"), "0");
+        assert_eq!(
+            query(&mut query_exec, &format!("select lo_unlink({})", oid)),
+            "1"
+        );
+        let created = query(&mut query_exec, "select lo_create(0)");
+        assert_eq!(query(&mut query_exec, "ROLLBACK"), "");
+
+        assert_eq!(store.read(oid, 0, 10).unwrap(), b"hello");
+        assert!(!store.exists(created.parse().unwrap()).unwrap());
+
+        // Growing past the limit of the store fails instead of allocating
+        query(&mut query_exec, "BEGIN");
+        query(&mut query_exec, &format!("select lo_open({}, 393216)", oid));
+        assert_eq!(
+            query(&mut query_exec, "select lo_truncate64(0, 4000000000000)"),
+            "53200"
+        );
+        query(&mut query_exec, "ROLLBACK");
+        assert_eq!(store.size(oid).unwrap(), 5);
+
+        // Outside a transaction block every call commits on its own
+        assert_eq!(
+            query(&mut query_exec, &format!("select lo_unlink({})", oid)),
+            "1"
+        );
+        assert_eq!(
+            query(&mut query_exec, &format!("select lo_unlink({})", oid)),
+            "42704"
+        );
+        assert!(!store.exists(oid).unwrap());
+    }
+
+    // Calls the function with binary arguments, returns the binary result or the error code
+    fn call(
+        query_exec: &mut LargeObjectQueryExec<NoopQueryExec>,
+        oid: i32,
+        args: Vec<Vec<u8>>,
+    ) -> Result<Vec<u8>, String> {
+        let mut call = FunctionCall::new(oid, args.into_iter().map(Some).collect());
+        call.arg_formats = vec![1];
+        call.result_format = 1;
+
+        let mut messages = vec![];
+        query_exec.function_call(call, &mut messages).unwrap();
+
+        match messages.pop() {
+            Some(BackendMessage::FunctionCallResponse(response)) => Ok(response.value.unwrap()),
+            Some(BackendMessage::ErrorResponse(e)) => {
+                Err(e.get_field(Field::Code).unwrap().to_string())
+            }
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    #[test]
+    fn test_function_call() {
+        let store = Arc::new(MemoryLargeObjects::new());
+        let mut query_exec = query_exec(store.clone());
+        let int4 = |n: i32| n.to_be_bytes().to_vec();
+
+        let mut messages = vec![];
+        query_exec
+            .execute_to(
+                "select proname, oid from pg_catalog.pg_proc where proname in ('lo_open', \
+                 'lo_lseek64', 'lo_foo')",
+                &mut messages,
+            )
+            .unwrap();
+        assert_eq!(messages.len(), 4);
+
+        query_exec.execute_to("BEGIN", &mut vec![]).unwrap();
+
+        let q = &mut query_exec;
+        let oid = call(q, 957, vec![int4(-1)]).unwrap();
+        let fd = call(q, 952, vec![oid.clone(), int4(0x60000)]).unwrap();
+        assert_eq!(
+            call(q, 955, vec![fd.clone(), b"blob".to_vec()]),
+            Ok(int4(4))
+        );
+        assert_eq!(
+            call(
+                q,
+                3170,
+                vec![fd.clone(), 1i64.to_be_bytes().to_vec(), int4(0)]
+            ),
+            Ok(1i64.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            call(q, 954, vec![fd.clone(), int4(100)]),
+            Ok(b"lob".to_vec())
+        );
+        assert_eq!(call(q, 954, vec![fd.clone()]), Err("08P01".to_string()));
+        assert_eq!(
+            call(q, 956, vec![fd, int4(0), int4(5)]),
+            Err("22023".to_string())
+        );
+        assert_eq!(call(q, 958, vec![int4(0)]), Err("25P02".to_string()));
+
+        // COMMIT rolls back the failed transaction
+        query_exec.execute_to("COMMIT", &mut vec![]).unwrap();
+
+        let oid = u32::from_be_bytes(oid.try_into().unwrap());
+        assert!(!store.exists(oid).unwrap());
+    }
+}
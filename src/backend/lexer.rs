@@ -0,0 +1,233 @@
+// A minimal SQL tokenizer, just enough to find statement boundaries, keywords and comments
+// without being confused by literals
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Token<'a> {
+    // Keywords and unquoted identifiers
+    Word(&'a str),
+    // Including the quotes
+    QuotedIdent(&'a str),
+    // String, dollar quoted and numeric constants (including the quotes)
+    Literal(&'a str),
+    // Positional parameters ($1)
+    Param(&'a str),
+    // Without the comment markers
+    Comment(&'a str),
+    Semicolon,
+    Punct(char),
+}
+
+fn is_word_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || !c.is_ascii()
+}
+
+// Returns the end of a quoted string or identifier starting at `start`, quotes are escaped by
+// doubling them
+fn quoted_end(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+
+    while i < bytes.len() {
+        if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+
+            return i + 1;
+        }
+
+        i += 1;
+    }
+
+    bytes.len()
+}
+
+// Returns the contents of a quoted string or identifier with doubled quotes unescaped, None when
+// the token isn't quoted or the quote isn't terminated
+pub(crate) fn unquote(token: &str) -> Option<String> {
+    let quote = match token.chars().next()? {
+        quote @ ('\'' | '"') => quote,
+        _ => return None,
+    };
+    let doubled = format!("{0}{0}", quote);
+    let inner = token.strip_prefix(quote)?.strip_suffix(quote)?;
+
+    if inner.replace(&doubled, "").contains(quote) {
+        return None;
+    }
+
+    Some(inner.replace(&doubled, &quote.to_string()))
+}
+
+pub(crate) fn tokenize(query: &str) -> Vec<Token<'_>> {
+    let bytes = query.as_bytes();
+    let len = bytes.len();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < len {
+        let next = bytes.get(i + 1).copied();
+
+        match bytes[i] {
+            b'-' if next == Some(b'-') => {
+                let end = query[i..].find('\n').map_or(len, |n| i + n);
+                tokens.push(Token::Comment(&query[i + 2..end]));
+                i = end;
+            }
+            b'/' if next == Some(b'*') => {
+                let start = i + 2;
+                let mut depth = 1;
+                i = start;
+
+                while i < len && depth > 0 {
+                    if bytes[i..].starts_with(b"/*") {
+                        depth += 1;
+                        i += 2;
+                    } else if bytes[i..].starts_with(b"*/") {
+                        depth -= 1;
+                        i += 2;
+                    } else {
+                        i += 1;
+                    }
+                }
+
+                // An unterminated comment runs to the end of the query
+                let end = if depth == 0 { i - 2 } else { len };
+                tokens.push(Token::Comment(&query[start..end]));
+            }
+            b'\'' => {
+                let start = i;
+                i = quoted_end(bytes, i);
+                tokens.push(Token::Literal(&query[start..i]));
+            }
+            b'"' => {
+                let start = i;
+                i = quoted_end(bytes, i);
+                tokens.push(Token::QuotedIdent(&query[start..i]));
+            }
+            b'$' => {
+                let tag_len = bytes[i + 1..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+                    .count();
+                let tag_end = i + 1 + tag_len;
+
+                // Positional parameters ($1) share the prefix with dollar quoted strings ($tag$)
+                if bytes.get(tag_end) != Some(&b'$') || bytes[i + 1].is_ascii_digit() {
+                    tokens.push(if tag_len > 0 {
+                        Token::Param(&query[i..tag_end])
+                    } else {
+                        Token::Punct('$')
+                    });
+                    i = tag_end;
+                    continue;
+                }
+
+                let start = i;
+                let tag = &query[i..=tag_end];
+                i = query[tag_end + 1..]
+                    .find(tag)
+                    .map_or(len, |n| tag_end + 1 + n + tag.len());
+                tokens.push(Token::Literal(&query[start..i]));
+            }
+            b';' => {
+                tokens.push(Token::Semicolon);
+                i += 1;
+            }
+            c if c.is_ascii_digit() || (c == b'.' && next.is_some_and(|c| c.is_ascii_digit())) => {
+                let start = i;
+                i += 1;
+
+                while i < len {
+                    match bytes[i] {
+                        b'e' | b'E' if matches!(bytes.get(i + 1), Some(b'+' | b'-')) => i += 2,
+                        c if c.is_ascii_alphanumeric() || c == b'.' || c == b'_' => i += 1,
+                        _ => break,
+                    }
+                }
+
+                tokens.push(Token::Literal(&query[start..i]));
+            }
+            // Escape strings (E'..') may contain backslash escaped quotes
+            b'e' | b'E' if next == Some(b'\'') => {
+                let start = i;
+                i += 2;
+
+                while i < len && bytes[i] != b'\'' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+
+                i = (i + 1).min(len);
+                tokens.push(Token::Literal(&query[start..i]));
+            }
+            c if is_word_byte(c) => {
+                let start = i;
+
+                while i < len && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+
+                tokens.push(Token::Word(&query[start..i]));
+            }
+            c if c.is_ascii_whitespace() => i += 1,
+            _ => {
+                let c = query[i..].chars().next().unwrap();
+                tokens.push(Token::Punct(c));
+                i += c.len_utf8();
+            }
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("select 'a''b', $1, 1.5e-3 -- c\n/* d /* e */ */; \"X\""),
+            vec![
+                Token::Word("select"),
+                Token::Literal("'a''b'"),
+                Token::Punct(','),
+                Token::Param("$1"),
+                Token::Punct(','),
+                Token::Literal("1.5e-3"),
+                Token::Comment(" c"),
+                Token::Comment(" d /* e */ "),
+                Token::Semicolon,
+                Token::QuotedIdent("\"X\""),
+            ]
+        );
+        assert_eq!(
+            tokenize("$x$ it's $x$ E'\\''"),
+            vec![Token::Literal("$x$ it's $x$"), Token::Literal("E'\\''")]
+        );
+
+        // Unterminated tokens run to the end, without splitting characters
+        assert_eq!(
+            tokenize("select 1 /* 日"),
+            vec![
+                Token::Word("select"),
+                Token::Literal("1"),
+                Token::Comment(" 日")
+            ]
+        );
+        assert_eq!(tokenize("'日"), vec![Token::Literal("'日")]);
+    }
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote("'it''s'").as_deref(), Some("it's"));
+        assert_eq!(unquote("\"a\"\"b\"").as_deref(), Some("a\"b"));
+        assert_eq!(unquote("''").as_deref(), Some(""));
+        assert_eq!(unquote("'"), None);
+        assert_eq!(unquote("'日"), None);
+        assert_eq!(unquote("'a''"), None);
+        assert_eq!(unquote("\""), None);
+        assert_eq!(unquote("abc"), None);
+    }
+}
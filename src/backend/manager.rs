@@ -1,167 +1,515 @@
-use std::collections::HashMap;
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::backend::auth::{AuthMethod, AuthResult};
-use crate::backend::{Auth, Conn, QueryExec};
-
+use crate::backend::audit::{AuditedSink, Auditor};
+use crate::backend::cancel::CancelKey;
+use crate::backend::copy_both::{DEFAULT_TIMEOUT, POLL_INTERVAL};
+use crate::backend::replication::{
+    self, ChangeSource, PhysicalSource, ReplicationCommand, WalSender,
+};
+use crate::backend::stream::FLUSH_SIZE;
+use crate::backend::{
+    settings, AuditSink, Auth, CancelRegistry, Conn, CopyBoth, CopyOutput, Event, Phase, QueryExec,
+    Replication, ResultSink, RowStream, Session,
+};
 use crate::proto::messages::{
-    AuthenticationCleartextPassword, AuthenticationOk, ErrorResponse, Field, Handshake,
-    IncomingMessage, ReadyForQuery, SSLResponse, Severity, TransactionStatus,
+    BackendMessage, CommandComplete, CommandTag, CopyData, ErrorResponse, Severity,
+    TransactionStatus, PROTOCOL_VERSION_3_2,
 };
 
-pub enum Replication {
-    Enabled,
-    Disabled,
-    Database,
-}
-
-pub struct State {
-    user: String,
-    database: String,
-    replication: Replication,
-    extra_params: HashMap<String, String>,
-}
-
-impl Default for State {
-    fn default() -> Self {
-        Self {
-            user: String::new(),
-            database: String::new(),
-            replication: Replication::Disabled,
-            extra_params: HashMap::new(),
-        }
-    }
-}
-
+// Blocking driver around a `Session`, all protocol logic lives in the session and this only
+// performs the socket I/O and calls out to the auth and query executor
 pub struct Manager<A: Auth, Q: QueryExec> {
     conn: Conn,
-    state: State,
+    session: Session,
     auth: A,
     query_exec: Q,
-    postgres_version: i32,
+    auditor: Option<Auditor>,
+    change_source: Option<Box<dyn ChangeSource + Send>>,
+    physical_source: Option<Box<dyn PhysicalSource + Send>>,
+    copy_timeout: Option<Duration>,
+    cancel_registry: Option<Arc<CancelRegistry>>,
+    cancel_key: Option<CancelKey>,
 }
 
 impl<A: Auth, Q: QueryExec> Manager<A, Q> {
     pub fn new(conn: Conn, auth: A, query_exec: Q) -> io::Result<Self> {
         Ok(Self {
             conn,
-            auth: auth,
-            query_exec: query_exec,
-            postgres_version: 0,
-            state: State::default(),
+            auth,
+            query_exec,
+            session: Session::new(),
+            auditor: None,
+            change_source: None,
+            physical_source: None,
+            copy_timeout: Some(DEFAULT_TIMEOUT),
+            cancel_registry: None,
+            cancel_key: None,
         })
     }
 
-    // In the startup phase we optionally setup SSL encryption (not implemented yet) and parse the
-    // startup message which contains the initial state
-    // @TODO: support cancel-request
-    fn handle_startup(&mut self) -> io::Result<()> {
-        let handshake: Handshake = self.conn.recv()?;
-        let startup_msg = match handshake {
-            // @TODO: currently we don't support SSL encryption
-            Handshake::SSLRequest(_) => {
-                self.conn.send(SSLResponse::NoSsl)?;
-                self.conn.recv()?
-            }
-            Handshake::StartupMessage(msg) => msg,
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.session.set_max_message_size(max_message_size);
+    }
+
+    // Answers IDENTIFY_SYSTEM, TIMELINE_HISTORY and BASE_BACKUP on `replication=true` connections
+    pub fn set_physical_source(&mut self, source: Box<dyn PhysicalSource + Send>) {
+        self.physical_source = Some(source);
+    }
+
+    // How long the client may stay silent in copy-both mode before the connection is closed, None
+    // waits forever
+    pub fn set_copy_timeout(&mut self, timeout: Option<Duration>) {
+        self.copy_timeout = timeout;
+    }
+
+    // Gives the client a key to cancel its queries with and accepts cancel requests for the other
+    // connections of the registry
+    pub fn set_cancel_registry(&mut self, registry: Arc<CancelRegistry>) {
+        self.cancel_registry = Some(registry);
+    }
+
+    // Records every statement executed on this connection
+    pub fn set_audit_sink(&mut self, sink: Arc<dyn AuditSink>) {
+        self.auditor = Some(Auditor::new(sink, self.conn.peer_addr()));
+    }
+
+    // Accepts the logical replication commands on `replication=database` connections and streams
+    // the changes of the source
+    pub fn set_change_source(&mut self, source: Box<dyn ChangeSource + Send>) {
+        self.change_source = Some(source);
+    }
+
+    fn audit_complete(&mut self, transaction_status: TransactionStatus) {
+        if let Some(auditor) = self.auditor.as_mut() {
+            auditor.complete(self.session.state(), transaction_status);
+        }
+    }
+
+    // Reverts SET LOCAL once the transaction has ended and finishes the query
+    fn complete(&mut self, transaction_status: TransactionStatus) -> io::Result<()> {
+        if transaction_status == TransactionStatus::Idle
+            && self.session.transaction_status() != TransactionStatus::Idle
+        {
+            let state = self.session.state().clone();
+            let mut settings = state.settings();
+            let reported = settings.reportable();
+
+            settings.end_transaction();
+            settings::report(&reported, &settings, &mut self.session)?;
+        }
+
+        self.audit_complete(transaction_status);
+        self.session.query_complete(transaction_status)
+    }
+
+    // SET, RESET and SHOW are handled here so the session settings stay in sync with what the
+    // client sees, returns false when the executor has to handle the query instead
+    fn handle_settings(&mut self, query: &str) -> io::Result<bool> {
+        let statements = match settings::parse(query) {
+            Some(statements) => statements,
+            None => return Ok(false),
         };
 
-        self.postgres_version = startup_msg.version;
-
-        for (name, value) in startup_msg.params.into_iter() {
-            match name.as_str() {
-                "user" => self.state.user = value,
-                "database" => self.state.database = value,
-                "replication" => {
-                    self.state.replication = match value.as_str() {
-                        "database" => Replication::Database,
-                        "disabled" => Replication::Disabled,
-                        "enabled" => Replication::Enabled,
-                        _ => unreachable!(),
+        let state = self.session.state().clone();
+        let transaction_status = self.session.transaction_status();
+        let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+        let handled = settings::execute(
+            &state,
+            &statements,
+            transaction_status,
+            &mut self.query_exec,
+            &mut sink,
+        )?;
+
+        if handled {
+            self.complete(transaction_status)?;
+        }
+
+        Ok(handled)
+    }
+
+    // Returns false when the query has to be handled elsewhere, such as regular SQL on logical
+    // replication connections or any query on connections without a source
+    fn handle_replication(&mut self, query: &str) -> io::Result<bool> {
+        match self.session.state().replication() {
+            Replication::Database => self.handle_logical(query),
+            Replication::Enabled => self.handle_physical(query),
+            Replication::Disabled => Ok(false),
+        }
+    }
+
+    // Physical replication connections only accept replication commands and settings
+    fn handle_physical(&mut self, query: &str) -> io::Result<bool> {
+        let mut source = match self.physical_source.take() {
+            Some(source) => source,
+            None => return Ok(false),
+        };
+
+        let transaction_status = self.session.transaction_status();
+        let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+
+        match replication::parse_command(query) {
+            Some(command) => replication::execute_physical(&command, source.as_mut(), &mut sink)?,
+            None if settings::parse(query).is_some() => {
+                self.physical_source = Some(source);
+                return Ok(false);
+            }
+            None => sink.push(BackendMessage::ErrorResponse(ErrorResponse::new(
+                Severity::Error,
+                "0A000".to_string(),
+                "cannot execute SQL commands in WAL sender for physical replication".to_string(),
+            )))?,
+        }
+
+        self.physical_source = Some(source);
+        self.complete(transaction_status)?;
+
+        Ok(true)
+    }
+
+    fn handle_logical(&mut self, query: &str) -> io::Result<bool> {
+        let (mut source, command) =
+            match (self.change_source.take(), replication::parse_command(query)) {
+                (Some(source), Some(command)) => (source, command),
+                (source, _) => {
+                    self.change_source = source;
+                    return Ok(false);
+                }
+            };
+
+        let state = self.session.state().clone();
+        let transaction_status = self.session.transaction_status();
+        let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+
+        match command {
+            ReplicationCommand::StartReplication {
+                slot,
+                start,
+                options,
+            } => match source.start(&slot, start, &options) {
+                Ok(_) => self.copy_both(&mut WalSender::new(source.as_mut(), start))?,
+                Err(e) => sink.push(BackendMessage::ErrorResponse(e))?,
+            },
+            command => replication::execute(&command, source.as_mut(), &state, &mut sink)?,
+        }
+
+        self.change_source = Some(source);
+
+        if self.session.phase() != Phase::Closed {
+            self.complete(transaction_status)?;
+        }
+
+        Ok(true)
+    }
+
+    // Exchanges CopyData with the client until either side ends copy-both mode. The client has to
+    // send something within the timeout, the handler can ask it to with a keepalive.
+    fn copy_both(&mut self, handler: &mut dyn CopyBoth) -> io::Result<()> {
+        self.session.start_copy_both()?;
+
+        let mut last_received = Instant::now();
+        let mut keepalive_sent = false;
+
+        loop {
+            loop {
+                let event = match self.session.poll_event() {
+                    Ok(Some(event)) => event,
+                    Ok(None) => break,
+                    Err(e) => {
+                        self.conn.flush(&mut self.session)?;
+
+                        return Err(e.into());
+                    }
+                };
+
+                last_received = Instant::now();
+                keepalive_sent = false;
+
+                match event {
+                    Event::CopyData(data) => match handler.receive(data.data)? {
+                        Ok(Some(reply)) => self.session.send(CopyData::new(reply))?,
+                        Ok(None) => {}
+                        Err(e) => return self.session.copy_both_error(e),
+                    },
+                    Event::CopyDone => {
+                        self.session.end_copy_both()?;
+
+                        return self
+                            .session
+                            .send(CommandComplete::new(handler.command_tag()));
+                    }
+                    Event::CopyFail(fail) => {
+                        return self.session.send(ErrorResponse::new(
+                            Severity::Error,
+                            "57014".to_string(),
+                            format!("copy-both mode aborted by the client: {}", fail.message),
+                        ));
+                    }
+                    Event::Terminate => return Ok(()),
+                    _ => unreachable!("only copy events are produced in copy-both mode"),
+                }
+            }
+
+            if let Some(timeout) = self.copy_timeout {
+                let silent = last_received.elapsed();
+
+                if silent >= timeout {
+                    log::warn!("client stopped replying in copy-both mode");
+
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+
+                if silent >= timeout / 2 && !keepalive_sent {
+                    if let Some(keepalive) = handler.keepalive()? {
+                        self.session.send(CopyData::new(keepalive))?;
                     }
+
+                    keepalive_sent = true;
                 }
-                _ => {
-                    self.state.extra_params.insert(name, value);
+            }
+
+            match handler.next(POLL_INTERVAL)? {
+                Ok(CopyOutput::Data(data)) => self.session.send(CopyData::new(data))?,
+                Ok(CopyOutput::Idle) => {}
+                Ok(CopyOutput::Done) => {
+                    self.session.end_copy_both()?;
+
+                    return self
+                        .session
+                        .send(CommandComplete::new(handler.command_tag()));
                 }
+                Err(e) => return self.session.copy_both_error(e),
             }
-        }
 
-        if self.state.database.is_empty() {
-            self.state.database = self.state.user.clone();
+            self.conn.flush(&mut self.session)?;
+
+            if self.conn.try_recv(&mut self.session)? == Some(0) {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
         }
+    }
 
-        Ok(())
+    fn push(&mut self, msg: BackendMessage) -> io::Result<()> {
+        AuditedSink::new(&mut self.session, self.auditor.as_mut()).push(msg)
     }
 
-    pub fn handle_auth(&mut self, method: AuthMethod) -> io::Result<AuthResult> {
-        Ok(match method {
-            AuthMethod::CleartextPassword => {
-                self.conn.send(AuthenticationCleartextPassword {})?;
-                self.auth
-                    .clear_text_password(&self.state, self.conn.recv()?)
-            }
-            AuthMethod::None => Ok(()),
-        })
+    fn is_cancelled(&self) -> bool {
+        self.cancel_key
+            .as_ref()
+            .is_some_and(|key| key.is_cancelled())
     }
 
-    pub fn handle(&mut self) -> io::Result<()> {
-        log::debug!("entering startup phase");
+    // Sends the rows as the client reads them, the query ends early when the client disconnects
+    // or cancels it
+    fn stream(&mut self, stream: RowStream) -> io::Result<()> {
+        self.push(BackendMessage::RowDescription(stream.description))?;
 
-        loop {
-            self.handle_startup()?;
+        let mut sent = 0;
 
-            if !self.state.user.is_empty() {
-                break;
+        for row in stream.rows {
+            if self.is_cancelled() {
+                return self.push(BackendMessage::ErrorResponse(ErrorResponse::new(
+                    Severity::Error,
+                    "57014".to_string(),
+                    "canceling statement due to user request".to_string(),
+                )));
+            }
+
+            match row {
+                Ok(row) => self.push(BackendMessage::DataRow(row))?,
+                Err(e) => return self.push(BackendMessage::ErrorResponse(e)),
             }
 
-            log::error!("no user specified, retrying startup");
+            sent += 1;
 
-            self.conn.send(ErrorResponse::new(
-                Severity::Error,
-                "P0001".to_string(),
-                "the 'user' option is mandatory".to_string(),
-            ))?;
+            if self.session.output().len() >= FLUSH_SIZE {
+                self.conn.flush(&mut self.session)?;
+
+                if self.conn.try_recv(&mut self.session)? == Some(0) {
+                    log::info!("client disconnected after {} streamed rows", sent);
+
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
         }
 
-        let method = self.auth.method(&self.state);
-        log::debug!("selecting auth method: {:?}", method);
+        self.push(BackendMessage::CommandComplete(CommandComplete::new(
+            CommandTag::Select(sent),
+        )))
+    }
 
-        match self.handle_auth(method)? {
-            Ok(_) => self.conn.send(AuthenticationOk {})?,
-            Err(e) => {
-                let msg = e.get_field(Field::Message).unwrap_or_default();
+    fn handle_event(&mut self, event: Event) -> io::Result<()> {
+        match event {
+            Event::Startup => {
+                let method = self.auth.method(self.session.state());
+                self.session.authenticate(method)
+            }
+            Event::Password(password) => {
+                let result = self
+                    .auth
+                    .clear_text_password(self.session.state(), password);
+                self.session.auth_result(result)
+            }
+            Event::Authenticated => {
+                // The key is only registered once the protocol version is known, clients using
+                // 3.2 get a 32 byte key like PostgreSQL sends
+                if let Some(registry) = self.cancel_registry.as_ref() {
+                    let len = match self.session.protocol_version() >= PROTOCOL_VERSION_3_2 {
+                        true => 32,
+                        false => 4,
+                    };
+                    let key = registry.register(len);
 
-                log::debug!("auth failed: {}", msg);
+                    self.session
+                        .set_backend_key(key.process_id, key.secret_key.clone());
+                    self.cancel_key = Some(key);
+                }
 
-                return self.conn.send(ErrorResponse::new(
-                    Severity::Error,
-                    "28P01".to_string(),
-                    msg.to_string(),
-                ));
+                let result = self.query_exec.startup(self.session.state())?;
+                self.session.startup_result(result)
             }
-        };
+            Event::Query(query) => {
+                if let Some(auditor) = self.auditor.as_mut() {
+                    auditor.query(&query.query);
+                }
+
+                if let Some(key) = self.cancel_key.as_ref() {
+                    key.reset();
+                }
+
+                if self.handle_replication(&query.query)? || self.handle_settings(&query.query)? {
+                    return Ok(());
+                }
 
-        self.conn
-            .send(ReadyForQuery::new(TransactionStatus::Idle))?;
+                if let Some(mut handler) = self.query_exec.copy_both(&query.query)? {
+                    let transaction_status = self.session.transaction_status();
+                    self.copy_both(handler.as_mut())?;
+
+                    if self.session.phase() != Phase::Closed {
+                        self.complete(transaction_status)?;
+                    }
+
+                    return Ok(());
+                }
 
-        log::debug!("waiting for queries");
+                if let Some(stream) = self.query_exec.stream(&query.query)? {
+                    let transaction_status = self.session.transaction_status();
+                    self.stream(stream)?;
+
+                    return self.complete(transaction_status);
+                }
+
+                let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+
+                #[cfg(feature = "sql-parser")]
+                let status = self.query_exec.execute_parsed(
+                    &query.query,
+                    crate::backend::parse(&query.query),
+                    &mut sink,
+                )?;
+                #[cfg(not(feature = "sql-parser"))]
+                let status = self.query_exec.execute_to(&query.query, &mut sink)?;
+
+                self.complete(status)
+            }
+            Event::Extended(msg) => {
+                if let Some(auditor) = self.auditor.as_mut() {
+                    auditor.extended(&msg);
+                }
+
+                let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+
+                #[cfg(feature = "sql-parser")]
+                let result = match &msg {
+                    crate::proto::messages::ExtendedMessage::Parse(parse) => {
+                        let parsed = crate::backend::parse(&parse.query);
+                        self.query_exec.extended_parsed(msg, parsed, &mut sink)?
+                    }
+                    _ => self.query_exec.extended(msg, &mut sink)?,
+                };
+                #[cfg(not(feature = "sql-parser"))]
+                let result = self.query_exec.extended(msg, &mut sink)?;
+
+                if let (Some(auditor), Err(e)) = (self.auditor.as_mut(), &result) {
+                    auditor.error(e);
+                }
+
+                self.session.extended_result(result)
+            }
+            Event::Sync => {
+                let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+                let status = self.query_exec.sync(&mut sink)?;
+
+                self.complete(status)
+            }
+            Event::Flush => {
+                let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+                self.query_exec.flush(&mut sink)?;
+
+                self.conn.flush(&mut self.session)
+            }
+            Event::FunctionCall(call) => {
+                if let Some(auditor) = self.auditor.as_mut() {
+                    auditor.query(&format!(
+                        "fastpath function call: OID {}",
+                        call.function_oid
+                    ));
+                }
+
+                let mut sink = AuditedSink::new(&mut self.session, self.auditor.as_mut());
+                let status = self.query_exec.function_call(call, &mut sink)?;
+
+                self.complete(status)
+            }
+            // Copy-both mode only happens while streaming changes
+            Event::CopyData(_) | Event::CopyDone | Event::CopyFail(_) => Ok(()),
+            Event::Cancel(request) => {
+                if let Some(registry) = self.cancel_registry.as_ref() {
+                    if registry.cancel(request.process_id, &request.secret_key) {
+                        log::info!("cancelled the query of process {}", request.process_id);
+                    }
+                }
+
+                Ok(())
+            }
+            Event::Terminate => Ok(()),
+        }
+    }
+
+    pub fn handle(&mut self) -> io::Result<()> {
+        log::debug!("entering startup phase");
 
         loop {
-            let msg: IncomingMessage = self.conn.recv()?;
+            match self.session.poll_event() {
+                Ok(Some(event)) => {
+                    self.handle_event(event)?;
 
-            match msg {
-                IncomingMessage::Query(query) => {
-                    log::debug!("received query: {}", query.query);
+                    // Long pipelines are answered while they're processed, like PostgreSQL does
+                    // once its output buffer is full
+                    if self.session.output().len() >= FLUSH_SIZE {
+                        self.conn.flush(&mut self.session)?;
+                    }
+                }
+                Ok(None) if self.session.phase() == Phase::Closed => {
+                    return self.conn.flush(&mut self.session)
+                }
+                // Output is only flushed once we have to wait for the client
+                Ok(None) => {
+                    self.conn.flush(&mut self.session)?;
+
+                    if self.conn.recv(&mut self.session)? == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                }
+                Err(e) => {
+                    log::error!("protocol error: {}", e);
 
-                    match self.query_exec.execute(&query.query) {
-                        Ok(command_complete) => self.conn.send(command_complete),
-                        Err(e) => self.conn.send(e),
-                    }?;
+                    self.conn.flush(&mut self.session)?;
 
-                    self.conn
-                        .send(ReadyForQuery::new(TransactionStatus::Idle))?;
+                    return Err(e.into());
                 }
-                IncomingMessage::Terminate(_) => return Ok(()),
             }
         }
     }
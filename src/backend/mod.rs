@@ -1,9 +1,50 @@
+mod audit;
 mod auth;
+mod cancel;
+mod catalog;
 mod conn;
+mod copy_both;
+mod firewall;
+mod interceptor;
+mod large_object;
+mod lexer;
 mod manager;
+#[cfg(feature = "sql-parser")]
+mod parser;
+mod pattern;
+mod pool;
+mod portal;
+mod proxy;
 mod query_exec;
+mod replication;
+mod response;
+mod router;
+mod session;
+mod settings;
+mod stream;
 
-pub use auth::{Auth, NoopAuth};
+pub use audit::{redact_literals, AuditEntry, AuditSink, JsonLinesAudit};
+pub use auth::{Auth, AuthMethod, AuthResult, NoopAuth};
+pub use cancel::CancelRegistry;
+pub use catalog::{Catalog, CatalogQueryExec, Column, Table, TableKind, Type};
 pub use conn::Conn;
-pub use manager::{Manager, State};
-pub use query_exec::{NoopQueryExec, QueryExec};
+pub use copy_both::{CopyBoth, CopyOutput};
+pub use firewall::{fingerprints, Firewall, FirewallInterceptor};
+pub use interceptor::{Intercept, InterceptingQueryExec, Interceptor};
+pub use large_object::{
+    FileLargeObjects, LargeObjectQueryExec, LargeObjectStore, MemoryLargeObjects,
+};
+pub use manager::Manager;
+#[cfg(feature = "sql-parser")]
+pub use parser::{parse, ParseResult};
+pub use pool::{Pool, PoolAuth, PoolConfig, PoolKey, PoolMode, PooledQueryExec};
+pub use portal::PortalQueryExec;
+pub use proxy::{ProxyAuth, ProxyQueryExec};
+pub use query_exec::{NoopQueryExec, QueryExec, QueryResult, ResultSink};
+pub use replication::{parse_command, Change, ChangeSource, PhysicalSource, ReplicationCommand};
+pub use router::{
+    classify, route_hint, Route, Router, RouterAuth, RoutingQueryExec, StatementKind,
+};
+pub use session::{Event, Phase, Replication, Session, State};
+pub use settings::{parse_options, SetStatement, Settings};
+pub use stream::RowStream;
@@ -0,0 +1,65 @@
+use sqlparser::ast::Statement;
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::{Parser, ParserError};
+
+use crate::proto::messages::{ErrorResponse, Field, Severity};
+
+pub type ParseResult = Result<Vec<Statement>, ErrorResponse>;
+
+// Converts a (1-based) line and column to the character position PostgreSQL reports
+fn position(query: &str, line: usize, column: usize) -> usize {
+    query
+        .split('\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.chars().count() + 1)
+        .sum::<usize>()
+        + column
+}
+
+fn syntax_error(query: &str, e: ParserError) -> ErrorResponse {
+    let message = match e {
+        ParserError::TokenizerError(message) | ParserError::ParserError(message) => message,
+        ParserError::RecursionLimitExceeded => "statement is too deeply nested".to_string(),
+    };
+
+    // The location is only available as part of the message, e.g. "... at Line: 1, Column: 8"
+    let location = message
+        .rsplit_once(" at Line: ")
+        .and_then(|(message, location)| {
+            let (line, column) = location.split_once(", Column: ")?;
+            Some((
+                message.to_string(),
+                line.parse().ok()?,
+                column.parse().ok()?,
+            ))
+        });
+
+    match location {
+        Some((message, line, column)) => {
+            ErrorResponse::new(Severity::Error, "42601".to_string(), message)
+                .with_field(Field::Position, position(query, line, column).to_string())
+        }
+        None => ErrorResponse::new(Severity::Error, "42601".to_string(), message),
+    }
+}
+
+// Parses the query with the PostgreSQL dialect, errors are reported as syntax_error
+pub fn parse(query: &str) -> ParseResult {
+    Parser::parse_sql(&PostgreSqlDialect {}, query).map_err(|e| syntax_error(query, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let statements = parse("select 1; select 2").unwrap();
+        assert_eq!(statements.len(), 2);
+
+        let e = parse("select 1;\nselect * fro t").unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("42601"));
+        assert_eq!(e.get_field(Field::Position), Some("20"));
+        assert!(!e.get_field(Field::Message).unwrap().contains("Line"));
+    }
+}
@@ -0,0 +1,390 @@
+// A small backtracking matcher for the patterns catalog queries use: POSIX regular expressions
+// with `~` (psql turns `\d us*` into `^(us.*)$`) and LIKE patterns. Only the common subset is
+// supported, None is returned for anything else so that the query can be left to the executor.
+use std::cell::Cell;
+
+// Backtracking is exponential for some patterns, matching gives up after this many steps
+const MAX_STEPS: usize = 100_000;
+// Repeated groups recurse for every repetition, longer patterns and texts (which catalog names
+// never are) aren't matched
+const MAX_LEN: usize = 256;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    // Ranges of characters, negated with `[^...]`
+    Class(Vec<(char, char)>, bool),
+    Start,
+    End,
+    // Alternatives separated by `|`
+    Group(Vec<Vec<Node>>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn alternatives(&mut self) -> Option<Vec<Vec<Node>>> {
+        let mut alternatives = vec![self.sequence()?];
+
+        while self.chars.peek() == Some(&'|') {
+            self.chars.next();
+            alternatives.push(self.sequence()?);
+        }
+
+        Some(alternatives)
+    }
+
+    fn sequence(&mut self) -> Option<Vec<Node>> {
+        let mut nodes = vec![];
+
+        while let Some(&c) = self.chars.peek() {
+            let node = match c {
+                '|' | ')' => break,
+                '(' => {
+                    self.chars.next();
+                    let alternatives = self.alternatives()?;
+
+                    if self.chars.next() != Some(')') {
+                        return None;
+                    }
+
+                    Node::Group(alternatives)
+                }
+                '*' | '+' | '?' | '{' => {
+                    self.chars.next();
+
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        '?' => (0, Some(1)),
+                        _ => self.bounds()?,
+                    };
+
+                    match nodes.pop()? {
+                        Node::Start | Node::End | Node::Repeat(..) => return None,
+                        node => Node::Repeat(Box::new(node), min, max),
+                    }
+                }
+                _ => {
+                    self.chars.next();
+                    self.atom(c)?
+                }
+            };
+
+            nodes.push(node);
+        }
+
+        Some(nodes)
+    }
+
+    // `{n}`, `{n,}` or `{n,m}`, after the opening brace
+    fn bounds(&mut self) -> Option<(usize, Option<usize>)> {
+        let mut bounds = String::new();
+
+        loop {
+            match self.chars.next()? {
+                '}' => break,
+                c => bounds.push(c),
+            }
+        }
+
+        match bounds.split_once(',') {
+            None => {
+                let n = bounds.parse().ok()?;
+                Some((n, Some(n)))
+            }
+            Some((min, "")) => Some((min.parse().ok()?, None)),
+            Some((min, max)) => Some((min.parse().ok()?, Some(max.parse().ok()?))),
+        }
+    }
+
+    fn atom(&mut self, c: char) -> Option<Node> {
+        Some(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '[' => self.class()?,
+            '\\' => escape(self.chars.next()?)?,
+            c => Node::Char(c),
+        })
+    }
+
+    // A bracket expression, after the opening bracket
+    fn class(&mut self) -> Option<Node> {
+        let negated = self.chars.peek() == Some(&'^');
+
+        if negated {
+            self.chars.next();
+        }
+
+        let mut ranges = vec![];
+        let mut first = true;
+
+        loop {
+            let c = match self.chars.next()? {
+                ']' if !first => break,
+                // Character classes such as [:alpha:] aren't supported
+                '[' if self.chars.peek() == Some(&':') => return None,
+                '\\' => match escape(self.chars.next()?)? {
+                    Node::Char(c) => c,
+                    Node::Class(class, false) => {
+                        ranges.extend(class);
+                        first = false;
+                        continue;
+                    }
+                    _ => return None,
+                },
+                c => c,
+            };
+
+            first = false;
+
+            if self.chars.peek() == Some(&'-') {
+                self.chars.next();
+
+                match self.chars.next()? {
+                    ']' => {
+                        ranges.push((c, c));
+                        ranges.push(('-', '-'));
+                        break;
+                    }
+                    end => ranges.push((c, end)),
+                }
+            } else {
+                ranges.push((c, c));
+            }
+        }
+
+        Some(Node::Class(ranges, negated))
+    }
+}
+
+fn escape(c: char) -> Option<Node> {
+    let class = |ranges: &[(char, char)]| Node::Class(ranges.to_vec(), false);
+
+    Some(match c {
+        'd' => class(&[('0', '9')]),
+        'w' => class(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+        's' => class(&[(' ', ' '), ('\t', '\r')]),
+        'n' => Node::Char('\n'),
+        't' => Node::Char('\t'),
+        c if c.is_ascii_alphanumeric() => return None,
+        c => Node::Char(c),
+    })
+}
+
+fn char_matches(node: &Node, c: char, case_insensitive: bool) -> bool {
+    let candidates = if case_insensitive {
+        [c, c.to_ascii_lowercase(), c.to_ascii_uppercase()]
+    } else {
+        [c; 3]
+    };
+
+    match node {
+        Node::Any => true,
+        Node::Char(expected) => candidates.contains(expected),
+        Node::Class(ranges, negated) => {
+            let found = candidates
+                .iter()
+                .any(|c| ranges.iter().any(|(start, end)| start <= c && c <= end));
+
+            found != *negated
+        }
+        _ => false,
+    }
+}
+
+struct Matcher<'a> {
+    text: &'a [char],
+    case_insensitive: bool,
+    steps: Cell<usize>,
+}
+
+impl Matcher<'_> {
+    // Matches the nodes at position `i`, calling `k` with the end of every match until it
+    // accepts one
+    fn nodes(&self, mut nodes: &[Node], mut i: usize, k: &mut dyn FnMut(usize) -> bool) -> bool {
+        loop {
+            self.steps.set(self.steps.get() + 1);
+
+            if self.exhausted() {
+                return false;
+            }
+
+            let (node, rest) = match nodes.split_first() {
+                Some(split) => split,
+                None => return k(i),
+            };
+
+            match node {
+                Node::Start if i != 0 => return false,
+                Node::End if i != self.text.len() => return false,
+                Node::Start | Node::End => {}
+                Node::Group(alternatives) => {
+                    return alternatives.iter().any(|alternative| {
+                        self.nodes(alternative, i, &mut |j| self.nodes(rest, j, k))
+                    })
+                }
+                // Repeated characters are counted rather than matched one by one
+                Node::Repeat(node, min, max) if !matches!(**node, Node::Group(_)) => {
+                    let mut n = 0;
+
+                    while max.is_none_or(|max| n < max) && self.char_at(node, i + n) {
+                        n += 1;
+                    }
+
+                    return (*min..=n).rev().any(|n| self.nodes(rest, i + n, k));
+                }
+                Node::Repeat(node, min, max) => {
+                    return self.repeat(node, *min, *max, 0, rest, i, k)
+                }
+                node if self.char_at(node, i) => i += 1,
+                _ => return false,
+            }
+
+            nodes = rest;
+        }
+    }
+
+    fn char_at(&self, node: &Node, i: usize) -> bool {
+        i < self.text.len() && char_matches(node, self.text[i], self.case_insensitive)
+    }
+
+    // Greedy, so one more repetition is tried before the rest of the pattern
+    #[allow(clippy::too_many_arguments)]
+    fn repeat(
+        &self,
+        node: &Node,
+        min: usize,
+        max: Option<usize>,
+        count: usize,
+        rest: &[Node],
+        i: usize,
+        k: &mut dyn FnMut(usize) -> bool,
+    ) -> bool {
+        let more = max.is_none_or(|max| count < max)
+            && self.nodes(std::slice::from_ref(node), i, &mut |j| {
+                // Repeating an empty match doesn't get anywhere
+                (j != i || count < min) && self.repeat(node, min, max, count + 1, rest, j, k)
+            });
+
+        more || (count >= min && self.nodes(rest, i, k))
+    }
+
+    fn exhausted(&self) -> bool {
+        self.steps.get() > MAX_STEPS
+    }
+}
+
+// Whether the regular expression matches anywhere in the text
+pub(crate) fn regex_match(pattern: &str, text: &str, case_insensitive: bool) -> Option<bool> {
+    if pattern.len() > MAX_LEN || text.len() > MAX_LEN {
+        return None;
+    }
+
+    let mut parser = Parser {
+        chars: pattern.chars().peekable(),
+    };
+    let alternatives = parser.alternatives()?;
+
+    if parser.chars.next().is_some() {
+        return None;
+    }
+
+    let text = text.chars().collect::<Vec<_>>();
+    let matcher = Matcher {
+        text: &text,
+        case_insensitive,
+        steps: Cell::new(0),
+    };
+    let nodes = [Node::Group(alternatives)];
+    let found = (0..=text.len()).any(|i| matcher.nodes(&nodes, i, &mut |_| true));
+
+    match matcher.exhausted() {
+        true => None,
+        false => Some(found),
+    }
+}
+
+// Whether the LIKE pattern matches the whole text, `%` matches any number of characters and `_`
+// a single one unless escaped with a backslash
+pub(crate) fn like_match(pattern: &str, text: &str, case_insensitive: bool) -> Option<bool> {
+    if pattern.len() > MAX_LEN || text.len() > MAX_LEN {
+        return None;
+    }
+
+    let mut nodes = vec![Node::Start];
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        nodes.push(match c {
+            '%' => Node::Repeat(Box::new(Node::Any), 0, None),
+            '_' => Node::Any,
+            '\\' => Node::Char(chars.next().unwrap_or('\\')),
+            c => Node::Char(c),
+        });
+    }
+
+    nodes.push(Node::End);
+
+    let text = text.chars().collect::<Vec<_>>();
+    let matcher = Matcher {
+        text: &text,
+        case_insensitive,
+        steps: Cell::new(0),
+    };
+    let found = matcher.nodes(&nodes, 0, &mut |_| true);
+
+    match matcher.exhausted() {
+        true => None,
+        false => Some(found),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regex_match() {
+        assert_eq!(regex_match("^(users)$", "users", false), Some(true));
+        assert_eq!(regex_match("^(users)$", "users2", false), Some(false));
+        assert_eq!(regex_match("^pg_", "pg_toast", false), Some(true));
+        assert_eq!(regex_match("toast", "pg_toast", false), Some(true));
+        assert_eq!(regex_match("^(us.*)$", "users", false), Some(true));
+        assert_eq!(regex_match("^(a|b)+c?$", "abba", false), Some(true));
+        assert_eq!(regex_match("^[a-c_]{2,3}\\d$", "a_b7", false), Some(true));
+        assert_eq!(regex_match("^[^a]", "abc", false), Some(false));
+        assert_eq!(regex_match("^USERS$", "users", true), Some(true));
+        assert_eq!(regex_match("(a*)*$", "aaaab", false), Some(true));
+        assert_eq!(
+            regex_match("^(a|aa)*$", &format!("{}b", "a".repeat(40)), false),
+            None
+        );
+
+        // Unsupported patterns aren't guessed
+        assert_eq!(regex_match("[[:alpha:]]", "a", false), None);
+        assert_eq!(regex_match("\\y", "a", false), None);
+        assert_eq!(regex_match("(a", "a", false), None);
+        assert_eq!(regex_match("*a", "a", false), None);
+        assert_eq!(
+            regex_match("^(a|b)*$", &"ab".repeat(128), false),
+            Some(true)
+        );
+        assert_eq!(regex_match("^a*b?.*$", &"a".repeat(256), false), Some(true));
+        assert_eq!(regex_match("a", &"a".repeat(2000), false), None);
+    }
+
+    #[test]
+    fn test_like_match() {
+        assert_eq!(like_match("us%", "users", false), Some(true));
+        assert_eq!(like_match("u_ers", "users", false), Some(true));
+        assert_eq!(like_match("us", "users", false), Some(false));
+        assert_eq!(like_match("100\\%", "100%", false), Some(true));
+        assert_eq!(like_match("US%", "users", true), Some(true));
+    }
+}
@@ -0,0 +1,670 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use secstr::SecStr;
+
+use crate::backend::auth::{AuthMethod, AuthResult};
+use crate::backend::proxy::{command_result, connect_error, forward_set, relay, Pending};
+use crate::backend::query_exec::{QueryResult, ResultSink};
+use crate::backend::{parse_options, Auth, QueryExec, SetStatement, State};
+use crate::frontend::{Client, ClientError, Config};
+use crate::proto::messages::{
+    BackendMessage, ErrorResponse, ExtendedMessage, FunctionCall, PasswordMessage, Query, Severity,
+    Sync, TransactionStatus,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PoolMode {
+    // A client keeps its upstream connection until it disconnects
+    Session,
+    // The upstream connection is returned to the pool after every transaction
+    Transaction,
+}
+
+impl FromStr for PoolMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "session" => Ok(Self::Session),
+            "transaction" => Ok(Self::Transaction),
+            _ => Err(format!("invalid pool mode: {}", s)),
+        }
+    }
+}
+
+pub struct PoolConfig {
+    pub upstream: SocketAddr,
+    pub mode: PoolMode,
+    // Maximum number of upstream connections per user and database
+    pub max_size: usize,
+    // How long a client waits for a connection when all of them are in use
+    pub checkout_timeout: Duration,
+    // Executed when a connection is returned to the pool
+    pub reset_query: Option<String>,
+    // Passwords used to log in to the upstream server (by user)
+    pub passwords: HashMap<String, SecStr>,
+}
+
+impl PoolConfig {
+    pub fn new(upstream: SocketAddr) -> Self {
+        Self {
+            upstream,
+            mode: PoolMode::Session,
+            max_size: 10,
+            checkout_timeout: Duration::from_secs(30),
+            reset_query: Some("DISCARD ALL".to_string()),
+            passwords: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub user: String,
+    pub database: String,
+}
+
+#[derive(Default)]
+struct Slot {
+    idle: Vec<Client>,
+    // Number of connections that are open, both idle and checked out
+    open: usize,
+}
+
+// Upstream connections shared by all client sessions, bounded per user and database
+pub struct Pool {
+    config: PoolConfig,
+    slots: Mutex<HashMap<PoolKey, Slot>>,
+    available: Condvar,
+}
+
+impl Pool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            slots: Mutex::new(HashMap::new()),
+            available: Condvar::new(),
+        }
+    }
+
+    pub fn mode(&self) -> PoolMode {
+        self.config.mode
+    }
+
+    // Returns the number of open connections for the given key
+    pub fn size(&self, key: &PoolKey) -> usize {
+        self.slots
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|slot| slot.open)
+            .unwrap_or_default()
+    }
+
+    fn connect(&self, key: &PoolKey, password: Option<SecStr>) -> Result<Client, ClientError> {
+        let mut config = Config::new(key.user.clone());
+        config.database = Some(key.database.clone());
+        config.password = password;
+
+        Client::connect(self.config.upstream, config)
+    }
+
+    // Logs in to the upstream server with the credentials of a client, never with the configured
+    // passwords. The connection is kept in the pool if there's room for it.
+    pub fn authenticate(&self, key: &PoolKey, password: Option<SecStr>) -> Result<(), ClientError> {
+        let client = self.connect(key, password)?;
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.entry(key.clone()).or_default();
+
+        if slot.open < self.config.max_size {
+            slot.open += 1;
+            slot.idle.push(client);
+
+            self.available.notify_one();
+        }
+
+        Ok(())
+    }
+
+    // New connections log in with the configured password of the user, or else the given one
+    pub fn checkout(
+        &self,
+        key: &PoolKey,
+        password: Option<&SecStr>,
+    ) -> Result<Client, ErrorResponse> {
+        let deadline = Instant::now() + self.config.checkout_timeout;
+        let mut slots = self.slots.lock().unwrap();
+
+        loop {
+            let slot = slots.entry(key.clone()).or_default();
+
+            if let Some(client) = slot.idle.pop() {
+                return Ok(client);
+            }
+
+            if slot.open < self.config.max_size {
+                slot.open += 1;
+                drop(slots);
+
+                // Connecting happens outside of the lock, the slot is reserved in the meantime
+                let password = self.config.passwords.get(&key.user).or(password);
+
+                return self
+                    .connect(key, password.cloned())
+                    .map_err(connect_error)
+                    .inspect_err(|_| self.release_slot(key));
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                return Err(ErrorResponse::new(
+                    Severity::Error,
+                    "53300".to_string(),
+                    "timed out waiting for a pooled connection".to_string(),
+                ));
+            }
+
+            slots = self
+                .available
+                .wait_timeout(slots, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    pub fn checkin(&self, key: &PoolKey, mut client: Client) {
+        if !self.reset(&mut client) {
+            log::warn!("discarding upstream connection which couldn't be reset");
+
+            return self.release_slot(key);
+        }
+
+        let mut slots = self.slots.lock().unwrap();
+        slots.entry(key.clone()).or_default().idle.push(client);
+
+        self.available.notify_one();
+    }
+
+    fn release_slot(&self, key: &PoolKey) {
+        let mut slots = self.slots.lock().unwrap();
+
+        if let Some(slot) = slots.get_mut(key) {
+            slot.open -= 1;
+        }
+
+        self.available.notify_one();
+    }
+
+    // Makes sure nothing of the previous client leaks into the next one
+    fn reset(&self, client: &mut Client) -> bool {
+        let mut queries = vec![];
+
+        if client.transaction_status() != TransactionStatus::Idle {
+            queries.push("ROLLBACK");
+        }
+
+        if let Some(query) = self.config.reset_query.as_deref() {
+            queries.push(query);
+        }
+
+        for query in queries {
+            match client.simple_query(query) {
+                Ok(response) if response.error().is_none() => {}
+                _ => return false,
+            }
+        }
+
+        client.transaction_status() == TransactionStatus::Idle
+    }
+}
+
+// The password a client authenticated with, the executor opens connections with it
+type Password = Rc<RefCell<Option<SecStr>>>;
+
+// Authenticates clients by logging in to the upstream server with the same credentials, like
+// `ProxyAuth`, before they can check out pooled connections
+pub struct PoolAuth {
+    pool: Arc<Pool>,
+    password: Password,
+}
+
+impl PoolAuth {
+    pub fn new(pool: Arc<Pool>) -> Self {
+        Self {
+            pool,
+            password: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    // Returns the executor which uses the password checked by this auth
+    pub fn query_exec(&self) -> PooledQueryExec {
+        let mut query_exec = PooledQueryExec::new(self.pool.clone());
+        query_exec.password = self.password.clone();
+
+        query_exec
+    }
+}
+
+fn pool_key(state: &State) -> PoolKey {
+    PoolKey {
+        user: state.user().to_string(),
+        database: state.database().to_string(),
+    }
+}
+
+impl Auth for PoolAuth {
+    // We only ask the client for a password when the upstream server wants one
+    fn method(&self, state: &State) -> AuthMethod {
+        match self.pool.authenticate(&pool_key(state), None) {
+            Ok(_) => AuthMethod::None,
+            Err(ClientError::PasswordRequired) => AuthMethod::CleartextPassword,
+            Err(e) => AuthMethod::Reject(connect_error(e)),
+        }
+    }
+
+    fn clear_text_password(&self, state: &State, password: PasswordMessage) -> AuthResult {
+        self.pool
+            .authenticate(&pool_key(state), Some(password.password.clone()))
+            .map_err(connect_error)?;
+        self.password.replace(Some(password.password));
+
+        Ok(())
+    }
+}
+
+// Executes queries on pooled upstream connections, see `PoolMode` for when connections are
+// returned to the pool
+pub struct PooledQueryExec {
+    pool: Arc<Pool>,
+    password: Password,
+    key: Option<PoolKey>,
+    client: Option<Client>,
+    pending: Pending,
+    // The startup parameters and the session level SET and RESET statements since, pooled
+    // connections are reset when returned so both are replayed on every checkout
+    params: Vec<SetStatement>,
+    settings: Vec<SetStatement>,
+}
+
+impl PooledQueryExec {
+    pub fn new(pool: Arc<Pool>) -> Self {
+        Self {
+            pool,
+            password: Rc::new(RefCell::new(None)),
+            key: None,
+            client: None,
+            pending: Pending::default(),
+            params: vec![],
+            settings: vec![],
+        }
+    }
+
+    fn acquire(&mut self) -> Result<&mut Client, ErrorResponse> {
+        if self.client.is_none() {
+            let key = self.key.as_ref().expect("startup must be called first");
+            let mut client = self.pool.checkout(key, self.password.borrow().as_ref())?;
+
+            // The upstream server checks the startup parameters, like it would have on login
+            for statement in self.params.iter() {
+                match forward_set(&mut client, statement) {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        self.pool.checkin(key, client);
+                        return Err(e);
+                    }
+                    Err(e) => log::warn!("failed to apply {}: {}", statement, e),
+                }
+            }
+
+            for statement in self.settings.iter() {
+                match forward_set(&mut client, statement) {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => log::warn!("failed to apply {}: {}", statement, e),
+                    Err(e) => log::warn!("failed to apply {}: {}", statement, e),
+                }
+            }
+
+            self.client = Some(client);
+        }
+
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    fn release(&mut self, transaction_status: TransactionStatus) {
+        if self.pool.mode() != PoolMode::Transaction
+            || transaction_status != TransactionStatus::Idle
+        {
+            return;
+        }
+
+        if let (Some(key), Some(client)) = (self.key.as_ref(), self.client.take()) {
+            self.pool.checkin(key, client);
+        }
+    }
+}
+
+impl QueryExec for PooledQueryExec {
+    fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        self.key = Some(pool_key(state));
+
+        let params = state.extra_params();
+        let options = params
+            .get("options")
+            .map(|options| parse_options(options).unwrap_or_default())
+            .unwrap_or_default();
+
+        self.params = params
+            .iter()
+            .filter(|(name, _)| *name != "options")
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .chain(options)
+            .map(|(name, value)| SetStatement::Set {
+                name,
+                value: Some(vec![format!("'{}'", value.replace('\'', "''"))]),
+                local: false,
+            })
+            .collect();
+
+        // A connection is checked out in transaction mode as well for the settings it reports, what
+        // it reported when it logged in doesn't include the replayed startup parameters so they're
+        // kept as the client passed them
+        let client = match self.acquire() {
+            Ok(client) => client,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        state.settings().seed(
+            client
+                .parameters()
+                .filter(|(name, _)| !params.contains_key(*name))
+                .chain(
+                    params
+                        .iter()
+                        .filter(|(name, _)| *name != "options")
+                        .map(|(name, value)| (name.as_str(), value.as_str())),
+                ),
+        );
+        self.release(TransactionStatus::Idle);
+
+        Ok(Ok(()))
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult {
+        let mut messages = vec![];
+        let result = self.execute_to(query, &mut messages);
+
+        command_result(result, messages)
+    }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let client = match self.acquire() {
+            Ok(client) => client,
+            Err(e) => {
+                sink.push(BackendMessage::ErrorResponse(e))?;
+                return Ok(TransactionStatus::Idle);
+            }
+        };
+
+        client.send(Query::new(query.to_string()))?;
+        client.flush()?;
+
+        let status = relay(client, sink)?;
+        self.release(status);
+
+        Ok(status)
+    }
+
+    fn extended(
+        &mut self,
+        msg: ExtendedMessage,
+        _sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        match self.acquire() {
+            Ok(client) => {
+                client.send(msg)?;
+                self.pending.sent();
+                Ok(Ok(()))
+            }
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    fn flush(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        match self.client.as_mut() {
+            Some(client) => self.pending.flush(client, sink),
+            None => Ok(()),
+        }
+    }
+
+    fn sync(&mut self, sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        let client = match self.client.as_mut() {
+            Some(client) => client,
+            None => return Ok(TransactionStatus::Idle),
+        };
+
+        self.pending.sync();
+        client.send(Sync::new())?;
+        client.flush()?;
+
+        let status = relay(client, sink)?;
+        self.release(status);
+
+        Ok(status)
+    }
+
+    fn function_call(
+        &mut self,
+        call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let client = match self.acquire() {
+            Ok(client) => client,
+            Err(e) => {
+                sink.push(BackendMessage::ErrorResponse(e))?;
+                return Ok(TransactionStatus::Idle);
+            }
+        };
+
+        client.send(call)?;
+        client.flush()?;
+
+        let status = relay(client, sink)?;
+        self.release(status);
+
+        Ok(status)
+    }
+
+    // In transaction mode a connection is checked out to check the statement, it's replayed on the
+    // connections checked out later
+    fn set(&mut self, statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        let result = match self.acquire() {
+            Ok(client) => forward_set(client, statement)?,
+            Err(e) => Err(e),
+        };
+
+        if result.is_ok() {
+            match statement {
+                SetStatement::Set { local: true, .. } => {}
+                SetStatement::ResetAll => self.settings.clear(),
+                statement => self.settings.push(statement.clone()),
+            }
+        }
+
+        if let Some(client) = self.client.as_ref() {
+            self.release(client.transaction_status());
+        }
+
+        Ok(result)
+    }
+}
+
+impl Drop for PooledQueryExec {
+    fn drop(&mut self) {
+        if let (Some(key), Some(client)) = (self.key.as_ref(), self.client.take()) {
+            self.pool.checkin(key, client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::backend::{Conn, Manager, NoopAuth, NoopQueryExec};
+    use crate::proto::messages::Field;
+
+    // Only lets clients in with the password "secret"
+    struct Secret;
+
+    impl Auth for Secret {
+        fn method(&self, _state: &State) -> AuthMethod {
+            AuthMethod::CleartextPassword
+        }
+
+        fn clear_text_password(&self, _state: &State, password: PasswordMessage) -> AuthResult {
+            match password.password == SecStr::from("secret") {
+                true => Ok(()),
+                false => Err(ErrorResponse::new(
+                    Severity::Fatal,
+                    "28P01".to_string(),
+                    "password authentication failed".to_string(),
+                )),
+            }
+        }
+    }
+
+    fn upstream<A: Auth + 'static>(auth: fn() -> A) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+
+                thread::spawn(move || {
+                    Manager::new(Conn::new(stream).unwrap(), auth(), NoopQueryExec::new())
+                        .unwrap()
+                        .handle()
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn state() -> State {
+        State::new("bob".to_string(), "bob".to_string())
+    }
+
+    #[test]
+    fn test_transaction_mode() {
+        let mut config = PoolConfig::new(upstream(NoopAuth::new));
+        config.mode = PoolMode::Transaction;
+        config.max_size = 1;
+
+        let pool = Arc::new(Pool::new(config));
+        let key = PoolKey {
+            user: "bob".to_string(),
+            database: "bob".to_string(),
+        };
+
+        let mut first = PooledQueryExec::new(pool.clone());
+        let mut second = PooledQueryExec::new(pool.clone());
+        first.startup(&state()).unwrap().unwrap();
+        second.startup(&state()).unwrap().unwrap();
+
+        // Both clients share the same upstream connection
+        assert!(first.execute("select 1").is_ok());
+        assert!(second.execute("select 1").is_ok());
+        assert_eq!(pool.size(&key), 1);
+
+        // Session settings are replayed on the connection checked out for the next transaction
+        let statement = SetStatement::Set {
+            name: "application_name".to_string(),
+            value: Some(vec!["'app'".to_string()]),
+            local: false,
+        };
+        first.set(&statement).unwrap().unwrap();
+        assert!(first.client.is_none());
+        assert!(second.execute("select 1").is_ok());
+
+        let mut messages = vec![];
+        first
+            .execute_to("show application_name", &mut messages)
+            .unwrap();
+        assert!(messages.iter().any(|msg| matches!(
+            msg,
+            BackendMessage::DataRow(row) if row.values == vec![Some(b"app".to_vec())]
+        )));
+    }
+
+    #[test]
+    fn test_session_mode_timeout() {
+        let mut config = PoolConfig::new(upstream(NoopAuth::new));
+        config.max_size = 1;
+        config.checkout_timeout = Duration::from_millis(50);
+
+        let pool = Arc::new(Pool::new(config));
+
+        let mut first = PooledQueryExec::new(pool.clone());
+        first.startup(&state()).unwrap().unwrap();
+
+        let mut second = PooledQueryExec::new(pool.clone());
+        let e = second.startup(&state()).unwrap().unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("53300"));
+
+        // Once the first client disconnects its connection can be reused
+        drop(first);
+        assert!(second.startup(&state()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_auth() {
+        let mut config = PoolConfig::new(upstream(|| Secret));
+        config.max_size = 1;
+        // Configured passwords are only used for the pool's own connections
+        config
+            .passwords
+            .insert("bob".to_string(), SecStr::from("secret"));
+
+        let pool = Arc::new(Pool::new(config));
+        let key = pool_key(&state());
+        let password = |password: &str| PasswordMessage {
+            len: 0,
+            password: SecStr::from(password),
+        };
+
+        let auth = PoolAuth::new(pool.clone());
+        assert_eq!(auth.method(&state()), AuthMethod::CleartextPassword);
+
+        let e = auth
+            .clear_text_password(&state(), password("guess"))
+            .unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("28P01"));
+        assert_eq!(pool.size(&key), 0);
+
+        // The connection the client logged in with is checked out by its executor
+        auth.clear_text_password(&state(), password("secret"))
+            .unwrap();
+        assert_eq!(pool.size(&key), 1);
+
+        let mut query_exec = auth.query_exec();
+        query_exec.startup(&state()).unwrap().unwrap();
+        assert!(query_exec.execute("select 1").is_ok());
+        assert_eq!(pool.size(&key), 1);
+    }
+}
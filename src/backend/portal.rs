@@ -0,0 +1,1329 @@
+use std::collections::HashMap;
+use std::io;
+
+use crate::backend::lexer::{tokenize, unquote, Token};
+use crate::backend::query_exec::{QueryResult, ResultSink};
+use crate::backend::response::{error, not_supported};
+#[cfg(feature = "sql-parser")]
+use crate::backend::ParseResult;
+use crate::backend::{
+    classify, CopyBoth, QueryExec, RowStream, SetStatement, State, StatementKind,
+};
+use crate::proto::messages::{
+    BackendMessage, Bind, BindComplete, CloseComplete, CommandComplete, CommandTag, DataRow,
+    Describe, EmptyQueryResponse, ErrorResponse, Execute, ExtendedMessage, Field, FunctionCall,
+    NoData, ParameterDescription, Parse, ParseComplete, PortalSuspended, RowDescription, Target,
+    TransactionStatus,
+};
+
+fn no_portal(name: &str) -> ErrorResponse {
+    // invalid_cursor_name
+    error("34000", format!("portal \"{}\" does not exist", name))
+}
+
+// A statement prepared with Parse, parameters are substituted as literals on Bind
+struct Statement {
+    query: String,
+    param_types: Vec<i32>,
+}
+
+impl Statement {
+    // Parameters without a declared type are described as text
+    fn param_types(&self) -> Vec<i32> {
+        let count = tokenize(&self.query)
+            .iter()
+            .filter_map(|token| match token {
+                Token::Param(param) => param[1..].parse::<usize>().ok(),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+            .max(self.param_types.len());
+
+        (0..count)
+            .map(|i| match self.param_types.get(i) {
+                Some(oid) if *oid != 0 => *oid,
+                _ => 25,
+            })
+            .collect()
+    }
+
+    fn bind(&self, params: &[Option<Vec<u8>>]) -> Result<String, ErrorResponse> {
+        let mut query = String::with_capacity(self.query.len());
+        let mut last = 0;
+
+        for token in tokenize(&self.query) {
+            let param = match token {
+                Token::Param(param) => param,
+                _ => continue,
+            };
+
+            let value = match param[1..].parse::<usize>() {
+                Ok(n) if n >= 1 && n <= params.len() => &params[n - 1],
+                _ => return Err(error("42P02", format!("there is no parameter {}", param))),
+            };
+
+            let start = param.as_ptr() as usize - self.query.as_ptr() as usize;
+            query.push_str(&self.query[last..start]);
+            last = start + param.len();
+
+            match value {
+                Some(value) => {
+                    let value = String::from_utf8_lossy(value);
+                    query.push('\'');
+                    query.push_str(&value.replace('\'', "''"));
+                    query.push('\'');
+                }
+                None => query.push_str("NULL"),
+            }
+        }
+
+        query.push_str(&self.query[last..]);
+        Ok(query)
+    }
+}
+
+// The complete result of a query, kept so it can be sent in parts
+struct Buffered {
+    description: Option<RowDescription>,
+    rows: Vec<DataRow>,
+    // None for empty queries
+    tag: Option<CommandTag>,
+}
+
+enum Direction {
+    // None for ALL
+    Forward(Option<usize>),
+    Backward(Option<usize>),
+    Absolute(i64),
+    Relative(i64),
+}
+
+struct Portal {
+    query: String,
+    // Queries run the first time the portal is described or executed
+    result: Option<Buffered>,
+    // Like in PostgreSQL 0 is before the first row and `rows.len() + 1` after the last
+    pos: usize,
+    // Survives the end of the transaction (WITH HOLD)
+    hold: bool,
+    scroll: bool,
+}
+
+impl Portal {
+    fn new(query: String) -> Self {
+        Self {
+            query,
+            result: None,
+            pos: 0,
+            hold: false,
+            scroll: true,
+        }
+    }
+
+    // Moves the cursor and returns the rows it passed over, in the order they're returned
+    fn seek(&mut self, direction: Direction) -> Result<Vec<DataRow>, ErrorResponse> {
+        let rows = match self.result.as_ref() {
+            Some(result) => &result.rows,
+            None => return Ok(vec![]),
+        };
+        let len = rows.len();
+        let pos = self.pos.min(len + 1);
+
+        let target = match direction {
+            Direction::Forward(count) => {
+                let count = count.unwrap_or(usize::MAX);
+                let current = pos.min(len);
+                let taken = count.min(len - current);
+
+                self.pos = if taken < count { len + 1 } else { pos + taken };
+                return Ok(rows[current..current + taken].to_vec());
+            }
+            Direction::Backward(count) => {
+                if !self.scroll {
+                    return Err(error("55000", "cursor can only scan forward".to_string())
+                        .with_field(
+                            Field::Hint,
+                            "Declare it with SCROLL option to enable backward scan.".to_string(),
+                        ));
+                }
+
+                let count = count.unwrap_or(usize::MAX);
+                let current = pos.saturating_sub(1);
+                let taken = count.min(current);
+
+                self.pos = if taken < count { 0 } else { pos - taken };
+                return Ok((current - taken..current)
+                    .rev()
+                    .map(|i| rows[i].clone())
+                    .collect());
+            }
+            Direction::Absolute(n) if n < 0 => len as i64 + 1 + n,
+            Direction::Absolute(n) => n,
+            Direction::Relative(n) => pos as i64 + n,
+        };
+
+        let target = target.clamp(0, len as i64 + 1) as usize;
+
+        if !self.scroll && target < pos {
+            return Err(error("55000", "cursor can only scan forward".to_string()));
+        }
+
+        self.pos = target;
+
+        Ok(match target {
+            0 => vec![],
+            target if target > len => vec![],
+            target => vec![rows[target - 1].clone()],
+        })
+    }
+}
+
+enum CursorStatement {
+    Declare {
+        name: String,
+        query: String,
+        hold: bool,
+        scroll: bool,
+    },
+    Fetch {
+        name: String,
+        direction: Direction,
+        // MOVE only repositions the cursor
+        fetch: bool,
+    },
+    Close(Option<String>),
+}
+
+fn cursor_name(token: Option<&Token>) -> Option<String> {
+    match token? {
+        Token::Word(word) => Some(word.to_lowercase()),
+        Token::QuotedIdent(ident) => unquote(ident),
+        _ => None,
+    }
+}
+
+fn is_keyword(token: Option<&Token>, keyword: &str) -> bool {
+    matches!(token, Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+}
+
+// Parses a (possibly negative) row count, returning the number of tokens used
+fn count(tokens: &[Token]) -> Option<(i64, usize)> {
+    match tokens {
+        [Token::Punct('-'), Token::Literal(n), ..] => Some((-n.parse::<i64>().ok()?, 2)),
+        [Token::Punct('+'), Token::Literal(n), ..] => Some((n.parse().ok()?, 2)),
+        [Token::Literal(n), ..] => Some((n.parse().ok()?, 1)),
+        _ => None,
+    }
+}
+
+// Zero fetches the current row again
+fn forward(n: i64) -> Direction {
+    if n == 0 {
+        Direction::Relative(0)
+    } else if n < 0 {
+        Direction::Backward(Some(n.unsigned_abs() as usize))
+    } else {
+        Direction::Forward(Some(n as usize))
+    }
+}
+
+fn parse_direction(tokens: &[Token]) -> Option<(Direction, usize)> {
+    let word = match tokens.first() {
+        Some(Token::Word(word)) => word.to_uppercase(),
+        _ => return count(tokens).map(|(n, len)| (forward(n), len)),
+    };
+
+    let count_after = |tokens: &[Token]| count(&tokens[1..]).map(|(n, len)| (n, len + 1));
+
+    Some(match word.as_str() {
+        "NEXT" => (Direction::Forward(Some(1)), 1),
+        "PRIOR" => (Direction::Backward(Some(1)), 1),
+        "FIRST" => (Direction::Absolute(1), 1),
+        "LAST" => (Direction::Absolute(-1), 1),
+        "ALL" => (Direction::Forward(None), 1),
+        "ABSOLUTE" => {
+            let (n, len) = count_after(tokens)?;
+            (Direction::Absolute(n), len)
+        }
+        "RELATIVE" => {
+            let (n, len) = count_after(tokens)?;
+            (Direction::Relative(n), len)
+        }
+        "FORWARD" | "BACKWARD" => {
+            let backward = word == "BACKWARD";
+
+            let (direction, len) = if is_keyword(tokens.get(1), "all") {
+                (Direction::Forward(None), 2)
+            } else {
+                match count_after(tokens) {
+                    Some((n, len)) => (forward(n), len),
+                    None => (Direction::Forward(Some(1)), 1),
+                }
+            };
+
+            let direction = match (backward, direction) {
+                (false, direction) => direction,
+                (true, Direction::Forward(n)) => Direction::Backward(n),
+                (true, Direction::Backward(n)) => Direction::Forward(n),
+                (true, direction) => direction,
+            };
+
+            (direction, len)
+        }
+        // Only the cursor name
+        _ => (Direction::Forward(Some(1)), 0),
+    })
+}
+
+fn parse_cursor_statement(query: &str) -> Option<Result<CursorStatement, ErrorResponse>> {
+    let tokens = tokenize(query)
+        .into_iter()
+        .filter(|token| !matches!(token, Token::Comment(_)))
+        .collect::<Vec<_>>();
+
+    let tokens = match tokens.split_last() {
+        Some((Token::Semicolon, tokens)) => tokens,
+        _ => &tokens[..],
+    };
+
+    // Multiple statements are left to the executor
+    if tokens.contains(&Token::Semicolon) {
+        return None;
+    }
+
+    let command = match tokens.first() {
+        Some(Token::Word(word)) => word.to_uppercase(),
+        _ => return None,
+    };
+
+    match command.as_str() {
+        "DECLARE" => {
+            let name = cursor_name(tokens.get(1))?;
+            let mut scroll = true;
+            let mut hold = false;
+            let mut i = 2;
+
+            loop {
+                let word = match tokens.get(i) {
+                    Some(Token::Word(word)) => word.to_uppercase(),
+                    _ => return None,
+                };
+
+                match word.as_str() {
+                    "BINARY" => {
+                        return Some(Err(not_supported("binary cursors are not supported")))
+                    }
+                    "ASENSITIVE" | "INSENSITIVE" | "SCROLL" | "CURSOR" => {}
+                    "NO" if is_keyword(tokens.get(i + 1), "scroll") => {
+                        scroll = false;
+                        i += 1;
+                    }
+                    "WITH" | "WITHOUT" if is_keyword(tokens.get(i + 1), "hold") => {
+                        hold = word == "WITH";
+                        i += 1;
+                    }
+                    "FOR" => break,
+                    _ => return None,
+                }
+
+                i += 1;
+            }
+
+            // The query is everything after FOR, taken from the original text
+            let query = match tokens.get(i + 1) {
+                Some(Token::Word(word) | Token::Literal(word) | Token::QuotedIdent(word)) => {
+                    let start = word.as_ptr() as usize - query.as_ptr() as usize;
+                    query[start..].to_string()
+                }
+                _ => return None,
+            };
+
+            Some(Ok(CursorStatement::Declare {
+                name,
+                query,
+                hold,
+                scroll,
+            }))
+        }
+        "FETCH" | "MOVE" => {
+            let rest = &tokens[1..];
+            let (direction, len) = parse_direction(rest)?;
+            let rest = &rest[len..];
+
+            let rest = match rest.first() {
+                Some(_) if is_keyword(rest.first(), "from") || is_keyword(rest.first(), "in") => {
+                    &rest[1..]
+                }
+                _ => rest,
+            };
+
+            if rest.len() != 1 {
+                return None;
+            }
+
+            Some(Ok(CursorStatement::Fetch {
+                name: cursor_name(rest.first())?,
+                direction,
+                fetch: command == "FETCH",
+            }))
+        }
+        "CLOSE" if tokens.len() == 2 => Some(Ok(CursorStatement::Close(
+            if is_keyword(tokens.get(1), "all") {
+                None
+            } else {
+                Some(cursor_name(tokens.get(1))?)
+            },
+        ))),
+        _ => None,
+    }
+}
+
+fn is_begin(query: &str) -> bool {
+    matches!(classify(query)[..], [StatementKind::Begin { .. }])
+}
+
+// Implements the extended query protocol and SQL cursors (DECLARE, FETCH, MOVE and CLOSE) on top
+// of an executor which only runs simple queries. Results are buffered so portals can be executed
+// with a row limit and resumed, they're kept until they're closed or the transaction ends. Like in
+// PostgreSQL the messages up to Sync run in one implicit transaction.
+pub struct PortalQueryExec<Q: QueryExec> {
+    inner: Q,
+    statements: HashMap<String, Statement>,
+    portals: HashMap<String, Portal>,
+    transaction_status: TransactionStatus,
+    // The executor is in a transaction we started, it's committed on Sync
+    implicit: bool,
+    // A message failed during the implicit transaction, it's rolled back on Sync
+    aborted: bool,
+}
+
+impl<Q: QueryExec> PortalQueryExec<Q> {
+    pub fn new(inner: Q) -> Self {
+        Self {
+            inner,
+            statements: HashMap::new(),
+            portals: HashMap::new(),
+            transaction_status: TransactionStatus::Idle,
+            implicit: false,
+            aborted: false,
+        }
+    }
+
+    fn begin_implicit(&mut self) -> io::Result<()> {
+        if self.transaction_status == TransactionStatus::Idle && !self.implicit {
+            self.transaction_status = self.inner.execute_to("BEGIN", &mut vec![])?;
+            self.implicit = true;
+        }
+
+        Ok(())
+    }
+
+    fn end_implicit(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        if !self.implicit {
+            return Ok(());
+        }
+
+        let end = if self.aborted || self.transaction_status == TransactionStatus::Failed {
+            "ROLLBACK"
+        } else {
+            "COMMIT"
+        };
+
+        self.implicit = false;
+        self.aborted = false;
+
+        // Only errors and notices are passed on, a failed commit is reported to the client
+        let mut messages = vec![];
+        self.transaction_status = self.inner.execute_to(end, &mut messages)?;
+
+        for msg in messages {
+            if !matches!(msg, BackendMessage::CommandComplete(_)) {
+                sink.push(msg)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Runs the query of a portal and keeps the result, notices are sent right away
+    fn run(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<Buffered, ErrorResponse>> {
+        let mut messages = vec![];
+
+        // Statements are parsed once their parameters are bound, as that's the query which runs
+        #[cfg(feature = "sql-parser")]
+        let status =
+            self.inner
+                .execute_parsed(query, crate::backend::parse(query), &mut messages)?;
+        #[cfg(not(feature = "sql-parser"))]
+        let status = self.inner.execute_to(query, &mut messages)?;
+
+        self.transaction_status = status;
+
+        // The query ended the transaction (COMMIT), the next one starts a new implicit transaction
+        if self.transaction_status == TransactionStatus::Idle {
+            self.implicit = false;
+        }
+
+        let mut result = Buffered {
+            description: None,
+            rows: vec![],
+            tag: None,
+        };
+
+        for msg in messages {
+            match msg {
+                BackendMessage::RowDescription(description) if result.description.is_none() => {
+                    result.description = Some(description)
+                }
+                BackendMessage::DataRow(row) => result.rows.push(row),
+                BackendMessage::CommandComplete(cc) => result.tag = Some(cc.command_tag),
+                BackendMessage::ErrorResponse(e) => return Ok(Err(e)),
+                BackendMessage::EmptyQueryResponse(_) | BackendMessage::RowDescription(_) => {}
+                msg => sink.push(msg)?,
+            }
+        }
+
+        Ok(Ok(result))
+    }
+
+    // Runs the query of the portal unless it already ran, an error removes the portal
+    fn prepare(
+        &mut self,
+        name: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        let query = match self.portals.get(name) {
+            Some(Portal {
+                result: Some(_), ..
+            }) => return Ok(Ok(())),
+            Some(portal) => portal.query.clone(),
+            None => return Ok(Err(no_portal(name))),
+        };
+
+        match self.run(&query, sink)? {
+            Ok(result) => {
+                if let Some(portal) = self.portals.get_mut(name) {
+                    portal.result = Some(result);
+                }
+
+                Ok(Ok(()))
+            }
+            Err(e) => {
+                self.portals.remove(name);
+                Ok(Err(e))
+            }
+        }
+    }
+
+    // Portals which aren't held end with the transaction
+    fn end_transaction(&mut self) {
+        if self.transaction_status == TransactionStatus::Idle {
+            self.portals.retain(|_, portal| portal.hold);
+        }
+    }
+
+    fn parse(
+        &mut self,
+        msg: Parse,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        if !msg.name.is_empty() && self.statements.contains_key(&msg.name) {
+            return Ok(Err(error(
+                "42P05",
+                format!("prepared statement \"{}\" already exists", msg.name),
+            )));
+        }
+
+        self.statements.insert(
+            msg.name,
+            Statement {
+                query: msg.query,
+                param_types: msg.param_types,
+            },
+        );
+
+        sink.push(BackendMessage::ParseComplete(ParseComplete {}))
+            .map(Ok)
+    }
+
+    fn bind(
+        &mut self,
+        msg: Bind,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        let statement = match self.statements.get(&msg.statement) {
+            Some(statement) => statement,
+            None => {
+                return Ok(Err(error(
+                    "26000",
+                    format!("prepared statement \"{}\" does not exist", msg.statement),
+                )))
+            }
+        };
+
+        if msg.param_formats.contains(&1) {
+            return Ok(Err(not_supported("binary parameters are not supported")));
+        }
+
+        if msg.result_formats.contains(&1) {
+            return Ok(Err(not_supported("binary results are not supported")));
+        }
+
+        let expected = statement.param_types().len();
+
+        if msg.params.len() != expected {
+            return Ok(Err(error(
+                "08P01",
+                format!(
+                    "bind message supplies {} parameters, but prepared statement \"{}\" requires {}",
+                    msg.params.len(),
+                    msg.statement,
+                    expected
+                ),
+            )));
+        }
+
+        if !msg.portal.is_empty() && self.portals.contains_key(&msg.portal) {
+            return Ok(Err(error(
+                "42P03",
+                format!("cursor \"{}\" already exists", msg.portal),
+            )));
+        }
+
+        let query = match statement.bind(&msg.params) {
+            Ok(query) => query,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        self.portals.insert(msg.portal, Portal::new(query));
+
+        sink.push(BackendMessage::BindComplete(BindComplete {}))
+            .map(Ok)
+    }
+
+    fn describe_target(
+        &mut self,
+        msg: Describe,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        let description = match msg.target {
+            Target::Statement => {
+                let (query, param_types) = match self.statements.get(&msg.name) {
+                    Some(statement) => (statement.query.clone(), statement.param_types()),
+                    None => {
+                        return Ok(Err(error(
+                            "26000",
+                            format!("prepared statement \"{}\" does not exist", msg.name),
+                        )))
+                    }
+                };
+
+                sink.push(BackendMessage::ParameterDescription(
+                    ParameterDescription::new(param_types.clone()),
+                ))?;
+
+                // The executor is asked with NULL parameters, the statement isn't executed
+                let statement = Statement {
+                    query,
+                    param_types: vec![],
+                };
+
+                match statement.bind(&vec![None; param_types.len()]) {
+                    Ok(query) => self.inner.describe(&query)?,
+                    Err(_) => None,
+                }
+            }
+            Target::Portal => {
+                if let Err(e) = self.prepare(&msg.name, sink)? {
+                    return Ok(Err(e));
+                }
+
+                self.portals
+                    .get(&msg.name)
+                    .and_then(|portal| portal.result.as_ref())
+                    .and_then(|result| result.description.clone())
+            }
+        };
+
+        sink.push(match description {
+            Some(description) => BackendMessage::RowDescription(description),
+            None => BackendMessage::NoData(NoData {}),
+        })
+        .map(Ok)
+    }
+
+    fn execute_portal(
+        &mut self,
+        msg: Execute,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        let begin = self
+            .portals
+            .get(&msg.portal)
+            .is_some_and(|portal| portal.result.is_none() && is_begin(&portal.query));
+
+        // BEGIN turns the implicit transaction into a transaction block
+        if self.implicit && begin {
+            self.implicit = false;
+
+            if let Some(portal) = self.portals.get_mut(&msg.portal) {
+                portal.result = Some(Buffered {
+                    description: None,
+                    rows: vec![],
+                    tag: Some(CommandTag::Other("BEGIN".to_string())),
+                });
+            }
+        }
+
+        if let Err(e) = self.prepare(&msg.portal, sink)? {
+            return Ok(Err(e));
+        }
+
+        let portal = match self.portals.get_mut(&msg.portal) {
+            Some(portal) => portal,
+            None => return Ok(Err(no_portal(&msg.portal))),
+        };
+
+        let max_rows = (msg.max_rows > 0).then_some(msg.max_rows as usize);
+        let rows = match portal.seek(Direction::Forward(max_rows)) {
+            Ok(rows) => rows,
+            Err(e) => return Ok(Err(e)),
+        };
+        let sent = rows.len();
+
+        for row in rows {
+            sink.push(BackendMessage::DataRow(row))?;
+        }
+
+        if max_rows == Some(sent) {
+            return sink
+                .push(BackendMessage::PortalSuspended(PortalSuspended {}))
+                .map(Ok);
+        }
+
+        let tag = portal.result.as_ref().and_then(|result| result.tag.clone());
+
+        sink.push(match tag {
+            Some(CommandTag::Select(_)) => BackendMessage::CommandComplete(CommandComplete::new(
+                CommandTag::Select(sent as i32),
+            )),
+            Some(tag) => BackendMessage::CommandComplete(CommandComplete::new(tag)),
+            None => BackendMessage::EmptyQueryResponse(EmptyQueryResponse {}),
+        })
+        .map(Ok)
+    }
+
+    fn execute_cursor_statement(
+        &mut self,
+        statement: CursorStatement,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<CommandTag, ErrorResponse>> {
+        match statement {
+            CursorStatement::Declare {
+                name,
+                query,
+                hold,
+                scroll,
+            } => {
+                if self.portals.contains_key(&name) {
+                    return Ok(Err(error(
+                        "42P03",
+                        format!("cursor \"{}\" already exists", name),
+                    )));
+                }
+
+                if !hold && self.transaction_status == TransactionStatus::Idle {
+                    return Ok(Err(error(
+                        "25P01",
+                        "DECLARE CURSOR can only be used in transaction blocks".to_string(),
+                    )));
+                }
+
+                if classify(&query) != [StatementKind::Read] {
+                    return Ok(Err(error(
+                        "42P11",
+                        "cannot open a query which doesn't return rows as cursor".to_string(),
+                    )));
+                }
+
+                let portal = Portal {
+                    hold,
+                    scroll,
+                    ..Portal::new(query)
+                };
+                self.portals.insert(name.clone(), portal);
+
+                if let Err(e) = self.prepare(&name, sink)? {
+                    return Ok(Err(e));
+                }
+
+                Ok(Ok(CommandTag::Other("DECLARE CURSOR".to_string())))
+            }
+            CursorStatement::Fetch {
+                name,
+                direction,
+                fetch,
+            } => {
+                let portal = match self.portals.get_mut(&name) {
+                    Some(portal) => portal,
+                    None => {
+                        return Ok(Err(error(
+                            "34000",
+                            format!("cursor \"{}\" does not exist", name),
+                        )))
+                    }
+                };
+
+                let rows = match portal.seek(direction) {
+                    Ok(rows) => rows,
+                    Err(e) => return Ok(Err(e)),
+                };
+                let count = rows.len() as i32;
+
+                if !fetch {
+                    return Ok(Ok(CommandTag::Move(count)));
+                }
+
+                let description = portal
+                    .result
+                    .as_ref()
+                    .and_then(|result| result.description.clone());
+
+                if let Some(description) = description {
+                    sink.push(BackendMessage::RowDescription(description))?;
+                }
+
+                for row in rows {
+                    sink.push(BackendMessage::DataRow(row))?;
+                }
+
+                Ok(Ok(CommandTag::Fetch(count)))
+            }
+            CursorStatement::Close(Some(name)) => {
+                if self.portals.remove(&name).is_none() {
+                    return Ok(Err(error(
+                        "34000",
+                        format!("cursor \"{}\" does not exist", name),
+                    )));
+                }
+
+                Ok(Ok(CommandTag::Other("CLOSE CURSOR".to_string())))
+            }
+            CursorStatement::Close(None) => {
+                // The unnamed portal isn't a cursor
+                self.portals.retain(|name, _| name.is_empty());
+
+                Ok(Ok(CommandTag::Other("CLOSE CURSOR".to_string())))
+            }
+        }
+    }
+
+    // Runs cursor statements, `run` passes everything else to the executor
+    fn cursor_or(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+        run: impl FnOnce(&mut Q, &mut dyn ResultSink) -> io::Result<TransactionStatus>,
+    ) -> io::Result<TransactionStatus> {
+        match parse_cursor_statement(query) {
+            Some(Ok(statement)) => {
+                let msg = match self.execute_cursor_statement(statement, sink)? {
+                    Ok(tag) => BackendMessage::CommandComplete(CommandComplete::new(tag)),
+                    Err(e) => BackendMessage::ErrorResponse(e),
+                };
+
+                sink.push(msg)?;
+            }
+            Some(Err(e)) => sink.push(BackendMessage::ErrorResponse(e))?,
+            None => self.transaction_status = run(&mut self.inner, sink)?,
+        }
+
+        // A simple query ends the implicit transaction it ran in
+        self.end_implicit(sink)?;
+        self.end_transaction();
+
+        Ok(self.transaction_status)
+    }
+}
+
+impl<Q: QueryExec> QueryExec for PortalQueryExec<Q> {
+    fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        self.inner.startup(state)
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult {
+        self.inner.execute(query)
+    }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.cursor_or(query, sink, |inner, sink| inner.execute_to(query, sink))
+    }
+
+    #[cfg(feature = "sql-parser")]
+    fn execute_parsed(
+        &mut self,
+        query: &str,
+        parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.cursor_or(query, sink, |inner, sink| {
+            inner.execute_parsed(query, parsed, sink)
+        })
+    }
+
+    fn extended(
+        &mut self,
+        msg: ExtendedMessage,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        if matches!(
+            msg,
+            ExtendedMessage::Describe(_) | ExtendedMessage::Execute(_)
+        ) {
+            self.begin_implicit()?;
+        }
+
+        let result = match msg {
+            ExtendedMessage::Parse(msg) => self.parse(msg, sink),
+            ExtendedMessage::Bind(msg) => self.bind(msg, sink),
+            ExtendedMessage::Describe(msg) => self.describe_target(msg, sink),
+            ExtendedMessage::Execute(msg) => self.execute_portal(msg, sink),
+            ExtendedMessage::Close(msg) => {
+                match msg.target {
+                    Target::Statement => self.statements.remove(&msg.name).is_some(),
+                    Target::Portal => self.portals.remove(&msg.name).is_some(),
+                };
+
+                sink.push(BackendMessage::CloseComplete(CloseComplete {}))
+                    .map(Ok)
+            }
+        }?;
+
+        if result.is_err() && self.implicit {
+            self.aborted = true;
+        }
+
+        Ok(result)
+    }
+
+    fn flush(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        self.inner.flush(sink)
+    }
+
+    // The status the executor reports for Sync is ignored, as the transaction is tracked here
+    fn sync(&mut self, sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        self.end_implicit(sink)?;
+        self.inner.sync(sink)?;
+        self.end_transaction();
+
+        Ok(self.transaction_status)
+    }
+
+    fn set(&mut self, statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        self.inner.set(statement)
+    }
+
+    fn function_call(
+        &mut self,
+        call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.end_implicit(sink)?;
+        self.transaction_status = self.inner.function_call(call, sink)?;
+        self.end_transaction();
+
+        Ok(self.transaction_status)
+    }
+
+    fn copy_both(&mut self, query: &str) -> io::Result<Option<Box<dyn CopyBoth>>> {
+        self.inner.copy_both(query)
+    }
+
+    fn describe(&mut self, query: &str) -> io::Result<Option<RowDescription>> {
+        self.inner.describe(query)
+    }
+
+    fn stream(&mut self, query: &str) -> io::Result<Option<RowStream>> {
+        match parse_cursor_statement(query) {
+            Some(_) => Ok(None),
+            None => self.inner.stream(query),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::response::field;
+    use crate::backend::Type;
+
+    // Returns the numbers 1 to 5 for any SELECT, fails for `fail` and keeps track of transaction
+    // blocks
+    struct Series {
+        status: TransactionStatus,
+        executed: Vec<String>,
+    }
+
+    impl Series {
+        fn new() -> PortalQueryExec<Self> {
+            PortalQueryExec::new(Self {
+                status: TransactionStatus::Idle,
+                executed: vec![],
+            })
+        }
+    }
+
+    impl QueryExec for Series {
+        fn execute(&mut self, _query: &str) -> QueryResult {
+            unreachable!()
+        }
+
+        fn execute_to(
+            &mut self,
+            query: &str,
+            sink: &mut dyn ResultSink,
+        ) -> io::Result<TransactionStatus> {
+            self.executed.push(query.to_string());
+
+            let tag = match query.to_lowercase().split_whitespace().next() {
+                Some("begin") => {
+                    self.status = TransactionStatus::InTransaction;
+                    CommandTag::Other("BEGIN".to_string())
+                }
+                Some("commit") | Some("rollback") => {
+                    self.status = TransactionStatus::Idle;
+                    CommandTag::Other(query.to_uppercase())
+                }
+                Some("fail") => {
+                    if self.status != TransactionStatus::Idle {
+                        self.status = TransactionStatus::Failed;
+                    }
+
+                    sink.push(BackendMessage::ErrorResponse(error(
+                        "42000",
+                        "failed".to_string(),
+                    )))?;
+
+                    return Ok(self.status);
+                }
+                _ => {
+                    sink.push(BackendMessage::RowDescription(RowDescription::new(vec![
+                        field("n", Type::INT4),
+                    ])))?;
+
+                    for n in 1..=5 {
+                        sink.push(BackendMessage::DataRow(DataRow::new(vec![Some(
+                            n.to_string().into_bytes(),
+                        )])))?;
+                    }
+
+                    CommandTag::Select(5)
+                }
+            };
+
+            sink.push(BackendMessage::CommandComplete(CommandComplete::new(tag)))?;
+
+            Ok(self.status)
+        }
+
+        fn sync(&mut self, _sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+            Ok(self.status)
+        }
+
+        fn describe(&mut self, query: &str) -> io::Result<Option<RowDescription>> {
+            Ok(query
+                .starts_with("select")
+                .then(|| RowDescription::new(vec![field("n", Type::INT4)])))
+        }
+    }
+
+    // Summarizes the messages as the values of the rows followed by the command tag or error code
+    fn summary(messages: Vec<BackendMessage>) -> Vec<String> {
+        messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                BackendMessage::DataRow(row) => {
+                    Some(String::from_utf8(row.values[0].clone().unwrap()).unwrap())
+                }
+                BackendMessage::CommandComplete(cc) => Some(format!("{:?}", cc.command_tag)),
+                BackendMessage::PortalSuspended(_) => Some("suspended".to_string()),
+                BackendMessage::ErrorResponse(e) => {
+                    Some(e.get_field(Field::Code).unwrap().to_string())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn query(query_exec: &mut PortalQueryExec<Series>, query: &str) -> Vec<String> {
+        let mut messages = vec![];
+        query_exec.execute_to(query, &mut messages).unwrap();
+
+        summary(messages)
+    }
+
+    fn extended(query_exec: &mut PortalQueryExec<Series>, msg: ExtendedMessage) -> Vec<String> {
+        let mut messages = vec![];
+
+        if let Err(e) = query_exec.extended(msg, &mut messages).unwrap() {
+            messages.push(BackendMessage::ErrorResponse(e));
+        }
+
+        summary(messages)
+    }
+
+    fn execute(portal: &str, max_rows: i32) -> ExtendedMessage {
+        ExtendedMessage::Execute(Execute {
+            len: 0,
+            portal: portal.to_string(),
+            max_rows,
+        })
+    }
+
+    #[test]
+    fn test_execute_max_rows() {
+        let mut query_exec = Series::new();
+
+        extended(
+            &mut query_exec,
+            ExtendedMessage::Parse(Parse {
+                len: 0,
+                name: "s".to_string(),
+                query: "select n from series where n > $1".to_string(),
+                param_types: vec![],
+            }),
+        );
+        assert_eq!(
+            extended(
+                &mut query_exec,
+                ExtendedMessage::Bind(Bind {
+                    len: 0,
+                    portal: "p".to_string(),
+                    statement: "s".to_string(),
+                    param_formats: vec![],
+                    params: vec![],
+                    result_formats: vec![],
+                })
+            ),
+            vec!["08P01"]
+        );
+
+        let bind = |portal: &str| {
+            ExtendedMessage::Bind(Bind {
+                len: 0,
+                portal: portal.to_string(),
+                statement: "s".to_string(),
+                param_formats: vec![],
+                params: vec![Some(b"0".to_vec())],
+                result_formats: vec![],
+            })
+        };
+        extended(&mut query_exec, bind("p"));
+        assert_eq!(
+            query_exec.portals["p"].query,
+            "select n from series where n > '0'"
+        );
+
+        // Describing the statement doesn't execute it
+        let mut messages = vec![];
+        query_exec
+            .extended(
+                ExtendedMessage::Describe(Describe {
+                    len: 0,
+                    target: Target::Statement,
+                    name: "s".to_string(),
+                }),
+                &mut messages,
+            )
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            &messages[..],
+            [
+                BackendMessage::ParameterDescription(_),
+                BackendMessage::RowDescription(description)
+            ] if description.fields[0].name == "n"
+        ));
+        assert_eq!(query_exec.inner.executed, vec!["BEGIN"]);
+
+        assert_eq!(
+            extended(&mut query_exec, execute("p", 2)),
+            vec!["1", "2", "suspended"]
+        );
+        assert_eq!(
+            extended(&mut query_exec, execute("p", 2)),
+            vec!["3", "4", "suspended"]
+        );
+        assert_eq!(
+            extended(&mut query_exec, execute("p", 2)),
+            vec!["5", "Select(1)"]
+        );
+        assert_eq!(
+            extended(&mut query_exec, execute("p", 0)),
+            vec!["Select(0)"]
+        );
+
+        // Portals end with the transaction
+        query_exec.sync(&mut vec![]).unwrap();
+        assert_eq!(extended(&mut query_exec, execute("p", 0)), vec!["34000"]);
+        query_exec.sync(&mut vec![]).unwrap();
+
+        query(&mut query_exec, "BEGIN");
+        extended(&mut query_exec, bind("p"));
+        query_exec.sync(&mut vec![]).unwrap();
+        assert_eq!(
+            extended(&mut query_exec, execute("p", 1)),
+            vec!["1", "suspended"]
+        );
+        query(&mut query_exec, "COMMIT");
+        assert_eq!(extended(&mut query_exec, execute("p", 1)), vec!["34000"]);
+    }
+
+    #[test]
+    fn test_cursors() {
+        let mut query_exec = Series::new();
+
+        assert_eq!(
+            query(&mut query_exec, "DECLARE c CURSOR FOR SELECT n FROM series"),
+            vec!["25P01"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "declare c cursor with hold for begin"),
+            vec!["42P11"]
+        );
+        assert_eq!(
+            query(
+                &mut query_exec,
+                "DECLARE c SCROLL CURSOR WITH HOLD FOR SELECT n FROM series"
+            ),
+            vec!["Other(\"DECLARE CURSOR\")"]
+        );
+
+        assert_eq!(
+            query(&mut query_exec, "FETCH 2 FROM c"),
+            vec!["1", "2", "Fetch(2)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "fetch prior c;"),
+            vec!["1", "Fetch(1)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "MOVE FORWARD ALL IN c"),
+            vec!["Move(4)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "FETCH NEXT FROM c"),
+            vec!["Fetch(0)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "FETCH BACKWARD 2 FROM c"),
+            vec!["5", "4", "Fetch(2)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "FETCH ABSOLUTE -1 FROM c"),
+            vec!["5", "Fetch(1)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "FETCH RELATIVE -4 FROM c"),
+            vec!["1", "Fetch(1)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "MOVE BACKWARD ALL IN c"),
+            vec!["Move(0)"]
+        );
+        assert_eq!(
+            query(&mut query_exec, "FETCH FIRST FROM c"),
+            vec!["1", "Fetch(1)"]
+        );
+
+        assert_eq!(
+            query(&mut query_exec, "CLOSE c"),
+            vec!["Other(\"CLOSE CURSOR\")"]
+        );
+        assert_eq!(query(&mut query_exec, "FETCH c"), vec!["34000"]);
+        // Unterminated names are left to the executor to reject
+        assert_eq!(
+            query(&mut query_exec, "fetch 1 from \"").pop().unwrap(),
+            "Select(5)"
+        );
+
+        query(&mut query_exec, "BEGIN");
+        query(
+            &mut query_exec,
+            "DECLARE c NO SCROLL CURSOR FOR SELECT n FROM series",
+        );
+        assert_eq!(
+            query(&mut query_exec, "FETCH ALL c"),
+            vec!["1", "2", "3", "4", "5", "Fetch(5)"]
+        );
+        assert_eq!(query(&mut query_exec, "FETCH PRIOR c"), vec!["55000"]);
+        query(&mut query_exec, "COMMIT");
+        assert_eq!(query(&mut query_exec, "FETCH c"), vec!["34000"]);
+    }
+
+    #[test]
+    fn test_implicit_transaction() {
+        let mut query_exec = Series::new();
+        let pipeline = |query_exec: &mut PortalQueryExec<Series>, queries: &[&str]| {
+            let mut results = vec![];
+
+            for query in queries {
+                extended(
+                    query_exec,
+                    ExtendedMessage::Parse(Parse {
+                        len: 0,
+                        name: String::new(),
+                        query: query.to_string(),
+                        param_types: vec![],
+                    }),
+                );
+                extended(
+                    query_exec,
+                    ExtendedMessage::Bind(Bind {
+                        len: 0,
+                        portal: String::new(),
+                        statement: String::new(),
+                        param_formats: vec![],
+                        params: vec![],
+                        result_formats: vec![],
+                    }),
+                );
+                results.extend(extended(query_exec, execute("", 0)));
+            }
+
+            let status = query_exec.sync(&mut vec![]).unwrap();
+            let executed = std::mem::take(&mut query_exec.inner.executed);
+
+            (results, status, executed)
+        };
+
+        let (_, status, executed) = pipeline(&mut query_exec, &["select 1", "select 2"]);
+        assert_eq!(status, TransactionStatus::Idle);
+        assert_eq!(executed, vec!["BEGIN", "select 1", "select 2", "COMMIT"]);
+
+        // An error rolls back the whole pipeline
+        let (results, status, executed) = pipeline(&mut query_exec, &["select 1", "fail"]);
+        assert_eq!(results.last().unwrap(), "42000");
+        assert_eq!(status, TransactionStatus::Idle);
+        assert_eq!(executed, vec!["BEGIN", "select 1", "fail", "ROLLBACK"]);
+
+        // BEGIN turns it into a transaction block which continues after Sync
+        let (results, status, executed) = pipeline(&mut query_exec, &["begin", "select 1"]);
+        assert_eq!(results[0], "Other(\"BEGIN\")");
+        assert_eq!(status, TransactionStatus::InTransaction);
+        assert_eq!(executed, vec!["BEGIN", "select 1"]);
+
+        let (_, status, executed) = pipeline(&mut query_exec, &["commit", "select 1"]);
+        assert_eq!(status, TransactionStatus::Idle);
+        assert_eq!(executed, vec!["commit", "BEGIN", "select 1", "COMMIT"]);
+    }
+}
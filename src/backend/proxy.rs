@@ -0,0 +1,626 @@
+use std::cell::RefCell;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use secstr::SecStr;
+
+use crate::backend::auth::{AuthMethod, AuthResult};
+use crate::backend::query_exec::{QueryResult, ResultSink};
+use crate::backend::{Auth, QueryExec, SetStatement, State};
+use crate::frontend::{Client, ClientError, Config};
+use crate::proto::messages::{
+    BackendMessage, CopyFail, ErrorResponse, ExtendedMessage, Flush, FunctionCall, PasswordMessage,
+    Query, Severity, Sync, TransactionStatus,
+};
+
+// The upstream connection is opened during authentication and used by the executor afterwards
+type Upstream = Rc<RefCell<Option<Client>>>;
+
+pub(crate) fn connect_error(e: ClientError) -> ErrorResponse {
+    match e {
+        ClientError::Server(e) => e,
+        e => ErrorResponse::new(
+            Severity::Fatal,
+            "08001".to_string(),
+            format!("failed to connect to upstream: {}", e),
+        ),
+    }
+}
+
+// Receives the next response to relay. The client's CopyData isn't relayed, so COPY FROM STDIN
+// is failed right away instead of leaving the upstream waiting for it; the upstream answers with
+// an error.
+fn recv(client: &mut Client) -> io::Result<BackendMessage> {
+    loop {
+        match client.recv()? {
+            BackendMessage::CopyInResponse(_) => {
+                client.send(CopyFail::new(
+                    "COPY FROM STDIN is not supported by the proxy".to_string(),
+                ))?;
+                client.flush()?;
+            }
+            msg => return Ok(msg),
+        }
+    }
+}
+
+// Relays everything up to (but not including) ReadyForQuery
+pub(crate) fn relay(
+    client: &mut Client,
+    sink: &mut dyn ResultSink,
+) -> io::Result<TransactionStatus> {
+    loop {
+        match recv(client)? {
+            BackendMessage::ReadyForQuery(ready) => return Ok(ready.transaction_status),
+            msg => sink.push(msg)?,
+        }
+    }
+}
+
+// Counts the extended protocol messages sent upstream whose responses haven't been relayed yet,
+// as nothing marks the end of the responses to a Flush
+#[derive(Default)]
+pub(crate) struct Pending {
+    sent: usize,
+    completed: usize,
+    // The server skips everything up to the next Sync after an error
+    failed: bool,
+    // Messages the client didn't send, whose responses (other than errors) it mustn't see
+    hidden: Vec<usize>,
+}
+
+impl Pending {
+    pub(crate) fn sent(&mut self) {
+        self.sent += 1;
+    }
+
+    pub(crate) fn sent_hidden(&mut self) {
+        self.hidden.push(self.sent);
+        self.sent += 1;
+    }
+
+    pub(crate) fn failed(&self) -> bool {
+        self.failed
+    }
+
+    // Counts the responses, returns whether the message is relayed
+    fn receive(&mut self, msg: &BackendMessage) -> bool {
+        match msg {
+            BackendMessage::ErrorResponse(_) => {
+                self.failed = true;
+                true
+            }
+            // The last message of the response to each kind of message
+            BackendMessage::ParseComplete(_)
+            | BackendMessage::BindComplete(_)
+            | BackendMessage::CloseComplete(_)
+            | BackendMessage::RowDescription(_)
+            | BackendMessage::NoData(_)
+            | BackendMessage::CommandComplete(_)
+            | BackendMessage::EmptyQueryResponse(_)
+            | BackendMessage::PortalSuspended(_) => {
+                let hidden = self.hidden.contains(&self.completed);
+                self.completed += 1;
+                !hidden
+            }
+            _ => true,
+        }
+    }
+
+    // Forwards a Flush and relays the responses to the messages sent since the last one
+    pub(crate) fn flush(
+        &mut self,
+        client: &mut Client,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<()> {
+        client.send(Flush::new())?;
+        client.flush()?;
+
+        while self.completed < self.sent && !self.failed {
+            let msg = recv(client)?;
+
+            if self.receive(&msg) {
+                sink.push(msg)?;
+            }
+        }
+
+        self.completed = self.sent;
+        Ok(())
+    }
+
+    // Everything left is relayed up to ReadyForQuery once the Sync is forwarded
+    pub(crate) fn sync(&mut self) {
+        *self = Self::default();
+    }
+
+    // Relays the responses to a forwarded Sync, up to (but not including) ReadyForQuery
+    pub(crate) fn relay(
+        &mut self,
+        client: &mut Client,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        loop {
+            let msg = recv(client)?;
+
+            if let BackendMessage::ReadyForQuery(ready) = msg {
+                self.sync();
+                return Ok(ready.transaction_status);
+            }
+
+            if self.receive(&msg) {
+                sink.push(msg)?;
+            }
+        }
+    }
+}
+
+// Reduces relayed messages to the result of the last command
+pub(crate) fn command_result(
+    result: io::Result<TransactionStatus>,
+    messages: Vec<BackendMessage>,
+) -> QueryResult {
+    if let Err(e) = result {
+        return Err(ErrorResponse::new(
+            Severity::Error,
+            "08006".to_string(),
+            e.to_string(),
+        ));
+    }
+
+    let mut result = Err(not_connected());
+
+    for msg in messages {
+        match msg {
+            BackendMessage::CommandComplete(cc) => result = Ok(cc),
+            BackendMessage::ErrorResponse(e) => return Err(e),
+            _ => {}
+        }
+    }
+
+    result
+}
+
+// Applies a SET or RESET handled by the manager on the upstream server as well
+pub(crate) fn forward_set(
+    client: &mut Client,
+    statement: &SetStatement,
+) -> io::Result<Result<(), ErrorResponse>> {
+    client.send(Query::new(statement.to_string()))?;
+    client.flush()?;
+
+    let mut messages = vec![];
+    let result = relay(client, &mut messages);
+
+    Ok(command_result(result, messages).map(|_| ()))
+}
+
+fn not_connected() -> ErrorResponse {
+    ErrorResponse::new(
+        Severity::Error,
+        "08003".to_string(),
+        "not connected to upstream".to_string(),
+    )
+}
+
+// Authenticates clients by logging in to the upstream server with the same credentials
+pub struct ProxyAuth {
+    addr: SocketAddr,
+    upstream: Upstream,
+}
+
+impl ProxyAuth {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            upstream: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    // Returns the executor which uses the upstream connection opened by this auth
+    pub fn query_exec(&self) -> ProxyQueryExec {
+        ProxyQueryExec {
+            upstream: self.upstream.clone(),
+            pending: Pending::default(),
+        }
+    }
+
+    fn connect(&self, state: &State, password: Option<SecStr>) -> Result<(), ClientError> {
+        let mut config = Config::new(state.user().to_string());
+        config.database = Some(state.database().to_string());
+        config.password = password;
+        config.params = state
+            .extra_params()
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        let client = Client::connect(self.addr, config)?;
+        self.upstream.replace(Some(client));
+
+        Ok(())
+    }
+}
+
+impl Auth for ProxyAuth {
+    // We only ask the client for a password when the upstream server wants one
+    fn method(&self, state: &State) -> AuthMethod {
+        match self.connect(state, None) {
+            Ok(_) => AuthMethod::None,
+            Err(ClientError::PasswordRequired) => AuthMethod::CleartextPassword,
+            Err(e) => AuthMethod::Reject(connect_error(e)),
+        }
+    }
+
+    fn clear_text_password(&self, state: &State, password: PasswordMessage) -> AuthResult {
+        self.connect(state, Some(password.password))
+            .map_err(connect_error)
+    }
+}
+
+// Forwards queries to the upstream server and relays its responses verbatim
+pub struct ProxyQueryExec {
+    upstream: Upstream,
+    pending: Pending,
+}
+
+impl QueryExec for ProxyQueryExec {
+    fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        if let Some(client) = self.upstream.borrow().as_ref() {
+            state.settings().seed(client.parameters());
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult {
+        let mut messages = vec![];
+        let result = self.execute_to(query, &mut messages);
+
+        command_result(result, messages)
+    }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let mut upstream = self.upstream.borrow_mut();
+        let client = match upstream.as_mut() {
+            Some(client) => client,
+            None => {
+                sink.push(BackendMessage::ErrorResponse(not_connected()))?;
+                return Ok(TransactionStatus::Idle);
+            }
+        };
+
+        client.send(Query::new(query.to_string()))?;
+        client.flush()?;
+
+        relay(client, sink)
+    }
+
+    // Messages are only buffered, the upstream server processes them (and skips everything after
+    // an error) once we forward the Sync
+    fn extended(
+        &mut self,
+        msg: ExtendedMessage,
+        _sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        match self.upstream.borrow_mut().as_mut() {
+            Some(client) => {
+                self.pending.sent();
+                client.send(msg).map(Ok)
+            }
+            None => Ok(Err(not_connected())),
+        }
+    }
+
+    fn flush(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        match self.upstream.borrow_mut().as_mut() {
+            Some(client) => self.pending.flush(client, sink),
+            None => Ok(()),
+        }
+    }
+
+    fn sync(&mut self, sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        let mut upstream = self.upstream.borrow_mut();
+        let client = match upstream.as_mut() {
+            Some(client) => client,
+            None => return Ok(TransactionStatus::Idle),
+        };
+
+        self.pending.sync();
+        client.send(Sync::new())?;
+        client.flush()?;
+
+        relay(client, sink)
+    }
+
+    fn function_call(
+        &mut self,
+        call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let mut upstream = self.upstream.borrow_mut();
+        let client = match upstream.as_mut() {
+            Some(client) => client,
+            None => {
+                sink.push(BackendMessage::ErrorResponse(not_connected()))?;
+                return Ok(TransactionStatus::Idle);
+            }
+        };
+
+        client.send(call)?;
+        client.flush()?;
+
+        relay(client, sink)
+    }
+
+    fn set(&mut self, statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        match self.upstream.borrow_mut().as_mut() {
+            Some(client) => forward_set(client, statement),
+            None => Ok(Err(not_connected())),
+        }
+    }
+}
+
+impl Drop for ProxyQueryExec {
+    fn drop(&mut self) {
+        if let Some(client) = self.upstream.take() {
+            if let Err(e) = client.close() {
+                log::warn!("failed to close upstream connection: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::backend::{Conn, Manager, NoopAuth, NoopQueryExec};
+    use crate::proto::messages::{
+        AuthenticationOk, CommandComplete, CommandTag, CopyInResponse, Execute, Field, Parse,
+        ReadyForQuery,
+    };
+    use crate::proto::{Encode, Reader, Writer};
+
+    #[test]
+    fn test_proxy_to_upstream() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            Manager::new(
+                Conn::new(stream).unwrap(),
+                NoopAuth::new(),
+                NoopQueryExec::new(),
+            )
+            .unwrap()
+            .handle()
+        });
+        let server = thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            let auth = ProxyAuth::new(upstream_addr);
+            let query_exec = auth.query_exec();
+
+            Manager::new(Conn::new(stream).unwrap(), auth, query_exec)
+                .unwrap()
+                .handle()
+        });
+
+        let mut client = Client::connect(proxy_addr, Config::new("bob".to_string())).unwrap();
+        let response = client.simple_query("select 1").unwrap();
+        assert_eq!(
+            response.command_tags().collect::<Vec<_>>(),
+            vec![&CommandTag::Select(0)]
+        );
+
+        // The upstream doesn't support the extended protocol, its error should be relayed
+        client
+            .send(Parse {
+                len: 0,
+                name: String::new(),
+                query: "select 1".to_string(),
+                param_types: vec![],
+            })
+            .unwrap();
+        client
+            .send(Execute {
+                len: 0,
+                portal: String::new(),
+                max_rows: 0,
+            })
+            .unwrap();
+        client.send(Sync::new()).unwrap();
+        client.flush().unwrap();
+
+        match client.recv().unwrap() {
+            BackendMessage::ErrorResponse(e) => assert_eq!(e.get_field(Field::Code), Some("0A000")),
+            _ => panic!("expected an error response"),
+        }
+        assert!(matches!(
+            client.recv().unwrap(),
+            BackendMessage::ReadyForQuery(_)
+        ));
+
+        // Responses are relayed on Flush without waiting for Sync
+        client
+            .send(Parse {
+                len: 0,
+                name: String::new(),
+                query: "select 1".to_string(),
+                param_types: vec![],
+            })
+            .unwrap();
+        client.send(Flush::new()).unwrap();
+        client.flush().unwrap();
+
+        match client.recv().unwrap() {
+            BackendMessage::ErrorResponse(e) => assert_eq!(e.get_field(Field::Code), Some("0A000")),
+            _ => panic!("expected an error response"),
+        }
+
+        client.send(Sync::new()).unwrap();
+        client.flush().unwrap();
+        assert!(matches!(
+            client.recv().unwrap(),
+            BackendMessage::ReadyForQuery(_)
+        ));
+
+        // Function calls are relayed as well
+        client
+            .send(FunctionCall::new(954, vec![Some(b"1".to_vec())]))
+            .unwrap();
+        client.flush().unwrap();
+
+        match client.recv().unwrap() {
+            BackendMessage::ErrorResponse(e) => assert_eq!(e.get_field(Field::Code), Some("0A000")),
+            _ => panic!("expected an error response"),
+        }
+        assert!(matches!(
+            client.recv().unwrap(),
+            BackendMessage::ReadyForQuery(_)
+        ));
+
+        client.close().unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    // Knows every setting, like PostgreSQL it reports some of them
+    struct Server;
+
+    impl QueryExec for Server {
+        fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+            state.settings().seed([("server_version", "16.2")]);
+            Ok(Ok(()))
+        }
+
+        fn execute(&mut self, _query: &str) -> QueryResult {
+            Ok(CommandComplete::new(CommandTag::Select(0)))
+        }
+    }
+
+    #[test]
+    fn test_unknown_options() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            Manager::new(Conn::new(stream).unwrap(), NoopAuth::new(), Server)
+                .unwrap()
+                .handle()
+        });
+        thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            let auth = ProxyAuth::new(upstream_addr);
+            let query_exec = auth.query_exec();
+
+            Manager::new(Conn::new(stream).unwrap(), auth, query_exec)
+                .unwrap()
+                .handle()
+        });
+
+        // Settings the proxy doesn't know are left to the upstream server
+        let mut config = Config::new("bob".to_string());
+        config.params = vec![("options".to_string(), "-c jit=off".to_string())];
+        let mut client = Client::connect(proxy_addr, config).unwrap();
+        assert!(client.simple_query("select 1").unwrap().error().is_none());
+        client.close().unwrap();
+
+        // Without a server behind it they're refused
+        let noop = TcpListener::bind("127.0.0.1:0").unwrap();
+        let noop_addr = noop.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (stream, _) = noop.accept().unwrap();
+            Manager::new(
+                Conn::new(stream).unwrap(),
+                NoopAuth::new(),
+                NoopQueryExec::new(),
+            )
+            .unwrap()
+            .handle()
+        });
+
+        let mut config = Config::new("bob".to_string());
+        config.params = vec![("options".to_string(), "-c jit=off".to_string())];
+        match Client::connect(noop_addr, config) {
+            Err(ClientError::Server(e)) => assert_eq!(e.get_field(Field::Code), Some("42704")),
+            _ => panic!("expected the connection to be refused"),
+        }
+    }
+
+    #[test]
+    fn test_copy_from_stdin() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+
+        // Asks for the data of the first query, the way PostgreSQL answers COPY FROM STDIN
+        thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            let mut reader = Reader::new(stream.try_clone().unwrap());
+            let mut writer = Writer::new(stream);
+
+            let len = reader.read_i32().unwrap();
+            reader.skip(len as u64 - 4).unwrap();
+            AuthenticationOk {}.encode(&mut writer).unwrap();
+            ReadyForQuery::new(TransactionStatus::Idle)
+                .encode(&mut writer)
+                .unwrap();
+            writer.flush().unwrap();
+
+            let mut recv = || {
+                let tag = reader.read_byte().unwrap();
+                let len = reader.read_i32().unwrap();
+                reader.skip(len as u64 - 4).unwrap();
+                tag
+            };
+
+            assert_eq!(recv(), b'Q');
+            CopyInResponse::new(0, vec![]).encode(&mut writer).unwrap();
+            writer.flush().unwrap();
+
+            assert_eq!(recv(), b'f');
+            ErrorResponse::new(
+                Severity::Error,
+                "57014".to_string(),
+                "COPY from stdin failed".to_string(),
+            )
+            .encode(&mut writer)
+            .unwrap();
+            ReadyForQuery::new(TransactionStatus::Idle)
+                .encode(&mut writer)
+                .unwrap();
+            writer.flush().unwrap();
+        });
+        thread::spawn(move || {
+            let (stream, _) = proxy.accept().unwrap();
+            let auth = ProxyAuth::new(upstream_addr);
+            let query_exec = auth.query_exec();
+
+            Manager::new(Conn::new(stream).unwrap(), auth, query_exec)
+                .unwrap()
+                .handle()
+        });
+
+        // The copy is failed instead of waiting for data the client can't send
+        let mut client = Client::connect(proxy_addr, Config::new("bob".to_string())).unwrap();
+        let response = client.simple_query("copy t from stdin").unwrap();
+        assert_eq!(
+            response.error().and_then(|e| e.get_field(Field::Code)),
+            Some("57014")
+        );
+    }
+}
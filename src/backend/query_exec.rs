@@ -1,23 +1,192 @@
-use crate::proto::messages::{CommandComplete, CommandTag, ErrorResponse};
+use std::io;
+
+#[cfg(feature = "sql-parser")]
+use crate::backend::ParseResult;
+use crate::backend::{classify, CopyBoth, RowStream, SetStatement, State, StatementKind};
+use crate::proto::messages::{
+    BackendMessage, CommandComplete, CommandTag, ErrorResponse, ExtendedMessage, FunctionCall,
+    RowDescription, Severity, TransactionStatus,
+};
 
 pub type QueryResult = Result<CommandComplete, ErrorResponse>;
 
+// Receives the messages an executor produces before they are sent to the client
+pub trait ResultSink {
+    fn push(&mut self, msg: BackendMessage) -> io::Result<()>;
+}
+
+impl ResultSink for Vec<BackendMessage> {
+    fn push(&mut self, msg: BackendMessage) -> io::Result<()> {
+        Vec::push(self, msg);
+        Ok(())
+    }
+}
+
 pub trait QueryExec {
-    fn execute(&self, query: &str) -> QueryResult;
+    // Called once the client is authenticated, before the first query. Returning an error refuses
+    // the connection.
+    fn startup(&mut self, _state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        Ok(Ok(()))
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult;
+
+    // Executors which produce rows (or relay results from elsewhere) can override this to send
+    // any number of messages, the returned status is reported to the client in ReadyForQuery
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        sink.push(match self.execute(query) {
+            Ok(command_complete) => BackendMessage::CommandComplete(command_complete),
+            Err(e) => BackendMessage::ErrorResponse(e),
+        })?;
+
+        Ok(TransactionStatus::Idle)
+    }
+
+    // Receives the parsed statements next to the original text. By default the parse result is
+    // ignored, as the executor might support syntax the parser doesn't. Wrappers pass it on to the
+    // executor they wrap, executors relaying the text to a server keep the default.
+    #[cfg(feature = "sql-parser")]
+    fn execute_parsed(
+        &mut self,
+        query: &str,
+        _parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        self.execute_to(query, sink)
+    }
+
+    // Called for every message of the extended query protocol, once an error is returned all
+    // following messages are skipped until the next Sync
+    fn extended(
+        &mut self,
+        _msg: ExtendedMessage,
+        _sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        Ok(Err(ErrorResponse::new(
+            Severity::Error,
+            "0A000".to_string(),
+            "extended query protocol not supported".to_string(),
+        )))
+    }
+
+    // Receives Parse messages along with the parsed statements, all other extended query protocol
+    // messages only go to `extended`
+    #[cfg(feature = "sql-parser")]
+    fn extended_parsed(
+        &mut self,
+        msg: ExtendedMessage,
+        _parsed: ParseResult,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        self.extended(msg, sink)
+    }
+
+    // Called when the client sends Flush, executors which buffer extended protocol messages for a
+    // server have to send the responses to the messages received so far
+    fn flush(&mut self, _sink: &mut dyn ResultSink) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Called when the client sends Sync, the returned status is reported in ReadyForQuery
+    fn sync(&mut self, _sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        Ok(TransactionStatus::Idle)
+    }
+
+    // Called for the legacy FunctionCall message, the executor should send a FunctionCallResponse
+    // (or an error) and return the status reported in ReadyForQuery
+    fn function_call(
+        &mut self,
+        _call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        sink.push(BackendMessage::ErrorResponse(ErrorResponse::new(
+            Severity::Error,
+            "0A000".to_string(),
+            "function calls not supported".to_string(),
+        )))?;
+
+        Ok(TransactionStatus::Idle)
+    }
+
+    // Called before a SET or RESET handled by the manager changes the session settings, returning
+    // an error rejects the change. Executors backed by a server can forward the statement.
+    fn set(&mut self, _statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        Ok(Ok(()))
+    }
+
+    // Returns a handler when the query switches to copy-both mode, the manager drives it until
+    // either side ends the mode
+    fn copy_both(&mut self, _query: &str) -> io::Result<Option<Box<dyn CopyBoth>>> {
+        Ok(None)
+    }
+
+    // Returns the columns a query would return without executing it, for describing prepared
+    // statements. None when they aren't known (or it doesn't return rows).
+    fn describe(&mut self, _query: &str) -> io::Result<Option<RowDescription>> {
+        Ok(None)
+    }
+
+    // Returns the rows of a query as a stream instead of executing it with `execute_to`, the
+    // manager sends them as the client reads them and stops when it disconnects or cancels
+    fn stream(&mut self, _query: &str) -> io::Result<Option<RowStream>> {
+        Ok(None)
+    }
 }
 
-pub struct NoopQueryExec {}
+// Doesn't run anything, but keeps track of transaction blocks so executors which scope state to
+// transactions behave as they would in front of a server
+pub struct NoopQueryExec {
+    transaction_status: TransactionStatus,
+}
+
+impl Default for NoopQueryExec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl NoopQueryExec {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            transaction_status: TransactionStatus::Idle,
+        }
     }
 }
 
 impl QueryExec for NoopQueryExec {
-    fn execute(&self, _query: &str) -> QueryResult {
+    fn execute(&mut self, _query: &str) -> QueryResult {
         Ok(CommandComplete {
             command_tag: CommandTag::Select(0),
         })
     }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        for kind in classify(query) {
+            match kind {
+                StatementKind::Begin { .. } => {
+                    self.transaction_status = TransactionStatus::InTransaction
+                }
+                StatementKind::TransactionEnd => self.transaction_status = TransactionStatus::Idle,
+                _ => {}
+            }
+        }
+
+        sink.push(BackendMessage::CommandComplete(CommandComplete::new(
+            CommandTag::Select(0),
+        )))?;
+
+        Ok(self.transaction_status)
+    }
+
+    fn sync(&mut self, _sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        Ok(self.transaction_status)
+    }
 }
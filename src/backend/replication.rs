@@ -0,0 +1,606 @@
+use std::io;
+use std::time::Duration;
+
+use crate::backend::copy_both::{CopyBoth, CopyOutput};
+use crate::backend::lexer::{tokenize, unquote, Token};
+use crate::backend::query_exec::ResultSink;
+use crate::backend::response::{field, not_supported, text};
+use crate::backend::{State, Type};
+use crate::proto::messages::{
+    BackendMessage, CommandComplete, CommandTag, CopyData, DataRow, ErrorResponse, Lsn,
+    PrimaryKeepalive, RowDescription, StandbyMessage, XLogData,
+};
+use crate::proto::{Decode, Reader};
+
+// A change produced by a logical decoding output plugin (or anything speaking its format)
+pub struct Change {
+    // The WAL position of the change, clients confirm it once they have processed it
+    pub lsn: Lsn,
+    pub data: Vec<u8>,
+}
+
+// Produces the changes streamed to logical replication clients, every replication connection
+// gets its own source
+pub trait ChangeSource {
+    // Reported by IDENTIFY_SYSTEM, clients use it to make sure they stream from the same system
+    fn system_id(&self) -> u64;
+
+    // The current end of the WAL
+    fn current_lsn(&mut self) -> Lsn;
+
+    // Returns the consistent point of the new slot, the position streaming starts from
+    fn create_slot(
+        &mut self,
+        name: &str,
+        plugin: &str,
+        temporary: bool,
+    ) -> Result<Lsn, ErrorResponse>;
+
+    fn drop_slot(&mut self, name: &str) -> Result<(), ErrorResponse>;
+
+    // Called by START_REPLICATION with the options for the output plugin, changes the client has
+    // already confirmed (before `start`) shouldn't be sent again
+    fn start(
+        &mut self,
+        slot: &str,
+        start: Lsn,
+        options: &[(String, Option<String>)],
+    ) -> Result<(), ErrorResponse>;
+
+    // Waits up to `timeout` for the next change
+    fn next(&mut self, timeout: Duration) -> Result<Option<Change>, ErrorResponse>;
+
+    // The client has durably processed everything up to `lsn`
+    fn confirm(&mut self, _lsn: Lsn) {}
+}
+
+// Answers the physical replication commands on connections with `replication=true`
+pub trait PhysicalSource {
+    // Reported by IDENTIFY_SYSTEM, clients use it to make sure they stream from the same system
+    fn system_id(&self) -> u64;
+
+    fn timeline(&self) -> u32 {
+        1
+    }
+
+    // The current end of the WAL
+    fn current_lsn(&mut self) -> Lsn;
+
+    // Returns the name and the content of the history file of the timeline
+    fn timeline_history(&mut self, timeline: u32) -> Result<(String, Vec<u8>), ErrorResponse>;
+
+    // Sends the whole result of BASE_BACKUP (the result sets, and the archives in copy-out mode)
+    // except the final CommandComplete
+    fn base_backup(
+        &mut self,
+        options: &[(String, Option<String>)],
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>>;
+}
+
+// The commands of the replication protocol. Connections with `replication=database` accept them
+// next to regular SQL, connections with `replication=true` only accept these.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplicationCommand {
+    IdentifySystem,
+    CreateSlot {
+        name: String,
+        temporary: bool,
+        // None for physical slots
+        plugin: Option<String>,
+    },
+    DropSlot(String),
+    StartReplication {
+        slot: String,
+        start: Lsn,
+        options: Vec<(String, Option<String>)>,
+    },
+    TimelineHistory(u32),
+    BaseBackup(Vec<(String, Option<String>)>),
+}
+
+fn identifier(token: Option<&Token>) -> Option<String> {
+    match token? {
+        Token::Word(word) => Some(word.to_lowercase()),
+        Token::QuotedIdent(ident) => unquote(ident),
+        _ => None,
+    }
+}
+
+fn keyword(token: Option<&Token>, keyword: &str) -> bool {
+    matches!(token, Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+}
+
+// LSNs aren't a single token (`0/16B3748` is a number, a slash and another number)
+fn lsn(tokens: &[Token]) -> Option<Lsn> {
+    let part = |token: &Token| match token {
+        Token::Word(part) | Token::Literal(part) => Some(part.to_string()),
+        _ => None,
+    };
+
+    match tokens {
+        [high, Token::Punct('/'), low, ..] => {
+            format!("{}/{}", part(high)?, part(low)?).parse().ok()
+        }
+        _ => None,
+    }
+}
+
+fn option_value(token: Option<&Token>) -> Option<String> {
+    match token? {
+        Token::Literal(value) if value.starts_with('\'') => unquote(value),
+        Token::Literal(value) | Token::Word(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// Parses `( name 'value' [, ...] )`, values are optional
+fn options(tokens: &[Token]) -> Option<Vec<(String, Option<String>)>> {
+    let mut options = vec![];
+
+    let mut rest = match tokens {
+        [] => return Some(options),
+        [Token::Punct('('), rest @ ..] => rest,
+        _ => return None,
+    };
+
+    loop {
+        let name = identifier(rest.first())?;
+
+        let (value, len) = match option_value(rest.get(1)) {
+            Some(value) => (Some(value), 2),
+            None => (None, 1),
+        };
+
+        options.push((name, value));
+
+        match &rest[len..] {
+            [Token::Punct(','), tail @ ..] => rest = tail,
+            [Token::Punct(')')] => return Some(options),
+            _ => return None,
+        }
+    }
+}
+
+// BASE_BACKUP before PostgreSQL 15 took its options without parentheses (`LABEL 'x' FAST`),
+// only literals can be values there
+fn legacy_options(mut tokens: &[Token]) -> Option<Vec<(String, Option<String>)>> {
+    let mut options = vec![];
+
+    while !tokens.is_empty() {
+        let name = match tokens.first() {
+            Some(Token::Word(word)) => word.to_lowercase(),
+            _ => return None,
+        };
+
+        let value = match tokens.get(1) {
+            Some(Token::Literal(_)) => option_value(tokens.get(1)),
+            _ => None,
+        };
+
+        tokens = &tokens[1 + value.is_some() as usize..];
+        options.push((name, value));
+    }
+
+    Some(options)
+}
+
+pub fn parse_command(query: &str) -> Option<ReplicationCommand> {
+    let tokens = tokenize(query)
+        .into_iter()
+        .filter(|token| !matches!(token, Token::Comment(_)))
+        .collect::<Vec<_>>();
+
+    let tokens = match tokens.split_last() {
+        Some((Token::Semicolon, tokens)) => tokens,
+        _ => &tokens[..],
+    };
+
+    let command = match tokens.first() {
+        Some(Token::Word(word)) => word.to_uppercase(),
+        _ => return None,
+    };
+
+    match command.as_str() {
+        "IDENTIFY_SYSTEM" if tokens.len() == 1 => Some(ReplicationCommand::IdentifySystem),
+        "CREATE_REPLICATION_SLOT" => {
+            let name = identifier(tokens.get(1))?;
+            let temporary = keyword(tokens.get(2), "temporary");
+            let rest = &tokens[2 + temporary as usize..];
+
+            // Snapshot options are accepted but snapshots aren't exported
+            let plugin = match rest.first() {
+                Some(_) if keyword(rest.first(), "logical") => Some(identifier(rest.get(1))?),
+                Some(_) if keyword(rest.first(), "physical") => None,
+                _ => return None,
+            };
+
+            Some(ReplicationCommand::CreateSlot {
+                name,
+                temporary,
+                plugin,
+            })
+        }
+        "DROP_REPLICATION_SLOT" => match tokens.len() {
+            2 => Some(ReplicationCommand::DropSlot(identifier(tokens.get(1))?)),
+            3 if keyword(tokens.get(2), "wait") => {
+                Some(ReplicationCommand::DropSlot(identifier(tokens.get(1))?))
+            }
+            _ => None,
+        },
+        "START_REPLICATION" => {
+            if !keyword(tokens.get(1), "slot") || !keyword(tokens.get(3), "logical") {
+                return None;
+            }
+
+            Some(ReplicationCommand::StartReplication {
+                slot: identifier(tokens.get(2))?,
+                start: lsn(&tokens[4..])?,
+                options: options(tokens.get(7..)?)?,
+            })
+        }
+        "TIMELINE_HISTORY" if tokens.len() == 2 => match tokens[1] {
+            Token::Literal(timeline) => timeline
+                .parse()
+                .ok()
+                .map(ReplicationCommand::TimelineHistory),
+            _ => None,
+        },
+        "BASE_BACKUP" => match tokens.get(1) {
+            Some(Token::Punct('(')) => options(&tokens[1..]).map(ReplicationCommand::BaseBackup),
+            _ => legacy_options(&tokens[1..]).map(ReplicationCommand::BaseBackup),
+        },
+        _ => None,
+    }
+}
+
+fn command_complete(tag: &str) -> BackendMessage {
+    BackendMessage::CommandComplete(CommandComplete::new(CommandTag::Other(tag.to_string())))
+}
+
+// Physical replication connections aren't bound to a database so they report a null dbname
+fn identify_system(
+    system_id: u64,
+    timeline: u32,
+    lsn: Lsn,
+    database: Option<&str>,
+) -> Vec<BackendMessage> {
+    vec![
+        BackendMessage::RowDescription(RowDescription::new(vec![
+            field("systemid", Type::TEXT),
+            field("timeline", Type::INT4),
+            field("xlogpos", Type::TEXT),
+            field("dbname", Type::TEXT),
+        ])),
+        BackendMessage::DataRow(DataRow::new(vec![
+            text(&system_id.to_string()),
+            text(&timeline.to_string()),
+            text(&lsn.to_string()),
+            database.map(|database| database.as_bytes().to_vec()),
+        ])),
+        command_complete("IDENTIFY_SYSTEM"),
+    ]
+}
+
+// Executes every command except START_REPLICATION, which the manager handles as it switches to
+// copy-both mode
+pub(crate) fn execute(
+    command: &ReplicationCommand,
+    source: &mut dyn ChangeSource,
+    state: &State,
+    sink: &mut dyn ResultSink,
+) -> io::Result<()> {
+    let messages = match command {
+        ReplicationCommand::IdentifySystem => identify_system(
+            source.system_id(),
+            1,
+            source.current_lsn(),
+            Some(state.database()),
+        ),
+        ReplicationCommand::CreateSlot { plugin: None, .. } => {
+            vec![BackendMessage::ErrorResponse(not_supported(
+                "physical replication slots are not supported",
+            ))]
+        }
+        ReplicationCommand::CreateSlot {
+            name,
+            temporary,
+            plugin: Some(plugin),
+        } => match source.create_slot(name, plugin, *temporary) {
+            Ok(consistent_point) => vec![
+                BackendMessage::RowDescription(RowDescription::new(vec![
+                    field("slot_name", Type::TEXT),
+                    field("consistent_point", Type::TEXT),
+                    field("snapshot_name", Type::TEXT),
+                    field("output_plugin", Type::TEXT),
+                ])),
+                BackendMessage::DataRow(DataRow::new(vec![
+                    text(name),
+                    text(&consistent_point.to_string()),
+                    None,
+                    text(plugin),
+                ])),
+                command_complete("CREATE_REPLICATION_SLOT"),
+            ],
+            Err(e) => vec![BackendMessage::ErrorResponse(e)],
+        },
+        ReplicationCommand::DropSlot(name) => match source.drop_slot(name) {
+            Ok(_) => vec![command_complete("DROP_REPLICATION_SLOT")],
+            Err(e) => vec![BackendMessage::ErrorResponse(e)],
+        },
+        ReplicationCommand::StartReplication { .. } => {
+            unreachable!("START_REPLICATION is handled by the manager")
+        }
+        ReplicationCommand::TimelineHistory(_) | ReplicationCommand::BaseBackup(_) => {
+            vec![BackendMessage::ErrorResponse(not_supported(
+                "physical replication commands need replication=true",
+            ))]
+        }
+    };
+
+    for msg in messages {
+        sink.push(msg)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn execute_physical(
+    command: &ReplicationCommand,
+    source: &mut dyn PhysicalSource,
+    sink: &mut dyn ResultSink,
+) -> io::Result<()> {
+    let messages = match command {
+        ReplicationCommand::IdentifySystem => identify_system(
+            source.system_id(),
+            source.timeline(),
+            source.current_lsn(),
+            None,
+        ),
+        ReplicationCommand::TimelineHistory(timeline) => match source.timeline_history(*timeline) {
+            Ok((filename, content)) => vec![
+                BackendMessage::RowDescription(RowDescription::new(vec![
+                    field("filename", Type::TEXT),
+                    field("content", Type::BYTEA),
+                ])),
+                BackendMessage::DataRow(DataRow::new(vec![text(&filename), Some(content)])),
+                command_complete("TIMELINE_HISTORY"),
+            ],
+            Err(e) => vec![BackendMessage::ErrorResponse(e)],
+        },
+        ReplicationCommand::BaseBackup(options) => match source.base_backup(options, sink)? {
+            Ok(_) => vec![command_complete("BASE_BACKUP")],
+            Err(e) => vec![BackendMessage::ErrorResponse(e)],
+        },
+        _ => vec![BackendMessage::ErrorResponse(not_supported(
+            "this replication command is not supported with physical replication",
+        ))],
+    };
+
+    for msg in messages {
+        sink.push(msg)?;
+    }
+
+    Ok(())
+}
+
+// Streams the changes of a source after START_REPLICATION
+pub(crate) struct WalSender<'a> {
+    source: &'a mut dyn ChangeSource,
+    // The end of what was sent so far
+    sent: Lsn,
+}
+
+impl<'a> WalSender<'a> {
+    pub(crate) fn new(source: &'a mut dyn ChangeSource, start: Lsn) -> Self {
+        Self {
+            source,
+            sent: start,
+        }
+    }
+
+    fn primary_keepalive(&self, reply_requested: bool) -> io::Result<Vec<u8>> {
+        Ok(CopyData::wrap(&PrimaryKeepalive::new(self.sent, reply_requested))?.data)
+    }
+}
+
+impl CopyBoth for WalSender<'_> {
+    fn receive(&mut self, data: Vec<u8>) -> io::Result<Result<Option<Vec<u8>>, ErrorResponse>> {
+        match StandbyMessage::decode(&mut Reader::new(&data[..])) {
+            Ok(StandbyMessage::StatusUpdate(update)) => {
+                if update.flushed.is_valid() {
+                    self.source.confirm(update.flushed);
+                }
+
+                if update.reply_requested {
+                    return Ok(Ok(Some(self.primary_keepalive(false)?)));
+                }
+            }
+            // Only matters for physical replication
+            Ok(StandbyMessage::HotStandbyFeedback(_)) => {}
+            Err(e) => log::warn!("ignoring standby message: {}", e),
+        }
+
+        Ok(Ok(None))
+    }
+
+    fn next(&mut self, timeout: Duration) -> io::Result<Result<CopyOutput, ErrorResponse>> {
+        let change = match self.source.next(timeout) {
+            Ok(Some(change)) => change,
+            Ok(None) => return Ok(Ok(CopyOutput::Idle)),
+            Err(e) => return Ok(Err(e)),
+        };
+
+        self.sent = self.sent.max(change.lsn);
+
+        let msg = XLogData::new(change.lsn, self.sent, change.data);
+        Ok(Ok(CopyOutput::Data(CopyData::wrap(&msg)?.data)))
+    }
+
+    fn keepalive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        Ok(Some(self.primary_keepalive(true)?))
+    }
+
+    fn command_tag(&self) -> CommandTag {
+        CommandTag::Other("START_REPLICATION".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::messages::{ReplicationMessage, StandbyStatusUpdate};
+
+    #[derive(Default)]
+    struct VecSource {
+        changes: Vec<Change>,
+        confirmed: Lsn,
+    }
+
+    impl ChangeSource for VecSource {
+        fn system_id(&self) -> u64 {
+            1
+        }
+
+        fn current_lsn(&mut self) -> Lsn {
+            Lsn(100)
+        }
+
+        fn create_slot(&mut self, _: &str, _: &str, _: bool) -> Result<Lsn, ErrorResponse> {
+            Ok(Lsn(100))
+        }
+
+        fn drop_slot(&mut self, _: &str) -> Result<(), ErrorResponse> {
+            Ok(())
+        }
+
+        fn start(
+            &mut self,
+            _: &str,
+            _: Lsn,
+            _: &[(String, Option<String>)],
+        ) -> Result<(), ErrorResponse> {
+            Ok(())
+        }
+
+        fn next(&mut self, _: Duration) -> Result<Option<Change>, ErrorResponse> {
+            Ok(self.changes.pop())
+        }
+
+        fn confirm(&mut self, lsn: Lsn) {
+            self.confirmed = lsn;
+        }
+    }
+
+    #[test]
+    fn test_wal_sender() {
+        let mut source = VecSource::default();
+        source.changes.push(Change {
+            lsn: Lsn(120),
+            data: b"change".to_vec(),
+        });
+
+        let mut sender = WalSender::new(&mut source, Lsn(100));
+
+        let data = match sender.next(Duration::ZERO).unwrap() {
+            Ok(CopyOutput::Data(data)) => data,
+            _ => panic!("expected data"),
+        };
+        match ReplicationMessage::decode(&mut Reader::new(&data[..])).unwrap() {
+            ReplicationMessage::XLogData(xlog) => {
+                assert_eq!(xlog.wal_start, Lsn(120));
+                assert_eq!(xlog.data, b"change");
+            }
+            _ => panic!("expected XLogData"),
+        }
+        assert!(matches!(
+            sender.next(Duration::ZERO).unwrap(),
+            Ok(CopyOutput::Idle)
+        ));
+
+        // Status updates confirm the flushed position and may ask for a keepalive
+        let update = StandbyStatusUpdate {
+            written: Lsn(120),
+            flushed: Lsn(120),
+            applied: Lsn::INVALID,
+            client_time: 0,
+            reply_requested: true,
+        };
+        let reply = sender
+            .receive(CopyData::wrap(&update).unwrap().data)
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match ReplicationMessage::decode(&mut Reader::new(&reply[..])).unwrap() {
+            ReplicationMessage::PrimaryKeepalive(keepalive) => {
+                assert_eq!(keepalive.wal_end, Lsn(120));
+                assert!(!keepalive.reply_requested);
+            }
+            _ => panic!("expected a keepalive"),
+        }
+        assert_eq!(source.confirmed, Lsn(120));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command("IDENTIFY_SYSTEM"),
+            Some(ReplicationCommand::IdentifySystem)
+        );
+        assert_eq!(
+            parse_command(
+                "CREATE_REPLICATION_SLOT \"Events\" TEMPORARY LOGICAL pgoutput NOEXPORT_SNAPSHOT"
+            ),
+            Some(ReplicationCommand::CreateSlot {
+                name: "Events".to_string(),
+                temporary: true,
+                plugin: Some("pgoutput".to_string()),
+            })
+        );
+        assert_eq!(
+            parse_command("START_REPLICATION SLOT events LOGICAL 16/B374D848 (\"proto_version\" '1', publication_names 'pub', binary)"),
+            Some(ReplicationCommand::StartReplication {
+                slot: "events".to_string(),
+                start: Lsn(0x16_B374_D848),
+                options: vec![
+                    ("proto_version".to_string(), Some("1".to_string())),
+                    ("publication_names".to_string(), Some("pub".to_string())),
+                    ("binary".to_string(), None),
+                ],
+            })
+        );
+        assert_eq!(
+            parse_command("START_REPLICATION SLOT events LOGICAL A/0;"),
+            Some(ReplicationCommand::StartReplication {
+                slot: "events".to_string(),
+                start: Lsn(0xA_0000_0000),
+                options: vec![],
+            })
+        );
+        assert_eq!(parse_command("START_REPLICATION SLOT events LOGICAL"), None);
+        assert_eq!(
+            parse_command("TIMELINE_HISTORY 2"),
+            Some(ReplicationCommand::TimelineHistory(2))
+        );
+        assert_eq!(
+            parse_command("BASE_BACKUP (LABEL 'nightly', PROGRESS, CHECKPOINT fast, MAX_RATE 32)"),
+            Some(ReplicationCommand::BaseBackup(vec![
+                ("label".to_string(), Some("nightly".to_string())),
+                ("progress".to_string(), None),
+                ("checkpoint".to_string(), Some("fast".to_string())),
+                ("max_rate".to_string(), Some("32".to_string())),
+            ]))
+        );
+        assert_eq!(
+            parse_command("BASE_BACKUP LABEL 'nightly' FAST NOWAIT"),
+            Some(ReplicationCommand::BaseBackup(vec![
+                ("label".to_string(), Some("nightly".to_string())),
+                ("fast".to_string(), None),
+                ("nowait".to_string(), None),
+            ]))
+        );
+        assert_eq!(parse_command("select 1"), None);
+        assert_eq!(parse_command("CREATE_REPLICATION_SLOT \"日"), None);
+        assert_eq!(parse_command("BASE_BACKUP (LABEL '日)"), None);
+    }
+}
@@ -0,0 +1,30 @@
+// Building blocks for the responses executors answer queries with themselves
+use crate::backend::Type;
+use crate::proto::messages::{ErrorResponse, FieldDescription, Severity};
+
+pub(crate) fn error(code: &str, message: String) -> ErrorResponse {
+    ErrorResponse::new(Severity::Error, code.to_string(), message)
+}
+
+pub(crate) fn not_supported(message: &str) -> ErrorResponse {
+    // feature_not_supported
+    error("0A000", message.to_string())
+}
+
+// A text format column which isn't taken from a table
+pub(crate) fn field(name: &str, ty: Type) -> FieldDescription {
+    FieldDescription {
+        name: name.to_string(),
+        table_oid: 0,
+        column_attr: 0,
+        type_oid: ty.oid,
+        type_size: ty.len,
+        type_modifier: -1,
+        format_code: 0,
+    }
+}
+
+// A text format value
+pub(crate) fn text(value: &str) -> Option<Vec<u8>> {
+    Some(value.as_bytes().to_vec())
+}
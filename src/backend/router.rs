@@ -0,0 +1,995 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use secstr::SecStr;
+
+use crate::backend::auth::{AuthMethod, AuthResult};
+use crate::backend::lexer::{tokenize, Token};
+use crate::backend::proxy::{command_result, connect_error, forward_set, relay, Pending};
+use crate::backend::query_exec::{QueryResult, ResultSink};
+use crate::backend::{Auth, QueryExec, SetStatement, State};
+use crate::frontend::{Client, ClientError, Config};
+use crate::proto::messages::{
+    BackendMessage, Close, ErrorResponse, ExtendedMessage, FunctionCall, Parse, PasswordMessage,
+    Query, Severity, Sync, Target, TransactionStatus,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Primary,
+    Replica,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatementKind {
+    Read,
+    Write,
+    Begin { read_only: bool },
+    // COMMIT, ROLLBACK and friends
+    TransactionEnd,
+}
+
+impl StatementKind {
+    fn is_read_only(&self) -> bool {
+        match self {
+            Self::Read | Self::TransactionEnd => true,
+            Self::Begin { read_only } => *read_only,
+            Self::Write => false,
+        }
+    }
+}
+
+// Splits a query into statements of (uppercased) keywords and identifiers. The comments are
+// returned separately.
+fn scan(query: &str) -> (Vec<Vec<String>>, Vec<&str>) {
+    let mut statements = vec![vec![]];
+    let mut comments = vec![];
+
+    for token in tokenize(query) {
+        match token {
+            Token::Word(word) => statements
+                .last_mut()
+                .unwrap()
+                .push(word.to_ascii_uppercase()),
+            Token::Comment(comment) => comments.push(comment),
+            Token::Semicolon => statements.push(vec![]),
+            _ => {}
+        }
+    }
+
+    statements.retain(|words| !words.is_empty());
+
+    (statements, comments)
+}
+
+fn modifies(words: &[String]) -> bool {
+    words.iter().any(|w| {
+        matches!(
+            w.as_str(),
+            "INSERT" | "UPDATE" | "DELETE" | "MERGE" | "INTO"
+        )
+    }) || words
+        .windows(2)
+        .any(|w| w[0] == "FOR" && matches!(w[1].as_str(), "SHARE" | "NO" | "KEY"))
+}
+
+fn classify_statement(words: &[String]) -> StatementKind {
+    match words[0].as_str() {
+        "BEGIN" | "START" => StatementKind::Begin {
+            read_only: words.windows(2).any(|w| w[0] == "READ" && w[1] == "ONLY"),
+        },
+        "COMMIT" | "END" | "ROLLBACK" | "ABORT" => StatementKind::TransactionEnd,
+        "SELECT" | "VALUES" | "TABLE" | "SHOW" | "EXPLAIN" | "WITH" if !modifies(words) => {
+            StatementKind::Read
+        }
+        _ => StatementKind::Write,
+    }
+}
+
+// Classifies every statement in the query. Anything we don't recognize as read-only is
+// considered a write, functions with side effects (such as nextval) require a route hint.
+pub fn classify(query: &str) -> Vec<StatementKind> {
+    scan(query)
+        .0
+        .iter()
+        .map(|words| classify_statement(words))
+        .collect()
+}
+
+// Returns the route requested by a comment such as `/* route=primary */`
+pub fn route_hint(query: &str) -> Option<Route> {
+    scan(query).1.iter().find_map(|comment| {
+        comment
+            .split_whitespace()
+            .find_map(|word| match word.strip_prefix("route=")? {
+                "primary" => Some(Route::Primary),
+                "replica" => Some(Route::Replica),
+                _ => None,
+            })
+    })
+}
+
+// The upstream servers shared by all routing sessions
+pub struct Router {
+    primary: SocketAddr,
+    replicas: Vec<SocketAddr>,
+    next: AtomicUsize,
+}
+
+impl Router {
+    pub fn new(primary: SocketAddr, replicas: Vec<SocketAddr>) -> Self {
+        Self {
+            primary,
+            replicas,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // Replicas are assigned to sessions round-robin
+    fn next_replica(&self) -> Option<SocketAddr> {
+        if self.replicas.is_empty() {
+            return None;
+        }
+
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+
+        Some(self.replicas[n % self.replicas.len()])
+    }
+}
+
+// The primary connection opened during authentication and the password it was opened with, the
+// executor connects to the replicas with the same password
+#[derive(Default)]
+struct Login {
+    primary: Option<Client>,
+    password: Option<SecStr>,
+}
+
+fn upstream_config(state: &State, password: Option<SecStr>) -> Config {
+    let mut config = Config::new(state.user().to_string());
+    config.database = Some(state.database().to_string());
+    config.password = password;
+    config.params = state
+        .extra_params()
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+
+    config
+}
+
+// Authenticates clients by logging in to the primary with the same credentials, like `ProxyAuth`
+pub struct RouterAuth {
+    router: Arc<Router>,
+    login: Rc<RefCell<Login>>,
+}
+
+impl RouterAuth {
+    pub fn new(router: Arc<Router>) -> Self {
+        Self {
+            router,
+            login: Rc::new(RefCell::new(Login::default())),
+        }
+    }
+
+    // Returns the executor which uses the primary connection opened by this auth
+    pub fn query_exec(&self) -> RoutingQueryExec {
+        let mut query_exec = RoutingQueryExec::new(self.router.clone());
+        query_exec.login = self.login.clone();
+
+        query_exec
+    }
+
+    fn connect(&self, state: &State, password: Option<SecStr>) -> Result<(), ClientError> {
+        let config = upstream_config(state, password.clone());
+        let client = Client::connect(self.router.primary, config)?;
+        self.login.replace(Login {
+            primary: Some(client),
+            password,
+        });
+
+        Ok(())
+    }
+}
+
+impl Auth for RouterAuth {
+    // We only ask the client for a password when the primary wants one
+    fn method(&self, state: &State) -> AuthMethod {
+        match self.connect(state, None) {
+            Ok(_) => AuthMethod::None,
+            Err(ClientError::PasswordRequired) => AuthMethod::CleartextPassword,
+            Err(e) => AuthMethod::Reject(connect_error(e)),
+        }
+    }
+
+    fn clear_text_password(&self, state: &State, password: PasswordMessage) -> AuthResult {
+        self.connect(state, Some(password.password))
+            .map_err(connect_error)
+    }
+}
+
+// A prepared statement, it's prepared again when it's needed on another upstream
+struct Prepared {
+    query: String,
+    param_types: Vec<i32>,
+    // The upstreams it's prepared on
+    routes: Vec<Route>,
+}
+
+// The statement a Bind or Describe refers to
+fn statement_name(msg: &ExtendedMessage) -> Option<&str> {
+    match msg {
+        ExtendedMessage::Bind(bind) => Some(&bind.statement),
+        ExtendedMessage::Describe(describe) if describe.target == Target::Statement => {
+            Some(&describe.name)
+        }
+        _ => None,
+    }
+}
+
+fn other(route: Route) -> Route {
+    match route {
+        Route::Primary => Route::Replica,
+        Route::Replica => Route::Primary,
+    }
+}
+
+// Sends read-only statements and transactions to a replica and everything else to the primary.
+// Open transactions stay on the upstream they started on and after the first write the session
+// is pinned to the primary so it can read its own writes.
+pub struct RoutingQueryExec {
+    router: Arc<Router>,
+    login: Rc<RefCell<Login>>,
+    config: Option<Config>,
+    primary: Option<Client>,
+    replica: Option<Client>,
+    pinned: bool,
+    transaction: Option<Route>,
+    // Every prepared statement by name (the unnamed one uses the empty name)
+    statements: HashMap<String, Prepared>,
+    // Extended protocol messages up to the next Flush or Sync, they're sent once the route of all
+    // of them is known
+    buffered: Vec<ExtendedMessage>,
+    // The route the buffered messages need, any one of them needing the primary decides
+    wanted: Option<Route>,
+    // Route of the extended protocol messages sent since the last Sync
+    batch: Option<Route>,
+    batch_writes: bool,
+    pending: Pending,
+    // Session level SET and RESET statements, replayed on replicas connected later
+    settings: Vec<SetStatement>,
+}
+
+impl RoutingQueryExec {
+    pub fn new(router: Arc<Router>) -> Self {
+        Self {
+            router,
+            login: Rc::new(RefCell::new(Login::default())),
+            config: None,
+            primary: None,
+            replica: None,
+            pinned: false,
+            transaction: None,
+            statements: HashMap::new(),
+            buffered: vec![],
+            wanted: None,
+            batch: None,
+            batch_writes: false,
+            pending: Pending::default(),
+            settings: vec![],
+        }
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Result<Client, ErrorResponse> {
+        let config = self.config.as_ref().expect("startup must be called first");
+        let mut upstream = Config::new(config.user.clone());
+        upstream.database = config.database.clone();
+        upstream.password = config.password.clone();
+        upstream.params = config.params.clone();
+
+        Client::connect(addr, upstream).map_err(connect_error)
+    }
+
+    fn route(&self, query: &str, kinds: &[StatementKind]) -> Route {
+        if let Some(route) = self.transaction {
+            return route;
+        }
+
+        if let Some(route) = route_hint(query) {
+            return route;
+        }
+
+        if !self.pinned && kinds.iter().all(StatementKind::is_read_only) {
+            Route::Replica
+        } else {
+            Route::Primary
+        }
+    }
+
+    // Returns the upstream for the route, reads fall back to the primary when no replica is
+    // available
+    fn upstream(&mut self, route: Route) -> Result<(Route, &mut Client), ErrorResponse> {
+        if route == Route::Replica && self.replica.is_none() {
+            if let Some(addr) = self.router.next_replica() {
+                match self.connect(addr) {
+                    Ok(mut client) => {
+                        for statement in self.settings.iter() {
+                            match forward_set(&mut client, statement) {
+                                Ok(Ok(_)) => {}
+                                Ok(Err(e)) => log::warn!("failed to apply {}: {}", statement, e),
+                                Err(e) => log::warn!("failed to apply {}: {}", statement, e),
+                            }
+                        }
+
+                        self.replica = Some(client);
+                    }
+                    Err(e) => log::warn!("failed to connect to replica {}: {}", addr, e),
+                }
+            }
+        }
+
+        match (route, self.replica.as_mut()) {
+            (Route::Replica, Some(client)) => Ok((Route::Replica, client)),
+            _ => match self.primary.as_mut() {
+                Some(client) => Ok((Route::Primary, client)),
+                None => Err(ErrorResponse::new(
+                    Severity::Error,
+                    "08003".to_string(),
+                    "not connected to upstream".to_string(),
+                )),
+            },
+        }
+    }
+
+    // Sends the buffered messages and returns their route. Once part of a batch was sent its
+    // route is kept, except for a write following reads on a replica outside of a transaction:
+    // the replica's part is completed and the rest goes to the primary.
+    fn send_batch(&mut self) -> io::Result<Result<Route, ErrorResponse>> {
+        let route = match (self.batch, self.wanted.take()) {
+            (Some(Route::Replica), Some(Route::Primary))
+                if self.transaction.is_none() && !self.pending.failed() =>
+            {
+                if let Some(client) = self.replica.as_mut() {
+                    client.send(Sync::new())?;
+                    client.flush()?;
+                    self.pending.relay(client, &mut vec![])?;
+                }
+
+                Route::Primary
+            }
+            (Some(route), _) => route,
+            (None, wanted) => wanted.or(self.transaction).unwrap_or(Route::Primary),
+        };
+        let route = match self.upstream(route) {
+            Ok((route, _)) => route,
+            Err(e) => {
+                self.buffered.clear();
+                return Ok(Err(e));
+            }
+        };
+        let mut closed = vec![];
+
+        // Prepared statements used on an upstream they weren't prepared on are prepared there
+        // first, the client doesn't see the responses
+
+        for msg in std::mem::take(&mut self.buffered) {
+            match &msg {
+                ExtendedMessage::Parse(parse) => {
+                    self.statements.insert(
+                        parse.name.clone(),
+                        Prepared {
+                            query: parse.query.clone(),
+                            param_types: parse.param_types.clone(),
+                            routes: vec![route],
+                        },
+                    );
+                }
+                ExtendedMessage::Close(close) if close.target == Target::Statement => {
+                    if let Some(prepared) = self.statements.remove(&close.name) {
+                        if prepared.routes.contains(&other(route)) {
+                            closed.push(close.name.clone());
+                        }
+                    }
+                }
+                msg => {
+                    let name = statement_name(msg).unwrap_or_default();
+                    let parse = match self.statements.get_mut(name) {
+                        Some(prepared) if !prepared.routes.contains(&route) => {
+                            prepared.routes.push(route);
+
+                            Some(Parse {
+                                len: 0,
+                                name: name.to_string(),
+                                query: prepared.query.clone(),
+                                param_types: prepared.param_types.clone(),
+                            })
+                        }
+                        _ => None,
+                    };
+
+                    if let (Some(parse), Some(client)) = (parse, self.client(route)) {
+                        client.send(parse)?;
+                        self.pending.sent_hidden();
+                    }
+                }
+            }
+
+            if let Some(client) = self.client(route) {
+                client.send(msg)?;
+                self.pending.sent();
+            }
+        }
+
+        // Statements closed by the client are closed on the other upstream as well, so that their
+        // names can be used again
+        if let (false, Some(client)) = (closed.is_empty(), self.client(other(route))) {
+            for name in closed {
+                client.send(Close {
+                    len: 0,
+                    target: Target::Statement,
+                    name,
+                })?;
+            }
+
+            client.send(Sync::new())?;
+            client.flush()?;
+            relay(client, &mut vec![])?;
+        }
+
+        self.batch = Some(route);
+        Ok(Ok(route))
+    }
+
+    fn client(&mut self, route: Route) -> Option<&mut Client> {
+        match route {
+            Route::Primary => self.primary.as_mut(),
+            Route::Replica => self.replica.as_mut(),
+        }
+    }
+
+    fn completed(&mut self, route: Route, writes: bool, transaction_status: TransactionStatus) {
+        if route == Route::Primary && writes {
+            self.pinned = true;
+        }
+
+        self.transaction = match transaction_status {
+            TransactionStatus::Idle => None,
+            _ => Some(route),
+        };
+    }
+}
+
+impl QueryExec for RoutingQueryExec {
+    // The primary is connected right away (unless `RouterAuth` already did) so that invalid users
+    // or databases are refused
+    fn startup(&mut self, state: &State) -> io::Result<Result<(), ErrorResponse>> {
+        let login = self.login.take();
+        self.config = Some(upstream_config(state, login.password));
+
+        let primary = match login.primary {
+            Some(client) => Ok(client),
+            None => self.connect(self.router.primary),
+        };
+
+        match primary {
+            Ok(client) => {
+                state.settings().seed(client.parameters());
+                self.primary = Some(client);
+            }
+            Err(e) => return Ok(Err(e)),
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn execute(&mut self, query: &str) -> QueryResult {
+        let mut messages = vec![];
+        let result = self.execute_to(query, &mut messages);
+
+        command_result(result, messages)
+    }
+
+    fn execute_to(
+        &mut self,
+        query: &str,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let kinds = classify(query);
+        let writes = kinds.contains(&StatementKind::Write);
+        let (route, client) = match self.upstream(self.route(query, &kinds)) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                sink.push(BackendMessage::ErrorResponse(e))?;
+                return Ok(TransactionStatus::Idle);
+            }
+        };
+
+        log::debug!("routing query to {:?}", route);
+
+        client.send(Query::new(query.to_string()))?;
+        client.flush()?;
+
+        let status = relay(client, sink)?;
+        self.completed(route, writes, status);
+
+        Ok(status)
+    }
+
+    // Messages are buffered up to the next Flush or Sync, the batch goes to the primary if any of
+    // its statements needs it
+    fn extended(
+        &mut self,
+        msg: ExtendedMessage,
+        _sink: &mut dyn ResultSink,
+    ) -> io::Result<Result<(), ErrorResponse>> {
+        let query = match &msg {
+            ExtendedMessage::Parse(parse) => Some(parse.query.clone()),
+            msg => statement_name(msg)
+                .and_then(|name| self.statements.get(name))
+                .map(|prepared| prepared.query.clone()),
+        };
+
+        if let Some(query) = query {
+            let kinds = classify(&query);
+            self.batch_writes |= kinds.contains(&StatementKind::Write);
+
+            self.wanted = match (self.wanted, self.route(&query, &kinds)) {
+                (Some(Route::Primary), _) | (_, Route::Primary) => Some(Route::Primary),
+                _ => Some(Route::Replica),
+            };
+        }
+
+        self.buffered.push(msg);
+
+        Ok(Ok(()))
+    }
+
+    fn flush(&mut self, sink: &mut dyn ResultSink) -> io::Result<()> {
+        if self.buffered.is_empty() && self.batch.is_none() {
+            return Ok(());
+        }
+
+        let route = match self.send_batch()? {
+            Ok(route) => route,
+            Err(e) => return sink.push(BackendMessage::ErrorResponse(e)),
+        };
+
+        let client = match route {
+            Route::Primary => self.primary.as_mut(),
+            Route::Replica => self.replica.as_mut(),
+        };
+
+        match client {
+            Some(client) => self.pending.flush(client, sink),
+            None => Ok(()),
+        }
+    }
+
+    fn sync(&mut self, sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+        let writes = std::mem::take(&mut self.batch_writes);
+        let route = self.send_batch()?;
+        self.batch = None;
+
+        let route = match route {
+            Ok(route) => route,
+            Err(e) => {
+                self.pending.sync();
+                sink.push(BackendMessage::ErrorResponse(e))?;
+
+                return Ok(TransactionStatus::Idle);
+            }
+        };
+        let client = match route {
+            Route::Primary => self.primary.as_mut(),
+            Route::Replica => self.replica.as_mut(),
+        };
+        let client = match client {
+            Some(client) => client,
+            None => return Ok(TransactionStatus::Idle),
+        };
+
+        client.send(Sync::new())?;
+        client.flush()?;
+
+        let status = self.pending.relay(client, sink)?;
+        self.completed(route, writes, status);
+
+        Ok(status)
+    }
+
+    // Functions might write (large objects do), so outside of a transaction they're called on the
+    // primary
+    fn function_call(
+        &mut self,
+        call: FunctionCall,
+        sink: &mut dyn ResultSink,
+    ) -> io::Result<TransactionStatus> {
+        let route = self.transaction.unwrap_or(Route::Primary);
+        let (route, client) = match self.upstream(route) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                sink.push(BackendMessage::ErrorResponse(e))?;
+                return Ok(TransactionStatus::Idle);
+            }
+        };
+
+        client.send(call)?;
+        client.flush()?;
+
+        let status = relay(client, sink)?;
+        self.completed(route, true, status);
+
+        Ok(status)
+    }
+
+    // Settings apply to every upstream, the primary decides whether the change is accepted
+    fn set(&mut self, statement: &SetStatement) -> io::Result<Result<(), ErrorResponse>> {
+        for client in [self.primary.as_mut(), self.replica.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = forward_set(client, statement)? {
+                return Ok(Err(e));
+            }
+        }
+
+        match statement {
+            SetStatement::Set { local: true, .. } => {}
+            SetStatement::ResetAll => self.settings.clear(),
+            statement => self.settings.push(statement.clone()),
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+impl Drop for RoutingQueryExec {
+    fn drop(&mut self) {
+        for client in [self.primary.take(), self.replica.take()]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = client.close() {
+                log::warn!("failed to close upstream connection: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::backend::{Conn, Manager, NoopAuth, PortalQueryExec};
+    use crate::proto::messages::{Bind, CommandComplete, CommandTag, Execute, Field};
+
+    #[test]
+    fn test_classify() {
+        use StatementKind::*;
+
+        assert_eq!(classify("select 1"), vec![Read]);
+        assert_eq!(classify("SELECT * FROM t FOR UPDATE"), vec![Write]);
+        assert_eq!(classify("select 'insert'; -- delete"), vec![Read]);
+        assert_eq!(classify("select $1, $x$ update $x$"), vec![Read]);
+        assert_eq!(
+            classify("with x as (delete from t returning *) select * from x"),
+            vec![Write]
+        );
+        assert_eq!(
+            classify("BEGIN READ ONLY; select 1; commit"),
+            vec![Begin { read_only: true }, Read, TransactionEnd]
+        );
+        assert_eq!(
+            classify("start transaction read write"),
+            vec![Begin { read_only: false }]
+        );
+        assert_eq!(classify("create table t ()"), vec![Write]);
+    }
+
+    #[test]
+    fn test_route_hint() {
+        assert_eq!(
+            route_hint("/* route=primary */ select 1"),
+            Some(Route::Primary)
+        );
+        assert_eq!(
+            route_hint("select 1 -- route=replica"),
+            Some(Route::Replica)
+        );
+        assert_eq!(route_hint("select '/* route=primary */'"), None);
+    }
+
+    // Responds to every query with its own name as command tag and keeps track of transaction
+    // blocks
+    struct NamedQueryExec(&'static str, TransactionStatus);
+
+    impl QueryExec for NamedQueryExec {
+        fn execute(&mut self, _query: &str) -> QueryResult {
+            Ok(CommandComplete::new(CommandTag::Other(self.0.to_string())))
+        }
+
+        fn execute_to(
+            &mut self,
+            query: &str,
+            sink: &mut dyn ResultSink,
+        ) -> io::Result<TransactionStatus> {
+            match classify(query).first() {
+                Some(StatementKind::Begin { .. }) => self.1 = TransactionStatus::InTransaction,
+                Some(StatementKind::TransactionEnd) => self.1 = TransactionStatus::Idle,
+                _ => {}
+            }
+
+            sink.push(BackendMessage::CommandComplete(CommandComplete::new(
+                CommandTag::Other(self.0.to_string()),
+            )))?;
+            Ok(self.1)
+        }
+
+        fn sync(&mut self, _sink: &mut dyn ResultSink) -> io::Result<TransactionStatus> {
+            Ok(self.1)
+        }
+    }
+
+    // Only lets clients in with the password "secret"
+    struct Secret;
+
+    impl Auth for Secret {
+        fn method(&self, _state: &State) -> AuthMethod {
+            AuthMethod::CleartextPassword
+        }
+
+        fn clear_text_password(&self, _state: &State, password: PasswordMessage) -> AuthResult {
+            match password.password == SecStr::from("secret") {
+                true => Ok(()),
+                false => Err(ErrorResponse::new(
+                    Severity::Fatal,
+                    "28P01".to_string(),
+                    "password authentication failed".to_string(),
+                )),
+            }
+        }
+    }
+
+    fn upstream<A: Auth + 'static>(name: &'static str, auth: fn() -> A) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+
+                thread::spawn(move || {
+                    let query_exec =
+                        PortalQueryExec::new(NamedQueryExec(name, TransactionStatus::Idle));
+
+                    Manager::new(Conn::new(stream).unwrap(), auth(), query_exec)
+                        .unwrap()
+                        .handle()
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn test_routing() {
+        let router = Router::new(
+            upstream("primary", NoopAuth::new),
+            vec![upstream("replica", NoopAuth::new)],
+        );
+        let mut query_exec = RoutingQueryExec::new(Arc::new(router));
+        query_exec
+            .startup(&State::new("bob".to_string(), "bob".to_string()))
+            .unwrap()
+            .unwrap();
+
+        let mut route = |query: &str| query_exec.execute(query).unwrap().command_tag.to_string();
+
+        assert_eq!(route("select 1"), "replica");
+        assert_eq!(route("/* route=primary */ select 1"), "primary");
+        assert_eq!(route("insert into t values (1)"), "primary");
+
+        // Reads after a write are pinned to the primary
+        assert_eq!(route("select 1"), "primary");
+    }
+
+    #[test]
+    fn test_auth() {
+        let router = Router::new(
+            upstream("primary", || Secret),
+            vec![upstream("replica", || Secret)],
+        );
+        let state = State::new("bob".to_string(), "bob".to_string());
+        let password = |password: &str| PasswordMessage {
+            len: 0,
+            password: SecStr::from(password),
+        };
+
+        let auth = RouterAuth::new(Arc::new(router));
+        assert_eq!(auth.method(&state), AuthMethod::CleartextPassword);
+
+        let e = auth
+            .clear_text_password(&state, password("guess"))
+            .unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("28P01"));
+
+        // The replica is connected with the password the client logged in with
+        auth.clear_text_password(&state, password("secret"))
+            .unwrap();
+
+        let mut query_exec = auth.query_exec();
+        query_exec.startup(&state).unwrap().unwrap();
+        assert_eq!(
+            query_exec
+                .execute("select 1")
+                .unwrap()
+                .command_tag
+                .to_string(),
+            "replica"
+        );
+    }
+
+    fn parse(name: &str, query: &str) -> ExtendedMessage {
+        ExtendedMessage::Parse(Parse {
+            len: 0,
+            name: name.to_string(),
+            query: query.to_string(),
+            param_types: vec![],
+        })
+    }
+
+    fn bind(statement: &str) -> ExtendedMessage {
+        ExtendedMessage::Bind(Bind {
+            len: 0,
+            portal: String::new(),
+            statement: statement.to_string(),
+            param_formats: vec![],
+            params: vec![],
+            result_formats: vec![],
+        })
+    }
+
+    fn execute() -> ExtendedMessage {
+        ExtendedMessage::Execute(Execute {
+            len: 0,
+            portal: String::new(),
+            max_rows: 0,
+        })
+    }
+
+    // Sends the messages followed by a Sync, returns the relayed messages (as the command tags and
+    // the names of the others) and the transaction status
+    fn pipeline(
+        query_exec: &mut RoutingQueryExec,
+        msgs: Vec<Option<ExtendedMessage>>,
+    ) -> (Vec<String>, TransactionStatus) {
+        let mut messages = vec![];
+
+        // `None` stands for a Flush
+        for msg in msgs {
+            match msg {
+                Some(msg) => query_exec.extended(msg, &mut messages).unwrap().unwrap(),
+                None => query_exec.flush(&mut messages).unwrap(),
+            }
+        }
+
+        let status = query_exec.sync(&mut messages).unwrap();
+        let messages = messages
+            .into_iter()
+            .map(|msg| match msg {
+                BackendMessage::CommandComplete(cc) => cc.command_tag.to_string(),
+                BackendMessage::ParseComplete(_) => "ParseComplete".to_string(),
+                BackendMessage::BindComplete(_) => "BindComplete".to_string(),
+                _ => "other".to_string(),
+            })
+            .collect();
+
+        (messages, status)
+    }
+
+    #[test]
+    fn test_prepared_statements() {
+        let router = Router::new(
+            upstream("primary", NoopAuth::new),
+            vec![upstream("replica", NoopAuth::new)],
+        );
+        let mut query_exec = RoutingQueryExec::new(Arc::new(router));
+        query_exec
+            .startup(&State::new("bob".to_string(), "bob".to_string()))
+            .unwrap()
+            .unwrap();
+
+        // Prepared outside of a transaction, so on the replica
+        let (messages, _) = pipeline(&mut query_exec, vec![Some(parse("s", "select 1"))]);
+        assert_eq!(messages, vec!["ParseComplete"]);
+
+        // Executed in a transaction on the primary, where it's prepared again
+        assert_eq!(
+            query_exec.execute("begin").unwrap().command_tag.to_string(),
+            "primary"
+        );
+        let (messages, status) = pipeline(&mut query_exec, vec![Some(bind("s")), Some(execute())]);
+        assert_eq!(messages, vec!["BindComplete", "primary"]);
+        assert_eq!(status, TransactionStatus::InTransaction);
+
+        query_exec.execute("commit").unwrap();
+        let (messages, status) = pipeline(&mut query_exec, vec![Some(bind("s")), Some(execute())]);
+        assert_eq!(messages, vec!["BindComplete", "replica"]);
+        assert_eq!(status, TransactionStatus::Idle);
+    }
+
+    #[test]
+    fn test_pipeline_writes() {
+        let router = Router::new(
+            upstream("primary", NoopAuth::new),
+            vec![upstream("replica", NoopAuth::new)],
+        );
+        let mut query_exec = RoutingQueryExec::new(Arc::new(router));
+        query_exec
+            .startup(&State::new("bob".to_string(), "bob".to_string()))
+            .unwrap()
+            .unwrap();
+
+        // A write anywhere in the pipeline sends all of it to the primary
+        let (messages, _) = pipeline(
+            &mut query_exec,
+            vec![
+                Some(parse("", "select 1")),
+                Some(bind("")),
+                Some(execute()),
+                Some(parse("", "insert into t values (1)")),
+                Some(bind("")),
+                Some(execute()),
+            ],
+        );
+        assert_eq!(
+            messages,
+            vec![
+                "ParseComplete",
+                "BindComplete",
+                "primary",
+                "ParseComplete",
+                "BindComplete",
+                "primary"
+            ]
+        );
+
+        // Unless the reads were flushed to the replica before
+        let mut query_exec = RoutingQueryExec::new(query_exec.router.clone());
+        query_exec
+            .startup(&State::new("bob".to_string(), "bob".to_string()))
+            .unwrap()
+            .unwrap();
+        let (messages, _) = pipeline(
+            &mut query_exec,
+            vec![
+                Some(parse("", "select 1")),
+                Some(bind("")),
+                Some(execute()),
+                None,
+                Some(parse("", "insert into t values (1)")),
+                Some(bind("")),
+                Some(execute()),
+            ],
+        );
+        assert_eq!(
+            messages,
+            vec![
+                "ParseComplete",
+                "BindComplete",
+                "replica",
+                "ParseComplete",
+                "BindComplete",
+                "primary"
+            ]
+        );
+    }
+}
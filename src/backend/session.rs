@@ -0,0 +1,990 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::backend::auth::{AuthMethod, AuthResult};
+use crate::backend::query_exec::{QueryResult, ResultSink};
+use crate::backend::Settings;
+use crate::proto::messages::{
+    AuthenticationCleartextPassword, AuthenticationOk, BackendKeyData, BackendMessage,
+    CancelRequest, CopyBothResponse, CopyData, CopyDone, CopyFail, ErrorResponse, ExtendedMessage,
+    Field, FunctionCall, Handshake, IncomingMessage, NegotiateProtocolVersion, ParameterStatus,
+    PasswordMessage, Query, ReadyForQuery, SSLResponse, Severity, StartupMessage,
+    TransactionStatus, MAX_STARTUP_PACKET_LENGTH, PROTOCOL_VERSION, PROTOCOL_VERSION_3_2,
+};
+use crate::proto::{
+    Decode, Encode, ProtocolError, ProtocolResult, Reader, Writer, DEFAULT_MAX_MESSAGE_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replication {
+    // Physical replication (`replication=true`)
+    Enabled,
+    Disabled,
+    // Logical replication, the connection is bound to the database
+    Database,
+}
+
+impl Replication {
+    // Booleans are accepted the way PostgreSQL parses them, including unique prefixes
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.to_lowercase();
+
+        match value.as_str() {
+            "database" => Some(Self::Database),
+            "1" | "on" => Some(Self::Enabled),
+            "0" | "of" | "off" => Some(Self::Disabled),
+            "" => None,
+            value if "true".starts_with(value) || "yes".starts_with(value) => Some(Self::Enabled),
+            value if "false".starts_with(value) || "no".starts_with(value) => Some(Self::Disabled),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct State {
+    user: String,
+    database: String,
+    replication: Replication,
+    extra_params: HashMap<String, String>,
+    // Shared between clones of the state, so executors which keep a copy see changes made with SET
+    settings: Arc<Mutex<Settings>>,
+}
+
+impl State {
+    pub fn new(user: String, database: String) -> Self {
+        Self {
+            settings: Arc::new(Mutex::new(Settings::new(&user, &HashMap::new()))),
+            user,
+            database,
+            ..Self::default()
+        }
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    pub fn extra_params(&self) -> &HashMap<String, String> {
+        &self.extra_params
+    }
+
+    pub fn settings(&self) -> MutexGuard<'_, Settings> {
+        self.settings.lock().unwrap()
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            user: String::new(),
+            database: String::new(),
+            replication: Replication::Disabled,
+            extra_params: HashMap::new(),
+            settings: Arc::new(Mutex::new(Settings::new("", &HashMap::new()))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    // Waiting for an SSLRequest or StartupMessage
+    Startup,
+    // The startup message was accepted and the driver has to select an authentication method
+    SelectingAuth,
+    // Waiting for the client to respond to an authentication request
+    Authenticating,
+    // The client is authenticated and the driver has to prepare the session
+    Initializing,
+    // Waiting for the next query
+    Ready,
+    // A query (or a message of the extended query protocol) was handed to the driver and we're
+    // waiting for its result
+    Executing,
+    // Both sides exchange CopyData (streaming replication) until the client sends CopyDone
+    CopyBoth,
+    Closed,
+}
+
+pub enum Event {
+    // The startup message was accepted, the driver should call `authenticate`
+    Startup,
+    // The driver should verify the password and call `auth_result`
+    Password(PasswordMessage),
+    // The client was authenticated, the driver should call `startup_result`
+    Authenticated,
+    // The driver should execute the query and call `query_result` or `query_complete`
+    Query(Query),
+    // The driver should process the message and call `extended_result`
+    Extended(ExtendedMessage),
+    // The driver should call `query_complete` once all pending results have been sent
+    Sync,
+    // The client asked for all pending output to be sent
+    Flush,
+    // The driver should call the function and `query_complete`
+    FunctionCall(FunctionCall),
+    // Data the client sent in copy-both mode
+    CopyData(CopyData),
+    // The client finished copy-both mode, the driver should call `end_copy_both`
+    CopyDone,
+    // The client aborted copy-both mode, the driver should respond with an error
+    CopyFail(CopyFail),
+    // Sent on a connection of its own, which is closed right away
+    Cancel(CancelRequest),
+    Terminate,
+}
+
+// The protocol core without any I/O: bytes received from the client are fed in with `receive`,
+// decoded into events by `poll_event` and everything that should be sent back to the client is
+// collected until the driver picks it up with `take_output`
+pub struct Session {
+    phase: Phase,
+    state: State,
+    transaction_status: TransactionStatus,
+    // Set when an extended query protocol message failed, all messages are skipped until Sync
+    skip_until_sync: bool,
+    // Set while extended query protocol messages wait for a Sync
+    in_pipeline: bool,
+    // Invalid startup options, reported once the client is authenticated
+    startup_error: Option<ErrorResponse>,
+    // Sent in BackendKeyData so the client can cancel queries
+    backend_key: Option<(i32, Vec<u8>)>,
+    // The version requested by the client, capped to the newest one we support
+    protocol_version: i32,
+    max_message_size: usize,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self {
+            phase: Phase::Startup,
+            state: State::default(),
+            transaction_status: TransactionStatus::Idle,
+            skip_until_sync: false,
+            in_pipeline: false,
+            startup_error: None,
+            backend_key: None,
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    pub fn set_backend_key(&mut self, process_id: i32, secret_key: Vec<u8>) {
+        self.backend_key = Some((process_id, secret_key));
+    }
+
+    pub fn protocol_version(&self) -> i32 {
+        self.protocol_version
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn send<T: Encode>(&mut self, msg: T) -> io::Result<()> {
+        let mut writer = Writer::with_buffer_size(0, &mut self.output);
+        msg.encode(&mut writer)?;
+        writer.flush()
+    }
+
+    // Returns the next event or `None` when more input is needed (or the driver has to respond to
+    // an earlier event first). When a fatal error is returned the error response has already been
+    // queued and the session is closed.
+    pub fn poll_event(&mut self) -> ProtocolResult<Option<Event>> {
+        loop {
+            let result = match self.phase {
+                Phase::Startup => self.poll_startup(),
+                Phase::Authenticating => self.poll_password(),
+                Phase::Ready => self.poll_message(),
+                Phase::CopyBoth => self.poll_copy(),
+                Phase::Initializing => return Ok(Some(Event::Authenticated)),
+                Phase::SelectingAuth | Phase::Executing | Phase::Closed => return Ok(None),
+            };
+
+            match result {
+                Ok(event) => return Ok(event),
+                // The body of the unknown message was skipped so it's safe to carry on, except in
+                // copy-both mode which can't be ended by us with ReadyForQuery
+                Err(e @ ProtocolError::Unsupported(_)) if self.phase != Phase::CopyBoth => {
+                    log::warn!("skipping message: {}", e);
+
+                    if self.skip_until_sync {
+                        continue;
+                    }
+
+                    self.send(ErrorResponse::new(
+                        Severity::Error,
+                        e.code().to_string(),
+                        e.to_string(),
+                    ))?;
+
+                    // In a pipeline it fails like any other message, ReadyForQuery follows the Sync
+                    match self.in_pipeline {
+                        true => self.skip_until_sync = true,
+                        false => self.ready_for_query()?,
+                    }
+                }
+                Err(e) => {
+                    if !matches!(e, ProtocolError::Io(_)) {
+                        self.send(ErrorResponse::new(
+                            Severity::Fatal,
+                            e.code().to_string(),
+                            e.to_string(),
+                        ))?;
+                    }
+
+                    self.phase = Phase::Closed;
+
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Returns the length of the next message when it has been received completely
+    fn frame_len(&self, tagged: bool, limit: usize) -> ProtocolResult<Option<usize>> {
+        let offset = tagged as usize;
+
+        if self.input.len() < offset + 4 {
+            return Ok(None);
+        }
+
+        // Validating the length up front prevents us from buffering an oversized message
+        let len = Reader::new(&self.input[offset..offset + 4]).read_len(limit)? as usize;
+
+        if self.input.len() < offset + len {
+            return Ok(None);
+        }
+
+        Ok(Some(offset + len))
+    }
+
+    fn decode<T: Decode>(&mut self, len: usize) -> ProtocolResult<T> {
+        let mut reader = Reader::new(&self.input[..len]);
+        reader.set_max_message_size(self.max_message_size);
+
+        let result = T::decode(&mut reader);
+        self.input.drain(..len);
+
+        result
+    }
+
+    // In the startup phase we optionally setup SSL encryption (not implemented yet) and parse the
+    // startup message which contains the initial state. Returns `None` without consuming anything
+    // when the message is incomplete, otherwise the startup is retried until it is accepted. A
+    // cancel request ends the connection.
+    fn poll_startup(&mut self) -> ProtocolResult<Option<Event>> {
+        loop {
+            let len = match self.frame_len(false, MAX_STARTUP_PACKET_LENGTH)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+
+            match self.decode(len)? {
+                // @TODO: currently we don't support SSL encryption
+                Handshake::SSLRequest(_) => self.send(SSLResponse::NoSsl)?,
+                Handshake::CancelRequest(msg) => {
+                    self.phase = Phase::Closed;
+
+                    return Ok(Some(Event::Cancel(msg)));
+                }
+                Handshake::StartupMessage(msg) => {
+                    let (major, minor) = (msg.version >> 16, msg.version & 0xffff);
+
+                    if major != PROTOCOL_VERSION >> 16 {
+                        self.phase = Phase::Closed;
+                        self.send(ErrorResponse::new(
+                            Severity::Fatal,
+                            "0A000".to_string(),
+                            format!(
+                                "unsupported frontend protocol {}.{}: server supports 3.0 to 3.{}",
+                                major,
+                                minor,
+                                PROTOCOL_VERSION_3_2 & 0xffff
+                            ),
+                        ))?;
+
+                        return Ok(None);
+                    }
+
+                    // Newer minor versions are answered with the newest one we support
+                    let downgraded = msg.version > PROTOCOL_VERSION_3_2;
+                    self.protocol_version = msg.version.min(PROTOCOL_VERSION_3_2);
+
+                    let unrecognized = self.handle_startup(msg);
+
+                    if downgraded || !unrecognized.is_empty() {
+                        self.send(NegotiateProtocolVersion::new(
+                            self.protocol_version & 0xffff,
+                            unrecognized,
+                        ))?;
+                    }
+
+                    if !self.state.user.is_empty() {
+                        self.phase = Phase::SelectingAuth;
+
+                        return Ok(Some(Event::Startup));
+                    }
+
+                    log::error!("no user specified, retrying startup");
+
+                    self.state = State::default();
+                    self.send(ErrorResponse::new(
+                        Severity::Error,
+                        "P0001".to_string(),
+                        "the 'user' option is mandatory".to_string(),
+                    ))?;
+                }
+            }
+        }
+    }
+
+    // Returns the protocol options (`_pq_.` parameters) we don't support, which is all of them
+    fn handle_startup(&mut self, startup_msg: StartupMessage) -> Vec<String> {
+        let mut unrecognized = vec![];
+
+        for (name, value) in startup_msg.params.into_iter() {
+            match name.as_str() {
+                _ if name.starts_with("_pq_.") => unrecognized.push(name),
+                "user" => self.state.user = value,
+                "database" => self.state.database = value,
+                "replication" => match Replication::parse(&value) {
+                    Some(replication) => self.state.replication = replication,
+                    None => {
+                        self.startup_error = Some(
+                            ErrorResponse::new(
+                                Severity::Fatal,
+                                "22023".to_string(),
+                                format!(
+                                    "invalid value for parameter \"replication\": \"{}\"",
+                                    value
+                                ),
+                            )
+                            .with_field(
+                                Field::Hint,
+                                "Valid values are: \"false\", 0, \"true\", 1, \"database\"."
+                                    .to_string(),
+                            ),
+                        )
+                    }
+                },
+                _ => {
+                    self.state.extra_params.insert(name, value);
+                }
+            }
+        }
+
+        if self.state.database.is_empty() {
+            self.state.database = self.state.user.clone();
+        }
+
+        let mut settings = Settings::new(&self.state.user, &self.state.extra_params);
+
+        if let Some(options) = self.state.extra_params.get("options") {
+            if let Err(e) = settings.apply_options(options) {
+                self.startup_error.get_or_insert(e);
+            }
+        }
+
+        *self.state.settings() = settings;
+
+        unrecognized
+    }
+
+    fn poll_password(&mut self) -> ProtocolResult<Option<Event>> {
+        let len = match self.frame_len(true, self.max_message_size)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        if self.input[0] != b'p' {
+            return Err(ProtocolError::malformed(format!(
+                "expected password response, got message type {:?}",
+                self.input[0] as char
+            )));
+        }
+
+        self.input.remove(0);
+
+        Ok(Some(Event::Password(self.decode(len - 1)?)))
+    }
+
+    fn poll_message(&mut self) -> ProtocolResult<Option<Event>> {
+        loop {
+            let len = match self.frame_len(true, self.max_message_size)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+
+            let msg = self.decode(len)?;
+
+            // Like PostgreSQL everything the client pipelined after the failed message is ignored
+            if self.skip_until_sync
+                && !matches!(
+                    msg,
+                    IncomingMessage::Sync(_) | IncomingMessage::Terminate(_)
+                )
+            {
+                continue;
+            }
+
+            return Ok(Some(match msg {
+                IncomingMessage::Query(query) => {
+                    log::debug!("received query: {}", query.query);
+
+                    // Answered with ReadyForQuery, which ends a pipeline like Sync
+                    self.phase = Phase::Executing;
+                    self.in_pipeline = false;
+                    Event::Query(query)
+                }
+                IncomingMessage::Extended(msg) => {
+                    self.phase = Phase::Executing;
+                    self.in_pipeline = true;
+                    Event::Extended(msg)
+                }
+                IncomingMessage::Sync(_) => {
+                    self.phase = Phase::Executing;
+                    self.skip_until_sync = false;
+                    self.in_pipeline = false;
+                    Event::Sync
+                }
+                IncomingMessage::Flush(_) => Event::Flush,
+                IncomingMessage::FunctionCall(call) => {
+                    self.phase = Phase::Executing;
+                    Event::FunctionCall(call)
+                }
+                IncomingMessage::Terminate(_) => {
+                    self.phase = Phase::Closed;
+                    Event::Terminate
+                }
+                // Left over from a copy which ended with an error, the protocol says to ignore them
+                IncomingMessage::CopyData(_)
+                | IncomingMessage::CopyDone(_)
+                | IncomingMessage::CopyFail(_) => continue,
+            }));
+        }
+    }
+
+    fn poll_copy(&mut self) -> ProtocolResult<Option<Event>> {
+        loop {
+            let len = match self.frame_len(true, self.max_message_size)? {
+                Some(len) => len,
+                None => return Ok(None),
+            };
+
+            return Ok(Some(match self.decode(len)? {
+                IncomingMessage::CopyData(data) => Event::CopyData(data),
+                IncomingMessage::CopyDone(_) => {
+                    self.phase = Phase::Executing;
+                    Event::CopyDone
+                }
+                IncomingMessage::CopyFail(fail) => {
+                    self.phase = Phase::Executing;
+                    Event::CopyFail(fail)
+                }
+                IncomingMessage::Flush(_) | IncomingMessage::Sync(_) => continue,
+                IncomingMessage::Terminate(_) => {
+                    self.phase = Phase::Closed;
+                    Event::Terminate
+                }
+                _ => {
+                    return Err(ProtocolError::malformed(
+                        "unexpected message type during copy-both mode",
+                    ))
+                }
+            }));
+        }
+    }
+
+    fn ready_for_query(&mut self) -> io::Result<()> {
+        self.phase = Phase::Ready;
+        self.send(ReadyForQuery::new(self.transaction_status))
+    }
+
+    pub fn authenticate(&mut self, method: AuthMethod) -> io::Result<()> {
+        debug_assert_eq!(self.phase, Phase::SelectingAuth);
+        log::debug!("selecting auth method: {:?}", method);
+
+        match method {
+            AuthMethod::CleartextPassword => {
+                self.phase = Phase::Authenticating;
+                self.send(AuthenticationCleartextPassword {})
+            }
+            AuthMethod::None => self.auth_result(Ok(())),
+            AuthMethod::Reject(e) => {
+                self.phase = Phase::Closed;
+                self.send(e)
+            }
+        }
+    }
+
+    pub fn auth_result(&mut self, result: AuthResult) -> io::Result<()> {
+        match result {
+            Ok(_) => {
+                self.phase = Phase::Initializing;
+                self.send(AuthenticationOk {})?;
+
+                // Like PostgreSQL the options are only validated for authenticated clients
+                if let Some(e) = self.startup_error.take() {
+                    self.phase = Phase::Closed;
+                    self.send(e)?;
+                }
+
+                Ok(())
+            }
+            Err(e) => {
+                let msg = e.get_field(Field::Message).unwrap_or_default();
+
+                log::debug!("auth failed: {}", msg);
+
+                self.phase = Phase::Closed;
+                self.send(ErrorResponse::new(
+                    Severity::Error,
+                    "28P01".to_string(),
+                    msg.to_string(),
+                ))
+            }
+        }
+    }
+
+    pub fn startup_result(&mut self, result: Result<(), ErrorResponse>) -> io::Result<()> {
+        debug_assert_eq!(self.phase, Phase::Initializing);
+
+        // The executor seeds the settings when it's backed by a server, which checked the options
+        let result = result.and_then(|_| self.state.settings().check_options());
+
+        match result {
+            Ok(_) => {
+                let reportable = self.state.settings().reportable();
+
+                for (name, value) in reportable {
+                    self.send(ParameterStatus::new(name, value))?;
+                }
+
+                if let Some((process_id, secret_key)) = self.backend_key.clone() {
+                    self.send(BackendKeyData::new(process_id, secret_key))?;
+                }
+
+                self.ready_for_query()
+            }
+            Err(e) => {
+                self.phase = Phase::Closed;
+                self.send(e)
+            }
+        }
+    }
+
+    pub fn query_result(&mut self, result: QueryResult) -> io::Result<()> {
+        debug_assert_eq!(self.phase, Phase::Executing);
+
+        match result {
+            Ok(command_complete) => self.send(command_complete),
+            Err(e) => self.send(e),
+        }?;
+
+        self.ready_for_query()
+    }
+
+    // Finishes a query (or Sync) after its results have been sent through the `ResultSink`
+    pub fn query_complete(&mut self, transaction_status: TransactionStatus) -> io::Result<()> {
+        debug_assert_eq!(self.phase, Phase::Executing);
+
+        self.transaction_status = transaction_status;
+        self.ready_for_query()
+    }
+
+    // Switches to copy-both mode while executing a query, events for the data the client sends
+    // are produced until it sends CopyDone
+    pub fn start_copy_both(&mut self) -> io::Result<()> {
+        debug_assert_eq!(self.phase, Phase::Executing);
+
+        self.phase = Phase::CopyBoth;
+        self.send(CopyBothResponse::new())
+    }
+
+    // Ends copy-both mode from our side, the query still has to be completed afterwards. When
+    // the client hasn't sent CopyDone yet its remaining CopyData is ignored.
+    pub fn end_copy_both(&mut self) -> io::Result<()> {
+        self.phase = Phase::Executing;
+        self.send(CopyDone::new())
+    }
+
+    // Leaves copy-both mode because of an error, the error ends the query without CommandComplete
+    pub fn copy_both_error(&mut self, error: ErrorResponse) -> io::Result<()> {
+        self.phase = Phase::Executing;
+        self.send(error)
+    }
+
+    pub fn extended_result(&mut self, result: Result<(), ErrorResponse>) -> io::Result<()> {
+        debug_assert_eq!(self.phase, Phase::Executing);
+
+        self.phase = Phase::Ready;
+
+        if let Err(e) = result {
+            self.skip_until_sync = true;
+            self.send(e)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ResultSink for Session {
+    fn push(&mut self, msg: BackendMessage) -> io::Result<()> {
+        self.send(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::messages::{CommandComplete, CommandTag};
+
+    const STARTUP: &[u8] = b"\0\0\0\x12\0\x03\0\0user\0bob\0\0";
+
+    #[test]
+    fn test_startup_and_query() {
+        let mut session = Session::new();
+
+        // Any number of SSL requests is declined
+        session.receive(&b"\0\0\0\x08\x04\xd2\x16\x2f".repeat(50_000));
+
+        // Incomplete messages don't produce events
+        session.receive(&STARTUP[..6]);
+        assert!(session.poll_event().unwrap().is_none());
+        assert_eq!(session.take_output(), b"N".repeat(50_000));
+
+        session.receive(&STARTUP[6..]);
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Startup)
+        ));
+        assert_eq!(session.state().user, "bob");
+        assert_eq!(session.state().database, "bob");
+
+        session.authenticate(AuthMethod::None).unwrap();
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Authenticated)
+        ));
+        session.startup_result(Ok(())).unwrap();
+        assert_eq!(session.phase(), Phase::Ready);
+
+        // The reportable settings are sent before ReadyForQuery
+        let output = session.take_output();
+        assert!(output.starts_with(b"R\0\0\0\x08\0\0\0\0S"));
+        assert!(output.ends_with(b"TimeZone\0UTC\0Z\0\0\0\x05I"));
+        assert!(output
+            .windows(26)
+            .any(|w| w == b"session_authorization\0bob\0"));
+
+        session.receive(b"Q\0\0\0\x0dselect 1\0");
+        match session.poll_event().unwrap() {
+            Some(Event::Query(query)) => assert_eq!(query.query, "select 1"),
+            _ => panic!("expected a query"),
+        }
+
+        // No new events are produced until the query has been answered
+        session.receive(b"X\0\0\0\x04");
+        assert!(session.poll_event().unwrap().is_none());
+
+        session
+            .query_result(Ok(CommandComplete::new(CommandTag::Select(0))))
+            .unwrap();
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Terminate)
+        ));
+        assert_eq!(session.phase(), Phase::Closed);
+    }
+
+    #[test]
+    fn test_password() {
+        let mut session = Session::new();
+        session.receive(STARTUP);
+        session.poll_event().unwrap();
+        session.authenticate(AuthMethod::CleartextPassword).unwrap();
+        assert_eq!(session.take_output(), b"R\0\0\0\x08\0\0\0\x03");
+
+        session.receive(b"p\0\0\0\x0bsecret\0");
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Password(_))
+        ));
+
+        session
+            .auth_result(Err(ErrorResponse::new(
+                Severity::Error,
+                "28P01".to_string(),
+                "invalid password".to_string(),
+            )))
+            .unwrap();
+        assert_eq!(session.phase(), Phase::Closed);
+    }
+
+    #[test]
+    fn test_protocol_negotiation() {
+        let startup = |version: i32, params: Vec<(&str, &str)>| {
+            let params = params
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>();
+            let mut msg = StartupMessage::new(params.into());
+            msg.version = version;
+
+            let mut writer = Writer::new(vec![]);
+            msg.encode(&mut writer).unwrap();
+            writer.buffer().to_vec()
+        };
+
+        // 3.2 is supported as is
+        let mut session = Session::new();
+        session.receive(&startup(PROTOCOL_VERSION_3_2, vec![("user", "bob")]));
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Startup)
+        ));
+        assert_eq!(session.protocol_version(), PROTOCOL_VERSION_3_2);
+        assert!(session.output().is_empty());
+
+        // Newer minor versions and protocol options are negotiated down
+        let mut session = Session::new();
+        session.receive(&startup(
+            PROTOCOL_VERSION + 5,
+            vec![("user", "bob"), ("_pq_.compression", "on")],
+        ));
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Startup)
+        ));
+        assert_eq!(session.protocol_version(), PROTOCOL_VERSION_3_2);
+        assert!(session.state().extra_params().is_empty());
+        assert_eq!(
+            session.take_output(),
+            b"v\0\0\0\x1d\0\0\0\x02\0\0\0\x01_pq_.compression\0"
+        );
+
+        // Other major versions are rejected
+        let mut session = Session::new();
+        session.receive(&startup(4 << 16, vec![("user", "bob")]));
+        assert!(session.poll_event().unwrap().is_none());
+        assert_eq!(session.phase(), Phase::Closed);
+
+        let output = session.take_output();
+        assert!(output.starts_with(b"E"));
+        assert!(output.windows(5).any(|w| w == b"0A000"));
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        let mut session = Session::new();
+        session.receive(STARTUP);
+        session.poll_event().unwrap();
+        session.authenticate(AuthMethod::None).unwrap();
+        session.poll_event().unwrap();
+        session.startup_result(Ok(())).unwrap();
+        session.take_output();
+
+        session.receive(b"Q\0\0\0\x02");
+        assert!(matches!(
+            session.poll_event(),
+            Err(ProtocolError::Malformed(_))
+        ));
+        assert_eq!(session.phase(), Phase::Closed);
+        assert_eq!(session.output()[0], b'E');
+    }
+
+    // The types of the messages in the output
+    fn tags(mut output: &[u8]) -> Vec<u8> {
+        let mut tags = vec![];
+
+        while !output.is_empty() {
+            let len = u32::from_be_bytes(output[1..5].try_into().unwrap()) as usize;
+            tags.push(output[0]);
+            output = &output[1 + len..];
+        }
+
+        tags
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let mut session = Session::new();
+        session.receive(STARTUP);
+        session.poll_event().unwrap();
+        session.authenticate(AuthMethod::None).unwrap();
+        session.poll_event().unwrap();
+        session.startup_result(Ok(())).unwrap();
+        session.take_output();
+
+        // Execute, Execute, Query, Flush, Sync and Execute sent at once
+        session.receive(b"E\0\0\0\x09\0\0\0\0\0E\0\0\0\x09\0\0\0\0\0Q\0\0\0\x0dselect 1\0");
+        session.receive(b"H\0\0\0\x04S\0\0\0\x04E\0\0\0\x09\0\0\0\0\0");
+
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Extended(ExtendedMessage::Execute(_)))
+        ));
+        session
+            .extended_result(Err(ErrorResponse::new(
+                Severity::Error,
+                "42000".to_string(),
+                "oops".to_string(),
+            )))
+            .unwrap();
+
+        // Everything up to Sync is skipped after the error
+        assert!(matches!(session.poll_event().unwrap(), Some(Event::Sync)));
+        session.query_complete(TransactionStatus::Idle).unwrap();
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::Extended(ExtendedMessage::Execute(_)))
+        ));
+
+        let output = session.take_output();
+        assert_eq!(output[0], b'E');
+        assert!(output.ends_with(b"Z\0\0\0\x05I"));
+
+        // Unknown messages in a pipeline fail it, ReadyForQuery only follows the Sync
+        session.extended_result(Ok(())).unwrap();
+        session.receive(b"z\0\0\0\x04E\0\0\0\x09\0\0\0\0\0z\0\0\0\x04S\0\0\0\x04");
+        assert!(matches!(session.poll_event().unwrap(), Some(Event::Sync)));
+        session.query_complete(TransactionStatus::Idle).unwrap();
+
+        assert_eq!(tags(&session.take_output()), b"EZ");
+
+        // Outside of a pipeline they're answered right away
+        session.receive(b"z\0\0\0\x04");
+        assert!(session.poll_event().unwrap().is_none());
+        assert_eq!(tags(&session.take_output()), b"EZ");
+
+        // Any number of messages is skipped without growing the stack
+        session.receive(b"E\0\0\0\x09\0\0\0\0\0");
+        session.poll_event().unwrap();
+        session.extended_result(Ok(())).unwrap();
+        session.receive(&b"c\0\0\0\x04".repeat(50_000));
+        session.receive(b"S\0\0\0\x04");
+        assert!(matches!(session.poll_event().unwrap(), Some(Event::Sync)));
+    }
+
+    #[test]
+    fn test_copy_both() {
+        let mut session = Session::new();
+        session.receive(STARTUP);
+        session.poll_event().unwrap();
+        session.authenticate(AuthMethod::None).unwrap();
+        session.poll_event().unwrap();
+        session.startup_result(Ok(())).unwrap();
+        session.take_output();
+
+        session.receive(b"Q\0\0\0\x0dstart it\0");
+        session.poll_event().unwrap();
+        session.start_copy_both().unwrap();
+        assert_eq!(session.phase(), Phase::CopyBoth);
+        assert_eq!(session.take_output(), b"W\0\0\0\x07\0\0\0");
+
+        session.receive(b"d\0\0\0\x06hic\0\0\0\x04");
+        match session.poll_event().unwrap() {
+            Some(Event::CopyData(data)) => assert_eq!(data.data, b"hi"),
+            _ => panic!("expected copy data"),
+        }
+        assert!(matches!(
+            session.poll_event().unwrap(),
+            Some(Event::CopyDone)
+        ));
+        assert_eq!(session.phase(), Phase::Executing);
+
+        session.end_copy_both().unwrap();
+        session.query_complete(TransactionStatus::Idle).unwrap();
+        assert_eq!(session.take_output(), b"c\0\0\0\x04Z\0\0\0\x05I");
+
+        // Unknown messages during copy-both mode are fatal
+        session.receive(b"Q\0\0\0\x0dstart it\0");
+        session.poll_event().unwrap();
+        session.start_copy_both().unwrap();
+        session.take_output();
+
+        session.receive(b"z\0\0\0\x04");
+        assert!(matches!(
+            session.poll_event(),
+            Err(ProtocolError::Unsupported(b'z'))
+        ));
+        assert_eq!(session.phase(), Phase::Closed);
+
+        let output = session.take_output();
+        assert_eq!(output[0], b'E');
+        assert!(!output.contains(&b'Z'));
+    }
+
+    #[test]
+    fn test_replication() {
+        for (value, replication) in [
+            ("true", Some(Replication::Enabled)),
+            ("ON", Some(Replication::Enabled)),
+            ("y", Some(Replication::Enabled)),
+            ("1", Some(Replication::Enabled)),
+            ("off", Some(Replication::Disabled)),
+            ("f", Some(Replication::Disabled)),
+            ("0", Some(Replication::Disabled)),
+            ("database", Some(Replication::Database)),
+            ("o", None),
+            ("enabled", None),
+            ("", None),
+        ] {
+            assert_eq!(Replication::parse(value), replication, "{}", value);
+        }
+
+        // Invalid values are reported once the client is authenticated
+        let mut session = Session::new();
+        session.receive(b"\0\0\0\x24\0\x03\0\0user\0bob\0replication\0maybe\0\0");
+        session.poll_event().unwrap();
+        session.authenticate(AuthMethod::None).unwrap();
+        assert_eq!(session.phase(), Phase::Closed);
+
+        let output = session.take_output();
+        assert!(output.windows(6).any(|w| w == b"C22023"));
+    }
+}
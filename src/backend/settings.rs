@@ -0,0 +1,809 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use crate::backend::lexer::{tokenize, unquote, Token};
+use crate::backend::query_exec::ResultSink;
+use crate::backend::response::{field, text};
+use crate::backend::{QueryExec, State, Type};
+use crate::proto::messages::{
+    BackendMessage, CommandComplete, CommandTag, DataRow, ErrorResponse, Field, NoticeResponse,
+    ParameterStatus, RowDescription, Severity, TransactionStatus,
+};
+
+const DEFAULTS: [(&str, &str); 22] = [
+    ("application_name", ""),
+    ("client_encoding", "UTF8"),
+    ("DateStyle", "ISO, MDY"),
+    ("default_transaction_isolation", "read committed"),
+    ("default_transaction_read_only", "off"),
+    ("extra_float_digits", "1"),
+    ("idle_in_transaction_session_timeout", "0"),
+    ("in_hot_standby", "off"),
+    ("integer_datetimes", "on"),
+    ("IntervalStyle", "postgres"),
+    ("is_superuser", "off"),
+    ("lock_timeout", "0"),
+    ("max_identifier_length", "63"),
+    ("search_path", "\"$user\", public"),
+    ("server_encoding", "UTF8"),
+    ("server_version", "14.0"),
+    ("server_version_num", "140000"),
+    ("standard_conforming_strings", "on"),
+    ("statement_timeout", "0"),
+    ("TimeZone", "UTC"),
+    ("transaction_isolation", "read committed"),
+    ("work_mem", "4MB"),
+];
+
+// Settings which are reported to clients but can't be changed by them
+const READ_ONLY: [&str; 8] = [
+    "in_hot_standby",
+    "integer_datetimes",
+    "is_superuser",
+    "max_identifier_length",
+    "server_encoding",
+    "server_version",
+    "server_version_num",
+    "session_authorization",
+];
+
+// Settings the client is informed about with ParameterStatus whenever they change
+const REPORTABLE: [&str; 13] = [
+    "application_name",
+    "client_encoding",
+    "datestyle",
+    "default_transaction_read_only",
+    "in_hot_standby",
+    "integer_datetimes",
+    "intervalstyle",
+    "is_superuser",
+    "server_encoding",
+    "server_version",
+    "session_authorization",
+    "standard_conforming_strings",
+    "timezone",
+];
+
+struct Setting {
+    // As spelled in the defaults or by the first SET
+    name: String,
+    value: String,
+    // The value RESET restores, the startup parameter if there was one
+    reset: String,
+    // The value to restore at the end of the transaction after SET LOCAL
+    saved: Option<String>,
+}
+
+// The session settings (GUCs), seeded with the defaults and the startup parameters
+pub struct Settings {
+    // By lowercase name, setting names are case-insensitive
+    settings: BTreeMap<String, Setting>,
+    // Whether the settings were seeded by a server, the ones it didn't report are left to it
+    seeded: bool,
+    // Settings passed in the options which aren't known here, a server validates them instead
+    unrecognized: Vec<String>,
+}
+
+impl Settings {
+    pub fn new(user: &str, params: &HashMap<String, String>) -> Self {
+        let mut settings = Self {
+            settings: BTreeMap::new(),
+            seeded: false,
+            unrecognized: vec![],
+        };
+
+        let session_authorization = ("session_authorization", user);
+
+        for (name, value) in DEFAULTS.into_iter().chain([session_authorization]) {
+            settings.insert(name, value);
+        }
+
+        // The options are applied separately as they can be rejected
+        for (name, value) in params.iter().filter(|(name, _)| *name != "options") {
+            settings.insert(name, value);
+        }
+
+        settings
+    }
+
+    // Replaces the settings with the parameters reported by the server the session is connected
+    // to, so that clients see its values rather than the defaults
+    pub fn seed<'a>(&mut self, params: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        self.settings.clear();
+        self.seeded = true;
+
+        for (name, value) in params {
+            self.insert(name, value);
+        }
+    }
+
+    fn insert(&mut self, name: &str, value: &str) {
+        let setting = self
+            .settings
+            .entry(name.to_lowercase())
+            .or_insert_with(|| Setting {
+                name: name.to_string(),
+                value: String::new(),
+                reset: String::new(),
+                saved: None,
+            });
+
+        setting.value = value.to_string();
+        setting.reset = value.to_string();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.settings
+            .get(&name.to_lowercase())
+            .map(|setting| setting.value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.settings
+            .values()
+            .map(|setting| (setting.name.as_str(), setting.value.as_str()))
+    }
+
+    pub fn is_reportable(name: &str) -> bool {
+        REPORTABLE.contains(&name.to_lowercase().as_str())
+    }
+
+    pub fn is_read_only(name: &str) -> bool {
+        READ_ONLY.contains(&name.to_lowercase().as_str())
+    }
+
+    // Applies the settings passed with the `options` startup parameter, such as
+    // `-c search_path=app --statement-timeout=5s`. Settings which aren't known here are left to
+    // `check_options`.
+    pub(crate) fn apply_options(&mut self, options: &str) -> Result<(), ErrorResponse> {
+        for (name, value) in parse_options(options)? {
+            if Self::is_read_only(&name) {
+                return Err(cannot_be_changed(Severity::Fatal, &name));
+            }
+
+            if self.get(&name).is_none() && !name.contains('.') {
+                self.unrecognized.push(name);
+                continue;
+            }
+
+            self.insert(&name, &value);
+        }
+
+        Ok(())
+    }
+
+    // Rejects the unrecognized settings in the options, unless the settings were seeded by a
+    // server which accepted them
+    pub(crate) fn check_options(&self) -> Result<(), ErrorResponse> {
+        match self.unrecognized.first() {
+            Some(name) if !self.seeded => Err(ErrorResponse::new(
+                Severity::Fatal,
+                "42704".to_string(),
+                format!("unrecognized configuration parameter \"{}\"", name),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn reportable(&self) -> Vec<(String, String)> {
+        self.iter()
+            .filter(|(name, _)| Self::is_reportable(name))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // Sets the value (or resets it when None), local values only last until the end of the
+    // transaction
+    pub(crate) fn set(&mut self, name: &str, value: Option<&str>, local: bool) {
+        let key = name.to_lowercase();
+
+        if self.seeded && !self.settings.contains_key(&key) {
+            return;
+        }
+
+        let setting = self.settings.entry(key).or_insert_with(|| Setting {
+            name: name.to_string(),
+            value: String::new(),
+            reset: String::new(),
+            saved: None,
+        });
+
+        let value = value.unwrap_or(&setting.reset).to_string();
+
+        match (local, setting.saved.as_mut()) {
+            (true, None) => setting.saved = Some(setting.value.clone()),
+            (false, Some(saved)) => *saved = value.clone(),
+            _ => {}
+        }
+
+        setting.value = value;
+    }
+
+    pub(crate) fn reset_all(&mut self) {
+        let names = self.settings.keys().cloned().collect::<Vec<_>>();
+
+        for name in names {
+            self.set(&name, None, false);
+        }
+    }
+
+    // Reverts the values set with SET LOCAL
+    pub(crate) fn end_transaction(&mut self) {
+        for setting in self.settings.values_mut() {
+            if let Some(saved) = setting.saved.take() {
+                setting.value = saved;
+            }
+        }
+    }
+}
+
+fn cannot_be_changed(severity: Severity, name: &str) -> ErrorResponse {
+    ErrorResponse::new(
+        severity,
+        "55P02".to_string(),
+        format!("parameter \"{}\" cannot be changed", name),
+    )
+}
+
+fn invalid_option(arg: &str) -> ErrorResponse {
+    ErrorResponse::new(
+        Severity::Fatal,
+        "42601".to_string(),
+        format!("invalid command-line argument for server process: {}", arg),
+    )
+    .with_field(
+        Field::Hint,
+        "Only -c name=value and --name=value are supported.".to_string(),
+    )
+}
+
+// Splits the options on whitespace, like PostgreSQL a backslash escapes the next character so
+// that values can contain spaces
+fn split_options(options: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut chars = options.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => arg.extend(chars.next()),
+            c if c.is_ascii_whitespace() => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+
+    args
+}
+
+// Parses `-c name=value`, `-cname=value` and `--name=value` arguments, dashes in names are
+// converted to underscores
+pub fn parse_options(options: &str) -> Result<Vec<(String, String)>, ErrorResponse> {
+    let mut settings = vec![];
+    let mut args = split_options(options).into_iter();
+
+    while let Some(arg) = args.next() {
+        let setting = match arg.strip_prefix("--") {
+            Some(setting) => setting.to_string(),
+            None if arg == "-c" => args.next().ok_or_else(|| invalid_option(&arg))?,
+            None => match arg.strip_prefix("-c") {
+                Some(setting) => setting.to_string(),
+                None => return Err(invalid_option(&arg)),
+            },
+        };
+
+        let (name, value) = setting.split_once('=').ok_or_else(|| {
+            ErrorResponse::new(
+                Severity::Fatal,
+                "42601".to_string(),
+                format!("--{} requires a value", setting),
+            )
+        })?;
+
+        settings.push((name.replace('-', "_"), value.to_string()));
+    }
+
+    Ok(settings)
+}
+
+// The SET, RESET and SHOW statements the manager handles, other forms (such as SET ROLE or SET
+// TRANSACTION) are left to the executor
+#[derive(Debug, Clone, PartialEq)]
+pub enum SetStatement {
+    // The value is kept as written (including quotes), None sets the default
+    Set {
+        name: String,
+        value: Option<Vec<String>>,
+        local: bool,
+    },
+    Reset(String),
+    ResetAll,
+    Show(String),
+    ShowAll,
+}
+
+impl Display for SetStatement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Set { name, value, local } => write!(
+                f,
+                "SET {}{} TO {}",
+                if *local { "LOCAL " } else { "" },
+                name,
+                value
+                    .as_ref()
+                    .map_or("DEFAULT".to_string(), |v| v.join(", "))
+            ),
+            Self::Reset(name) => write!(f, "RESET {}", name),
+            Self::ResetAll => f.write_str("RESET ALL"),
+            Self::Show(name) => write!(f, "SHOW {}", name),
+            Self::ShowAll => f.write_str("SHOW ALL"),
+        }
+    }
+}
+
+fn word(tokens: &[Token], n: usize) -> Option<String> {
+    match tokens.get(n)? {
+        Token::Word(word) => Some(word.to_lowercase()),
+        _ => None,
+    }
+}
+
+// Parses a setting name, custom settings are qualified (`myapp.tenant`)
+fn name(tokens: &[Token]) -> Option<(String, usize)> {
+    let part = |n: usize| match tokens.get(n)? {
+        Token::Word(word) => Some(word.to_lowercase()),
+        Token::QuotedIdent(ident) => unquote(ident),
+        _ => None,
+    };
+
+    let name = part(0)?;
+
+    match tokens.get(1) {
+        Some(Token::Punct('.')) => Some((format!("{}.{}", name, part(2)?), 3)),
+        _ => Some((name, 1)),
+    }
+}
+
+// Parses `value [, ...]` up to the end of the statement
+fn values(tokens: &[Token]) -> Option<Vec<String>> {
+    let mut values = vec![];
+    let mut rest = tokens;
+
+    loop {
+        let (value, len) = match rest {
+            [Token::Punct('-'), Token::Literal(n), ..] if !n.starts_with('\'') => {
+                (format!("-{}", n), 2)
+            }
+            [Token::Literal(literal), ..] if !literal.starts_with('$') => (literal.to_string(), 1),
+            [Token::Word(word), ..] | [Token::QuotedIdent(word), ..] => (word.to_string(), 1),
+            _ => return None,
+        };
+
+        values.push(value);
+
+        match &rest[len..] {
+            [] => return Some(values),
+            [Token::Punct(','), tail @ ..] => rest = tail,
+            _ => return None,
+        }
+    }
+}
+
+fn parse_statement(tokens: &[Token]) -> Option<SetStatement> {
+    let keyword = word(tokens, 0)?;
+    let second = word(tokens, 1);
+    let third = word(tokens, 2);
+
+    match (keyword.as_str(), second.as_deref(), third.as_deref()) {
+        ("show", Some("all"), None) if tokens.len() == 2 => Some(SetStatement::ShowAll),
+        ("show", Some("time"), Some("zone")) if tokens.len() == 3 => {
+            Some(SetStatement::Show("timezone".to_string()))
+        }
+        ("show", Some("transaction"), Some("isolation")) => match word(tokens, 3)?.as_str() {
+            "level" if tokens.len() == 4 => {
+                Some(SetStatement::Show("transaction_isolation".to_string()))
+            }
+            _ => None,
+        },
+        ("show", Some("session"), Some("authorization")) if tokens.len() == 3 => {
+            Some(SetStatement::Show("session_authorization".to_string()))
+        }
+        ("show", ..) => match name(&tokens[1..])? {
+            (name, len) if len + 1 == tokens.len() => Some(SetStatement::Show(name)),
+            _ => None,
+        },
+        ("reset", Some("all"), None) if tokens.len() == 2 => Some(SetStatement::ResetAll),
+        ("reset", Some("time"), Some("zone")) if tokens.len() == 3 => {
+            Some(SetStatement::Reset("timezone".to_string()))
+        }
+        ("reset", Some("role" | "session"), _) => None,
+        ("reset", ..) => match name(&tokens[1..])? {
+            (name, len) if len + 1 == tokens.len() => Some(SetStatement::Reset(name)),
+            _ => None,
+        },
+        ("set", ..) => {
+            let (local, tokens) = match second.as_deref() {
+                Some("local") => (true, &tokens[2..]),
+                // Not to be confused with SET SESSION AUTHORIZATION or CHARACTERISTICS
+                Some("session")
+                    if !matches!(third.as_deref(), Some("authorization" | "characteristics")) =>
+                {
+                    (false, &tokens[2..])
+                }
+                _ => (false, &tokens[1..]),
+            };
+
+            let (name, value) = match (word(tokens, 0).as_deref(), word(tokens, 1).as_deref()) {
+                (Some("time"), Some("zone")) => match word(tokens, 2).as_deref() {
+                    Some("local" | "default") if tokens.len() == 3 => {
+                        ("timezone".to_string(), None)
+                    }
+                    _ => ("timezone".to_string(), Some(&tokens[2..])),
+                },
+                (Some("names"), _) => ("client_encoding".to_string(), Some(&tokens[1..])),
+                (Some("schema"), _) => ("search_path".to_string(), Some(&tokens[1..])),
+                (Some("role" | "session" | "transaction" | "constraints"), _) => return None,
+                _ => {
+                    let (name, len) = name(tokens)?;
+
+                    match tokens.get(len) {
+                        Some(Token::Punct('=')) => {}
+                        Some(Token::Word(to)) if to.eq_ignore_ascii_case("to") => {}
+                        _ => return None,
+                    }
+
+                    match &tokens[len + 1..] {
+                        [Token::Word(default)] if default.eq_ignore_ascii_case("default") => {
+                            (name, None)
+                        }
+                        value => (name, Some(value)),
+                    }
+                }
+            };
+
+            let value = match value {
+                Some(value) => Some(values(value)?),
+                None => None,
+            };
+
+            Some(SetStatement::Set { name, value, local })
+        }
+        _ => None,
+    }
+}
+
+// Returns the statements when the query consists only of statements the manager handles
+pub fn parse(query: &str) -> Option<Vec<SetStatement>> {
+    let tokens = tokenize(query)
+        .into_iter()
+        .filter(|token| !matches!(token, Token::Comment(_)))
+        .collect::<Vec<_>>();
+
+    let statements = tokens
+        .split(|token| *token == Token::Semicolon)
+        .filter(|tokens| !tokens.is_empty())
+        .map(parse_statement)
+        .collect::<Option<Vec<_>>>()?;
+
+    match statements.is_empty() {
+        true => None,
+        false => Some(statements),
+    }
+}
+
+// Converts the value as written to the value reported by SHOW
+fn flatten(value: &[String]) -> String {
+    value
+        .iter()
+        .map(|v| match v.strip_prefix('\'') {
+            Some(v) => v.strip_suffix('\'').unwrap_or(v).replace("''", "'"),
+            None => v.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn show(settings: &Settings, name: &str) -> Option<Vec<BackendMessage>> {
+    let (fields, rows) = match name {
+        "" => (
+            vec![field("name", Type::TEXT), field("setting", Type::TEXT)],
+            settings
+                .iter()
+                .map(|(name, value)| vec![text(name), text(value)])
+                .collect(),
+        ),
+        name => (
+            vec![field(name, Type::TEXT)],
+            vec![vec![text(settings.get(name)?)]],
+        ),
+    };
+
+    let mut messages = vec![BackendMessage::RowDescription(RowDescription::new(fields))];
+    messages.extend(
+        rows.into_iter()
+            .map(|row| BackendMessage::DataRow(DataRow::new(row))),
+    );
+    messages.push(BackendMessage::CommandComplete(CommandComplete::new(
+        CommandTag::Other("SHOW".to_string()),
+    )));
+
+    Some(messages)
+}
+
+// Executes the statements against the session settings. The executor can reject changes before
+// they are applied. Returns false when a setting is shown the manager doesn't know about (or all
+// of them are, when seeded by a server), the query has to be executed by the executor in that case.
+pub(crate) fn execute<Q: QueryExec>(
+    state: &State,
+    statements: &[SetStatement],
+    transaction_status: TransactionStatus,
+    query_exec: &mut Q,
+    sink: &mut dyn ResultSink,
+) -> io::Result<bool> {
+    let settings = state.settings();
+    let unknown = statements.iter().any(|statement| match statement {
+        SetStatement::Show(name) => settings.get(name).is_none(),
+        SetStatement::ShowAll => settings.seeded,
+        _ => false,
+    });
+
+    if unknown {
+        return Ok(false);
+    }
+
+    let reported = settings.reportable();
+    drop(settings);
+
+    for statement in statements {
+        if transaction_status == TransactionStatus::Failed {
+            sink.push(BackendMessage::ErrorResponse(ErrorResponse::new(
+                Severity::Error,
+                "25P02".to_string(),
+                "current transaction is aborted, commands ignored until end of transaction block"
+                    .to_string(),
+            )))?;
+            break;
+        }
+
+        let (messages, tag) = match statement {
+            SetStatement::Show(name) => (show(&state.settings(), name).unwrap(), None),
+            SetStatement::ShowAll => (show(&state.settings(), "").unwrap(), None),
+            SetStatement::Set { local: true, .. }
+                if transaction_status == TransactionStatus::Idle =>
+            {
+                sink.push(BackendMessage::NoticeResponse(NoticeResponse::new(
+                    Severity::Warning,
+                    "25P01".to_string(),
+                    "SET LOCAL can only be used in transaction blocks".to_string(),
+                )))?;
+
+                (vec![], Some("SET"))
+            }
+            SetStatement::Set { name, .. } | SetStatement::Reset(name)
+                if Settings::is_read_only(name) =>
+            {
+                sink.push(BackendMessage::ErrorResponse(cannot_be_changed(
+                    Severity::Error,
+                    name,
+                )))?;
+                break;
+            }
+            // The settings aren't locked while the executor runs, its interceptors can read them
+            statement => {
+                if let Err(e) = query_exec.set(statement)? {
+                    sink.push(BackendMessage::ErrorResponse(e))?;
+                    break;
+                }
+
+                let mut settings = state.settings();
+
+                match statement {
+                    SetStatement::Set { name, value, local } => {
+                        let value = value.as_deref().map(flatten);
+                        settings.set(name, value.as_deref(), *local);
+                        (vec![], Some("SET"))
+                    }
+                    SetStatement::Reset(name) => {
+                        settings.set(name, None, false);
+                        (vec![], Some("RESET"))
+                    }
+                    _ => {
+                        settings.reset_all();
+                        (vec![], Some("RESET"))
+                    }
+                }
+            }
+        };
+
+        for msg in messages {
+            sink.push(msg)?;
+        }
+
+        if let Some(tag) = tag {
+            sink.push(BackendMessage::CommandComplete(CommandComplete::new(
+                CommandTag::Other(tag.to_string()),
+            )))?;
+        }
+    }
+
+    report(&reported, &state.settings(), sink)?;
+
+    Ok(true)
+}
+
+// Sends ParameterStatus for every reportable setting which changed since the snapshot
+pub(crate) fn report(
+    reported: &[(String, String)],
+    settings: &Settings,
+    sink: &mut dyn ResultSink,
+) -> io::Result<()> {
+    for (name, value) in settings.reportable() {
+        if !reported.iter().any(|(n, v)| *n == name && *v == value) {
+            sink.push(BackendMessage::ParameterStatus(ParameterStatus::new(
+                name, value,
+            )))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::NoopQueryExec;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("SET extra_float_digits = 3; set local search_path to 'app', public"),
+            Some(vec![
+                SetStatement::Set {
+                    name: "extra_float_digits".to_string(),
+                    value: Some(vec!["3".to_string()]),
+                    local: false,
+                },
+                SetStatement::Set {
+                    name: "search_path".to_string(),
+                    value: Some(vec!["'app'".to_string(), "public".to_string()]),
+                    local: true,
+                },
+            ])
+        );
+        assert_eq!(
+            parse("SHOW TRANSACTION ISOLATION LEVEL"),
+            Some(vec![SetStatement::Show(
+                "transaction_isolation".to_string()
+            )])
+        );
+        assert_eq!(
+            parse("set time zone default"),
+            Some(vec![SetStatement::Set {
+                name: "timezone".to_string(),
+                value: None,
+                local: false,
+            }])
+        );
+        assert_eq!(
+            parse("reset all").unwrap()[0].to_string(),
+            "RESET ALL".to_string()
+        );
+
+        // Only queries which consist of nothing else are handled
+        assert_eq!(parse("set role admin"), None);
+        assert_eq!(parse("set a = 1; select 1"), None);
+        assert_eq!(parse("show \""), None);
+        assert_eq!(
+            parse(r#"show "my""app".tenant"#),
+            Some(vec![SetStatement::Show("my\"app.tenant".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(
+            parse_options(r"-c search_path=app -cstatement_timeout=5s --application-name=my\ app")
+                .unwrap(),
+            vec![
+                ("search_path".to_string(), "app".to_string()),
+                ("statement_timeout".to_string(), "5s".to_string()),
+                ("application_name".to_string(), "my app".to_string()),
+            ]
+        );
+        assert!(parse_options("-B 100").is_err());
+        assert!(parse_options("-c search_path").is_err());
+
+        let mut settings = Settings::new("bob", &HashMap::new());
+        settings
+            .apply_options("--myapp.tenant=1 -c DateStyle=German")
+            .unwrap();
+        assert_eq!(settings.get("datestyle"), Some("German"));
+        assert_eq!(settings.get("myapp.tenant"), Some("1"));
+
+        let e = settings.apply_options("-c server_version=9").unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("55P02"));
+
+        // Settings unknown here are only rejected when there's no server to check them
+        settings.apply_options("-c jit=off").unwrap();
+        let e = settings.check_options().unwrap_err();
+        assert_eq!(e.get_field(Field::Code), Some("42704"));
+        settings.seed([("server_version", "16.2")]);
+        assert!(settings.check_options().is_ok());
+    }
+
+    #[test]
+    fn test_execute() {
+        let params = HashMap::from([("application_name".to_string(), "psql".to_string())]);
+        let state = State::new("bob".to_string(), "bob".to_string());
+        *state.settings() = Settings::new("bob", &params);
+        let mut query_exec = NoopQueryExec::new();
+        let mut messages = vec![];
+
+        let statements = parse("set application_name = 'app'; set local timezone = 'CET'").unwrap();
+        execute(
+            &state,
+            &statements,
+            TransactionStatus::InTransaction,
+            &mut query_exec,
+            &mut messages,
+        )
+        .unwrap();
+
+        assert_eq!(state.settings().get("TimeZone"), Some("CET"));
+        assert!(matches!(
+            &messages[2],
+            BackendMessage::ParameterStatus(status) if status.name == "application_name" && status.value == "app"
+        ));
+        assert!(matches!(
+            &messages[3],
+            BackendMessage::ParameterStatus(status) if status.name == "TimeZone" && status.value == "CET"
+        ));
+
+        state.settings().end_transaction();
+        assert_eq!(state.settings().get("timezone"), Some("UTC"));
+
+        state.settings().set("application_name", None, false);
+        assert_eq!(state.settings().get("application_name"), Some("psql"));
+
+        // Unknown settings are shown by the executor
+        let statements = parse("show shared_buffers").unwrap();
+        assert!(!execute(
+            &state,
+            &statements,
+            TransactionStatus::Idle,
+            &mut query_exec,
+            &mut messages
+        )
+        .unwrap());
+
+        // Once seeded by a server, only the settings it reported are shown here
+        state
+            .settings()
+            .seed([("TimeZone", "Europe/Berlin"), ("server_version", "16.2")]);
+        assert_eq!(state.settings().get("timezone"), Some("Europe/Berlin"));
+        assert_eq!(state.settings().get("work_mem"), None);
+
+        state.settings().set("work_mem", Some("8MB"), false);
+        assert_eq!(state.settings().get("work_mem"), None);
+
+        for query in ["show work_mem", "show all"] {
+            assert!(!execute(
+                &state,
+                &parse(query).unwrap(),
+                TransactionStatus::Idle,
+                &mut query_exec,
+                &mut messages
+            )
+            .unwrap());
+        }
+    }
+}
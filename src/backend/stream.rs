@@ -0,0 +1,24 @@
+use crate::proto::messages::{DataRow, ErrorResponse, RowDescription};
+
+// Once this much output is queued the manager writes it to the socket, which blocks while the
+// client isn't reading, so rows are only produced as fast as the client takes them
+pub(crate) const FLUSH_SIZE: usize = 64 * 1024;
+
+// The rows of a query produced one at a time, so results larger than memory can be sent. The
+// manager completes the query with the number of rows it actually sent, an error ends the stream.
+pub struct RowStream {
+    pub description: RowDescription,
+    pub rows: Box<dyn Iterator<Item = Result<DataRow, ErrorResponse>>>,
+}
+
+impl RowStream {
+    pub fn new<I>(description: RowDescription, rows: I) -> Self
+    where
+        I: Iterator<Item = Result<DataRow, ErrorResponse>> + 'static,
+    {
+        Self {
+            description,
+            rows: Box::new(rows),
+        }
+    }
+}
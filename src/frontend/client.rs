@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::{fmt, io};
+
+use secstr::SecStr;
+
+use crate::proto::messages::{
+    BackendMessage, CommandTag, DataRow, ErrorResponse, Params, PasswordMessage, Query,
+    StartupMessage, Terminate, TransactionStatus,
+};
+use crate::proto::{Decode, Encode, ProtocolError, Reader, Writer};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Protocol(ProtocolError),
+    // The server responded with an error
+    Server(ErrorResponse),
+    // The server asked for a password but none was configured
+    PasswordRequired,
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Protocol(e) => write!(f, "{}", e),
+            Self::Server(e) => write!(f, "{}", e),
+            Self::PasswordRequired => write!(f, "password required"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        Self::Protocol(e)
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        Self::Protocol(e.into())
+    }
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+pub struct Config {
+    pub user: String,
+    pub database: Option<String>,
+    pub password: Option<SecStr>,
+    pub params: Vec<(String, String)>,
+}
+
+impl Config {
+    pub fn new(user: String) -> Self {
+        Self {
+            user,
+            database: None,
+            password: None,
+            params: vec![],
+        }
+    }
+}
+
+// All messages the server sent in response to a (simple) query, up to the final ReadyForQuery
+pub struct QueryResponse {
+    pub messages: Vec<BackendMessage>,
+    pub transaction_status: TransactionStatus,
+}
+
+impl QueryResponse {
+    pub fn error(&self) -> Option<&ErrorResponse> {
+        self.messages.iter().find_map(|msg| match msg {
+            BackendMessage::ErrorResponse(e) => Some(e),
+            _ => None,
+        })
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &DataRow> {
+        self.messages.iter().filter_map(|msg| match msg {
+            BackendMessage::DataRow(row) => Some(row),
+            _ => None,
+        })
+    }
+
+    pub fn command_tags(&self) -> impl Iterator<Item = &CommandTag> {
+        self.messages.iter().filter_map(|msg| match msg {
+            BackendMessage::CommandComplete(cc) => Some(&cc.command_tag),
+            _ => None,
+        })
+    }
+}
+
+// A minimal blocking client which speaks the simple query protocol
+pub struct Client {
+    reader: Reader<TcpStream>,
+    writer: Writer<TcpStream>,
+    parameters: HashMap<String, String>,
+    backend_key: Option<(i32, Vec<u8>)>,
+    transaction_status: TransactionStatus,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A, config: Config) -> ClientResult<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let mut client = Self {
+            reader: Reader::new(stream.try_clone()?),
+            writer: Writer::new(stream),
+            parameters: HashMap::new(),
+            backend_key: None,
+            transaction_status: TransactionStatus::Idle,
+        };
+
+        client.startup(config)?;
+
+        Ok(client)
+    }
+
+    fn startup(&mut self, config: Config) -> ClientResult<()> {
+        let mut params = vec![("user".to_string(), config.user)];
+
+        if let Some(database) = config.database {
+            params.push(("database".to_string(), database));
+        }
+
+        params.extend(config.params);
+
+        self.send(StartupMessage::new(Params::from(params)))?;
+        self.flush()?;
+
+        let mut password = config.password;
+
+        loop {
+            match self.recv()? {
+                BackendMessage::AuthenticationCleartextPassword(_) => match password.take() {
+                    Some(password) => {
+                        self.send(PasswordMessage::new(password))?;
+                        self.flush()?;
+                    }
+                    None => return Err(ClientError::PasswordRequired),
+                },
+                BackendMessage::AuthenticationOk(_) => {}
+                BackendMessage::ParameterStatus(status) => {
+                    self.parameters.insert(status.name, status.value);
+                }
+                BackendMessage::BackendKeyData(key) => {
+                    self.backend_key = Some((key.process_id, key.secret_key));
+                }
+                BackendMessage::NoticeResponse(_) => {}
+                BackendMessage::ErrorResponse(e) => return Err(ClientError::Server(e)),
+                BackendMessage::ReadyForQuery(ready) => {
+                    self.transaction_status = ready.transaction_status;
+
+                    return Ok(());
+                }
+                _ => {
+                    return Err(
+                        ProtocolError::malformed("unexpected message during startup").into(),
+                    )
+                }
+            }
+        }
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters.get(name).map(|s| s.as_str())
+    }
+
+    pub fn parameters(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.parameters
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn backend_key(&self) -> Option<(i32, &[u8])> {
+        self.backend_key
+            .as_ref()
+            .map(|(process_id, secret_key)| (*process_id, &secret_key[..]))
+    }
+
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    pub fn send<T: Encode>(&mut self, msg: T) -> io::Result<()> {
+        msg.encode(&mut self.writer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn recv(&mut self) -> Result<BackendMessage, ProtocolError> {
+        BackendMessage::decode(&mut self.reader)
+    }
+
+    // Runs a simple query and collects everything the server sends back. Errors reported by the
+    // server are part of the response as the connection is still usable afterwards.
+    pub fn simple_query(&mut self, query: &str) -> ClientResult<QueryResponse> {
+        self.send(Query::new(query.to_string()))?;
+        self.flush()?;
+
+        let mut messages = vec![];
+
+        loop {
+            match self.recv()? {
+                BackendMessage::ReadyForQuery(ready) => {
+                    self.transaction_status = ready.transaction_status;
+
+                    return Ok(QueryResponse {
+                        messages,
+                        transaction_status: ready.transaction_status,
+                    });
+                }
+                BackendMessage::ParameterStatus(status) => {
+                    self.parameters
+                        .insert(status.name.clone(), status.value.clone());
+                    messages.push(BackendMessage::ParameterStatus(status));
+                }
+                msg => messages.push(msg),
+            }
+        }
+    }
+
+    pub fn close(mut self) -> io::Result<()> {
+        self.send(Terminate::new())?;
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::backend::{
+        CancelRegistry, Conn, Intercept, InterceptingQueryExec, Interceptor, Manager, NoopAuth,
+        NoopQueryExec, QueryExec, QueryResult, RowStream, State,
+    };
+    use crate::proto::messages::{CancelRequest, CommandComplete, Field, RowDescription};
+
+    #[test]
+    fn test_query_noop_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Manager::new(
+                Conn::new(stream).unwrap(),
+                NoopAuth::new(),
+                NoopQueryExec::new(),
+            )
+            .unwrap()
+            .handle()
+        });
+
+        let mut client = Client::connect(addr, Config::new("bob".to_string())).unwrap();
+        let response = client.simple_query("select 1").unwrap();
+
+        assert!(response.error().is_none());
+        assert_eq!(
+            response.command_tags().collect::<Vec<_>>(),
+            vec![&CommandTag::Select(0)]
+        );
+        assert_eq!(response.transaction_status, TransactionStatus::Idle);
+
+        client.close().unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    // Streams the numbers up to 100000, or endlessly and slowly
+    struct Numbers;
+
+    impl QueryExec for Numbers {
+        fn execute(&mut self, _query: &str) -> QueryResult {
+            Ok(CommandComplete::new(CommandTag::Select(0)))
+        }
+
+        fn stream(&mut self, query: &str) -> io::Result<Option<RowStream>> {
+            let row = |n: u64| Ok(DataRow::new(vec![Some(n.to_string().into_bytes())]));
+
+            Ok(Some(match query {
+                "endless" => RowStream::new(
+                    RowDescription::new(vec![]),
+                    (0..).map(move |n| {
+                        thread::sleep(Duration::from_millis(1));
+                        row(n)
+                    }),
+                ),
+                _ => RowStream::new(RowDescription::new(vec![]), (0..100_000).map(row)),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_stream_and_cancel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = Arc::new(CancelRegistry::new());
+
+        let server = thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let registry = registry.clone();
+
+                thread::spawn(move || {
+                    let mut manager = Manager::new(
+                        Conn::new(stream.unwrap()).unwrap(),
+                        NoopAuth::new(),
+                        Numbers,
+                    )
+                    .unwrap();
+                    manager.set_cancel_registry(registry);
+                    manager.handle()
+                });
+            }
+        });
+
+        let mut client = Client::connect(addr, Config::new("bob".to_string())).unwrap();
+
+        let response = client.simple_query("numbers").unwrap();
+        assert_eq!(response.rows().count(), 100_000);
+        assert_eq!(
+            response.command_tags().collect::<Vec<_>>(),
+            vec![&CommandTag::Select(100_000)]
+        );
+
+        let (process_id, secret_key) = client.backend_key().unwrap();
+        let secret_key = secret_key.to_vec();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+
+            let mut writer = Writer::new(TcpStream::connect(addr).unwrap());
+            CancelRequest::new(process_id, secret_key)
+                .encode(&mut writer)
+                .unwrap();
+            writer.flush().unwrap();
+        });
+
+        let response = client.simple_query("endless").unwrap();
+        assert!(response.rows().count() > 0);
+        assert_eq!(
+            response.error().and_then(|e| e.get_field(Field::Code)),
+            Some("57014")
+        );
+
+        client.close().unwrap();
+        server.join().unwrap();
+    }
+
+    // Echoes the query as command tag
+    struct Echo;
+
+    impl QueryExec for Echo {
+        fn execute(&mut self, query: &str) -> QueryResult {
+            Ok(CommandComplete::new(CommandTag::Other(query.to_string())))
+        }
+    }
+
+    // Rewrites queries for the tenant in the app.tenant setting
+    struct Tenant;
+
+    impl Interceptor for Tenant {
+        fn before(&mut self, state: &State, query: String) -> Intercept {
+            match state.settings().get("app.tenant") {
+                Some(tenant) => Intercept::Continue(format!("{} -- {}", query, tenant)),
+                None => Intercept::Continue(query),
+            }
+        }
+    }
+
+    #[test]
+    fn test_interceptor_reads_settings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Manager::new(
+                Conn::new(stream).unwrap(),
+                NoopAuth::new(),
+                InterceptingQueryExec::new(Echo).with(Tenant),
+            )
+            .unwrap()
+            .handle()
+        });
+
+        let mut client = Client::connect(addr, Config::new("bob".to_string())).unwrap();
+
+        // The interceptor sees the SET as well, while the settings are being changed
+        let response = client.simple_query("set app.tenant = 'acme'").unwrap();
+        assert!(response.error().is_none());
+
+        let response = client.simple_query("select 1").unwrap();
+        assert_eq!(
+            response.command_tags().collect::<Vec<_>>(),
+            vec![&CommandTag::Other("select 1 -- acme".to_string())]
+        );
+
+        client.close().unwrap();
+        server.join().unwrap().unwrap();
+    }
+}
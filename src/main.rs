@@ -1,16 +1,80 @@
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use clap::Parser;
 
-use crate::backend::{Conn, Manager, NoopAuth, NoopQueryExec};
-
-mod backend;
-mod proto;
+use postgres_conn::backend::{
+    AuditSink, Auth, CancelRegistry, Catalog, CatalogQueryExec, Conn, FileLargeObjects, Firewall,
+    FirewallInterceptor, InterceptingQueryExec, JsonLinesAudit, LargeObjectQueryExec,
+    LargeObjectStore, Manager, MemoryLargeObjects, NoopAuth, NoopQueryExec, Pool, PoolAuth,
+    PoolConfig, PoolMode, PortalQueryExec, ProxyAuth, QueryExec, Router, RouterAuth,
+};
 
 #[derive(Parser)]
 struct Opts {
     address: Option<SocketAddr>,
+    /// Maximum size in bytes of a single frontend message
+    #[clap(long)]
+    max_message_size: Option<usize>,
+    /// Forward all connections to this PostgreSQL server
+    #[clap(long)]
+    upstream: Option<SocketAddr>,
+    /// Share a pool of upstream connections between clients (session or transaction)
+    #[clap(long, requires = "upstream")]
+    pool_mode: Option<PoolMode>,
+    /// Maximum number of pooled connections per user and database
+    #[clap(long, default_value_t = 10)]
+    pool_size: usize,
+    /// Seconds a client waits for a pooled connection
+    #[clap(long, default_value_t = 30)]
+    pool_timeout: u64,
+    /// Route read-only queries to this replica of the upstream server (can be repeated)
+    #[clap(long, requires = "upstream", conflicts_with = "pool-mode")]
+    replica: Vec<SocketAddr>,
+    /// Only allow the statements permitted by the rules in this file
+    #[clap(long)]
+    firewall: Option<PathBuf>,
+    /// Allow all statements but record rules for those which aren't allowed to the firewall file
+    #[clap(long, requires = "firewall")]
+    firewall_learn: bool,
+    /// Record every statement as JSON lines in this file
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+    /// Rotate the audit log once it exceeds this many bytes
+    #[clap(long, requires = "audit-log")]
+    audit_log_max_size: Option<u64>,
+    /// Number of rotated audit logs to keep
+    #[clap(long, default_value_t = 5)]
+    audit_log_max_files: usize,
+    /// Replace literals in the audited statements
+    #[clap(long, requires = "audit-log")]
+    audit_redact: bool,
+    /// Keep large objects as files in this directory instead of in memory (without an upstream)
+    #[clap(long, conflicts_with = "upstream")]
+    large_objects: Option<PathBuf>,
+    /// Maximum total size in bytes of the large objects kept in memory
+    #[clap(long, default_value_t = 1 << 30, conflicts_with = "large-objects")]
+    large_objects_memory: u64,
+    /// Answer catalog queries with the schemas and tables described in this file (without an
+    /// upstream)
+    #[clap(long, conflicts_with = "upstream")]
+    catalog: Option<PathBuf>,
+}
+
+// Everything shared between the connections
+struct Shared {
+    opts: Opts,
+    pool: Option<Arc<Pool>>,
+    router: Option<Arc<Router>>,
+    firewall: Option<Arc<Firewall>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    catalog: Arc<Catalog>,
+    cancel: Arc<CancelRegistry>,
+    large_objects: Arc<dyn LargeObjectStore>,
 }
 
 fn main() -> io::Result<()> {
@@ -24,19 +88,138 @@ fn main() -> io::Result<()> {
     log::info!("starting postgres-conn on {}", addr);
 
     let listener = TcpListener::bind(addr)?;
+    let pool = match (opts.upstream, opts.pool_mode) {
+        (Some(upstream), Some(mode)) => {
+            let mut config = PoolConfig::new(upstream);
+            config.mode = mode;
+            config.max_size = opts.pool_size;
+            config.checkout_timeout = Duration::from_secs(opts.pool_timeout);
+
+            Some(Arc::new(Pool::new(config)))
+        }
+        _ => None,
+    };
+    let router = match opts.upstream {
+        Some(upstream) if !opts.replica.is_empty() => {
+            Some(Arc::new(Router::new(upstream, opts.replica.clone())))
+        }
+        _ => None,
+    };
+    let firewall = match &opts.firewall {
+        // The rules file doesn't have to exist yet when learning
+        Some(path) if opts.firewall_learn && !path.exists() => {
+            Some(Arc::new(Firewall::parse("").unwrap().learn(path)?))
+        }
+        Some(path) if opts.firewall_learn => Some(Arc::new(Firewall::load(path)?.learn(path)?)),
+        Some(path) => Some(Arc::new(Firewall::load(path)?)),
+        None => None,
+    };
+    let audit = match &opts.audit_log {
+        Some(path) => {
+            let mut audit = JsonLinesAudit::open(path)?;
+
+            if let Some(max_size) = opts.audit_log_max_size {
+                audit = audit.rotate(max_size, opts.audit_log_max_files);
+            }
+
+            if opts.audit_redact {
+                audit = audit.redact();
+            }
+
+            Some(Arc::new(audit) as Arc<dyn AuditSink>)
+        }
+        None => None,
+    };
+    let large_objects = match &opts.large_objects {
+        Some(dir) => Arc::new(FileLargeObjects::open(dir)?) as Arc<dyn LargeObjectStore>,
+        None => Arc::new(MemoryLargeObjects::with_limit(opts.large_objects_memory)),
+    };
+    let catalog = match &opts.catalog {
+        Some(path) => Catalog::load(path)?,
+        None => Catalog::new(),
+    };
+    let shared = Arc::new(Shared {
+        opts,
+        pool,
+        router,
+        firewall,
+        audit,
+        catalog: Arc::new(catalog),
+        cancel: Arc::new(CancelRegistry::new()),
+        large_objects,
+    });
 
     for stream in listener.incoming() {
-        handle(stream?);
+        let stream = stream?;
+        let shared = shared.clone();
+
+        thread::spawn(move || handle(stream, &shared));
     }
 
     Ok(())
 }
 
-fn handle(stream: TcpStream) {
+fn handle(stream: TcpStream, shared: &Shared) {
     log::info!("new connection");
 
+    // Pooled connections are opened by the executor, clients log in to the upstream server once
+    // to be authenticated
+    if let Some(pool) = &shared.pool {
+        let auth = PoolAuth::new(pool.clone());
+        let query_exec = auth.query_exec();
+
+        return serve(stream, shared, auth, query_exec);
+    }
+
+    // Clients log in to the primary when routing, the executor connects to the replicas
+    if let Some(router) = &shared.router {
+        let auth = RouterAuth::new(router.clone());
+        let query_exec = auth.query_exec();
+
+        return serve(stream, shared, auth, query_exec);
+    }
+
+    match shared.opts.upstream {
+        Some(upstream) => {
+            let auth = ProxyAuth::new(upstream);
+            let query_exec = auth.query_exec();
+
+            serve(stream, shared, auth, query_exec)
+        }
+        None => serve(
+            stream,
+            shared,
+            NoopAuth::new(),
+            PortalQueryExec::new(LargeObjectQueryExec::new(
+                CatalogQueryExec::new(NoopQueryExec::new(), shared.catalog.clone()),
+                shared.large_objects.clone(),
+            )),
+        ),
+    }
+}
+
+fn serve<A: Auth, Q: QueryExec>(stream: TcpStream, shared: &Shared, auth: A, query_exec: Q) {
+    let mut query_exec = InterceptingQueryExec::new(query_exec);
+
+    if let Some(firewall) = &shared.firewall {
+        query_exec = query_exec.with(FirewallInterceptor::new(firewall.clone()));
+    }
+
     match Conn::new(stream)
-        .and_then(|c| Manager::new(c, NoopAuth::new(), NoopQueryExec::new()))
+        .and_then(|c| Manager::new(c, auth, query_exec))
+        .map(|mut m| {
+            if let Some(size) = shared.opts.max_message_size {
+                m.set_max_message_size(size);
+            }
+
+            m.set_cancel_registry(shared.cancel.clone());
+
+            if let Some(audit) = &shared.audit {
+                m.set_audit_sink(audit.clone());
+            }
+
+            m
+        })
         .and_then(|mut b| b.handle())
     {
         Ok(_) => log::info!("connection closed"),
@@ -0,0 +1,63 @@
+use std::fmt::{Display, Formatter};
+use std::{fmt, io};
+
+pub type ProtocolResult<T> = Result<T, ProtocolError>;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    // The underlying stream failed (or was closed), nothing can be sent back to the client
+    Io(io::Error),
+    // The framing can't be trusted anymore so the connection must be closed
+    Malformed(String),
+    // The message was well-framed but we don't know how to handle it, its body has been skipped
+    Unsupported(u8),
+}
+
+impl ProtocolError {
+    pub fn malformed(msg: impl Into<String>) -> Self {
+        Self::Malformed(msg.into())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            // connection_failure
+            Self::Io(_) => "08006",
+            // protocol_violation
+            Self::Malformed(_) => "08P01",
+            // feature_not_supported
+            Self::Unsupported(_) => "0A000",
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Malformed(msg) => write!(f, "malformed message: {}", msg),
+            Self::Unsupported(tag) => {
+                write!(f, "unsupported frontend message type {:?}", *tag as char)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData => Self::Malformed(e.to_string()),
+            _ => Self::Io(e),
+        }
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
@@ -1,14 +1,123 @@
-use std::io;
-use std::io::Write;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::{fmt, io};
 
-use crate::proto::{Encode, Writer};
+use crate::proto::messages::{
+    CopyBothResponse, CopyData, CopyDone, CopyInResponse, CopyOutResponse,
+};
+use crate::proto::{Decode, Encode, ProtocolError, ProtocolResult, Reader, Writer};
 
-macro_rules! sizeof {
-    (i32) => { sizeof!(int i32) };
-    (u8) => { 1 };
+pub enum BackendMessage {
+    AuthenticationOk(AuthenticationOk),
+    AuthenticationCleartextPassword(AuthenticationCleartextPassword),
+    ParameterStatus(ParameterStatus),
+    BackendKeyData(BackendKeyData),
+    NegotiateProtocolVersion(NegotiateProtocolVersion),
+    ErrorResponse(ErrorResponse),
+    NoticeResponse(NoticeResponse),
+    ReadyForQuery(ReadyForQuery),
+    RowDescription(RowDescription),
+    DataRow(DataRow),
+    CommandComplete(CommandComplete),
+    EmptyQueryResponse(EmptyQueryResponse),
+    ParseComplete(ParseComplete),
+    BindComplete(BindComplete),
+    CloseComplete(CloseComplete),
+    NoData(NoData),
+    PortalSuspended(PortalSuspended),
+    ParameterDescription(ParameterDescription),
+    CopyBothResponse(CopyBothResponse),
+    CopyInResponse(CopyInResponse),
+    CopyOutResponse(CopyOutResponse),
+    CopyData(CopyData),
+    CopyDone(CopyDone),
+    FunctionCallResponse(FunctionCallResponse),
+}
+
+impl Decode for BackendMessage {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let id = reader.read_byte()?;
+
+        Ok(match id {
+            b'R' => {
+                let (_, kind) = reader.read_frame(|body| {
+                    let kind = body.read_i32()?;
+
+                    // Other kinds (such as SASL) carry additional data we don't support
+                    body.read_to_end()?;
+
+                    Ok(kind)
+                })?;
 
-    (int $kind:ty) => {
-        (<$kind>::BITS / 8) as i32
+                match kind {
+                    0 => Self::AuthenticationOk(AuthenticationOk {}),
+                    3 => Self::AuthenticationCleartextPassword(AuthenticationCleartextPassword {}),
+                    _ => return Err(ProtocolError::Unsupported(id)),
+                }
+            }
+            b'S' => Self::ParameterStatus(ParameterStatus::decode(reader)?),
+            b'K' => Self::BackendKeyData(BackendKeyData::decode(reader)?),
+            b'v' => Self::NegotiateProtocolVersion(NegotiateProtocolVersion::decode(reader)?),
+            b'E' => Self::ErrorResponse(ErrorResponse::decode(reader)?),
+            b'N' => Self::NoticeResponse(NoticeResponse::decode(reader)?),
+            b'Z' => Self::ReadyForQuery(ReadyForQuery::decode(reader)?),
+            b'T' => Self::RowDescription(RowDescription::decode(reader)?),
+            b'D' => Self::DataRow(DataRow::decode(reader)?),
+            b'C' => Self::CommandComplete(CommandComplete::decode(reader)?),
+            b'I' => Self::EmptyQueryResponse(EmptyQueryResponse::decode(reader)?),
+            b'1' => Self::ParseComplete(ParseComplete::decode(reader)?),
+            b'2' => Self::BindComplete(BindComplete::decode(reader)?),
+            b'3' => Self::CloseComplete(CloseComplete::decode(reader)?),
+            b'n' => Self::NoData(NoData::decode(reader)?),
+            b's' => Self::PortalSuspended(PortalSuspended::decode(reader)?),
+            b't' => Self::ParameterDescription(ParameterDescription::decode(reader)?),
+            b'W' => Self::CopyBothResponse(CopyBothResponse::decode(reader)?),
+            b'G' => Self::CopyInResponse(CopyInResponse::decode(reader)?),
+            b'H' => Self::CopyOutResponse(CopyOutResponse::decode(reader)?),
+            b'd' => Self::CopyData(CopyData::decode(reader)?),
+            b'c' => Self::CopyDone(CopyDone::decode(reader)?),
+            b'V' => Self::FunctionCallResponse(FunctionCallResponse::decode(reader)?),
+            _ => {
+                let len = reader.read_len(reader.max_message_size())?;
+                reader.skip(len as u64 - 4)?;
+
+                return Err(ProtocolError::Unsupported(id));
+            }
+        })
+    }
+}
+
+impl Encode for BackendMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        match self {
+            Self::AuthenticationOk(msg) => msg.encode(writer),
+            Self::AuthenticationCleartextPassword(msg) => msg.encode(writer),
+            Self::ParameterStatus(msg) => msg.encode(writer),
+            Self::BackendKeyData(msg) => msg.encode(writer),
+            Self::NegotiateProtocolVersion(msg) => msg.encode(writer),
+            Self::ErrorResponse(msg) => msg.encode(writer),
+            Self::NoticeResponse(msg) => msg.encode(writer),
+            Self::ReadyForQuery(msg) => msg.encode(writer),
+            Self::RowDescription(msg) => msg.encode(writer),
+            Self::DataRow(msg) => msg.encode(writer),
+            Self::CommandComplete(msg) => msg.encode(writer),
+            Self::EmptyQueryResponse(msg) => msg.encode(writer),
+            Self::ParseComplete(msg) => msg.encode(writer),
+            Self::BindComplete(msg) => msg.encode(writer),
+            Self::CloseComplete(msg) => msg.encode(writer),
+            Self::NoData(msg) => msg.encode(writer),
+            Self::PortalSuspended(msg) => msg.encode(writer),
+            Self::ParameterDescription(msg) => msg.encode(writer),
+            Self::CopyBothResponse(msg) => msg.encode(writer),
+            Self::CopyInResponse(msg) => msg.encode(writer),
+            Self::CopyOutResponse(msg) => msg.encode(writer),
+            Self::CopyData(msg) => msg.encode(writer),
+            Self::CopyDone(msg) => msg.encode(writer),
+            Self::FunctionCallResponse(msg) => msg.encode(writer),
+        }
     }
 }
 
@@ -27,6 +136,22 @@ impl Encode for SSLResponse {
     }
 }
 
+impl Decode for SSLResponse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        match reader.read_byte()? {
+            b'S' => Ok(Self::Ssl),
+            b'N' => Ok(Self::NoSsl),
+            b => Err(ProtocolError::malformed(format!(
+                "invalid SSL response {:?}",
+                b as char
+            ))),
+        }
+    }
+}
+
 macro_rules! impl_auth_msg {
     ($(($ty:ident, $kind:expr)),+) => {
         $(impl_auth_msg!{$ty, $kind})+
@@ -37,17 +162,159 @@ macro_rules! impl_auth_msg {
 
         impl Encode for $ty {
             fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
-                writer.write_byte(b'R')?;
-                writer.write_i32(sizeof!(i32) + sizeof!(i32))?;
-                writer.write_i32($kind)
+                writer.write_message(b'R', |w| w.write_i32($kind))
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+            where
+                Self: Sized,
+            {
+                let (_, kind) = reader.read_frame(|body| Ok(body.read_i32()?))?;
+
+                if kind != $kind {
+                    return Err(ProtocolError::malformed(format!(
+                        "unexpected authentication request {}",
+                        kind
+                    )));
+                }
+
+                Ok(Self {})
             }
         }
     };
 }
 impl_auth_msg!((AuthenticationOk, 0), (AuthenticationCleartextPassword, 3));
 
+pub struct ParameterStatus {
+    pub name: String,
+    pub value: String,
+}
+
+impl ParameterStatus {
+    pub fn new(name: String, value: String) -> Self {
+        Self { name, value }
+    }
+}
+
+impl Encode for ParameterStatus {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'S', |w| {
+            w.write_str(&self.name)?;
+            w.write_str(&self.value)
+        })
+    }
+}
+
+impl Decode for ParameterStatus {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, (name, value)) =
+            reader.read_frame(|body| Ok((body.read_string()?, body.read_string()?)))?;
+
+        Ok(Self { name, value })
+    }
+}
+
+// The key is 4 bytes before protocol 3.2, since then it can be up to 256 bytes
+pub struct BackendKeyData {
+    pub process_id: i32,
+    pub secret_key: Vec<u8>,
+}
+
+impl BackendKeyData {
+    pub fn new(process_id: i32, secret_key: Vec<u8>) -> Self {
+        Self {
+            process_id,
+            secret_key,
+        }
+    }
+}
+
+impl Encode for BackendKeyData {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'K', |w| {
+            w.write_i32(self.process_id)?;
+            w.write_bytes(&self.secret_key)
+        })
+    }
+}
+
+impl Decode for BackendKeyData {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, (process_id, secret_key)) =
+            reader.read_frame(|body| Ok((body.read_i32()?, body.read_to_end()?)))?;
+
+        Ok(Self {
+            process_id,
+            secret_key,
+        })
+    }
+}
+
+// Sent during startup when the client asked for a newer minor version of the protocol or for
+// protocol options (`_pq_.` parameters) we don't support, the connection carries on with the
+// newest minor version and without those options
+#[derive(Debug, PartialEq)]
+pub struct NegotiateProtocolVersion {
+    pub newest_minor_version: i32,
+    pub unrecognized_options: Vec<String>,
+}
+
+impl NegotiateProtocolVersion {
+    pub fn new(newest_minor_version: i32, unrecognized_options: Vec<String>) -> Self {
+        Self {
+            newest_minor_version,
+            unrecognized_options,
+        }
+    }
+}
+
+impl Encode for NegotiateProtocolVersion {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'v', |w| {
+            w.write_i32(self.newest_minor_version)?;
+            w.write_i32(self.unrecognized_options.len() as i32)?;
+
+            for option in self.unrecognized_options.iter() {
+                w.write_str(option)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Decode for NegotiateProtocolVersion {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, msg) = reader.read_frame(|body| {
+            let newest_minor_version = body.read_i32()?;
+            let count = body.read_i32()?;
+            let unrecognized_options = (0..count)
+                .map(|_| body.read_string())
+                .collect::<io::Result<_>>()?;
+
+            Ok(Self {
+                newest_minor_version,
+                unrecognized_options,
+            })
+        })?;
+
+        Ok(msg)
+    }
+}
+
 #[allow(dead_code)]
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Field {
     SeverityI18n,
     Severity,
@@ -69,6 +336,32 @@ pub enum Field {
     Routine,
 }
 
+impl Field {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            b'S' => Self::SeverityI18n,
+            b'V' => Self::Severity,
+            b'C' => Self::Code,
+            b'M' => Self::Message,
+            b'D' => Self::Detail,
+            b'H' => Self::Hint,
+            b'P' => Self::Position,
+            b'p' => Self::InternalPosition,
+            b'q' => Self::Query,
+            b'W' => Self::Where,
+            b's' => Self::Schema,
+            b't' => Self::Table,
+            b'c' => Self::Column,
+            b'd' => Self::DataType,
+            b'n' => Self::Constraint,
+            b'F' => Self::File,
+            b'L' => Self::Line,
+            b'R' => Self::Routine,
+            _ => return None,
+        })
+    }
+}
+
 impl Encode for Field {
     fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
         writer.write_byte(match self {
@@ -106,9 +399,9 @@ pub enum Severity {
     Log,
 }
 
-impl ToString for Severity {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             Self::Error => "ERROR",
             Self::Fatal => "FATAL",
             Self::Panic => "PANIC",
@@ -117,11 +410,51 @@ impl ToString for Severity {
             Self::Debug => "DEBUG",
             Self::Info => "INFO",
             Self::Log => "LOG",
-        }
-        .to_string()
+        })
     }
 }
 
+fn encode_fields<W: Write>(
+    writer: &mut Writer<W>,
+    tag: u8,
+    fields: &[(Field, String)],
+) -> io::Result<()> {
+    writer.write_message(tag, |w| {
+        for (field, value) in fields.iter() {
+            field.encode(w)?;
+            w.write_str(value)?;
+        }
+
+        w.write_byte(0)
+    })
+}
+
+// Unknown fields are silently ignored, as required by the protocol
+fn decode_fields<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Vec<(Field, String)>> {
+    let (_, fields) = reader.read_frame(|body| {
+        let mut fields = vec![];
+
+        loop {
+            let field = body.read_byte()?;
+
+            if field == 0 {
+                break;
+            }
+
+            let value = body.read_string()?;
+
+            if let Some(field) = Field::from_byte(field) {
+                fields.push((field, value));
+            }
+        }
+
+        Ok(fields)
+    })?;
+
+    Ok(fields)
+}
+
+#[derive(Debug, PartialEq)]
 pub struct ErrorResponse {
     fields: Vec<(Field, String)>,
 }
@@ -137,6 +470,12 @@ impl ErrorResponse {
         }
     }
 
+    // Adds an optional field such as Detail, Hint or Position
+    pub fn with_field(mut self, field: Field, value: String) -> Self {
+        self.fields.push((field, value));
+        self
+    }
+
     pub fn get_field(&self, field: Field) -> Option<&str> {
         self.fields
             .iter()
@@ -145,29 +484,77 @@ impl ErrorResponse {
     }
 }
 
+impl Display for ErrorResponse {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({})",
+            self.get_field(Field::Severity).unwrap_or_default(),
+            self.get_field(Field::Message).unwrap_or_default(),
+            self.get_field(Field::Code).unwrap_or_default(),
+        )
+    }
+}
+
 impl Encode for ErrorResponse {
     fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
-        writer.write_byte(b'E')?;
-        writer.write_i32(
-            sizeof!(i32)
-                + self
-                    .fields
-                    .iter()
-                    .map(|(_, value)| (value.len() + sizeof!(u8) + sizeof!(u8)) as i32)
-                    .sum::<i32>()
-                + sizeof!(u8),
-        )?;
+        encode_fields(writer, b'E', &self.fields)
+    }
+}
+
+impl Decode for ErrorResponse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            fields: decode_fields(reader)?,
+        })
+    }
+}
+
+pub struct NoticeResponse {
+    fields: Vec<(Field, String)>,
+}
 
-        for (field, value) in self.fields.iter() {
-            field.encode(writer)?;
-            writer.write_str(value)?;
+impl NoticeResponse {
+    pub fn new(severity: Severity, code: String, message: String) -> Self {
+        Self {
+            fields: vec![
+                (Field::Severity, severity.to_string()),
+                (Field::Code, code),
+                (Field::Message, message),
+            ],
         }
+    }
 
-        Ok(())
+    pub fn get_field(&self, field: Field) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(f, _)| f == &field)
+            .map(|(_, s)| s.as_str())
+    }
+}
+
+impl Encode for NoticeResponse {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        encode_fields(writer, b'N', &self.fields)
+    }
+}
+
+impl Decode for NoticeResponse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            fields: decode_fields(reader)?,
+        })
     }
 }
 
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TransactionStatus {
     Idle,
     InTransaction,
@@ -177,13 +564,27 @@ pub enum TransactionStatus {
 impl Encode for TransactionStatus {
     fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
         writer.write_byte(match self {
-            Self::Idle => b'r',
+            Self::Idle => b'I',
             Self::InTransaction => b'T',
             Self::Failed => b'E',
         })
     }
 }
 
+impl TransactionStatus {
+    pub fn from_byte(byte: u8) -> ProtocolResult<Self> {
+        match byte {
+            b'I' => Ok(Self::Idle),
+            b'T' => Ok(Self::InTransaction),
+            b'E' => Ok(Self::Failed),
+            b => Err(ProtocolError::malformed(format!(
+                "invalid transaction status {:?}",
+                b as char
+            ))),
+        }
+    }
+}
+
 pub struct ReadyForQuery {
     pub transaction_status: TransactionStatus,
 }
@@ -196,13 +597,181 @@ impl ReadyForQuery {
 
 impl Encode for ReadyForQuery {
     fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
-        writer.write_byte(b'Z')?;
-        writer.write_i32(sizeof!(i32) + sizeof!(u8))?;
-        self.transaction_status.encode(writer)
+        writer.write_message(b'Z', |w| self.transaction_status.encode(w))
+    }
+}
+
+impl Decode for ReadyForQuery {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, status) = reader.read_frame(|body| Ok(body.read_byte()?))?;
+
+        Ok(Self {
+            transaction_status: TransactionStatus::from_byte(status)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDescription {
+    pub name: String,
+    pub table_oid: i32,
+    pub column_attr: i16,
+    pub type_oid: i32,
+    pub type_size: i16,
+    pub type_modifier: i32,
+    pub format_code: i16,
+}
+
+#[derive(Clone)]
+pub struct RowDescription {
+    pub fields: Vec<FieldDescription>,
+}
+
+impl RowDescription {
+    pub fn new(fields: Vec<FieldDescription>) -> Self {
+        Self { fields }
+    }
+}
+
+impl Encode for RowDescription {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'T', |w| {
+            w.write_i16(self.fields.len() as i16)?;
+
+            for field in self.fields.iter() {
+                w.write_str(&field.name)?;
+                w.write_i32(field.table_oid)?;
+                w.write_i16(field.column_attr)?;
+                w.write_i32(field.type_oid)?;
+                w.write_i16(field.type_size)?;
+                w.write_i32(field.type_modifier)?;
+                w.write_i16(field.format_code)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Decode for RowDescription {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, fields) = reader.read_frame(|body| {
+            let count = body.read_i16()?;
+            let mut fields = Vec::with_capacity(count.max(0) as usize);
+
+            for _ in 0..count {
+                fields.push(FieldDescription {
+                    name: body.read_string()?,
+                    table_oid: body.read_i32()?,
+                    column_attr: body.read_i16()?,
+                    type_oid: body.read_i32()?,
+                    type_size: body.read_i16()?,
+                    type_modifier: body.read_i32()?,
+                    format_code: body.read_i16()?,
+                });
+            }
+
+            Ok(fields)
+        })?;
+
+        Ok(Self { fields })
+    }
+}
+
+#[derive(Clone)]
+pub struct DataRow {
+    // `None` represents a NULL value
+    pub values: Vec<Option<Vec<u8>>>,
+}
+
+impl DataRow {
+    pub fn new(values: Vec<Option<Vec<u8>>>) -> Self {
+        Self { values }
+    }
+}
+
+impl Encode for DataRow {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'D', |w| {
+            w.write_i16(self.values.len() as i16)?;
+
+            for value in self.values.iter() {
+                match value {
+                    Some(value) => {
+                        w.write_i32(value.len() as i32)?;
+                        w.write_bytes(value)?;
+                    }
+                    None => w.write_i32(-1)?,
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Decode for DataRow {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, values) = reader.read_frame(|body| {
+            let count = body.read_i16()?;
+            let mut values = Vec::with_capacity(count.max(0) as usize);
+
+            for _ in 0..count {
+                values.push(body.read_value()?);
+            }
+
+            Ok(values)
+        })?;
+
+        Ok(Self { values })
+    }
+}
+
+pub struct FunctionCallResponse {
+    // `None` represents a NULL result
+    pub value: Option<Vec<u8>>,
+}
+
+impl FunctionCallResponse {
+    pub fn new(value: Option<Vec<u8>>) -> Self {
+        Self { value }
+    }
+}
+
+impl Encode for FunctionCallResponse {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'V', |w| match &self.value {
+            Some(value) => {
+                w.write_i32(value.len() as i32)?;
+                w.write_bytes(value)
+            }
+            None => w.write_i32(-1),
+        })
+    }
+}
+
+impl Decode for FunctionCallResponse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, value) = reader.read_frame(|body| Ok(body.read_value()?))?;
+
+        Ok(Self { value })
     }
 }
 
 #[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CommandTag {
     Insert(String, i32),
     Delete(i32),
@@ -211,22 +780,64 @@ pub enum CommandTag {
     Move(i32),
     Fetch(i32),
     Copy(i32),
+    // Any other command (such as `BEGIN` or `CREATE TABLE`) which doesn't report a row count
+    Other(String),
+}
+
+impl CommandTag {
+    // Returns the number of rows affected or returned, if the command reports it
+    pub fn rows(&self) -> Option<i32> {
+        match self {
+            Self::Insert(_, rows)
+            | Self::Delete(rows)
+            | Self::Update(rows)
+            | Self::Select(rows)
+            | Self::Move(rows)
+            | Self::Fetch(rows)
+            | Self::Copy(rows) => Some(*rows),
+            Self::Other(_) => None,
+        }
+    }
+
+    pub fn parse(tag: &str) -> Self {
+        let parts = tag.split(' ').collect::<Vec<_>>();
+        let rows = |s: &str| s.parse::<i32>().ok();
+
+        match parts.as_slice() {
+            ["INSERT", oid, n] => match rows(n) {
+                Some(n) => Self::Insert(oid.to_string(), n),
+                None => Self::Other(tag.to_string()),
+            },
+            [cmd, n] => match (*cmd, rows(n)) {
+                ("DELETE", Some(n)) => Self::Delete(n),
+                ("UPDATE", Some(n)) => Self::Update(n),
+                ("SELECT", Some(n)) => Self::Select(n),
+                ("MOVE", Some(n)) => Self::Move(n),
+                ("FETCH", Some(n)) => Self::Fetch(n),
+                ("COPY", Some(n)) => Self::Copy(n),
+                _ => Self::Other(tag.to_string()),
+            },
+            _ => Self::Other(tag.to_string()),
+        }
+    }
 }
 
-impl ToString for CommandTag {
-    fn to_string(&self) -> String {
+impl Display for CommandTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Insert(oid, rows) => format!("SELECT {} {}", oid, rows),
-            Self::Delete(rows) => format!("DELETE {}", rows),
-            Self::Update(rows) => format!("UPDATE {}", rows),
-            Self::Select(rows) => format!("SELECT {}", rows),
-            Self::Move(rows) => format!("MOVE {}", rows),
-            Self::Fetch(rows) => format!("FETCH {}", rows),
-            Self::Copy(rows) => format!("COPY {}", rows),
+            Self::Insert(oid, rows) => write!(f, "INSERT {} {}", oid, rows),
+            Self::Delete(rows) => write!(f, "DELETE {}", rows),
+            Self::Update(rows) => write!(f, "UPDATE {}", rows),
+            Self::Select(rows) => write!(f, "SELECT {}", rows),
+            Self::Move(rows) => write!(f, "MOVE {}", rows),
+            Self::Fetch(rows) => write!(f, "FETCH {}", rows),
+            Self::Copy(rows) => write!(f, "COPY {}", rows),
+            Self::Other(tag) => f.write_str(tag),
         }
     }
 }
 
+#[derive(Debug)]
 pub struct CommandComplete {
     pub command_tag: CommandTag,
 }
@@ -239,10 +850,200 @@ impl CommandComplete {
 
 impl Encode for CommandComplete {
     fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
-        let cmd_tag = self.command_tag.to_string();
+        writer.write_message(b'C', |w| w.write_str(&self.command_tag.to_string()))
+    }
+}
+
+impl Decode for CommandComplete {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, tag) = reader.read_frame(|body| Ok(body.read_string()?))?;
+
+        Ok(Self {
+            command_tag: CommandTag::parse(&tag),
+        })
+    }
+}
+
+// Messages without a body
+macro_rules! impl_empty_msg {
+    ($(($ty:ident, $tag:expr)),+) => {
+        $(impl_empty_msg!{$ty, $tag})+
+    };
+
+    ($ty:ident, $tag:expr) => {
+        pub struct $ty {}
+
+        impl Encode for $ty {
+            fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+                writer.write_message($tag, |_| Ok(()))
+            }
+        }
+
+        impl Decode for $ty {
+            fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+            where
+                Self: Sized,
+            {
+                reader.read_frame(|_| Ok(()))?;
+
+                Ok(Self {})
+            }
+        }
+    };
+}
+impl_empty_msg!(
+    (EmptyQueryResponse, b'I'),
+    (ParseComplete, b'1'),
+    (BindComplete, b'2'),
+    (CloseComplete, b'3'),
+    (NoData, b'n'),
+    (PortalSuspended, b's')
+);
+
+pub struct ParameterDescription {
+    pub param_types: Vec<i32>,
+}
+
+impl ParameterDescription {
+    pub fn new(param_types: Vec<i32>) -> Self {
+        Self { param_types }
+    }
+}
+
+impl Encode for ParameterDescription {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b't', |w| {
+            w.write_i16(self.param_types.len() as i16)?;
+
+            for oid in self.param_types.iter() {
+                w.write_i32(*oid)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl Decode for ParameterDescription {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (_, param_types) = reader.read_frame(|body| {
+            let count = body.read_i16()?;
+
+            Ok((0..count)
+                .map(|_| body.read_i32())
+                .collect::<io::Result<_>>()?)
+        })?;
+
+        Ok(Self { param_types })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_error_response() {
+        let mut writer = Writer::new(vec![]);
+        ErrorResponse::new(Severity::Error, "42000".to_string(), "oops".to_string())
+            .encode(&mut writer)
+            .unwrap();
+
+        assert_eq!(writer.buffer(), b"E\0\0\0\x19VERROR\0C42000\0Moops\0\0");
+    }
+
+    #[test]
+    fn test_encode_command_complete() {
+        let mut writer = Writer::new(vec![]);
+        CommandComplete::new(CommandTag::Select(1))
+            .encode(&mut writer)
+            .unwrap();
+
+        assert_eq!(writer.buffer(), b"C\0\0\0\x0dSELECT 1\0");
+    }
+
+    #[test]
+    fn test_encode_function_call_response() {
+        let mut writer = Writer::new(vec![]);
+        FunctionCallResponse::new(Some(b"42".to_vec()))
+            .encode(&mut writer)
+            .unwrap();
+        FunctionCallResponse::new(None).encode(&mut writer).unwrap();
+
+        assert_eq!(
+            writer.buffer(),
+            b"V\0\0\0\x0a\0\0\0\x0242V\0\0\0\x08\xff\xff\xff\xff"
+        );
+    }
+
+    #[test]
+    fn test_decode_backend_messages() {
+        let mut writer = Writer::new(vec![]);
+        NegotiateProtocolVersion::new(2, vec!["_pq_.foo".to_string()])
+            .encode(&mut writer)
+            .unwrap();
+        ErrorResponse::new(Severity::Error, "42000".to_string(), "oops".to_string())
+            .encode(&mut writer)
+            .unwrap();
+        DataRow::new(vec![Some(b"1".to_vec()), None])
+            .encode(&mut writer)
+            .unwrap();
+        CommandComplete::new(CommandTag::Other("BEGIN".to_string()))
+            .encode(&mut writer)
+            .unwrap();
+
+        let mut reader = Reader::new(writer.buffer());
+
+        match BackendMessage::decode(&mut reader) {
+            Ok(BackendMessage::NegotiateProtocolVersion(msg)) => assert_eq!(
+                msg,
+                NegotiateProtocolVersion::new(2, vec!["_pq_.foo".to_string()])
+            ),
+            _ => panic!("expected a protocol version negotiation"),
+        }
+        match BackendMessage::decode(&mut reader) {
+            Ok(BackendMessage::ErrorResponse(e)) => {
+                assert_eq!(e.get_field(Field::Code), Some("42000"))
+            }
+            _ => panic!("expected an error response"),
+        }
+        match BackendMessage::decode(&mut reader) {
+            Ok(BackendMessage::DataRow(row)) => {
+                assert_eq!(row.values, vec![Some(b"1".to_vec()), None])
+            }
+            _ => panic!("expected a data row"),
+        }
+        match BackendMessage::decode(&mut reader) {
+            Ok(BackendMessage::CommandComplete(cc)) => {
+                assert_eq!(cc.command_tag, CommandTag::Other("BEGIN".to_string()))
+            }
+            _ => panic!("expected command complete"),
+        }
+
+        // Value lengths are checked before allocating
+        let mut reader = Reader::new(&b"D\0\0\0\x0a\0\x01\x7f\xff\xff\xff"[..]);
+        assert!(matches!(
+            BackendMessage::decode(&mut reader),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
 
-        writer.write_byte(b'C')?;
-        writer.write_i32(sizeof!(i32) + cmd_tag.len() as i32 + sizeof!(u8))?;
-        writer.write_str(&cmd_tag)
+    #[test]
+    fn test_parse_command_tag() {
+        assert_eq!(
+            CommandTag::parse("INSERT 0 5"),
+            CommandTag::Insert("0".to_string(), 5)
+        );
+        assert_eq!(CommandTag::parse("SELECT 3"), CommandTag::Select(3));
+        assert_eq!(
+            CommandTag::parse("CREATE TABLE"),
+            CommandTag::Other("CREATE TABLE".to_string())
+        );
     }
 }
@@ -0,0 +1,257 @@
+use std::io;
+use std::io::{Read, Write};
+
+use crate::proto::{Decode, Encode, ProtocolResult, Reader, Writer};
+
+// Carries the data of COPY and of the streaming replication protocol, it's sent in both
+// directions
+pub struct CopyData {
+    pub len: i32,
+    pub data: Vec<u8>,
+}
+
+impl CopyData {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            len: 4 + data.len() as i32,
+            data,
+        }
+    }
+
+    // Encodes a message (such as XLogData) as the payload
+    pub fn wrap<T: Encode>(msg: &T) -> io::Result<Self> {
+        let mut data = vec![];
+        let mut writer = Writer::with_buffer_size(0, &mut data);
+        msg.encode(&mut writer)?;
+        writer.flush()?;
+
+        Ok(Self::new(data))
+    }
+}
+
+impl Decode for CopyData {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, data) = reader.read_frame(|body| Ok(body.read_to_end()?))?;
+
+        Ok(Self { len, data })
+    }
+}
+
+impl Encode for CopyData {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'd', |w| w.write_bytes(&self.data))
+    }
+}
+
+pub struct CopyDone {
+    pub len: i32,
+}
+
+impl CopyDone {
+    pub fn new() -> Self {
+        Self { len: 4 }
+    }
+}
+
+impl Default for CopyDone {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decode for CopyDone {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, _) = reader.read_frame(|_| Ok(()))?;
+
+        Ok(Self { len })
+    }
+}
+
+impl Encode for CopyDone {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'c', |_| Ok(()))
+    }
+}
+
+pub struct CopyFail {
+    pub len: i32,
+    pub message: String,
+}
+
+impl CopyFail {
+    pub fn new(message: String) -> Self {
+        Self { len: 0, message }
+    }
+}
+
+impl Decode for CopyFail {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, message) = reader.read_frame(|body| Ok(body.read_string()?))?;
+
+        Ok(Self { len, message })
+    }
+}
+
+impl Encode for CopyFail {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'f', |w| w.write_str(&self.message))
+    }
+}
+
+// Starts copy-both mode, used by streaming replication. The replication protocol doesn't have
+// columns so they're usually empty.
+pub struct CopyBothResponse {
+    // 0 for text, 1 for binary
+    pub format: i8,
+    pub column_formats: Vec<i16>,
+}
+
+impl CopyBothResponse {
+    pub fn new() -> Self {
+        Self {
+            format: 0,
+            column_formats: vec![],
+        }
+    }
+}
+
+impl Default for CopyBothResponse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn decode_response<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<(i8, Vec<i16>)> {
+    let (_, response) = reader.read_frame(|body| {
+        let format = body.read_byte()? as i8;
+        let mut column_formats = vec![];
+
+        for _ in 0..body.read_i16()? {
+            column_formats.push(body.read_i16()?);
+        }
+
+        Ok((format, column_formats))
+    })?;
+
+    Ok(response)
+}
+
+fn encode_response<W: Write>(
+    writer: &mut Writer<W>,
+    tag: u8,
+    format: i8,
+    column_formats: &[i16],
+) -> io::Result<()> {
+    writer.write_message(tag, |w| {
+        w.write_byte(format as u8)?;
+        w.write_i16(column_formats.len() as i16)?;
+
+        for format in column_formats.iter() {
+            w.write_i16(*format)?;
+        }
+
+        Ok(())
+    })
+}
+
+impl Decode for CopyBothResponse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (format, column_formats) = decode_response(reader)?;
+
+        Ok(Self {
+            format,
+            column_formats,
+        })
+    }
+}
+
+impl Encode for CopyBothResponse {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        encode_response(writer, b'W', self.format, &self.column_formats)
+    }
+}
+
+// Starts sending CopyData to the client (COPY TO STDOUT, or the archives of BASE_BACKUP), ended
+// with CopyDone
+pub struct CopyOutResponse {
+    // 0 for text, 1 for binary
+    pub format: i8,
+    pub column_formats: Vec<i16>,
+}
+
+impl CopyOutResponse {
+    pub fn new(format: i8, column_formats: Vec<i16>) -> Self {
+        Self {
+            format,
+            column_formats,
+        }
+    }
+}
+
+impl Decode for CopyOutResponse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (format, column_formats) = decode_response(reader)?;
+
+        Ok(Self {
+            format,
+            column_formats,
+        })
+    }
+}
+
+impl Encode for CopyOutResponse {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        encode_response(writer, b'H', self.format, &self.column_formats)
+    }
+}
+
+// Starts receiving CopyData from the client (COPY FROM STDIN), ended with CopyDone or CopyFail
+pub struct CopyInResponse {
+    // 0 for text, 1 for binary
+    pub format: i8,
+    pub column_formats: Vec<i16>,
+}
+
+impl CopyInResponse {
+    pub fn new(format: i8, column_formats: Vec<i16>) -> Self {
+        Self {
+            format,
+            column_formats,
+        }
+    }
+}
+
+impl Decode for CopyInResponse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (format, column_formats) = decode_response(reader)?;
+
+        Ok(Self {
+            format,
+            column_formats,
+        })
+    }
+}
+
+impl Encode for CopyInResponse {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        encode_response(writer, b'G', self.format, &self.column_formats)
+    }
+}
@@ -1,36 +1,77 @@
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
 
 use secstr::SecStr;
 
-use crate::proto::{Decode, Reader};
+use crate::proto::messages::{CopyData, CopyDone, CopyFail};
+use crate::proto::{Decode, Encode, ProtocolError, ProtocolResult, Reader, Writer};
 
 const SSL_REQUEST_CODE: i32 = 80877103;
+const CANCEL_REQUEST_CODE: i32 = 80877102;
+
+// Protocol version 3.0
+pub const PROTOCOL_VERSION: i32 = 196608;
+// Protocol version 3.2, the newest we support. It allows cancel keys longer than 4 bytes.
+pub const PROTOCOL_VERSION_3_2: i32 = 196610;
+
+// The longest cancel key allowed by protocol 3.2
+pub const MAX_CANCEL_KEY_LENGTH: usize = 256;
+
+// Startup packets are read before authentication so they get a much smaller limit, this is the
+// same as MAX_STARTUP_PACKET_LENGTH in PostgreSQL
+pub const MAX_STARTUP_PACKET_LENGTH: usize = 10000;
 
 pub enum Handshake {
     SSLRequest(SSLRequest),
     StartupMessage(StartupMessage),
+    CancelRequest(CancelRequest),
 }
 
 impl Decode for Handshake {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
-        let len = reader.read_i32()?;
-        let version = reader.read_i32()?;
-
-        if version == SSL_REQUEST_CODE {
-            return Ok(Handshake::SSLRequest(SSLRequest { len, code: version }));
+        let (len, mut handshake) =
+            reader.read_frame_with_limit(MAX_STARTUP_PACKET_LENGTH, |body| {
+                let version = body.read_i32()?;
+
+                Ok(match version {
+                    SSL_REQUEST_CODE => Handshake::SSLRequest(SSLRequest {
+                        len: 0,
+                        code: version,
+                    }),
+                    CANCEL_REQUEST_CODE => {
+                        let process_id = body.read_i32()?;
+                        let secret_key = body.read_to_end()?;
+
+                        if secret_key.len() < 4 || secret_key.len() > MAX_CANCEL_KEY_LENGTH {
+                            return Err(ProtocolError::malformed(
+                                "invalid length of query cancel key",
+                            ));
+                        }
+
+                        Handshake::CancelRequest(CancelRequest {
+                            len: 0,
+                            process_id,
+                            secret_key,
+                        })
+                    }
+                    _ => Handshake::StartupMessage(StartupMessage {
+                        len: 0,
+                        version,
+                        params: Params::decode(body)?,
+                    }),
+                })
+            })?;
+
+        match &mut handshake {
+            Handshake::SSLRequest(msg) => msg.len = len,
+            Handshake::StartupMessage(msg) => msg.len = len,
+            Handshake::CancelRequest(msg) => msg.len = len,
         }
 
-        let params = Params::decode(reader)?;
-
-        Ok(Handshake::StartupMessage(StartupMessage {
-            len,
-            version,
-            params,
-        }))
+        Ok(handshake)
     }
 }
 
@@ -40,58 +81,124 @@ pub struct SSLRequest {
 }
 
 impl Decode for SSLRequest {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
-        let len = reader.read_i32()?;
-        let code = reader.read_i32()?;
+        let (len, code) =
+            reader.read_frame_with_limit(MAX_STARTUP_PACKET_LENGTH, |body| Ok(body.read_i32()?))?;
 
         if code != SSL_REQUEST_CODE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid SSLRequest code",
-            ));
+            return Err(ProtocolError::malformed("invalid SSLRequest code"));
         }
 
         Ok(Self { len, code })
     }
 }
 
+impl SSLRequest {
+    pub fn new() -> Self {
+        Self {
+            len: 8,
+            code: SSL_REQUEST_CODE,
+        }
+    }
+}
+
+impl Default for SSLRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encode for SSLRequest {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_frame(|w| w.write_i32(self.code))
+    }
+}
+
+// Sent on a new connection to cancel the query running on the connection the key belongs to. The
+// key is 4 bytes before protocol 3.2.
+#[derive(Debug)]
+pub struct CancelRequest {
+    pub len: i32,
+    pub process_id: i32,
+    pub secret_key: Vec<u8>,
+}
+
+impl CancelRequest {
+    pub fn new(process_id: i32, secret_key: Vec<u8>) -> Self {
+        Self {
+            len: 12 + secret_key.len() as i32,
+            process_id,
+            secret_key,
+        }
+    }
+}
+
+impl Encode for CancelRequest {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_frame(|w| {
+            w.write_i32(CANCEL_REQUEST_CODE)?;
+            w.write_i32(self.process_id)?;
+            w.write_bytes(&self.secret_key)
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct Params(Vec<(String, String)>);
 
-impl Params {
-    pub fn into_iter(self) -> impl Iterator<Item = (String, String)> {
+impl From<Vec<(String, String)>> for Params {
+    fn from(params: Vec<(String, String)>) -> Self {
+        Self(params)
+    }
+}
+
+impl IntoIterator for Params {
+    type Item = (String, String);
+    type IntoIter = std::vec::IntoIter<(String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
         self.0.into_iter()
     }
 }
 
 impl Decode for Params {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
         let mut params = vec![];
 
+        // The list of parameters is terminated by an empty name
         loop {
             let name = reader.read_string()?;
-            let value = reader.read_string()?;
-
-            params.push((name, value));
 
-            if let Some(b'\0') = reader.peek()? {
+            if name.is_empty() {
                 break;
             }
-        }
 
-        // Read the null byte that we peeked but didn't consume
-        reader.read_byte()?;
+            let value = reader.read_string()?;
+
+            params.push((name, value));
+        }
 
         Ok(Self(params))
     }
 }
 
+impl Encode for Params {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        for (name, value) in self.0.iter() {
+            writer.write_str(name)?;
+            writer.write_str(value)?;
+        }
+
+        writer.write_byte(0)
+    }
+}
+
 #[derive(Debug)]
 pub struct StartupMessage {
     pub len: i32,
@@ -100,13 +207,14 @@ pub struct StartupMessage {
 }
 
 impl Decode for StartupMessage {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
-        let len = reader.read_i32()?;
-        let version = reader.read_i32()?;
-        let params = Params::decode(reader)?;
+        let (len, (version, params)) = reader
+            .read_frame_with_limit(MAX_STARTUP_PACKET_LENGTH, |body| {
+                Ok((body.read_i32()?, Params::decode(body)?))
+            })?;
 
         Ok(Self {
             len,
@@ -116,13 +224,40 @@ impl Decode for StartupMessage {
     }
 }
 
+impl StartupMessage {
+    // The length is computed by the `Writer` when the message is encoded
+    pub fn new(params: Params) -> Self {
+        Self {
+            len: 0,
+            version: PROTOCOL_VERSION,
+            params,
+        }
+    }
+}
+
+impl Encode for StartupMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_frame(|w| {
+            w.write_i32(self.version)?;
+            self.params.encode(w)
+        })
+    }
+}
+
 pub enum IncomingMessage {
     Query(Query),
+    Extended(ExtendedMessage),
+    Sync(Sync),
+    Flush(Flush),
     Terminate(Terminate),
+    CopyData(CopyData),
+    CopyDone(CopyDone),
+    CopyFail(CopyFail),
+    FunctionCall(FunctionCall),
 }
 
 impl Decode for IncomingMessage {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
@@ -130,30 +265,122 @@ impl Decode for IncomingMessage {
 
         match id {
             b'Q' => Ok(IncomingMessage::Query(Query::decode(reader)?)),
+            b'P' => Ok(IncomingMessage::Extended(ExtendedMessage::Parse(
+                Parse::decode(reader)?,
+            ))),
+            b'B' => Ok(IncomingMessage::Extended(ExtendedMessage::Bind(
+                Bind::decode(reader)?,
+            ))),
+            b'D' => Ok(IncomingMessage::Extended(ExtendedMessage::Describe(
+                Describe::decode(reader)?,
+            ))),
+            b'E' => Ok(IncomingMessage::Extended(ExtendedMessage::Execute(
+                Execute::decode(reader)?,
+            ))),
+            b'C' => Ok(IncomingMessage::Extended(ExtendedMessage::Close(
+                Close::decode(reader)?,
+            ))),
+            b'S' => Ok(IncomingMessage::Sync(Sync::decode(reader)?)),
+            b'H' => Ok(IncomingMessage::Flush(Flush::decode(reader)?)),
             b'X' => Ok(IncomingMessage::Terminate(Terminate::decode(reader)?)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("invalid message type: {:?}", id),
-            )),
+            b'd' => Ok(IncomingMessage::CopyData(CopyData::decode(reader)?)),
+            b'c' => Ok(IncomingMessage::CopyDone(CopyDone::decode(reader)?)),
+            b'f' => Ok(IncomingMessage::CopyFail(CopyFail::decode(reader)?)),
+            b'F' => Ok(IncomingMessage::FunctionCall(FunctionCall::decode(reader)?)),
+            _ => {
+                // The length is the only thing we can trust for unknown messages, as long as it's
+                // sane we can skip the body and keep the stream in sync
+                let len = reader.read_len(reader.max_message_size())?;
+                reader.skip(len as u64 - 4)?;
+
+                Err(ProtocolError::Unsupported(id))
+            }
         }
     }
 }
 
+impl Encode for IncomingMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        match self {
+            Self::Query(msg) => msg.encode(writer),
+            Self::Extended(msg) => msg.encode(writer),
+            Self::Sync(msg) => msg.encode(writer),
+            Self::Flush(msg) => msg.encode(writer),
+            Self::Terminate(msg) => msg.encode(writer),
+            Self::CopyData(msg) => msg.encode(writer),
+            Self::CopyDone(msg) => msg.encode(writer),
+            Self::CopyFail(msg) => msg.encode(writer),
+            Self::FunctionCall(msg) => msg.encode(writer),
+        }
+    }
+}
+
+pub struct Flush {
+    pub len: i32,
+}
+
+impl Decode for Flush {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, _) = reader.read_frame(|_| Ok(()))?;
+
+        Ok(Self { len })
+    }
+}
+
+impl Flush {
+    pub fn new() -> Self {
+        Self { len: 4 }
+    }
+}
+
+impl Default for Flush {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encode for Flush {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'H', |_| Ok(()))
+    }
+}
+
 pub struct Terminate {
     pub len: i32,
 }
 
 impl Decode for Terminate {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
-        let len = reader.read_i32()?;
+        let (len, _) = reader.read_frame(|_| Ok(()))?;
 
         Ok(Self { len })
     }
 }
 
+impl Terminate {
+    pub fn new() -> Self {
+        Self { len: 4 }
+    }
+}
+
+impl Default for Terminate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encode for Terminate {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'X', |_| Ok(()))
+    }
+}
+
 #[derive(Debug)]
 pub struct Query {
     pub len: i32,
@@ -161,29 +388,42 @@ pub struct Query {
 }
 
 impl Decode for Query {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
-        let len = reader.read_i32()?;
-        let query = reader.read_string()?;
+        let (len, query) = reader.read_frame(|body| Ok(body.read_string()?))?;
 
         Ok(Self { len, query })
     }
 }
 
+impl Query {
+    pub fn new(query: String) -> Self {
+        Self {
+            len: (4 + query.len() + 1) as i32,
+            query,
+        }
+    }
+}
+
+impl Encode for Query {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'Q', |w| w.write_str(&self.query))
+    }
+}
+
 pub struct PasswordMessage {
     pub len: i32,
     pub password: SecStr,
 }
 
 impl Decode for PasswordMessage {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized,
     {
-        let len = reader.read_i32()?;
-        let password = reader.read_string_bytes()?;
+        let (len, password) = reader.read_frame(|body| Ok(body.read_string_bytes()?))?;
 
         Ok(Self {
             len,
@@ -191,3 +431,556 @@ impl Decode for PasswordMessage {
         })
     }
 }
+
+impl PasswordMessage {
+    pub fn new(password: SecStr) -> Self {
+        Self {
+            len: (4 + password.unsecure().len() + 1) as i32,
+            password,
+        }
+    }
+}
+
+impl Encode for PasswordMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'p', |w| {
+            w.write_bytes(self.password.unsecure())?;
+            w.write_byte(0)
+        })
+    }
+}
+
+// Messages of the extended query protocol, the results are only expected once a `Sync` is received
+pub enum ExtendedMessage {
+    Parse(Parse),
+    Bind(Bind),
+    Describe(Describe),
+    Execute(Execute),
+    Close(Close),
+}
+
+impl Encode for ExtendedMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        match self {
+            Self::Parse(msg) => msg.encode(writer),
+            Self::Bind(msg) => msg.encode(writer),
+            Self::Describe(msg) => msg.encode(writer),
+            Self::Execute(msg) => msg.encode(writer),
+            Self::Close(msg) => msg.encode(writer),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Parse {
+    pub len: i32,
+    pub name: String,
+    pub query: String,
+    pub param_types: Vec<i32>,
+}
+
+impl Decode for Parse {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, (name, query, param_types)) = reader.read_frame(|body| {
+            let name = body.read_string()?;
+            let query = body.read_string()?;
+            let count = body.read_i16()?;
+            let param_types = (0..count)
+                .map(|_| body.read_i32())
+                .collect::<io::Result<_>>()?;
+
+            Ok((name, query, param_types))
+        })?;
+
+        Ok(Self {
+            len,
+            name,
+            query,
+            param_types,
+        })
+    }
+}
+
+impl Encode for Parse {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'P', |w| {
+            w.write_str(&self.name)?;
+            w.write_str(&self.query)?;
+            w.write_i16(self.param_types.len() as i16)?;
+
+            for oid in self.param_types.iter() {
+                w.write_i32(*oid)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Bind {
+    pub len: i32,
+    pub portal: String,
+    pub statement: String,
+    pub param_formats: Vec<i16>,
+    // `None` represents a NULL value
+    pub params: Vec<Option<Vec<u8>>>,
+    pub result_formats: Vec<i16>,
+}
+
+impl Decode for Bind {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, bind) = reader.read_frame(|body| {
+            let portal = body.read_string()?;
+            let statement = body.read_string()?;
+
+            let count = body.read_i16()?;
+            let param_formats = (0..count)
+                .map(|_| body.read_i16())
+                .collect::<io::Result<_>>()?;
+
+            let count = body.read_i16()?;
+            let params = (0..count)
+                .map(|_| body.read_value())
+                .collect::<io::Result<_>>()?;
+
+            let count = body.read_i16()?;
+            let result_formats = (0..count)
+                .map(|_| body.read_i16())
+                .collect::<io::Result<_>>()?;
+
+            Ok(Self {
+                len: 0,
+                portal,
+                statement,
+                param_formats,
+                params,
+                result_formats,
+            })
+        })?;
+
+        Ok(Self { len, ..bind })
+    }
+}
+
+impl Encode for Bind {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'B', |w| {
+            w.write_str(&self.portal)?;
+            w.write_str(&self.statement)?;
+
+            w.write_i16(self.param_formats.len() as i16)?;
+            for format in self.param_formats.iter() {
+                w.write_i16(*format)?;
+            }
+
+            w.write_i16(self.params.len() as i16)?;
+            for param in self.params.iter() {
+                match param {
+                    Some(value) => {
+                        w.write_i32(value.len() as i32)?;
+                        w.write_bytes(value)?;
+                    }
+                    None => w.write_i32(-1)?,
+                }
+            }
+
+            w.write_i16(self.result_formats.len() as i16)?;
+            for format in self.result_formats.iter() {
+                w.write_i16(*format)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+// The legacy fast-path interface, libpq still uses it for the large object functions
+pub struct FunctionCall {
+    pub len: i32,
+    pub function_oid: i32,
+    pub arg_formats: Vec<i16>,
+    // `None` represents a NULL value
+    pub args: Vec<Option<Vec<u8>>>,
+    pub result_format: i16,
+}
+
+impl FunctionCall {
+    pub fn new(function_oid: i32, args: Vec<Option<Vec<u8>>>) -> Self {
+        Self {
+            len: 0,
+            function_oid,
+            arg_formats: vec![],
+            args,
+            result_format: 0,
+        }
+    }
+
+    // Like in Bind no formats means text and a single format applies to all arguments
+    pub fn arg_format(&self, i: usize) -> i16 {
+        match self.arg_formats[..] {
+            [] => 0,
+            [format] => format,
+            ref formats => formats.get(i).copied().unwrap_or(0),
+        }
+    }
+}
+
+impl Decode for FunctionCall {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, call) = reader.read_frame(|body| {
+            let function_oid = body.read_i32()?;
+
+            let count = body.read_i16()?;
+            let arg_formats = (0..count)
+                .map(|_| body.read_i16())
+                .collect::<io::Result<_>>()?;
+
+            let count = body.read_i16()?;
+            let args = (0..count)
+                .map(|_| body.read_value())
+                .collect::<io::Result<_>>()?;
+
+            Ok(Self {
+                len: 0,
+                function_oid,
+                arg_formats,
+                args,
+                result_format: body.read_i16()?,
+            })
+        })?;
+
+        Ok(Self { len, ..call })
+    }
+}
+
+impl Encode for FunctionCall {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'F', |w| {
+            w.write_i32(self.function_oid)?;
+
+            w.write_i16(self.arg_formats.len() as i16)?;
+            for format in self.arg_formats.iter() {
+                w.write_i16(*format)?;
+            }
+
+            w.write_i16(self.args.len() as i16)?;
+            for arg in self.args.iter() {
+                match arg {
+                    Some(value) => {
+                        w.write_i32(value.len() as i32)?;
+                        w.write_bytes(value)?;
+                    }
+                    None => w.write_i32(-1)?,
+                }
+            }
+
+            w.write_i16(self.result_format)
+        })
+    }
+}
+
+// Whether a Describe or Close refers to a prepared statement or a portal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Statement,
+    Portal,
+}
+
+impl Target {
+    fn from_byte(byte: u8) -> ProtocolResult<Self> {
+        match byte {
+            b'S' => Ok(Self::Statement),
+            b'P' => Ok(Self::Portal),
+            _ => Err(ProtocolError::malformed(format!(
+                "invalid target {:?}",
+                byte as char
+            ))),
+        }
+    }
+
+    fn as_byte(&self) -> u8 {
+        match self {
+            Self::Statement => b'S',
+            Self::Portal => b'P',
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Describe {
+    pub len: i32,
+    pub target: Target,
+    pub name: String,
+}
+
+impl Decode for Describe {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, (target, name)) =
+            reader.read_frame(|body| Ok((body.read_byte()?, body.read_string()?)))?;
+
+        Ok(Self {
+            len,
+            target: Target::from_byte(target)?,
+            name,
+        })
+    }
+}
+
+impl Encode for Describe {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'D', |w| {
+            w.write_byte(self.target.as_byte())?;
+            w.write_str(&self.name)
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Execute {
+    pub len: i32,
+    pub portal: String,
+    // Zero means no limit
+    pub max_rows: i32,
+}
+
+impl Decode for Execute {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, (portal, max_rows)) =
+            reader.read_frame(|body| Ok((body.read_string()?, body.read_i32()?)))?;
+
+        Ok(Self {
+            len,
+            portal,
+            max_rows,
+        })
+    }
+}
+
+impl Encode for Execute {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'E', |w| {
+            w.write_str(&self.portal)?;
+            w.write_i32(self.max_rows)
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Close {
+    pub len: i32,
+    pub target: Target,
+    pub name: String,
+}
+
+impl Decode for Close {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, (target, name)) =
+            reader.read_frame(|body| Ok((body.read_byte()?, body.read_string()?)))?;
+
+        Ok(Self {
+            len,
+            target: Target::from_byte(target)?,
+            name,
+        })
+    }
+}
+
+impl Encode for Close {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'C', |w| {
+            w.write_byte(self.target.as_byte())?;
+            w.write_str(&self.name)
+        })
+    }
+}
+
+pub struct Sync {
+    pub len: i32,
+}
+
+impl Decode for Sync {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let (len, _) = reader.read_frame(|_| Ok(()))?;
+
+        Ok(Self { len })
+    }
+}
+
+impl Sync {
+    pub fn new() -> Self {
+        Self { len: 4 }
+    }
+}
+
+impl Default for Sync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encode for Sync {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_message(b'S', |_| Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_unsupported_message() {
+        let mut reader = Reader::new(&b"z\0\0\0\x06abX\0\0\0\x04"[..]);
+
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Err(ProtocolError::Unsupported(b'z'))
+        ));
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Ok(IncomingMessage::Terminate(_))
+        ));
+    }
+
+    #[test]
+    fn test_query() {
+        let mut reader = Reader::new(&b"Q\0\0\0\x0dselect 1\0"[..]);
+
+        match IncomingMessage::decode(&mut reader) {
+            Ok(IncomingMessage::Query(query)) => assert_eq!(query.query, "select 1"),
+            _ => panic!("expected a query"),
+        }
+    }
+
+    #[test]
+    fn test_query_length_mismatch() {
+        // Declared length is one byte longer than the string
+        let mut reader = Reader::new(&b"Q\0\0\0\x0eselect 1\0X\0\0\0\x04"[..]);
+
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Err(ProtocolError::Malformed(_))
+        ));
+
+        // Missing null byte
+        let mut reader = Reader::new(&b"Q\0\0\0\x0cselect 1X\0\0\0\x04"[..]);
+
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let mut reader = Reader::new(&b"Q\0\0\0\x0dselect 1\0"[..]);
+        reader.set_max_message_size(8);
+
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_startup_message() {
+        let mut reader = Reader::new(&b"\0\0\0\x12\0\x03\0\0user\0bob\0\0"[..]);
+
+        match Handshake::decode(&mut reader) {
+            Ok(Handshake::StartupMessage(msg)) => {
+                assert_eq!(msg.version, 196608);
+                assert_eq!(
+                    msg.params.into_iter().collect::<Vec<_>>(),
+                    vec![("user".to_string(), "bob".to_string())]
+                );
+            }
+            _ => panic!("expected a startup message"),
+        }
+
+        let mut writer = Writer::new(vec![]);
+        CancelRequest::new(42, vec![7; 32])
+            .encode(&mut writer)
+            .unwrap();
+
+        match Handshake::decode(&mut Reader::new(writer.buffer())) {
+            Ok(Handshake::CancelRequest(msg)) => {
+                assert_eq!((msg.len, msg.process_id), (44, 42));
+                assert_eq!(msg.secret_key, vec![7; 32]);
+            }
+            _ => panic!("expected a cancel request"),
+        }
+
+        let mut writer = Writer::new(vec![]);
+        CancelRequest::new(42, vec![7; 2])
+            .encode(&mut writer)
+            .unwrap();
+        assert!(Handshake::decode(&mut Reader::new(writer.buffer())).is_err());
+    }
+
+    #[test]
+    fn test_function_call() {
+        let mut call = FunctionCall::new(954, vec![Some(b"\0\0\0\x01".to_vec()), None]);
+        call.arg_formats = vec![1];
+
+        let mut writer = Writer::new(vec![]);
+        call.encode(&mut writer).unwrap();
+
+        match IncomingMessage::decode(&mut Reader::new(writer.buffer())) {
+            Ok(IncomingMessage::FunctionCall(call)) => {
+                assert_eq!(call.function_oid, 954);
+                assert_eq!(call.args, vec![Some(b"\0\0\0\x01".to_vec()), None]);
+                assert_eq!((call.arg_format(0), call.arg_format(1)), (1, 1));
+                assert_eq!(call.result_format, 0);
+            }
+            _ => panic!("expected a function call"),
+        }
+
+        // Argument lengths are checked before allocating
+        let mut reader = Reader::new(&b"F\0\0\0\x12\0\0\x03\xba\0\0\0\x01\x7f\xff\xff\xff\0\0"[..]);
+
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_message_length() {
+        let mut reader = Reader::new(&b"z\0\0\0\x02"[..]);
+
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Err(ProtocolError::Malformed(_))
+        ));
+
+        // Parameter lengths are checked before allocating
+        let mut reader = Reader::new(&b"B\0\0\0\x10\0\0\0\0\0\x01\x7f\xff\xff\xff\0\0"[..]);
+
+        assert!(matches!(
+            IncomingMessage::decode(&mut reader),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+}
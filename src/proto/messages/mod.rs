@@ -1,5 +1,11 @@
 mod frontend;
 mod backend;
+mod copy;
+mod pgoutput;
+mod replication;
 
 pub use frontend::*;
-pub use backend::*;
\ No newline at end of file
+pub use backend::*;
+pub use copy::*;
+pub use pgoutput::*;
+pub use replication::*;
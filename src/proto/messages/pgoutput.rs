@@ -0,0 +1,693 @@
+use std::io::{self, Read, Write};
+
+use crate::proto::messages::Lsn;
+use crate::proto::{Decode, Encode, ProtocolError, ProtocolResult, Reader, Writer};
+
+// The logical replication protocol of the pgoutput plugin, the messages are sent as the data of
+// XLogData. Inside streamed transactions (protocol version 2 and later) most messages carry the
+// transaction id, it's None otherwise.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TupleValue {
+    Null,
+    // TOASTed values which weren't changed aren't sent again
+    Unchanged,
+    Text(Vec<u8>),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TupleData(pub Vec<TupleValue>);
+
+impl Encode for TupleData {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_i16(self.0.len() as i16)?;
+
+        for value in self.0.iter() {
+            match value {
+                TupleValue::Null => writer.write_byte(b'n')?,
+                TupleValue::Unchanged => writer.write_byte(b'u')?,
+                TupleValue::Text(data) | TupleValue::Binary(data) => {
+                    let kind = if matches!(value, TupleValue::Text(_)) {
+                        b't'
+                    } else {
+                        b'b'
+                    };
+
+                    writer.write_byte(kind)?;
+                    writer.write_i32(data.len() as i32)?;
+                    writer.write_bytes(data)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Decode for TupleData {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        let mut values = vec![];
+
+        for _ in 0..reader.read_i16()? {
+            values.push(match reader.read_byte()? {
+                b'n' => TupleValue::Null,
+                b'u' => TupleValue::Unchanged,
+                kind @ (b't' | b'b') => {
+                    let len = reader.read_i32()?;
+
+                    if len < 0 {
+                        return Err(ProtocolError::malformed("negative tuple value length"));
+                    }
+
+                    let data = reader.read_bytes(len as usize)?;
+
+                    if kind == b't' {
+                        TupleValue::Text(data)
+                    } else {
+                        TupleValue::Binary(data)
+                    }
+                }
+                _ => return Err(ProtocolError::malformed("invalid tuple value kind")),
+            });
+        }
+
+        Ok(Self(values))
+    }
+}
+
+// The row before an update or delete, with only the replica identity columns (Key) or all of
+// them (Full, for REPLICA IDENTITY FULL)
+#[derive(Debug, Clone, PartialEq)]
+pub enum OldTuple {
+    Key(TupleData),
+    Full(TupleData),
+}
+
+impl Encode for OldTuple {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        let (kind, tuple) = match self {
+            Self::Key(tuple) => (b'K', tuple),
+            Self::Full(tuple) => (b'O', tuple),
+        };
+
+        writer.write_byte(kind)?;
+        tuple.encode(writer)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplicaIdentity {
+    Default,
+    Nothing,
+    Full,
+    Index,
+}
+
+impl ReplicaIdentity {
+    fn as_byte(&self) -> u8 {
+        match self {
+            Self::Default => b'd',
+            Self::Nothing => b'n',
+            Self::Full => b'f',
+            Self::Index => b'i',
+        }
+    }
+
+    fn from_byte(byte: u8) -> ProtocolResult<Self> {
+        match byte {
+            b'd' => Ok(Self::Default),
+            b'n' => Ok(Self::Nothing),
+            b'f' => Ok(Self::Full),
+            b'i' => Ok(Self::Index),
+            _ => Err(ProtocolError::malformed("invalid replica identity")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelationColumn {
+    // Part of the replica identity
+    pub key: bool,
+    pub name: String,
+    pub type_oid: i32,
+    pub type_modifier: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Begin {
+    pub final_lsn: Lsn,
+    // Microseconds since the PostgreSQL epoch
+    pub commit_time: i64,
+    pub xid: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub commit_lsn: Lsn,
+    pub end_lsn: Lsn,
+    pub commit_time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub commit_lsn: Lsn,
+    pub name: String,
+}
+
+// Describes a table, it's sent before the first change of the table (and again when the table
+// changed) so the changes themselves only refer to the oid
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub xid: Option<u32>,
+    pub oid: i32,
+    pub namespace: String,
+    pub name: String,
+    pub replica_identity: ReplicaIdentity,
+    pub columns: Vec<RelationColumn>,
+}
+
+// Describes a non built-in type used by a relation
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeMessage {
+    pub xid: Option<u32>,
+    pub oid: i32,
+    pub namespace: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Insert {
+    pub xid: Option<u32>,
+    pub oid: i32,
+    pub new: TupleData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Update {
+    pub xid: Option<u32>,
+    pub oid: i32,
+    // Only sent when the key changed (or with REPLICA IDENTITY FULL)
+    pub old: Option<OldTuple>,
+    pub new: TupleData,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delete {
+    pub xid: Option<u32>,
+    pub oid: i32,
+    pub old: OldTuple,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Truncate {
+    pub xid: Option<u32>,
+    pub cascade: bool,
+    pub restart_identity: bool,
+    pub oids: Vec<i32>,
+}
+
+// Written with pg_logical_emit_message()
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogicalMessage {
+    pub xid: Option<u32>,
+    pub transactional: bool,
+    pub lsn: Lsn,
+    pub prefix: String,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamStart {
+    pub xid: u32,
+    // The first segment of the transaction
+    pub first: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamCommit {
+    pub xid: u32,
+    pub commit_lsn: Lsn,
+    pub end_lsn: Lsn,
+    pub commit_time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamAbort {
+    pub xid: u32,
+    // The same as `xid` when the whole transaction was aborted
+    pub subxid: u32,
+    // The abort LSN and time, only sent with protocol version 4 and parallel streaming
+    pub abort: Option<(Lsn, i64)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PgOutputMessage {
+    Begin(Begin),
+    Commit(Commit),
+    Origin(Origin),
+    Relation(Relation),
+    Type(TypeMessage),
+    Insert(Insert),
+    Update(Update),
+    Delete(Delete),
+    Truncate(Truncate),
+    Message(LogicalMessage),
+    StreamStart(StreamStart),
+    StreamStop,
+    StreamCommit(StreamCommit),
+    StreamAbort(StreamAbort),
+}
+
+fn write_xid<W: Write>(writer: &mut Writer<W>, xid: Option<u32>) -> io::Result<()> {
+    match xid {
+        Some(xid) => writer.write_i32(xid as i32),
+        None => Ok(()),
+    }
+}
+
+fn read_xid<R: Read>(reader: &mut Reader<R>, in_stream: bool) -> io::Result<Option<u32>> {
+    Ok(if in_stream {
+        Some(reader.read_i32()? as u32)
+    } else {
+        None
+    })
+}
+
+fn read_lsn<R: Read>(reader: &mut Reader<R>) -> io::Result<Lsn> {
+    Ok(Lsn(reader.read_i64()? as u64))
+}
+
+fn read_old_tuple<R: Read>(reader: &mut Reader<R>, kind: u8) -> ProtocolResult<OldTuple> {
+    match kind {
+        b'K' => Ok(OldTuple::Key(TupleData::decode(reader)?)),
+        b'O' => Ok(OldTuple::Full(TupleData::decode(reader)?)),
+        _ => Err(ProtocolError::malformed("invalid old tuple kind")),
+    }
+}
+
+fn expect_new<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<TupleData> {
+    match reader.read_byte()? {
+        b'N' => TupleData::decode(reader),
+        _ => Err(ProtocolError::malformed("expected a new tuple")),
+    }
+}
+
+impl PgOutputMessage {
+    // Whether the message is decoded as part of a streamed transaction (between StreamStart and
+    // StreamStop) can't be told from the message itself
+    pub fn decode_in_stream<R: Read>(
+        reader: &mut Reader<R>,
+        in_stream: bool,
+    ) -> ProtocolResult<Self> {
+        Ok(match reader.read_byte()? {
+            b'B' => Self::Begin(Begin {
+                final_lsn: read_lsn(reader)?,
+                commit_time: reader.read_i64()?,
+                xid: reader.read_i32()? as u32,
+            }),
+            b'C' => {
+                // Flags, currently unused
+                reader.read_byte()?;
+
+                Self::Commit(Commit {
+                    commit_lsn: read_lsn(reader)?,
+                    end_lsn: read_lsn(reader)?,
+                    commit_time: reader.read_i64()?,
+                })
+            }
+            b'O' => Self::Origin(Origin {
+                commit_lsn: read_lsn(reader)?,
+                name: reader.read_string()?,
+            }),
+            b'R' => {
+                let xid = read_xid(reader, in_stream)?;
+                let oid = reader.read_i32()?;
+                let namespace = reader.read_string()?;
+                let name = reader.read_string()?;
+                let replica_identity = ReplicaIdentity::from_byte(reader.read_byte()?)?;
+                let mut columns = vec![];
+
+                for _ in 0..reader.read_i16()? {
+                    columns.push(RelationColumn {
+                        key: reader.read_byte()? & 1 != 0,
+                        name: reader.read_string()?,
+                        type_oid: reader.read_i32()?,
+                        type_modifier: reader.read_i32()?,
+                    });
+                }
+
+                Self::Relation(Relation {
+                    xid,
+                    oid,
+                    namespace,
+                    name,
+                    replica_identity,
+                    columns,
+                })
+            }
+            b'Y' => Self::Type(TypeMessage {
+                xid: read_xid(reader, in_stream)?,
+                oid: reader.read_i32()?,
+                namespace: reader.read_string()?,
+                name: reader.read_string()?,
+            }),
+            b'I' => Self::Insert(Insert {
+                xid: read_xid(reader, in_stream)?,
+                oid: reader.read_i32()?,
+                new: expect_new(reader)?,
+            }),
+            b'U' => {
+                let xid = read_xid(reader, in_stream)?;
+                let oid = reader.read_i32()?;
+
+                let (old, new) = match reader.read_byte()? {
+                    b'N' => (None, TupleData::decode(reader)?),
+                    kind => (Some(read_old_tuple(reader, kind)?), expect_new(reader)?),
+                };
+
+                Self::Update(Update { xid, oid, old, new })
+            }
+            b'D' => {
+                let xid = read_xid(reader, in_stream)?;
+                let oid = reader.read_i32()?;
+                let kind = reader.read_byte()?;
+
+                Self::Delete(Delete {
+                    xid,
+                    oid,
+                    old: read_old_tuple(reader, kind)?,
+                })
+            }
+            b'T' => {
+                let xid = read_xid(reader, in_stream)?;
+                let count = reader.read_i32()?;
+                let options = reader.read_byte()?;
+                let mut oids = vec![];
+
+                for _ in 0..count {
+                    oids.push(reader.read_i32()?);
+                }
+
+                Self::Truncate(Truncate {
+                    xid,
+                    cascade: options & 1 != 0,
+                    restart_identity: options & 2 != 0,
+                    oids,
+                })
+            }
+            b'M' => {
+                let xid = read_xid(reader, in_stream)?;
+                let transactional = reader.read_byte()? & 1 != 0;
+                let lsn = read_lsn(reader)?;
+                let prefix = reader.read_string()?;
+                let len = reader.read_i32()?;
+
+                if len < 0 {
+                    return Err(ProtocolError::malformed("negative message length"));
+                }
+
+                Self::Message(LogicalMessage {
+                    xid,
+                    transactional,
+                    lsn,
+                    prefix,
+                    content: reader.read_bytes(len as usize)?,
+                })
+            }
+            b'S' => Self::StreamStart(StreamStart {
+                xid: reader.read_i32()? as u32,
+                first: reader.read_byte()? == 1,
+            }),
+            b'E' => Self::StreamStop,
+            b'c' => {
+                let xid = reader.read_i32()? as u32;
+                reader.read_byte()?;
+
+                Self::StreamCommit(StreamCommit {
+                    xid,
+                    commit_lsn: read_lsn(reader)?,
+                    end_lsn: read_lsn(reader)?,
+                    commit_time: reader.read_i64()?,
+                })
+            }
+            b'A' => {
+                let xid = reader.read_i32()? as u32;
+                let subxid = reader.read_i32()? as u32;
+                let rest = reader.read_to_end()?;
+
+                let abort = match rest.len() {
+                    0 => None,
+                    16 => {
+                        let mut rest = Reader::new(&rest[..]);
+                        Some((read_lsn(&mut rest)?, rest.read_i64()?))
+                    }
+                    _ => return Err(ProtocolError::malformed("invalid stream abort")),
+                };
+
+                Self::StreamAbort(StreamAbort { xid, subxid, abort })
+            }
+            tag => return Err(ProtocolError::Unsupported(tag)),
+        })
+    }
+}
+
+impl Decode for PgOutputMessage {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        Self::decode_in_stream(reader, false)
+    }
+}
+
+impl Encode for PgOutputMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        match self {
+            Self::Begin(msg) => {
+                writer.write_byte(b'B')?;
+                writer.write_i64(msg.final_lsn.0 as i64)?;
+                writer.write_i64(msg.commit_time)?;
+                writer.write_i32(msg.xid as i32)
+            }
+            Self::Commit(msg) => {
+                writer.write_byte(b'C')?;
+                writer.write_byte(0)?;
+                writer.write_i64(msg.commit_lsn.0 as i64)?;
+                writer.write_i64(msg.end_lsn.0 as i64)?;
+                writer.write_i64(msg.commit_time)
+            }
+            Self::Origin(msg) => {
+                writer.write_byte(b'O')?;
+                writer.write_i64(msg.commit_lsn.0 as i64)?;
+                writer.write_str(&msg.name)
+            }
+            Self::Relation(msg) => {
+                writer.write_byte(b'R')?;
+                write_xid(writer, msg.xid)?;
+                writer.write_i32(msg.oid)?;
+                writer.write_str(&msg.namespace)?;
+                writer.write_str(&msg.name)?;
+                writer.write_byte(msg.replica_identity.as_byte())?;
+                writer.write_i16(msg.columns.len() as i16)?;
+
+                for column in msg.columns.iter() {
+                    writer.write_byte(column.key as u8)?;
+                    writer.write_str(&column.name)?;
+                    writer.write_i32(column.type_oid)?;
+                    writer.write_i32(column.type_modifier)?;
+                }
+
+                Ok(())
+            }
+            Self::Type(msg) => {
+                writer.write_byte(b'Y')?;
+                write_xid(writer, msg.xid)?;
+                writer.write_i32(msg.oid)?;
+                writer.write_str(&msg.namespace)?;
+                writer.write_str(&msg.name)
+            }
+            Self::Insert(msg) => {
+                writer.write_byte(b'I')?;
+                write_xid(writer, msg.xid)?;
+                writer.write_i32(msg.oid)?;
+                writer.write_byte(b'N')?;
+                msg.new.encode(writer)
+            }
+            Self::Update(msg) => {
+                writer.write_byte(b'U')?;
+                write_xid(writer, msg.xid)?;
+                writer.write_i32(msg.oid)?;
+
+                if let Some(old) = msg.old.as_ref() {
+                    old.encode(writer)?;
+                }
+
+                writer.write_byte(b'N')?;
+                msg.new.encode(writer)
+            }
+            Self::Delete(msg) => {
+                writer.write_byte(b'D')?;
+                write_xid(writer, msg.xid)?;
+                writer.write_i32(msg.oid)?;
+                msg.old.encode(writer)
+            }
+            Self::Truncate(msg) => {
+                writer.write_byte(b'T')?;
+                write_xid(writer, msg.xid)?;
+                writer.write_i32(msg.oids.len() as i32)?;
+                writer.write_byte(msg.cascade as u8 | (msg.restart_identity as u8) << 1)?;
+
+                for oid in msg.oids.iter() {
+                    writer.write_i32(*oid)?;
+                }
+
+                Ok(())
+            }
+            Self::Message(msg) => {
+                writer.write_byte(b'M')?;
+                write_xid(writer, msg.xid)?;
+                writer.write_byte(msg.transactional as u8)?;
+                writer.write_i64(msg.lsn.0 as i64)?;
+                writer.write_str(&msg.prefix)?;
+                writer.write_i32(msg.content.len() as i32)?;
+                writer.write_bytes(&msg.content)
+            }
+            Self::StreamStart(msg) => {
+                writer.write_byte(b'S')?;
+                writer.write_i32(msg.xid as i32)?;
+                writer.write_byte(msg.first as u8)
+            }
+            Self::StreamStop => writer.write_byte(b'E'),
+            Self::StreamCommit(msg) => {
+                writer.write_byte(b'c')?;
+                writer.write_i32(msg.xid as i32)?;
+                writer.write_byte(0)?;
+                writer.write_i64(msg.commit_lsn.0 as i64)?;
+                writer.write_i64(msg.end_lsn.0 as i64)?;
+                writer.write_i64(msg.commit_time)
+            }
+            Self::StreamAbort(msg) => {
+                writer.write_byte(b'A')?;
+                writer.write_i32(msg.xid as i32)?;
+                writer.write_i32(msg.subxid as i32)?;
+
+                match msg.abort {
+                    Some((lsn, time)) => {
+                        writer.write_i64(lsn.0 as i64)?;
+                        writer.write_i64(time)
+                    }
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::messages::{CopyData, ReplicationMessage, XLogData};
+
+    fn round_trip(msg: PgOutputMessage, in_stream: bool) -> Vec<u8> {
+        let data = CopyData::wrap(&msg).unwrap().data;
+        let decoded =
+            PgOutputMessage::decode_in_stream(&mut Reader::new(&data[..]), in_stream).unwrap();
+        assert_eq!(decoded, msg);
+
+        data
+    }
+
+    #[test]
+    fn test_pgoutput() {
+        let data = round_trip(
+            PgOutputMessage::Begin(Begin {
+                final_lsn: Lsn(0x16_B374_D848),
+                commit_time: 1,
+                xid: 740,
+            }),
+            false,
+        );
+        assert_eq!(data.len(), 1 + 8 + 8 + 4);
+
+        let data = round_trip(
+            PgOutputMessage::Insert(Insert {
+                xid: None,
+                oid: 16385,
+                new: TupleData(vec![
+                    TupleValue::Text(b"1".to_vec()),
+                    TupleValue::Null,
+                    TupleValue::Unchanged,
+                ]),
+            }),
+            false,
+        );
+        assert_eq!(data, b"I\0\0\x40\x01N\0\x03t\0\0\0\x011nu".to_vec());
+
+        // The transaction id is only there inside streamed transactions
+        let update = PgOutputMessage::Update(Update {
+            xid: Some(741),
+            oid: 16385,
+            old: Some(OldTuple::Key(TupleData(vec![TupleValue::Binary(vec![
+                0, 0, 0, 1,
+            ])]))),
+            new: TupleData(vec![TupleValue::Text(b"2".to_vec())]),
+        });
+        let data = round_trip(update, true);
+        assert_eq!(&data[..5], b"U\0\0\x02\xe5");
+
+        round_trip(
+            PgOutputMessage::Relation(Relation {
+                xid: None,
+                oid: 16385,
+                namespace: "public".to_string(),
+                name: "users".to_string(),
+                replica_identity: ReplicaIdentity::Default,
+                columns: vec![RelationColumn {
+                    key: true,
+                    name: "id".to_string(),
+                    type_oid: 23,
+                    type_modifier: -1,
+                }],
+            }),
+            false,
+        );
+        round_trip(
+            PgOutputMessage::Truncate(Truncate {
+                xid: None,
+                cascade: false,
+                restart_identity: true,
+                oids: vec![16385, 16390],
+            }),
+            false,
+        );
+        round_trip(
+            PgOutputMessage::StreamAbort(StreamAbort {
+                xid: 741,
+                subxid: 742,
+                abort: Some((Lsn(10), 2)),
+            }),
+            false,
+        );
+
+        // Messages are sent as the data of XLogData
+        let msg = PgOutputMessage::Commit(Commit {
+            commit_lsn: Lsn(1),
+            end_lsn: Lsn(2),
+            commit_time: 3,
+        });
+        let xlog = XLogData::wrap(Lsn(1), Lsn(2), &msg).unwrap();
+        let data = CopyData::wrap(&xlog).unwrap().data;
+
+        match ReplicationMessage::decode(&mut Reader::new(&data[..])).unwrap() {
+            ReplicationMessage::XLogData(xlog) => assert_eq!(
+                PgOutputMessage::decode(&mut Reader::new(&xlog.data[..])).unwrap(),
+                msg
+            ),
+            _ => panic!("expected XLogData"),
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::proto::{Decode, Encode, ProtocolError, ProtocolResult, Reader, Writer};
+
+// Seconds between the Unix epoch and the PostgreSQL epoch (2000-01-01)
+const POSTGRES_EPOCH: u64 = 946_684_800;
+
+// The replication protocol uses microseconds since the PostgreSQL epoch
+pub fn current_timestamp() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    now.as_micros() as i64 - (POSTGRES_EPOCH * 1_000_000) as i64
+}
+
+// A position in the write-ahead log, written as two hexadecimal numbers (`16/B374D848`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Lsn(pub u64);
+
+impl Lsn {
+    // Sent for positions which aren't known (such as the applied position of most clients)
+    pub const INVALID: Lsn = Lsn(0);
+
+    pub fn is_valid(&self) -> bool {
+        *self != Self::INVALID
+    }
+}
+
+impl Display for Lsn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 & 0xffff_ffff)
+    }
+}
+
+impl FromStr for Lsn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid LSN: {}", s);
+        let (high, low) = s.split_once('/').ok_or_else(invalid)?;
+        let high = u32::from_str_radix(high, 16).map_err(|_| invalid())?;
+        let low = u32::from_str_radix(low, 16).map_err(|_| invalid())?;
+
+        Ok(Self(((high as u64) << 32) | low as u64))
+    }
+}
+
+// WAL data (or the output of a logical decoding plugin) sent to the client
+pub struct XLogData {
+    pub wal_start: Lsn,
+    pub wal_end: Lsn,
+    pub send_time: i64,
+    pub data: Vec<u8>,
+}
+
+impl XLogData {
+    pub fn new(wal_start: Lsn, wal_end: Lsn, data: Vec<u8>) -> Self {
+        Self {
+            wal_start,
+            wal_end,
+            send_time: current_timestamp(),
+            data,
+        }
+    }
+
+    // Encodes a message (such as a pgoutput message) as the data
+    pub fn wrap<T: Encode>(wal_start: Lsn, wal_end: Lsn, msg: &T) -> io::Result<Self> {
+        let mut data = vec![];
+        let mut writer = Writer::with_buffer_size(0, &mut data);
+        msg.encode(&mut writer)?;
+        writer.flush()?;
+
+        Ok(Self::new(wal_start, wal_end, data))
+    }
+}
+
+impl Encode for XLogData {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_byte(b'w')?;
+        writer.write_i64(self.wal_start.0 as i64)?;
+        writer.write_i64(self.wal_end.0 as i64)?;
+        writer.write_i64(self.send_time)?;
+        writer.write_bytes(&self.data)
+    }
+}
+
+impl Decode for XLogData {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            wal_start: Lsn(reader.read_i64()? as u64),
+            wal_end: Lsn(reader.read_i64()? as u64),
+            send_time: reader.read_i64()?,
+            data: reader.read_to_end()?,
+        })
+    }
+}
+
+// Sent when there's no WAL to stream, asking for a StandbyStatusUpdate when `reply_requested`
+pub struct PrimaryKeepalive {
+    pub wal_end: Lsn,
+    pub send_time: i64,
+    pub reply_requested: bool,
+}
+
+impl PrimaryKeepalive {
+    pub fn new(wal_end: Lsn, reply_requested: bool) -> Self {
+        Self {
+            wal_end,
+            send_time: current_timestamp(),
+            reply_requested,
+        }
+    }
+}
+
+impl Encode for PrimaryKeepalive {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_byte(b'k')?;
+        writer.write_i64(self.wal_end.0 as i64)?;
+        writer.write_i64(self.send_time)?;
+        writer.write_byte(self.reply_requested as u8)
+    }
+}
+
+impl Decode for PrimaryKeepalive {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            wal_end: Lsn(reader.read_i64()? as u64),
+            send_time: reader.read_i64()?,
+            reply_requested: reader.read_byte()? != 0,
+        })
+    }
+}
+
+// Messages the server sends inside CopyData while streaming
+pub enum ReplicationMessage {
+    XLogData(XLogData),
+    PrimaryKeepalive(PrimaryKeepalive),
+}
+
+impl Decode for ReplicationMessage {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        match reader.read_byte()? {
+            b'w' => Ok(Self::XLogData(XLogData::decode(reader)?)),
+            b'k' => Ok(Self::PrimaryKeepalive(PrimaryKeepalive::decode(reader)?)),
+            tag => Err(ProtocolError::Unsupported(tag)),
+        }
+    }
+}
+
+impl Encode for ReplicationMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        match self {
+            Self::XLogData(msg) => msg.encode(writer),
+            Self::PrimaryKeepalive(msg) => msg.encode(writer),
+        }
+    }
+}
+
+// The progress of the client, everything up to `flushed` can be discarded by the server
+pub struct StandbyStatusUpdate {
+    pub written: Lsn,
+    pub flushed: Lsn,
+    pub applied: Lsn,
+    pub client_time: i64,
+    pub reply_requested: bool,
+}
+
+impl Encode for StandbyStatusUpdate {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_byte(b'r')?;
+        writer.write_i64(self.written.0 as i64)?;
+        writer.write_i64(self.flushed.0 as i64)?;
+        writer.write_i64(self.applied.0 as i64)?;
+        writer.write_i64(self.client_time)?;
+        writer.write_byte(self.reply_requested as u8)
+    }
+}
+
+impl Decode for StandbyStatusUpdate {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            written: Lsn(reader.read_i64()? as u64),
+            flushed: Lsn(reader.read_i64()? as u64),
+            applied: Lsn(reader.read_i64()? as u64),
+            client_time: reader.read_i64()?,
+            reply_requested: reader.read_byte()? != 0,
+        })
+    }
+}
+
+// The oldest transactions still visible on a physical standby, so the server keeps the rows they
+// need. Transaction ids of 0 mean the standby doesn't need any.
+pub struct HotStandbyFeedback {
+    pub client_time: i64,
+    pub xmin: u32,
+    pub epoch: u32,
+    pub catalog_xmin: u32,
+    pub catalog_epoch: u32,
+}
+
+impl Encode for HotStandbyFeedback {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        writer.write_byte(b'h')?;
+        writer.write_i64(self.client_time)?;
+        writer.write_i32(self.xmin as i32)?;
+        writer.write_i32(self.epoch as i32)?;
+        writer.write_i32(self.catalog_xmin as i32)?;
+        writer.write_i32(self.catalog_epoch as i32)
+    }
+}
+
+impl Decode for HotStandbyFeedback {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            client_time: reader.read_i64()?,
+            xmin: reader.read_i32()? as u32,
+            epoch: reader.read_i32()? as u32,
+            catalog_xmin: reader.read_i32()? as u32,
+            catalog_epoch: reader.read_i32()? as u32,
+        })
+    }
+}
+
+// Messages the client sends inside CopyData while streaming
+pub enum StandbyMessage {
+    StatusUpdate(StandbyStatusUpdate),
+    HotStandbyFeedback(HotStandbyFeedback),
+}
+
+impl Decode for StandbyMessage {
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
+    where
+        Self: Sized,
+    {
+        match reader.read_byte()? {
+            b'r' => Ok(Self::StatusUpdate(StandbyStatusUpdate::decode(reader)?)),
+            b'h' => Ok(Self::HotStandbyFeedback(HotStandbyFeedback::decode(
+                reader,
+            )?)),
+            tag => Err(ProtocolError::Unsupported(tag)),
+        }
+    }
+}
+
+impl Encode for StandbyMessage {
+    fn encode<W: Write>(&self, writer: &mut Writer<W>) -> io::Result<()> {
+        match self {
+            Self::StatusUpdate(msg) => msg.encode(writer),
+            Self::HotStandbyFeedback(msg) => msg.encode(writer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::messages::CopyData;
+
+    #[test]
+    fn test_lsn() {
+        let lsn = "16/B374D848".parse::<Lsn>().unwrap();
+        assert_eq!(lsn, Lsn(0x16_B374_D848));
+        assert_eq!(lsn.to_string(), "16/B374D848");
+        assert_eq!(Lsn(0).to_string(), "0/0");
+        assert!("16B374D848".parse::<Lsn>().is_err());
+    }
+
+    #[test]
+    fn test_xlog_data() {
+        let msg = XLogData::new(Lsn(1), Lsn(2), b"change".to_vec());
+        let copy_data = CopyData::wrap(&msg).unwrap();
+
+        assert_eq!(copy_data.data.len(), 1 + 3 * 8 + 6);
+        assert_eq!(copy_data.data[0], b'w');
+
+        match ReplicationMessage::decode(&mut Reader::new(&copy_data.data[..])).unwrap() {
+            ReplicationMessage::XLogData(decoded) => {
+                assert_eq!(decoded.wal_end, Lsn(2));
+                assert_eq!(decoded.data, b"change");
+            }
+            _ => panic!("expected XLogData"),
+        }
+    }
+
+    #[test]
+    fn test_feedback() {
+        let data = CopyData::wrap(&PrimaryKeepalive::new(Lsn(7), true))
+            .unwrap()
+            .data;
+        assert_eq!(data.len(), 1 + 8 + 8 + 1);
+
+        match ReplicationMessage::decode(&mut Reader::new(&data[..])).unwrap() {
+            ReplicationMessage::PrimaryKeepalive(keepalive) => {
+                assert_eq!(keepalive.wal_end, Lsn(7));
+                assert!(keepalive.reply_requested);
+            }
+            _ => panic!("expected a keepalive"),
+        }
+
+        let feedback = HotStandbyFeedback {
+            client_time: current_timestamp(),
+            xmin: 740,
+            epoch: 0,
+            catalog_xmin: 0,
+            catalog_epoch: 0,
+        };
+        let data = CopyData::wrap(&feedback).unwrap().data;
+        assert_eq!(data.len(), 1 + 8 + 4 * 4);
+
+        match StandbyMessage::decode(&mut Reader::new(&data[..])).unwrap() {
+            StandbyMessage::HotStandbyFeedback(decoded) => assert_eq!(decoded.xmin, 740),
+            _ => panic!("expected hot standby feedback"),
+        }
+    }
+}
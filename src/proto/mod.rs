@@ -1,3 +1,4 @@
+mod error;
 pub mod messages;
 mod reader;
 mod writer;
@@ -5,7 +6,8 @@ mod writer;
 use std::io;
 use std::io::{Read, Write};
 
-pub use reader::Reader;
+pub use error::{ProtocolError, ProtocolResult};
+pub use reader::{Reader, DEFAULT_MAX_MESSAGE_SIZE};
 pub use writer::Writer;
 
 pub trait Encode {
@@ -13,7 +15,7 @@ pub trait Encode {
 }
 
 pub trait Decode {
-    fn decode<R: Read>(reader: &mut Reader<R>) -> io::Result<Self>
+    fn decode<R: Read>(reader: &mut Reader<R>) -> ProtocolResult<Self>
     where
         Self: Sized;
 }
@@ -1,17 +1,37 @@
 use std::io;
 use std::io::{BufRead, BufReader, Read};
 
+use crate::proto::{ProtocolError, ProtocolResult};
+
+// Same limit PostgreSQL uses for regular messages (PQ_LARGE_MESSAGE_LIMIT)
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 0x3fffffff;
+
 pub struct Reader<R: Read> {
     buf_reader: BufReader<R>,
+    max_message_size: usize,
 }
 
 impl<R: Read> Reader<R> {
     pub fn new(inner: R) -> Self {
         Self {
             buf_reader: BufReader::new(inner),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
         }
     }
 
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    // Whether there is data that can be read without blocking on the underlying stream
+    pub fn has_buffered(&self) -> bool {
+        !self.buf_reader.buffer().is_empty()
+    }
+
     pub fn peek(&mut self) -> io::Result<Option<&u8>> {
         let buf = self.buf_reader.fill_buf()?;
 
@@ -28,6 +48,13 @@ impl<R: Read> Reader<R> {
         Ok(buf[0])
     }
 
+    pub fn read_i16(&mut self) -> io::Result<i16> {
+        let mut buf = [0; 2];
+        self.buf_reader.read_exact(&mut buf)?;
+
+        Ok(i16::from_be_bytes(buf))
+    }
+
     pub fn read_i32(&mut self) -> io::Result<i32> {
         let mut buf = [0; 4];
         self.buf_reader.read_exact(&mut buf)?;
@@ -35,12 +62,104 @@ impl<R: Read> Reader<R> {
         Ok(i32::from_be_bytes(buf))
     }
 
+    pub fn read_i64(&mut self) -> io::Result<i64> {
+        let mut buf = [0; 8];
+        self.buf_reader.read_exact(&mut buf)?;
+
+        Ok(i64::from_be_bytes(buf))
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; n];
+        self.buf_reader.read_exact(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    pub fn read_to_end(&mut self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.buf_reader.read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    pub fn skip(&mut self, n: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.buf_reader).take(n), &mut io::sink())?;
+
+        if skipped < n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(())
+    }
+
+    // Reads the length of a message (which includes the length itself) and validates it against
+    // the given limit
+    pub fn read_len(&mut self, limit: usize) -> ProtocolResult<i32> {
+        let len = self.read_i32()?;
+
+        if len < 4 {
+            return Err(ProtocolError::malformed(format!(
+                "invalid message length {}",
+                len
+            )));
+        }
+
+        if len as usize > limit {
+            return Err(ProtocolError::malformed(format!(
+                "message length {} exceeds the maximum of {}",
+                len, limit
+            )));
+        }
+
+        Ok(len)
+    }
+
+    // Reads a length-prefixed message body and decodes it from a bounded buffer, which makes sure
+    // that a decoder can never read past the end of its message or leave bytes behind
+    pub fn read_frame<T, F>(&mut self, f: F) -> ProtocolResult<(i32, T)>
+    where
+        F: FnOnce(&mut Reader<&[u8]>) -> ProtocolResult<T>,
+    {
+        self.read_frame_with_limit(self.max_message_size, f)
+    }
+
+    pub fn read_frame_with_limit<T, F>(&mut self, limit: usize, f: F) -> ProtocolResult<(i32, T)>
+    where
+        F: FnOnce(&mut Reader<&[u8]>) -> ProtocolResult<T>,
+    {
+        let len = self.read_len(limit)?;
+        let buf = self.read_bytes(len as usize - 4)?;
+        let mut body = Reader::new(&buf[..]);
+
+        let value = f(&mut body).map_err(|e| match e {
+            ProtocolError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                ProtocolError::malformed(format!("message length {} is too short", len))
+            }
+            e => e,
+        })?;
+
+        if body.peek()?.is_some() {
+            return Err(ProtocolError::malformed(format!(
+                "message length {} doesn't match its contents",
+                len
+            )));
+        }
+
+        Ok((len, value))
+    }
+
     pub fn read_string_bytes(&mut self) -> io::Result<Vec<u8>> {
         let mut buf = vec![];
         self.buf_reader.read_until(b'\0', &mut buf)?;
 
-        // Remove the null byte
-        buf.pop();
+        // Remove the null byte (which is missing when we hit the end of the stream)
+        if buf.pop() != Some(b'\0') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unterminated string",
+            ));
+        }
 
         Ok(buf)
     }
@@ -50,3 +169,21 @@ impl<R: Read> Reader<R> {
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
 }
+
+// Readers over the body of a single message, as passed to the decoders by `read_frame`
+impl Reader<&[u8]> {
+    // The number of bytes left in the body
+    pub fn remaining(&self) -> usize {
+        self.buf_reader.buffer().len() + self.buf_reader.get_ref().len()
+    }
+
+    // Reads a value prefixed with its length, where -1 represents NULL. The length is checked
+    // against the rest of the body before allocating, lengths sent by a client can't be trusted.
+    pub fn read_value(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.read_i32()? {
+            len if len < 0 => Ok(None),
+            len if len as usize > self.remaining() => Err(io::ErrorKind::UnexpectedEof.into()),
+            len => Ok(Some(self.read_bytes(len as usize)?)),
+        }
+    }
+}
@@ -1,25 +1,104 @@
 use std::io;
 use std::io::Write;
 
+// Once this many bytes are buffered they are handed to the underlying stream even without an
+// explicit flush, so large results don't have to be kept in memory completely
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
 pub struct Writer<W: Write> {
     inner: W,
+    buf: Vec<u8>,
+    buffer_size: usize,
 }
 
 impl<W: Write> Writer<W> {
     pub fn new(inner: W) -> Self {
-        Self { inner }
+        Self::with_buffer_size(DEFAULT_BUFFER_SIZE, inner)
+    }
+
+    pub fn with_buffer_size(buffer_size: usize, inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(buffer_size),
+            buffer_size,
+        }
+    }
+
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
     }
 
     pub fn write_byte(&mut self, byte: u8) -> io::Result<()> {
-        self.inner.write_all(&[byte])
+        self.buf.push(byte);
+        Ok(())
+    }
+
+    pub fn write_i16(&mut self, value: i16) -> io::Result<()> {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        Ok(())
     }
 
     pub fn write_i32(&mut self, value: i32) -> io::Result<()> {
-        self.inner.write_all(&value.to_be_bytes())
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    pub fn write_i64(&mut self, value: i64) -> io::Result<()> {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(bytes);
+        Ok(())
     }
 
     pub fn write_str(&mut self, s: &str) -> io::Result<()> {
-        self.inner.write_all(s.as_bytes())?;
-        self.inner.write_all(&[0])
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.push(0);
+        Ok(())
+    }
+
+    // Writes a complete message, the length is back-patched once the body has been written so
+    // encoders don't have to compute it up front
+    pub fn write_message<F>(&mut self, tag: u8, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Self) -> io::Result<()>,
+    {
+        self.write_byte(tag)?;
+        self.write_frame(f)
+    }
+
+    // Same as `write_message` but without a tag (for example the startup message)
+    pub fn write_frame<F>(&mut self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Self) -> io::Result<()>,
+    {
+        let start = self.buf.len();
+        self.write_i32(0)?;
+
+        f(self)?;
+
+        let len = i32::try_from(self.buf.len() - start)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+        self.buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+
+        if self.buf.len() >= self.buffer_size {
+            self.write_buffered()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_buffered(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_buffered()?;
+        self.inner.flush()
     }
 }